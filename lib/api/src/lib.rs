@@ -96,7 +96,10 @@ pub mod compiler {
     };
     pub use wasmer_runtime_core::compile_with;
     #[cfg(unix)]
-    pub use wasmer_runtime_core::fault::{pop_code_version, push_code_version};
+    pub use wasmer_runtime_core::fault::{
+        pop_code_version, push_code_version, register_code_version, with_code_versions,
+        CodeVersionGuard,
+    };
     pub use wasmer_runtime_core::state::CodeVersion;
 
     /// Enum used to select which compiler should be used to generate code.
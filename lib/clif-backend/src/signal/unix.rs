@@ -111,7 +111,13 @@ pub fn call_protected<T>(
                             Signal::from_c_int(signum)
                         ),
                     };
-                    Err(InvokeError::TrapCode { srcloc, code })
+                    Err(InvokeError::TrapCode {
+                        srcloc,
+                        code,
+                        memory_size: None,
+                        code_base: None,
+                        module_offset: None,
+                    })
                 } else {
                     let signal = match Signal::from_c_int(signum) {
                         Ok(SIGFPE) => "floating-point exception",
@@ -124,6 +130,7 @@ pub fn call_protected<T>(
                     // When the trap-handler is fully implemented, this will return more information.
                     Err(InvokeError::UnknownTrap {
                         address: faulting_addr as usize,
+                        ip: Some(inst_ptr as usize),
                         signal,
                     })
                 }
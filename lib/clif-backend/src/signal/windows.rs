@@ -111,6 +111,7 @@ pub fn call_protected(
                 let signal = get_signal_name(code as DWORD);
                 return Err(InvokeError::UnknownTrap {
                     address: exception_address as usize,
+                    ip: Some(exception_address as usize),
                     signal,
                 });
             }
@@ -118,12 +119,16 @@ pub fn call_protected(
         return Err(InvokeError::TrapCode {
             srcloc,
             code: exception_code,
+            memory_size: None,
+            code_base: None,
+            module_offset: None,
         });
     } else {
         let signal = get_signal_name(code as DWORD);
 
         Err(InvokeError::UnknownTrap {
             address: exception_address as usize,
+            ip: Some(exception_address as usize),
             signal,
         })
     }
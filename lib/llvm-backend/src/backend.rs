@@ -133,19 +133,17 @@ unsafe extern "C" fn invoke_trampoline(
     // Translate trap code if an error occurred.
     if !ret && (*error_out).is_none() && trap_out != -1 {
         *error_out = {
-            let exception_code = match trap_out {
-                0 => ExceptionCode::Unreachable,
-                1 => ExceptionCode::IncorrectCallIndirectSignature,
-                2 => ExceptionCode::MemoryOutOfBounds,
-                3 => ExceptionCode::CallIndirectOOB,
-                4 => ExceptionCode::IllegalArithmetic,
-                5 => ExceptionCode::MisalignedAtomicAccess,
-                _ => return ret,
+            let exception_code = match ExceptionCode::from_u32(trap_out as u32) {
+                Some(code) => code,
+                None => return ret,
             };
             Some(RuntimeError::InvokeError(InvokeError::TrapCode {
                 code: exception_code,
                 // TODO:
                 srcloc: 0,
+                memory_size: None,
+                code_base: None,
+                module_offset: None,
             }))
         };
     }
@@ -23,6 +23,20 @@ fn main() {
         .write_all(hash_string.as_bytes())
         .expect("Could not write to file for wasmer hash value");
 
+    // The hard cap on the number of frames captured in an `ExecutionStateImage`.
+    println!("cargo:rerun-if-env-changed=WASMER_MAX_BACKTRACE_FRAMES");
+    let max_backtrace_frames: usize = env::var("WASMER_MAX_BACKTRACE_FRAMES")
+        .map(|x| {
+            x.parse()
+                .expect("WASMER_MAX_BACKTRACE_FRAMES must be an unsigned integer")
+        })
+        .unwrap_or(4096);
+    fs::write(
+        PathBuf::from(&crate_dir).join("max_backtrace_frames.rs"),
+        format!("{}", max_backtrace_frames),
+    )
+    .expect("Could not write max backtrace frames");
+
     // Enable "nightly" cfg if the current compiler is nightly.
     if rustc_version::version_meta().unwrap().channel == rustc_version::Channel::Nightly {
         println!("cargo:rustc-cfg=nightly");
@@ -32,7 +46,11 @@ fn main() {
     let target_arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap();
 
     match (target_os.as_str(), target_arch.as_str()) {
-        ("freebsd", "x86_64") => {
+        ("freebsd", "x86_64")
+        | ("openbsd", "x86_64")
+        | ("netbsd", "x86_64")
+        | ("illumos", "x86_64")
+        | ("solaris", "x86_64") => {
             cc::Build::new()
                 .file("image-loading-freebsd-x86-64.s")
                 .compile("image-loading");
@@ -47,6 +65,47 @@ fn main() {
                 .file("image-loading-macos-x86-64.s")
                 .compile("image-loading");
         }
+        // Other ELF targets share the System V calling convention.
+        (_, "x86_64") if env::var("CARGO_CFG_UNIX").is_ok() => {
+            cc::Build::new()
+                .file("image-loading-freebsd-x86-64.s")
+                .compile("image-loading");
+        }
+        ("macos", "aarch64") => {
+            cc::Build::new()
+                .file("image-loading-macos-aarch64.s")
+                .compile("image-loading");
+        }
+        (_, "aarch64") if env::var("CARGO_CFG_UNIX").is_ok() => {
+            cc::Build::new()
+                .file("image-loading-aarch64.s")
+                .compile("image-loading");
+        }
         _ => {}
     }
+
+    // Keep in sync with the `get_fault_info` implementations in `src/fault.rs`.
+    let fault_info_supported = match (target_os.as_str(), target_arch.as_str()) {
+        ("linux", "x86_64")
+        | ("linux", "aarch64")
+        | ("linux", "arm")
+        | ("linux", "riscv64")
+        | ("android", "x86_64")
+        | ("android", "aarch64")
+        | ("freebsd", "x86_64")
+        | ("freebsd", "aarch64")
+        | ("openbsd", "x86_64")
+        | ("netbsd", "x86_64")
+        | ("illumos", "x86_64")
+        | ("solaris", "x86_64")
+        | ("macos", "x86_64")
+        | ("macos", "aarch64") => true,
+        _ => false,
+    };
+    if !fault_info_supported && env::var("CARGO_CFG_UNIX").is_ok() {
+        println!(
+            "cargo:warning=Fault state cannot be read on {}-{}: traps are reported as generic errors without a backtrace",
+            target_arch, target_os
+        );
+    }
 }
@@ -0,0 +1,59 @@
+// NOTE: Keep this consistent with `AltStackLayout` in `fault.rs`.
+
+.globl run_on_alternative_stack
+run_on_alternative_stack:
+// (stack_end, stack_begin)
+// Save the callee-saved registers on the current stack, which stays 16-byte aligned.
+stp x29, x30, [sp, #-16]!
+stp x27, x28, [sp, #-16]!
+stp x25, x26, [sp, #-16]!
+stp x23, x24, [sp, #-16]!
+stp x21, x22, [sp, #-16]!
+stp x19, x20, [sp, #-16]!
+stp d14, d15, [sp, #-16]!
+stp d12, d13, [sp, #-16]!
+stp d10, d11, [sp, #-16]!
+stp d8, d9, [sp, #-16]!
+mov x2, sp
+str x2, [x0, #-16]
+
+adr x2, run_on_alternative_stack.returning
+str x2, [x0, #-24]
+
+// Load x0-x28 from [stack_begin..], skipping x16-x18: x16 and x17 are used here, and x18 is
+// the platform register.
+mov x16, x1
+ldp x0, x1, [x16], #16
+ldp x2, x3, [x16], #16
+ldp x4, x5, [x16], #16
+ldp x6, x7, [x16], #16
+ldp x8, x9, [x16], #16
+ldp x10, x11, [x16], #16
+ldp x12, x13, [x16], #16
+ldp x14, x15, [x16], #16
+add x16, x16, #24
+ldp x19, x20, [x16], #16
+ldp x21, x22, [x16], #16
+ldp x23, x24, [x16], #16
+ldp x25, x26, [x16], #16
+ldp x27, x28, [x16], #16
+
+// Skip the padding slot, then load the target and the return address.
+ldp x17, x30, [x16, #8]
+add sp, x16, #24
+br x17
+
+run_on_alternative_stack.returning:
+ldr x1, [sp]
+mov sp, x1
+ldp d8, d9, [sp], #16
+ldp d10, d11, [sp], #16
+ldp d12, d13, [sp], #16
+ldp d14, d15, [sp], #16
+ldp x19, x20, [sp], #16
+ldp x21, x22, [sp], #16
+ldp x23, x24, [sp], #16
+ldp x25, x26, [sp], #16
+ldp x27, x28, [sp], #16
+ldp x29, x30, [sp], #16
+ret
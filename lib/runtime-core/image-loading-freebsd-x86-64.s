@@ -1,4 +1,4 @@
-# NOTE: Keep this consistent with `fault.rs`.
+# NOTE: Keep this consistent with `AltStackLayout` in `fault.rs`.
 
 .globl run_on_alternative_stack
 run_on_alternative_stack:
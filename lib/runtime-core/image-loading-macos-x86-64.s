@@ -1,4 +1,4 @@
-# NOTE: Keep this consistent with `fault.rs`.
+# NOTE: Keep this consistent with `AltStackLayout` in `fault.rs`.
 
 .globl _run_on_alternative_stack
 _run_on_alternative_stack:
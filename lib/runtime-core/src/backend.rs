@@ -17,6 +17,7 @@ use std::fmt;
 use std::{any::Any, ptr::NonNull};
 
 use std::collections::HashMap;
+use std::ops::Range;
 
 pub mod sys {
     pub use crate::sys::*;
@@ -35,10 +36,28 @@ pub enum Architecture {
 
 /// The type of an inline breakpoint.
 #[repr(u8)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum InlineBreakpointType {
     /// A middleware invocation breakpoint.
-    Middleware,
+    Middleware = 0,
+    /// A tracing breakpoint, calling the handler set with `fault::set_trace_breakpoint_handler`.
+    /// The handler cannot fail, so execution always resumes after the breakpoint.
+    Trace = 1,
+    /// A counting breakpoint, incrementing a per-site counter before resuming; see
+    /// `fault::take_counter_breakpoint_counts`.
+    Counter = 2,
+}
+
+impl InlineBreakpointType {
+    /// Decodes the byte an inline breakpoint of this type is emitted with, i.e. `ty as u8`.
+    pub fn from_u8(x: u8) -> Option<InlineBreakpointType> {
+        match x {
+            0 => Some(InlineBreakpointType::Middleware),
+            1 => Some(InlineBreakpointType::Trace),
+            2 => Some(InlineBreakpointType::Counter),
+            _ => None,
+        }
+    }
 }
 
 /// Information of an inline breakpoint.
@@ -193,16 +212,38 @@ impl CompilerConfig {
 pub struct ExceptionTable {
     /// Mappings from offsets in generated machine code to the corresponding exception code.
     pub offset_to_code: HashMap<usize, ExceptionCode>,
+    /// Mappings from offsets in generated machine code to the source location, as an offset
+    /// into the wasm binary, of the operator that generated them.
+    pub offset_to_srcloc: HashMap<usize, u32>,
 }
 
 impl ExceptionTable {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// Returns the exception code of the trap raised at `offset` in the generated machine
+    /// code, if there is one.
+    pub fn code_at(&self, offset: usize) -> Option<ExceptionCode> {
+        self.offset_to_code.get(&offset).cloned()
+    }
+
+    /// Returns the trap sites whose offset is within `range`, e.g. the code of a function,
+    /// sorted by offset.
+    pub fn trap_sites(&self, range: Range<usize>) -> Vec<(usize, ExceptionCode)> {
+        let mut sites: Vec<(usize, ExceptionCode)> = self
+            .offset_to_code
+            .iter()
+            .filter(|(offset, _)| range.contains(*offset))
+            .map(|(offset, code)| (*offset, *code))
+            .collect();
+        sites.sort();
+        sites
+    }
 }
 
 /// The code of an exception.
-#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
 pub enum ExceptionCode {
     /// An `unreachable` opcode was executed.
     Unreachable = 0,
@@ -216,6 +257,24 @@ pub enum ExceptionCode {
     IllegalArithmetic = 4,
     /// Misaligned atomic access trap.
     MisalignedAtomicAccess = 5,
+    /// The native stack bound set in the `Ctx` was exceeded.
+    CallStackExhausted = 6,
+}
+
+impl ExceptionCode {
+    /// Decodes the raw numeric value of an exception code, i.e. `code as u32`.
+    pub fn from_u32(x: u32) -> Option<ExceptionCode> {
+        match x {
+            0 => Some(ExceptionCode::Unreachable),
+            1 => Some(ExceptionCode::IncorrectCallIndirectSignature),
+            2 => Some(ExceptionCode::MemoryOutOfBounds),
+            3 => Some(ExceptionCode::CallIndirectOOB),
+            4 => Some(ExceptionCode::IllegalArithmetic),
+            5 => Some(ExceptionCode::MisalignedAtomicAccess),
+            6 => Some(ExceptionCode::CallStackExhausted),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for ExceptionCode {
@@ -232,6 +291,7 @@ impl fmt::Display for ExceptionCode {
                 ExceptionCode::CallIndirectOOB => "`call_indirect` out-of-bounds",
                 ExceptionCode::IllegalArithmetic => "illegal arithmetic operation",
                 ExceptionCode::MisalignedAtomicAccess => "misaligned atomic access",
+                ExceptionCode::CallStackExhausted => "call stack exhausted",
             }
         )
     }
@@ -272,6 +332,12 @@ pub trait RunnableModule: Send + Sync {
         None
     }
 
+    /// Returns the exception code of the trap raised at `offset` in the machine code of this
+    /// module, without faulting. `None` if there is no trap there or no exception table.
+    fn exception_code_at(&self, offset: usize) -> Option<ExceptionCode> {
+        self.get_exception_table()?.code_at(offset)
+    }
+
     unsafe fn patch_local_function(&self, _idx: usize, _target_address: usize) -> bool {
         false
     }
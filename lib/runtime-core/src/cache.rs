@@ -91,7 +91,9 @@ impl WasmHash {
     }
 }
 
-const CURRENT_CACHE_VERSION: u64 = 0;
+/// Bumped whenever the serialized layout of a backend's cache image changes, e.g. when
+/// `ExceptionTable::offset_to_srcloc` was added.
+const CURRENT_CACHE_VERSION: u64 = 1;
 static WASMER_CACHE_MAGIC: [u8; 8] = *b"WASMER\0\0";
 
 /// The header of a cache file.
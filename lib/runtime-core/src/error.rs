@@ -183,6 +183,8 @@ pub enum InvokeError {
     UnknownTrap {
         /// The address that the trap occurred at.
         address: usize,
+        /// The address of the faulting instruction, if known.
+        ip: Option<usize>,
         /// The name of the signal.
         signal: &'static str,
     },
@@ -192,6 +194,14 @@ pub enum InvokeError {
         code: ExceptionCode,
         /// Where in the Wasm file this trap orginated from.
         srcloc: u32,
+        /// The size of the linear memory in bytes when the trap occurred, if known. Only
+        /// reported for `ExceptionCode::MemoryOutOfBounds` traps.
+        memory_size: Option<usize>,
+        /// The base address of the code of the `CodeVersion` the trap occurred in, if known.
+        /// Identifies the loaded module among those running on the thread.
+        code_base: Option<usize>,
+        /// Offset of the trapping instruction from `code_base`, if known.
+        module_offset: Option<usize>,
     },
     /// A trap occurred that Wasmer knows about but it had a trap code that
     /// we weren't expecting or that we do not handle.  This error may be backend-specific.
@@ -206,6 +216,49 @@ pub enum InvokeError {
     /// Indicates that a breakpoint was hit. The inner value is dependent upon
     /// the middleware or backend being used.
     Breakpoint(Box<RuntimeError>),
+    /// Execution was interrupted by `interrupt_and_capture_memory`. Contains a copy of the
+    /// linear memory at the time of the interrupt.
+    PartialResult(Vec<u8>),
+    /// Execution was interrupted because the deadline of `run_with_timeout` elapsed.
+    Timeout,
+    /// Execution was interrupted through an `InterruptHandle`, or by an interrupt set with
+    /// `set_wasm_interrupt_with_reason`.
+    Interrupted {
+        /// Whether a SIGINT was also delivered while the interrupt was pending.
+        from_sigint: bool,
+        /// The reason the interrupt was set with, if any.
+        reason: Option<InterruptReason>,
+    },
+    /// The stack overflowed into a guard region registered with
+    /// `fault::register_guard_region`.
+    StackOverflow,
+    /// Execution was suspended while no `Ctx` was current, e.g. by a fault in host code
+    /// running outside of `fault::with_ctx`, so no instance image could be built.
+    NoContext,
+    /// The process was sent `SIGABRT`, e.g. by a call to `abort()`, while running wasm code.
+    /// Only reported if enabled with `fault::set_catch_sigabrt`.
+    Aborted,
+}
+
+/// Why an interrupt was set, reported by `InvokeError::Interrupted`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptReason {
+    /// The instruction or gas budget of the call is exhausted.
+    GasExhausted,
+    /// The call ran out of time.
+    Timeout,
+    /// The host asked for the call to stop.
+    HostRequested,
+}
+
+impl std::fmt::Display for InterruptReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            InterruptReason::GasExhausted => write!(f, "gas exhausted"),
+            InterruptReason::Timeout => write!(f, "timeout"),
+            InterruptReason::HostRequested => write!(f, "requested by the host"),
+        }
+    }
 }
 
 impl From<InvokeError> for RuntimeError {
@@ -223,13 +276,28 @@ impl std::fmt::Display for InvokeError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             InvokeError::FailedWithNoError => write!(f, "Invoke failed with no error"),
-            InvokeError::UnknownTrap { address, signal } => write!(
-                f,
-                "An unknown trap (`{}`) occured at 0x{:X}",
-                signal, address
-            ),
-            InvokeError::TrapCode { code, srcloc } => {
-                write!(f, "A `{}` trap was thrown at code offset {}", code, srcloc)
+            InvokeError::UnknownTrap { address, ip, signal } => {
+                write!(
+                    f,
+                    "An unknown trap (`{}`) occured at 0x{:X}",
+                    signal, address
+                )?;
+                if let Some(ip) = ip {
+                    write!(f, " (instruction at 0x{:X})", ip)?;
+                }
+                Ok(())
+            }
+            InvokeError::TrapCode {
+                code,
+                srcloc,
+                memory_size,
+                ..
+            } => {
+                write!(f, "A `{}` trap was thrown at code offset {}", code, srcloc)?;
+                if let Some(size) = memory_size {
+                    write!(f, " (linear memory size: {} bytes)", size)?;
+                }
+                Ok(())
             }
             InvokeError::UnknownTrapCode { trap_code, srcloc } => write!(
                 f,
@@ -238,6 +306,28 @@ impl std::fmt::Display for InvokeError {
             ),
             InvokeError::EarlyTrap(rte) => write!(f, "Early trap: {}", rte),
             InvokeError::Breakpoint(rte) => write!(f, "Breakpoint hit: {}", rte),
+            InvokeError::PartialResult(memory) => write!(
+                f,
+                "Interrupted with {} bytes of linear memory captured",
+                memory.len()
+            ),
+            InvokeError::Timeout => write!(f, "Execution timed out"),
+            InvokeError::Interrupted {
+                from_sigint,
+                reason,
+            } => {
+                write!(f, "Execution interrupted")?;
+                if *from_sigint {
+                    write!(f, " by SIGINT")?;
+                }
+                if let Some(reason) = reason {
+                    write!(f, " ({})", reason)?;
+                }
+                Ok(())
+            }
+            InvokeError::StackOverflow => write!(f, "Stack overflow"),
+            InvokeError::NoContext => write!(f, "Execution was suspended outside of an instance"),
+            InvokeError::Aborted => write!(f, "Execution was aborted"),
         }
     }
 }
@@ -5,10 +5,14 @@ pub mod raw {
     //! The raw module contains required externed function interfaces for the fault module.
     use std::ffi::c_void;
 
-    #[cfg(target_arch = "x86_64")]
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
     extern "C" {
         /// Load registers and return on the stack [stack_end..stack_begin].
         pub fn run_on_alternative_stack(stack_end: *mut u64, stack_begin: *mut u64) -> u64;
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    extern "C" {
         /// Internal routine for switching into a backend without information about where registers are preserved.
         pub fn register_preservation_trampoline(); // NOT safe to call directly
     }
@@ -27,33 +31,116 @@ pub mod raw {
     }
 }
 
-use crate::codegen::{BreakpointInfo, BreakpointMap};
-use crate::error::{InvokeError, RuntimeError};
-use crate::state::x64::{build_instance_image, read_stack, X64Register, GPR};
-use crate::state::{CodeVersion, ExecutionStateImage};
+use crate::backend::ExceptionCode;
+use crate::codegen::{BreakpointHandler, BreakpointInfo, BreakpointMap};
+use crate::error::{InterruptReason, InvokeError, RuntimeError};
+use crate::instance::Instance;
+use crate::state::x64::{
+    build_instance_image, invoke_call_return_on_stack, read_stack, X64Register, GPR, XMM,
+};
+use crate::state::{
+    BacktraceFrame, CodeVersion, ExecutionStateImage, HostFrame, InstanceImage, RegisterIndex,
+    MAX_BACKTRACE_FRAMES,
+};
+use crate::structures::TypedIndex;
+use crate::types::{ImportedFuncIndex, LocalGlobalIndex};
 use crate::vm;
-use libc::{mmap, mprotect, siginfo_t, MAP_ANON, MAP_PRIVATE, PROT_NONE, PROT_READ, PROT_WRITE};
+use libc::{
+    mmap, mprotect, munmap, siginfo_t, MAP_ANON, MAP_PRIVATE, PROT_NONE, PROT_READ, PROT_WRITE,
+};
 use nix::sys::signal::{
-    sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal, SIGBUS, SIGFPE, SIGILL, SIGINT,
-    SIGSEGV, SIGTRAP,
+    sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal, SIGABRT, SIGBUS, SIGFPE, SIGILL,
+    SIGINT, SIGSEGV, SIGTRAP,
 };
+use std::any::Any;
 use std::cell::{Cell, RefCell, UnsafeCell};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
 use std::ffi::c_void;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::ops::Range;
+use std::panic;
 use std::process;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Once;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, Once, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-#[cfg(target_arch = "x86_64")]
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
 pub(crate) unsafe fn run_on_alternative_stack(stack_end: *mut u64, stack_begin: *mut u64) -> u64 {
     raw::run_on_alternative_stack(stack_end, stack_begin)
 }
 
-#[cfg(not(target_arch = "x86_64"))]
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
 pub(crate) unsafe fn run_on_alternative_stack(_stack_end: *mut u64, _stack_begin: *mut u64) -> u64 {
     unimplemented!("run_on_alternative_stack");
 }
 
-const TRAP_STACK_SIZE: usize = 1048576; // 1MB
+/// The layout of the stack that `run_on_alternative_stack` switches to, in 8-byte slots.
+///
+/// The registers are loaded from `stack_begin` up, after which the target is jumped to with the
+/// stack pointer right above its slot. The other slots are counted down from `stack_end`.
+///
+/// NOTE: Keep this consistent with `image-loading-*.s`.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct AltStackLayout {
+    /// The slot holding the address jumped to once the registers are loaded.
+    pub target_slot: usize,
+    /// The slot where the address returned to from the target is stored.
+    #[allow(dead_code)]
+    pub returning_slot: usize,
+    /// The slot where the stack pointer of the caller is stored.
+    #[allow(dead_code)]
+    pub saved_sp_slot: usize,
+    /// The number of slots loaded into registers, right below `target_slot`.
+    pub num_saved_registers: usize,
+    /// The index among them of the slot loaded into the first argument register.
+    pub arg_register_index: usize,
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+impl AltStackLayout {
+    /// Returns `stack_begin` for a stack ending at `stack_end`.
+    pub unsafe fn stack_begin(&self, stack_end: *mut u64) -> *mut u64 {
+        stack_end.sub(self.target_slot + self.num_saved_registers)
+    }
+}
+
+/// xmm0-xmm15, then rbp, rax, rbx, rcx, rdx, rdi, rsi and r8-r15.
+#[cfg(target_arch = "x86_64")]
+pub(crate) const ALT_STACK_LAYOUT: AltStackLayout = AltStackLayout {
+    target_slot: 4,
+    returning_slot: 3,
+    saved_sp_slot: 2,
+    num_saved_registers: 31,
+    arg_register_index: 21,
+};
+
+/// x0-x28, padded to keep the stack 16-byte aligned.
+#[cfg(target_arch = "aarch64")]
+pub(crate) const ALT_STACK_LAYOUT: AltStackLayout = AltStackLayout {
+    target_slot: 4,
+    returning_slot: 3,
+    saved_sp_slot: 2,
+    num_saved_registers: 30,
+    arg_register_index: 0,
+};
+
+const DEFAULT_TRAP_STACK_SIZE: usize = 1048576; // 1MB
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+lazy_static! {
+    /// One page, e.g. 16KB on some Android devices.
+    static ref TRAP_STACK_GUARD_SIZE: usize = page_size::get();
+}
+
+static TRAP_STACK_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_TRAP_STACK_SIZE);
+
+/// The maximum number of frames read for the backtrace of a trap. `usize::MAX` means no limit
+/// other than `MAX_BACKTRACE_FRAMES`.
+static MAX_BACKTRACE_DEPTH: AtomicUsize = AtomicUsize::new(std::usize::MAX);
 
 const SETJMP_BUFFER_LEN: usize = 128;
 type SetJmpBuffer = [i32; SETJMP_BUFFER_LEN];
@@ -61,7 +148,12 @@ type SetJmpBuffer = [i32; SETJMP_BUFFER_LEN];
 struct UnwindInfo {
     jmpbuf: SetJmpBuffer, // in
     breakpoints: Option<BreakpointMap>,
+    disarmed_breakpoints: HashSet<usize>,
+    /// The number of hits of each breakpoint wrapped with `ignore_count_breakpoint`.
+    breakpoint_hits: HashMap<usize, usize>,
     payload: Option<Box<RuntimeError>>, // out
+    stack_marker: usize,
+    outer: *const Option<UnwindInfo>,
 }
 
 /// A store for boundary register preservation.
@@ -83,986 +175,7680 @@ pub struct BoundaryRegisterPreservation {
 thread_local! {
     static UNWIND: UnsafeCell<Option<UnwindInfo>> = UnsafeCell::new(None);
     static CURRENT_CTX: UnsafeCell<*mut vm::Ctx> = UnsafeCell::new(::std::ptr::null_mut());
+    static CTX_STACK: RefCell<Vec<*mut vm::Ctx>> = RefCell::new(vec![]);
+    static CATCH_SCOPE_DEPTH: Cell<usize> = Cell::new(0);
+    static IN_HOST_FUNCTION: Cell<bool> = Cell::new(false);
     static CURRENT_CODE_VERSIONS: RefCell<Vec<CodeVersion>> = RefCell::new(vec![]);
+    static CURRENT_CODE_HASHES: RefCell<Vec<Option<u64>>> = RefCell::new(vec![]);
     static WAS_SIGINT_TRIGGERED: Cell<bool> = Cell::new(false);
     static BOUNDARY_REGISTER_PRESERVATION: UnsafeCell<BoundaryRegisterPreservation> = UnsafeCell::new(BoundaryRegisterPreservation::default());
+    static STACK_BOUNDS: Cell<Option<StackBounds>> = Cell::new(None);
+    static LAST_TRAP_STATE: RefCell<Option<ExecutionStateImage>> = RefCell::new(None);
+    static FAULT_TIMESTAMP: Cell<Option<FaultTimestamp>> = Cell::new(None);
+    static BREAKPOINT_STATS: RefCell<BreakpointStats> = RefCell::new(HashMap::new());
+    /// The hits of `InlineBreakpointType::Counter` sites as `(ip, count)`, in an open-addressed
+    /// table preallocated so that the signal handler does not allocate. An ip of 0 marks a free
+    /// slot.
+    static COUNTER_BREAKPOINT_COUNTS: UnsafeCell<[(usize, u64); MAX_COUNTER_BREAKPOINT_SITES]> =
+        UnsafeCell::new([(0, 0); MAX_COUNTER_BREAKPOINT_SITES]);
+    /// The pages below the stacks of the active `allocate_and_run` calls.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    static TRAP_STACK_GUARDS: RefCell<Vec<Range<usize>>> = RefCell::new(vec![]);
+    static BREAKPOINT_DISARM_REQUESTED: Cell<bool> = Cell::new(false);
+    static BREAKPOINT_SUSPEND_REQUESTED: Cell<bool> = Cell::new(false);
+    /// The `Ctx` whose linear memory is copied into the `InvokeError::PartialResult` being
+    /// unwound, once the unwind has completed.
+    static PENDING_MEMORY_CAPTURE: Cell<*mut vm::Ctx> = Cell::new(::std::ptr::null_mut());
+    /// The watched pages left accessible while the faulting access is single-stepped.
+    static WATCHPOINT_STEP: Cell<Option<(usize, usize)>> = Cell::new(None);
+    static TRAP_STACK_HIGH_WATER: Cell<usize> = Cell::new(0);
+    static ALT_STACK_PROVIDER: RefCell<Option<Box<dyn AltStackProvider>>> = RefCell::new(None);
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    static TRAP_STACK_POOL: Cell<Option<TrapStack>> = Cell::new(None);
+    static FAULT_RING: UnsafeCell<FaultRing> = UnsafeCell::new(FaultRing {
+        records: [None; FAULT_RING_SIZE],
+        next: 0,
+    });
 }
 
-/// Gets a mutable pointer to the `BoundaryRegisterPreservation`.
-#[no_mangle]
-pub unsafe extern "C" fn get_boundary_register_preservation() -> *mut BoundaryRegisterPreservation {
-    BOUNDARY_REGISTER_PRESERVATION.with(|x| x.get())
+/// How a fault was handled by the signal handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FaultClassification {
+    /// A breakpoint was hit.
+    Breakpoint,
+    /// An interrupt suspended execution.
+    Suspend,
+    /// A trap with a known exception code.
+    Trap(ExceptionCode),
+    /// An access to a guard region registered with `register_guard_region`.
+    StackOverflow,
+    /// A fault that could not be attributed to a known cause.
+    Unknown,
 }
 
-struct InterruptSignalMem(*mut u8);
-unsafe impl Send for InterruptSignalMem {}
-unsafe impl Sync for InterruptSignalMem {}
+/// What the signal handler should do after a fault recovery handler has run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryAction {
+    /// Return from the signal handler and continue at the instruction pointer of the fault,
+    /// retrying the faulting instruction unless the handler moved it.
+    Resume,
+    /// Handle the fault as if no recovery handler were registered.
+    Unwind,
+}
 
-const INTERRUPT_SIGNAL_MEM_SIZE: usize = 4096;
+/// A handler called for faults on an address range registered with `register_fault_recovery`.
+pub type FaultRecoveryHandler = fn(&FaultInfo) -> RecoveryAction;
 
-lazy_static! {
-    static ref INTERRUPT_SIGNAL_MEM: InterruptSignalMem = {
-        let ptr = unsafe {
-            mmap(
-                ::std::ptr::null_mut(),
-                INTERRUPT_SIGNAL_MEM_SIZE,
-                PROT_READ | PROT_WRITE,
-                MAP_PRIVATE | MAP_ANON,
-                -1,
-                0,
-            )
-        };
-        if ptr as isize == -1 {
-            panic!("cannot allocate code memory");
-        }
-        InterruptSignalMem(ptr as _)
-    };
-}
-static INTERRUPT_SIGNAL_DELIVERED: AtomicBool = AtomicBool::new(false);
+/// A handler called for accesses to a region registered with `register_watchpoint`.
+pub type WatchpointHandler = fn(&FaultInfo);
 
-/// Returns a boolean indicating if SIGINT triggered the fault.
-pub fn was_sigint_triggered_fault() -> bool {
-    WAS_SIGINT_TRIGGERED.with(|x| x.get())
-}
+/// A handler called for traps with an exception code registered with
+/// `set_trap_recovery_handler`.
+///
+/// The handler may fix up the registers and the instruction pointer of the fault before
+/// returning `RecoveryAction::Resume`.
+pub type TrapRecoveryHandler = fn(&mut FaultInfo) -> RecoveryAction;
 
-/// Runs a callback function with the given `Ctx`.
-pub unsafe fn with_ctx<R, F: FnOnce() -> R>(ctx: *mut vm::Ctx, cb: F) -> R {
-    let addr = CURRENT_CTX.with(|x| x.get());
-    let old = *addr;
-    *addr = ctx;
-    let ret = cb();
-    *addr = old;
-    ret
+/// A handler called for `InlineBreakpointType::Trace` inline breakpoints, set with
+/// `set_trace_breakpoint_handler`.
+pub type TraceBreakpointHandler = fn(&FaultInfo);
+
+/// The time at which the signal handler was entered for a fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FaultTimestamp {
+    /// `CLOCK_REALTIME`, since the Unix epoch.
+    pub realtime: Duration,
+    /// `CLOCK_MONOTONIC`, for measuring intervals between faults.
+    pub monotonic: Duration,
 }
 
-/// Pushes a new `CodeVersion` to the current code versions.
-pub fn push_code_version(version: CodeVersion) {
-    CURRENT_CODE_VERSIONS.with(|x| x.borrow_mut().push(version));
+impl FaultTimestamp {
+    /// Reads both clocks. `clock_gettime` is async-signal-safe, so this may be called from a
+    /// signal handler.
+    pub fn now() -> FaultTimestamp {
+        FaultTimestamp {
+            realtime: read_clock(libc::CLOCK_REALTIME),
+            monotonic: read_clock(libc::CLOCK_MONOTONIC),
+        }
+    }
+
+    /// Returns the wall clock time of the fault.
+    pub fn system_time(&self) -> SystemTime {
+        UNIX_EPOCH + self.realtime
+    }
 }
 
-/// Pops a `CodeVersion` from the current code versions.
-pub fn pop_code_version() -> Option<CodeVersion> {
-    CURRENT_CODE_VERSIONS.with(|x| x.borrow_mut().pop())
+fn read_clock(clock: libc::clockid_t) -> Duration {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(clock, &mut ts);
+    }
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
 }
 
-/// Gets the wasm interrupt signal mem.
-pub unsafe fn get_wasm_interrupt_signal_mem() -> *mut u8 {
-    INTERRUPT_SIGNAL_MEM.0
+/// A callback observing every fault unwound by the signal handler, with the error it unwinds
+/// with. See `set_trap_observer`.
+pub type TrapObserver = Box<dyn Fn(&FaultInfo, &RuntimeError) + Send + Sync>;
+
+/// A machine-readable record of a fault unwound by the signal handler, sent to the sink set
+/// with `set_trap_event_sink`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrapEvent {
+    /// The signal that raised the fault, if any.
+    pub signal: Option<i32>,
+    /// The faulting address.
+    pub faulting_addr: usize,
+    /// The instruction pointer of the fault.
+    pub ip: usize,
+    /// Offset of `ip` from the base of its code version, if it is within one.
+    pub module_offset: Option<usize>,
+    /// The trap code, if the fault is a known trap.
+    pub code: Option<ExceptionCode>,
+    /// The `pthread_t` of the faulting thread.
+    pub thread_id: u64,
+    /// When the event was sent, right before unwinding.
+    pub timestamp: FaultTimestamp,
 }
 
-/// Sets the wasm interrupt on the given `Ctx`.
-pub unsafe fn set_wasm_interrupt_on_ctx(ctx: *mut vm::Ctx) {
-    if mprotect(
-        (&*ctx).internal.interrupt_signal_mem as _,
-        INTERRUPT_SIGNAL_MEM_SIZE,
-        PROT_NONE,
-    ) < 0
-    {
-        panic!("cannot set PROT_NONE on signal mem");
-    }
+/// A callback notified when a `catch_unsafe_unwind` scope exits, with the error it exits with,
+/// if any, and the time its closure ran for. See `set_invocation_observer`.
+pub type InvocationObserver = fn(Option<&RuntimeError>, Duration);
+
+/// A compact record of a fault processed by the signal handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactFaultRecord {
+    /// The signal number.
+    pub signal: i32,
+    /// Offset of the faulting instruction from the base of its code version, or the absolute
+    /// instruction pointer if it is not within any code version.
+    pub ip_offset: usize,
+    /// Index of the code version containing the faulting instruction.
+    pub code_version: Option<usize>,
+    /// How the fault was handled.
+    pub classification: FaultClassification,
+    /// The size of the linear memory in bytes, for `ExceptionCode::MemoryOutOfBounds` traps
+    /// raised while a `Ctx` is current.
+    pub memory_size: Option<usize>,
+    /// When the signal handler was entered for this fault. Not part of `signature`.
+    pub timestamp: FaultTimestamp,
 }
 
-/// Sets a wasm interrupt.
-pub unsafe fn set_wasm_interrupt() {
-    let mem: *mut u8 = INTERRUPT_SIGNAL_MEM.0;
-    if mprotect(mem as _, INTERRUPT_SIGNAL_MEM_SIZE, PROT_NONE) < 0 {
-        panic!("cannot set PROT_NONE on signal mem");
+impl CompactFaultRecord {
+    /// Returns a hash identifying this fault, for deduplicating crash reports.
+    ///
+    /// The signature is the 64-bit FNV-1a hash of, in order:
+    ///
+    /// - the signal number, as a little-endian `i32`;
+    /// - the classification, as a little-endian `u32`: 0 for `Breakpoint`, 1 for `Suspend`,
+    ///   2 for `Trap`, 3 for `StackOverflow` and 4 for `Unknown`;
+    /// - for `Trap`, the exception code, as a little-endian `u32`;
+    /// - if the faulting instruction is within a code version, its offset from the base of
+    ///   that code version, as a little-endian `u64`.
+    ///
+    /// This is stable: absolute addresses are never included, so the same trap hashes the same
+    /// across runs, processes, platforms and builds of wasmer, regardless of where the code is
+    /// loaded.
+    pub fn signature(&self) -> u64 {
+        let (classification, code) = match self.classification {
+            FaultClassification::Breakpoint => (0u32, None),
+            FaultClassification::Suspend => (1, None),
+            FaultClassification::Trap(code) => (2, Some(code)),
+            FaultClassification::StackOverflow => (3, None),
+            FaultClassification::Unknown => (4, None),
+        };
+        let mut hash = fnv1a(FNV_OFFSET_BASIS, &self.signal.to_le_bytes());
+        hash = fnv1a(hash, &classification.to_le_bytes());
+        if let Some(code) = code {
+            hash = fnv1a(hash, &(code as u32).to_le_bytes());
+        }
+        if self.code_version.is_some() {
+            hash = fnv1a(hash, &(self.ip_offset as u64).to_le_bytes());
+        }
+        hash
     }
 }
 
-/// Clears the wasm interrupt.
-pub unsafe fn clear_wasm_interrupt() {
-    let mem: *mut u8 = INTERRUPT_SIGNAL_MEM.0;
-    if mprotect(mem as _, INTERRUPT_SIGNAL_MEM_SIZE, PROT_READ | PROT_WRITE) < 0 {
-        panic!("cannot set PROT_READ | PROT_WRITE on signal mem");
-    }
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// Folds `bytes` into the 64-bit FNV-1a hash `hash`.
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    })
 }
 
-/// Catches an unsafe unwind with the given functions and breakpoints.
-pub unsafe fn catch_unsafe_unwind<R, F: FnOnce() -> R>(
-    f: F,
-    breakpoints: Option<BreakpointMap>,
-) -> Result<R, RuntimeError> {
-    let unwind = UNWIND.with(|x| x.get());
-    let old = (*unwind).take();
-    *unwind = Some(UnwindInfo {
-        jmpbuf: [0; SETJMP_BUFFER_LEN],
-        breakpoints: breakpoints,
-        payload: None,
-    });
+const FAULT_RING_SIZE: usize = 32;
 
-    if raw::setjmp(&mut (*unwind).as_mut().unwrap().jmpbuf as *mut SetJmpBuffer as *mut _) != 0 {
-        // error
-        let ret = (*unwind).as_mut().unwrap().payload.take().unwrap();
-        *unwind = old;
-        Err(*ret)
-    } else {
-        let ret = f();
-        // implicit control flow to the error case...
-        *unwind = old;
-        Ok(ret)
-    }
+struct FaultRing {
+    records: [Option<CompactFaultRecord>; FAULT_RING_SIZE],
+    next: usize,
 }
 
-/// Begins an unsafe unwind.
-pub unsafe fn begin_unsafe_unwind(e: Box<RuntimeError>) -> ! {
-    let unwind = UNWIND.with(|x| x.get());
-    let inner = (*unwind)
-        .as_mut()
-        .expect("not within a catch_unsafe_unwind scope");
-    inner.payload = Some(e);
-    raw::longjmp(&mut inner.jmpbuf as *mut SetJmpBuffer as *mut _, 0xffff);
+/// Records a fault into the per-thread ring buffer. Does not allocate.
+fn record_fault(signal: i32, ip: usize, classification: FaultClassification) {
+    let (code_version, ip_offset) = match is_wasm_address(ip) {
+        Some(v) => (Some(v.index), v.offset),
+        None => (None, ip),
+    };
+    let memory_size = match classification {
+        FaultClassification::Trap(ExceptionCode::MemoryOutOfBounds) => unsafe {
+            current_memory_size()
+        },
+        _ => None,
+    };
+    // Use the time captured on entry to the signal handler, if any.
+    let timestamp = FAULT_TIMESTAMP
+        .with(|x| x.take())
+        .unwrap_or_else(FaultTimestamp::now);
+    FAULT_RING.with(|ring| unsafe {
+        let ring = &mut *ring.get();
+        ring.records[ring.next % FAULT_RING_SIZE] = Some(CompactFaultRecord {
+            signal,
+            ip_offset,
+            code_version,
+            classification,
+            memory_size,
+            timestamp,
+        });
+        ring.next = ring.next.wrapping_add(1);
+    });
 }
 
-unsafe fn with_breakpoint_map<R, F: FnOnce(Option<&BreakpointMap>) -> R>(f: F) -> R {
-    let unwind = UNWIND.with(|x| x.get());
-    let inner = (*unwind)
-        .as_mut()
-        .expect("not within a catch_unsafe_unwind scope");
-    f(inner.breakpoints.as_ref())
+/// Returns the size in bytes of the linear memory of the current `Ctx`, if any.
+unsafe fn current_memory_size() -> Option<usize> {
+    let ctx = CURRENT_CTX.with(|x| *x.get());
+    if ctx.is_null() || (*ctx).internal.memory_base.is_null() {
+        None
+    } else {
+        Some((*ctx).internal.memory_bound)
+    }
 }
 
-#[cfg(not(target_arch = "x86_64"))]
-/// Allocates and runs with the given stack size and closure.
-pub fn allocate_and_run<R, F: FnOnce() -> R>(_size: usize, f: F) -> R {
-    f()
+/// A structured report of a `RuntimeError`, for logging by hosts.
+///
+/// Besides the error itself, the report draws on the fault most recently processed on the
+/// current thread, so it should be built on the thread that made the failing call, right
+/// after the call returned.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrapReport {
+    /// The trap code, if the error is a known trap.
+    pub code: Option<ExceptionCode>,
+    /// A human-readable description of the error.
+    pub description: String,
+    /// Where in the Wasm file the trap originated from, if known.
+    pub srcloc: Option<u32>,
+    /// The innermost wasm frame at the time of the fault, if known.
+    pub top_frame: Option<BacktraceFrame>,
+    /// The full backtrace, only filled in by `with_backtrace`.
+    pub backtrace: Option<Vec<BacktraceFrame>>,
+    /// Host functions that called back into wasm, between the frames of `backtrace`.
+    #[serde(default)]
+    pub host_frames: Vec<HostFrame>,
+    /// The signal that raised the fault, if any.
+    pub signal: Option<i32>,
+    /// When the signal handler was entered for the fault, if any.
+    #[serde(default)]
+    pub timestamp: Option<FaultTimestamp>,
+    /// The thread the report was built on.
+    pub thread_id: String,
+    /// A label identifying the invocation, set with `with_label`.
+    pub label: Option<String>,
 }
 
-#[cfg(target_arch = "x86_64")]
-/// Allocates and runs with the given stack size and closure.
-pub fn allocate_and_run<R, F: FnOnce() -> R>(size: usize, f: F) -> R {
-    struct Context<F: FnOnce() -> R, R> {
-        f: Option<F>,
-        ret: Option<R>,
+impl TrapReport {
+    /// Builds a report of `error`.
+    pub fn from_runtime_error(error: &RuntimeError) -> TrapReport {
+        let (code, srcloc, description) = match error {
+            RuntimeError::InvokeError(InvokeError::TrapCode { code, srcloc, .. }) => {
+                (Some(*code), Some(*srcloc), code.to_string())
+            }
+            other => (None, None, other.to_string()),
+        };
+        let fault = match error {
+            RuntimeError::InvokeError(InvokeError::TrapCode { .. })
+            | RuntimeError::InvokeError(InvokeError::UnknownTrap { .. })
+            | RuntimeError::InvokeError(InvokeError::StackOverflow)
+            | RuntimeError::InvokeError(InvokeError::Timeout)
+            | RuntimeError::InvokeError(InvokeError::NoContext)
+            | RuntimeError::InvokeError(InvokeError::Aborted)
+            | RuntimeError::InvokeError(InvokeError::PartialResult(_))
+            | RuntimeError::InstanceImage(_) => recent_faults().last().cloned(),
+            _ => None,
+        };
+        TrapReport {
+            code,
+            description,
+            srcloc,
+            top_frame: Self::state_of(error).and_then(|x| x.backtrace_frames().into_iter().next()),
+            backtrace: None,
+            host_frames: vec![],
+            signal: fault.map(|x| x.signal),
+            timestamp: fault.map(|x| x.timestamp),
+            thread_id: format!("{:?}", std::thread::current().id()),
+            label: None,
+        }
     }
 
-    extern "C" fn invoke<F: FnOnce() -> R, R>(ctx: &mut Context<F, R>) {
-        let f = ctx.f.take().unwrap();
-        ctx.ret = Some(f());
+    /// Sets the invocation label.
+    pub fn with_label<S: Into<String>>(mut self, label: S) -> TrapReport {
+        self.label = Some(label.into());
+        self
     }
 
-    unsafe {
-        let mut ctx = Context {
-            f: Some(f),
-            ret: None,
-        };
-        assert!(size % 16 == 0);
-        assert!(size >= 4096);
+    /// Fills in the full backtrace of `error`.
+    pub fn with_backtrace(mut self, error: &RuntimeError) -> TrapReport {
+        if let Some(state) = Self::state_of(error) {
+            self.backtrace = Some(state.backtrace_frames());
+            self.host_frames = state.host_frames;
+        }
+        self
+    }
 
-        let mut stack: Vec<u64> = vec![0; size / 8];
-        let end_offset = stack.len();
+    fn state_of(error: &RuntimeError) -> Option<ExecutionStateImage> {
+        match error {
+            RuntimeError::InstanceImage(image) => image
+                .downcast_ref::<InstanceImage>()
+                .map(|x| x.execution_state.clone()),
+            RuntimeError::InvokeError(InvokeError::TrapCode { .. })
+            | RuntimeError::InvokeError(InvokeError::UnknownTrap { .. })
+            | RuntimeError::InvokeError(InvokeError::StackOverflow) => {
+                LAST_TRAP_STATE.with(|x| x.borrow().clone())
+            }
+            _ => None,
+        }
+    }
+}
 
-        stack[end_offset - 4] = invoke::<F, R> as usize as u64;
+/// Returns the code version of the module of `instance`, if it was compiled with `track_state`.
+fn code_version_of(instance: &Instance) -> Option<CodeVersion> {
+    let module = &instance.module;
+    match (
+        module.runnable_module.get_module_state_map(),
+        module.runnable_module.get_code(),
+    ) {
+        (Some(msm), Some(code)) => Some(CodeVersion {
+            baseline: true,
+            msm,
+            base: code.as_ptr() as usize,
+            backend: match module.info.backend.as_str() {
+                "singlepass" => "singlepass",
+                "cranelift" => "cranelift",
+                "llvm" => "llvm",
+                _ => "unknown",
+            },
+            runnable_module: module.runnable_module.clone(),
+            function_names: None,
+        }),
+        _ => None,
+    }
+}
 
-        // NOTE: Keep this consistent with `image-loading-*.s`.
-        stack[end_offset - 4 - 10] = &mut ctx as *mut Context<F, R> as usize as u64; // rdi
-        const NUM_SAVED_REGISTERS: usize = 31;
-        let stack_begin = stack.as_mut_ptr().add(end_offset - 4 - NUM_SAVED_REGISTERS);
-        let stack_end = stack.as_mut_ptr().add(end_offset);
+/// Requests that the code running on `ctx` suspends at its next interrupt check.
+///
+/// The suspended call fails with `RuntimeError::InstanceImage` holding an `InstanceImage`
+/// that can be passed to `resume_from_image`. The code version of the module must be pushed
+/// for the duration of the call, so that the stack can be read.
+pub unsafe fn request_suspend(ctx: *mut vm::Ctx) {
+    set_wasm_interrupt_on_ctx(ctx);
+}
 
-        raw::run_on_alternative_stack(stack_end, stack_begin);
-        ctx.ret.take().unwrap()
-    }
+/// An error resuming an `InstanceImage` with `resume_from_image`.
+#[derive(Debug)]
+pub enum ResumeError {
+    /// The module of the instance was not compiled with `track_state`.
+    StateNotTracked,
+    /// The image was not taken from the code of the module of the instance.
+    CodeVersionMismatch {
+        /// The code hash recorded in the image, if any.
+        expected: Option<u64>,
+        /// The hash of the code of the module of the instance.
+        found: u64,
+    },
+    /// The resumed execution failed, e.g. because it was suspended again.
+    Runtime(RuntimeError),
 }
 
-unsafe fn call_signal_handler(
-    sig: Signal,
-    siginfo: *mut siginfo_t,
-    ucontext: *mut c_void,
-    sig_action: &SigAction,
-) {
-    match sig_action.handler() {
-        SigHandler::SigDfl => {
-            sigaction(sig, sig_action).unwrap();
-            return;
+impl std::fmt::Display for ResumeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ResumeError::StateNotTracked => {
+                write!(f, "the module was not compiled with state tracking")
+            }
+            ResumeError::CodeVersionMismatch {
+                expected: Some(expected),
+                found,
+            } => write!(
+                f,
+                "the image was taken from code with hash {:x}, not {:x}",
+                expected, found
+            ),
+            ResumeError::CodeVersionMismatch {
+                expected: None,
+                found,
+            } => write!(
+                f,
+                "the image does not record its code version, expected {:x}",
+                found
+            ),
+            ResumeError::Runtime(e) => write!(f, "{}", e),
         }
-        SigHandler::SigIgn => return,
-        SigHandler::Handler(handler) => handler(sig as _),
-        SigHandler::SigAction(handler) => handler(sig as _, siginfo as _, ucontext),
     }
 }
 
-extern "C" fn signal_trap_handler(
-    signum: ::nix::libc::c_int,
-    siginfo: *mut siginfo_t,
-    ucontext: *mut c_void,
-) {
-    use crate::backend::{Architecture, InlineBreakpointType};
+impl std::error::Error for ResumeError {}
 
-    #[cfg(target_arch = "x86_64")]
-    static ARCH: Architecture = Architecture::X64;
+/// Resumes the execution suspended in `image` on `instance`, restoring its linear memory and
+/// globals first. Returns the return value of the outermost suspended function.
+///
+/// The image must have been taken from the same machine code as the module of `instance`,
+/// i.e. from a module compiled from the same wasm binary by the same build of the same backend
+/// with `track_state` enabled.
+pub unsafe fn resume_from_image(
+    instance: &mut Instance,
+    image: InstanceImage,
+) -> Result<u64, ResumeError> {
+    let version = match code_version_of(instance) {
+        Some(x) => x,
+        None => return Err(ResumeError::StateNotTracked),
+    };
+    let found = hash_code(&version);
+    if image.code_hash != Some(found) {
+        return Err(ResumeError::CodeVersionMismatch {
+            expected: image.code_hash,
+            found,
+        });
+    }
 
-    #[cfg(target_arch = "aarch64")]
-    static ARCH: Architecture = Architecture::Aarch64;
+    let msm = version.msm.clone();
+    let base = version.base;
+    let breakpoints = instance.module.runnable_module.get_breakpoints();
+    push_code_version(version);
+    let ctx = instance.context_mut() as *mut vm::Ctx;
+    let result = with_ctx(ctx, || {
+        invoke_call_return_on_stack(&msm, base, image, &mut *ctx, breakpoints)
+    });
+    pop_code_version();
+    result.map_err(ResumeError::Runtime)
+}
 
-    let mut should_unwind = false;
-    let mut unwind_result: Option<Box<RuntimeError>> = None;
-    let get_unwind_result = |uw_result: Option<Box<RuntimeError>>| -> Box<RuntimeError> {
-        uw_result
-            .unwrap_or_else(|| Box::new(RuntimeError::InvokeError(InvokeError::FailedWithNoError)))
-    };
+impl std::fmt::Display for TrapReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(label) = &self.label {
+            write!(f, "{}: ", label)?;
+        }
+        write!(f, "{}", self.description)?;
+        if let Some(srcloc) = self.srcloc {
+            write!(f, " at code offset {}", srcloc)?;
+        }
+        if let Some(signal) = self.signal {
+            write!(f, " (signal {})", signal)?;
+        }
+        write!(f, " on thread {}", self.thread_id)?;
+        if let Some(frame) = &self.top_frame {
+            write!(
+                f,
+                "\n  in local function {} at offset {}",
+                frame.local_function_id, frame.wasm_inst_offset
+            )?;
+        }
+        if let Some(backtrace) = &self.backtrace {
+            for (i, frame) in backtrace.iter().enumerate() {
+                write!(
+                    f,
+                    "\n  * Frame {} @ Local function {}, offset {}",
+                    i, frame.local_function_id, frame.wasm_inst_offset
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
 
-    unsafe {
-        let fault = get_fault_info(siginfo as _, ucontext);
-        let early_return = allocate_and_run(TRAP_STACK_SIZE, || {
-            CURRENT_CODE_VERSIONS.with(|versions| {
-                let versions = versions.borrow();
-                for v in versions.iter() {
-                    let magic_size =
-                        if let Some(x) = v.runnable_module.get_inline_breakpoint_size(ARCH) {
-                            x
-                        } else {
-                            continue;
-                        };
-                    let ip = fault.ip.get();
-                    let end = v.base + v.msm.total_size;
-                    if ip >= v.base && ip < end && ip + magic_size <= end {
-                        if let Some(ib) = v.runnable_module.read_inline_breakpoint(
-                            ARCH,
-                            std::slice::from_raw_parts(ip as *const u8, magic_size),
-                        ) {
-                            match ib.ty {
-                                InlineBreakpointType::Middleware => {
-                                    let out: Option<Result<(), RuntimeError>> =
-                                        with_breakpoint_map(|bkpt_map| {
-                                            bkpt_map.and_then(|x| x.get(&ip)).map(|x| {
-                                                x(BreakpointInfo {
-                                                    fault: Some(&fault),
-                                                })
-                                            })
-                                        });
-                                    if let Some(Ok(())) = out {
-                                    } else if let Some(Err(e)) = out {
-                                        should_unwind = true;
-                                        unwind_result = Some(Box::new(e));
-                                    }
-                                }
-                            }
+/// Enables or disables fault diagnostics.
+///
+/// In diagnostic mode, `classify_fault` reports every plausible classification instead of
+/// only the first, and the signal handler logs faults with more than one candidate.
+pub fn set_fault_diagnostics(enabled: bool) {
+    FAULT_DIAGNOSTICS.store(enabled, Ordering::SeqCst);
+}
 
-                            fault.ip.set(ip + magic_size);
-                            return true;
+/// Classifies a trap at `ip` using the exception tables of the current code versions.
+///
+/// Candidates are ranked in the order the code versions were pushed, and the first one is
+/// the classification acted upon. Unless diagnostic mode is enabled, only that candidate is
+/// returned.
+pub fn classify_fault(ip: usize) -> Vec<FaultClassification> {
+    let all = FAULT_DIAGNOSTICS.load(Ordering::SeqCst);
+    let mut candidates = vec![];
+    CURRENT_CODE_VERSIONS.with(|versions| {
+        for v in versions.borrow().iter() {
+            if let Some(table) = v.runnable_module.get_exception_table() {
+                let end = v.base + v.msm.total_size;
+                if ip >= v.base && ip < end {
+                    if let Some(exc_code) = table.code_at(ip - v.base) {
+                        candidates.push(FaultClassification::Trap(exc_code));
+                        if !all {
+                            return;
                         }
-                        break;
                     }
                 }
-                false
-            })
-        });
-        if should_unwind {
-            begin_unsafe_unwind(get_unwind_result(unwind_result));
-        }
-        if early_return {
-            return;
+            }
         }
+    });
+    if candidates.is_empty() {
+        candidates.push(FaultClassification::Unknown);
+    }
+    candidates
+}
 
-        should_unwind = allocate_and_run(TRAP_STACK_SIZE, || {
-            let mut is_suspend_signal = false;
+/// The code version containing an address, as returned by `is_wasm_address`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodeVersionRef {
+    /// Index of the code version in the code versions pushed on the current thread.
+    pub index: usize,
+    /// The base address of the code of the version.
+    pub base: usize,
+    /// Offset of the address from `base`.
+    pub offset: usize,
+    /// The backend the code was compiled with.
+    pub backend: &'static str,
+}
 
-            WAS_SIGINT_TRIGGERED.with(|x| x.set(false));
+/// Returns the code version pushed on the current thread whose generated code contains
+/// `addr`, or `None` if `addr` is not in wasm code, e.g. to attribute the samples of a
+/// profiler to wasm or native code.
+///
+/// The innermost code version is searched first, so that an address in a nested call into
+/// the same code is attributed to the code version pushed for that call.
+pub fn is_wasm_address(addr: usize) -> Option<CodeVersionRef> {
+    CURRENT_CODE_VERSIONS.with(|versions| {
+        versions
+            .borrow()
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, v)| addr >= v.base && addr < v.base + v.msm.total_size)
+            .map(|(index, v)| CodeVersionRef {
+                index,
+                base: v.base,
+                offset: addr - v.base,
+                backend: v.backend,
+            })
+    })
+}
 
-            match Signal::from_c_int(signum) {
-                Ok(SIGTRAP) => {
-                    // breakpoint
-                    let out: Option<Result<(), RuntimeError>> =
-                        with_breakpoint_map(|bkpt_map| -> Option<Result<(), RuntimeError>> {
-                            bkpt_map.and_then(|x| x.get(&(fault.ip.get()))).map(
-                                |x| -> Result<(), RuntimeError> {
-                                    x(BreakpointInfo {
-                                        fault: Some(&fault),
-                                    })
-                                },
-                            )
-                        });
-                    match out {
-                        Some(Ok(())) => {
-                            return false;
-                        }
-                        Some(Err(e)) => {
-                            unwind_result = Some(Box::new(e));
-                            return true;
-                        }
-                        None => {}
-                    }
-                }
-                Ok(SIGSEGV) | Ok(SIGBUS) => {
-                    if fault.faulting_addr as usize == get_wasm_interrupt_signal_mem() as usize {
-                        is_suspend_signal = true;
-                        clear_wasm_interrupt();
-                        if INTERRUPT_SIGNAL_DELIVERED.swap(false, Ordering::SeqCst) {
-                            WAS_SIGINT_TRIGGERED.with(|x| x.set(true));
-                        }
-                    }
-                }
-                _ => {}
+/// Returns the source location of the trapping instruction at `ip`, as an offset into the
+/// wasm binary, if the exception table of its code version records one.
+pub fn trap_srcloc(ip: usize) -> Option<u32> {
+    CURRENT_CODE_VERSIONS.with(|versions| {
+        versions.borrow().iter().find_map(|v| {
+            let table = v.runnable_module.get_exception_table()?;
+            if ip >= v.base && ip < v.base + v.msm.total_size {
+                table.offset_to_srcloc.get(&(ip - v.base)).cloned()
+            } else {
+                None
             }
+        })
+    })
+}
 
-            // Now we have looked up all possible handler tables but failed to find a handler
-            // for this exception that allows a normal return.
-            //
-            // So here we check whether this exception is caused by a suspend signal, return the
-            // state image if so, or throw the exception out otherwise.
+/// Returns every trap code that the code of `version` can raise, as recorded in its
+/// exception table.
+///
+/// Returns an empty set if the backend does not provide an exception table.
+pub fn possible_trap_codes(version: &CodeVersion) -> HashSet<ExceptionCode> {
+    version
+        .runnable_module
+        .get_exception_table()
+        .map(|table| table.offset_to_code.values().cloned().collect())
+        .unwrap_or_default()
+}
 
-            let ctx: &mut vm::Ctx = &mut **CURRENT_CTX.with(|x| x.get());
-            let es_image = fault
-                .read_stack(None)
-                .expect("fault.read_stack() failed. Broken invariants?");
+/// Returns the most recent faults processed on the current thread, oldest first.
+pub fn recent_faults() -> Vec<CompactFaultRecord> {
+    FAULT_RING.with(|ring| unsafe {
+        let ring = &*ring.get();
+        (0..FAULT_RING_SIZE)
+            .filter_map(|i| ring.records[(ring.next + i) % FAULT_RING_SIZE])
+            .collect()
+    })
+}
 
-            if is_suspend_signal {
-                // If this is a suspend signal, we parse the runtime state and return the resulting image.
-                let image = build_instance_image(ctx, es_image);
-                unwind_result = Some(Box::new(RuntimeError::InstanceImage(Box::new(image))));
-            } else {
-                // Otherwise, this is a real exception and we just throw it to the caller.
-                if !es_image.frames.is_empty() {
-                    eprintln!(
-                        "\n{}",
-                        "Wasmer encountered an error while running your WebAssembly program."
-                    );
-                    es_image.print_backtrace_if_needed();
-                }
+/// Gets a mutable pointer to the `BoundaryRegisterPreservation`.
+#[no_mangle]
+pub unsafe extern "C" fn get_boundary_register_preservation() -> *mut BoundaryRegisterPreservation {
+    BOUNDARY_REGISTER_PRESERVATION.with(|x| x.get())
+}
 
-                // Look up the exception tables and try to find an exception code.
-                let exc_code = CURRENT_CODE_VERSIONS.with(|versions| {
-                    let versions = versions.borrow();
-                    for v in versions.iter() {
-                        if let Some(table) = v.runnable_module.get_exception_table() {
-                            let ip = fault.ip.get();
-                            let end = v.base + v.msm.total_size;
-                            if ip >= v.base && ip < end {
-                                if let Some(exc_code) = table.offset_to_code.get(&(ip - v.base)) {
-                                    return Some(*exc_code);
-                                }
-                            }
-                        }
-                    }
-                    None
-                });
-                if let Some(code) = exc_code {
-                    unwind_result =
-                        Some(Box::new(RuntimeError::InvokeError(InvokeError::TrapCode {
-                            code,
-                            // TODO:
-                            srcloc: 0,
-                        })));
-                }
-            }
+/// The address range of a thread's stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackBounds {
+    /// The lowest address of the stack, which it grows down towards.
+    pub limit: usize,
+    /// The highest address of the stack.
+    pub base: usize,
+}
 
-            true
-        });
+impl StackBounds {
+    /// Returns true if `addr` lies within the stack.
+    pub fn contains(&self, addr: usize) -> bool {
+        addr >= self.limit && addr < self.base
+    }
+}
 
-        if should_unwind {
-            begin_unsafe_unwind(get_unwind_result(unwind_result));
+/// Returns the stack bounds of the current thread.
+///
+/// The bounds are queried once per thread and cached, so that the signal handler can read
+/// them without calling into non async-signal-safe functions.
+pub fn thread_stack_bounds() -> Option<StackBounds> {
+    if let Some(bounds) = cached_stack_bounds() {
+        return Some(bounds);
+    }
+    let bounds = unsafe { query_stack_bounds() };
+    STACK_BOUNDS.with(|x| x.set(bounds));
+    bounds
+}
+
+fn cached_stack_bounds() -> Option<StackBounds> {
+    STACK_BOUNDS.with(|x| x.get())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn query_stack_bounds() -> Option<StackBounds> {
+    let mut attr: libc::pthread_attr_t = std::mem::zeroed();
+    if libc::pthread_getattr_np(libc::pthread_self(), &mut attr) != 0 {
+        return None;
+    }
+    let mut addr: *mut c_void = ::std::ptr::null_mut();
+    let mut size: usize = 0;
+    let ret = libc::pthread_attr_getstack(&attr, &mut addr, &mut size);
+    libc::pthread_attr_destroy(&mut attr);
+    if ret != 0 {
+        return None;
+    }
+    Some(StackBounds {
+        limit: addr as usize,
+        base: addr as usize + size,
+    })
+}
+
+#[cfg(target_os = "macos")]
+unsafe fn query_stack_bounds() -> Option<StackBounds> {
+    let thread = libc::pthread_self();
+    let base = libc::pthread_get_stackaddr_np(thread) as usize;
+    let size = libc::pthread_get_stacksize_np(thread);
+    Some(StackBounds {
+        limit: base - size,
+        base,
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+unsafe fn query_stack_bounds() -> Option<StackBounds> {
+    None
+}
+
+struct InterruptSignalMem(*mut u8);
+unsafe impl Send for InterruptSignalMem {}
+unsafe impl Sync for InterruptSignalMem {}
+
+lazy_static! {
+    /// One page, e.g. 16KB on some Android devices, so that protecting it affects nothing else.
+    static ref INTERRUPT_SIGNAL_MEM_SIZE: usize = page_size::get();
+    static ref INTERRUPT_SIGNAL_MEM: InterruptSignalMem = {
+        let ptr =
+            unsafe { fault_allocator().map(*INTERRUPT_SIGNAL_MEM_SIZE, PROT_READ | PROT_WRITE) };
+        if ptr.is_null() {
+            panic!("cannot allocate code memory");
+        }
+        // Fault the page in now rather than on first use. Nothing can have armed it yet.
+        unsafe { std::ptr::write_volatile(ptr, 0) };
+        InterruptSignalMem(ptr)
+    };
+}
+
+/// The allocator set with `set_fault_allocator`, leaked so that the signal handler can use it
+/// without taking a lock, or null for `MmapFaultAllocator`.
+static FAULT_ALLOCATOR: AtomicPtr<Box<dyn FaultAllocator>> = AtomicPtr::new(std::ptr::null_mut());
+
+/// Maps the memory used by the fault subsystem, i.e. the interrupt signal page and the stacks
+/// of `allocate_and_run`.
+///
+/// Stacks are mapped and unmapped from within signal handlers, so implementations must be
+/// async-signal-safe.
+pub trait FaultAllocator: Send + Sync {
+    /// Maps `size` bytes with the `PROT_*` protection flags `prot`. Returns null on failure.
+    unsafe fn map(&self, size: usize, prot: i32) -> *mut u8;
+    /// Unmaps `size` bytes at `ptr`, previously returned by `map`.
+    unsafe fn unmap(&self, ptr: *mut u8, size: usize);
+}
+
+/// The default `FaultAllocator`, using anonymous private `mmap` mappings.
+pub struct MmapFaultAllocator;
+
+impl FaultAllocator for MmapFaultAllocator {
+    unsafe fn map(&self, size: usize, prot: i32) -> *mut u8 {
+        let ptr = mmap(
+            ::std::ptr::null_mut(),
+            size,
+            prot,
+            MAP_PRIVATE | MAP_ANON,
+            -1,
+            0,
+        );
+        if ptr as isize == -1 {
+            ::std::ptr::null_mut()
+        } else {
+            ptr as _
         }
     }
+
+    unsafe fn unmap(&self, ptr: *mut u8, size: usize) {
+        munmap(ptr as _, size);
+    }
 }
 
-static mut SIGINT_SYS_HANDLER: Option<SigAction> = None;
+/// Sets the allocator used for the mappings of the fault subsystem.
+///
+/// The interrupt signal page is mapped once, on first use, so this should be called before
+/// any interrupt is set or any wasm code is run. A replaced allocator is never dropped.
+pub fn set_fault_allocator(allocator: Box<dyn FaultAllocator>) {
+    FAULT_ALLOCATOR.store(Box::into_raw(Box::new(allocator)), Ordering::SeqCst);
+}
 
-extern "C" fn sigint_handler(
-    _signum: ::nix::libc::c_int,
-    _siginfo: *mut siginfo_t,
-    _ucontext: *mut c_void,
-) {
-    if INTERRUPT_SIGNAL_DELIVERED.swap(true, Ordering::SeqCst) {
-        eprintln!("Got another SIGINT before trap is triggered on WebAssembly side, aborting");
-        process::abort();
+fn fault_allocator() -> &'static dyn FaultAllocator {
+    match unsafe { FAULT_ALLOCATOR.load(Ordering::SeqCst).as_ref() } {
+        Some(allocator) => &**allocator,
+        None => &MmapFaultAllocator,
     }
+}
+static INTERRUPT_ARMED: AtomicBool = AtomicBool::new(false);
+/// Whether the interrupt was armed for a `Ctx` that is not running in `with_ctx`, e.g. with
+/// `set_wasm_interrupt`. Such an interrupt is cleared once all protected calls have returned.
+static INTERRUPT_ARMED_GLOBALLY: AtomicBool = AtomicBool::new(false);
+static INTERRUPTS_DISABLED: AtomicUsize = AtomicUsize::new(0);
+static INTERRUPT_PENDING: AtomicBool = AtomicBool::new(false);
+static CODE_INTEGRITY_CHECK: AtomicBool = AtomicBool::new(false);
+static FAULT_DIAGNOSTICS: AtomicBool = AtomicBool::new(false);
+static CAPTURE_RAW_SIGINFO: AtomicBool = AtomicBool::new(false);
+static CAPTURE_FP_REGISTERS: AtomicBool = AtomicBool::new(false);
+static PRINT_BACKTRACE_ON_TRAP: AtomicBool = AtomicBool::new(true);
+static TRAP_STACK_WATERMARK: AtomicBool = AtomicBool::new(false);
+static BREAKPOINT_STATS_ENABLED: AtomicBool = AtomicBool::new(false);
+static SIGINT_HANDLER_INSTALLED: AtomicBool = AtomicBool::new(false);
+/// The `DoubleSigintPolicy`, stored as its discriminant.
+static DOUBLE_SIGINT_POLICY: AtomicUsize = AtomicUsize::new(DoubleSigintPolicy::Abort as usize);
+static ACTIVE_UNWIND_SCOPES: AtomicUsize = AtomicUsize::new(0);
+static UNDELIVERED_INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+/// Whether hooks are installed with `set_execution_boundary_hooks`, so that scopes only take
+/// the lock of `EXECUTION_BOUNDARY_HOOKS` then.
+static HAS_EXECUTION_BOUNDARY_HOOKS: AtomicBool = AtomicBool::new(false);
+/// The observer set with `set_invocation_observer` as a `usize`, or 0.
+static INVOCATION_OBSERVER: AtomicUsize = AtomicUsize::new(0);
+/// The handler set with `set_trace_breakpoint_handler` as a `usize`, or 0.
+static TRACE_BREAKPOINT_HANDLER: AtomicUsize = AtomicUsize::new(0);
+/// The observer set with `set_trap_observer`, leaked so that the signal handler can call it
+/// without taking a lock.
+static TRAP_OBSERVER: AtomicPtr<TrapObserver> = AtomicPtr::new(std::ptr::null_mut());
+/// The handlers set with `set_trap_recovery_handler`, as `usize`s indexed by exception code, so
+/// that the signal handler reads them without taking a lock.
+static TRAP_RECOVERIES: [AtomicUsize; ExceptionCode::CallStackExhausted as usize + 1] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+static UNWIND_STRATEGY: AtomicUsize = AtomicUsize::new(UnwindStrategy::Longjmp as usize);
+/// The map set with `set_global_breakpoints`, leaked so that the signal handler can read it
+/// without taking a lock.
+static GLOBAL_BREAKPOINTS: AtomicPtr<BreakpointMap> = AtomicPtr::new(std::ptr::null_mut());
 
-    unsafe {
-        set_wasm_interrupt();
+lazy_static! {
+    /// The handlers registered with `register_fault_recovery`, as `usize`s.
+    static ref FAULT_RECOVERIES: RangeTable = RangeTable::new();
+    static ref GUARD_REGIONS: RangeTable = RangeTable::new();
+    /// The regions registered with `register_watchpoint`, with their handlers as `usize`s.
+    static ref WATCHPOINTS: RangeTable = RangeTable::new();
+    static ref DOUBLE_SIGINT_HANDLER: Mutex<Option<Box<dyn FnOnce() + Send>>> = Mutex::new(None);
+    static ref EXECUTION_BOUNDARY_HOOKS: RwLock<Option<(fn(), fn())>> = RwLock::new(None);
+    static ref TRAP_EVENT_SINK: Mutex<Option<mpsc::SyncSender<TrapEvent>>> = Mutex::new(None);
+    static ref ACTIVE_CTXS: Vec<ActiveCtxSlot> = (0..MAX_ACTIVE_CTXS)
+        .map(|_| ActiveCtxSlot::default())
+        .collect();
+}
 
-        if let Some(prev_handler) = SIGINT_SYS_HANDLER {
-            call_signal_handler(SIGINT, _siginfo, _ucontext, &prev_handler);
+/// The maximum number of `Ctx`s that are tracked for SIGINT delivery, `interrupt_ctx_thread`
+/// and the state of their interrupts at the same time.
+///
+/// A `Ctx` entered once the table is full is not interrupted by SIGINT, its thread cannot be
+/// signaled, and its interrupts are reported as if they were set with `set_wasm_interrupt`.
+const MAX_ACTIVE_CTXS: usize = 64;
+
+/// The interrupt of the `Ctx` was set through an `InterruptHandle`.
+const CTX_INTERRUPT_HANDLE: usize = 1 << 0;
+/// The interrupt of the `Ctx` is tagged with the trace id of its slot.
+const CTX_INTERRUPT_TRACE_ID: usize = 1 << 1;
+/// The interrupt of the `Ctx` captures only its linear memory.
+const CTX_INTERRUPT_MEMORY_CAPTURE: usize = 1 << 2;
+/// The interrupt of the `Ctx` passes a snapshot to the `run_with_watchdog` callback of its slot.
+const CTX_INTERRUPT_SNAPSHOT: usize = 1 << 3;
+/// The interrupt of the `Ctx` was set because the deadline of `run_with_timeout` elapsed.
+const CTX_INTERRUPT_TIMEOUT: usize = 1 << 4;
+/// The interrupt of the `Ctx` carries the reason of its slot.
+const CTX_INTERRUPT_REASON: usize = 1 << 5;
+/// The interrupt was armed for the `Ctx` and has not fired yet.
+const CTX_INTERRUPT_ARMED: usize = 1 << 6;
+
+/// A `Ctx` active in the outermost `with_ctx` call for it on a thread, the thread running it,
+/// whether a SIGINT was delivered to it, and how its next interrupt is reported.
+///
+/// The table is preallocated and lock-free so that `sigint_handler` and `signal_trap_handler`
+/// can walk it, and so that `with_ctx` neither locks nor allocates. The thread is 0 until the
+/// slot is fully claimed.
+#[derive(Default)]
+struct ActiveCtxSlot {
+    ctx: AtomicUsize,
+    thread: AtomicUsize,
+    delivered: AtomicBool,
+    /// `CTX_INTERRUPT_*` flags.
+    interrupt_flags: AtomicUsize,
+    /// Only meaningful while `CTX_INTERRUPT_TRACE_ID` is set.
+    trace_id: AtomicU64,
+    /// The `on_soft` callback of a `run_with_watchdog` call past its soft deadline, as a
+    /// `usize`. Only meaningful while `CTX_INTERRUPT_SNAPSHOT` is set.
+    snapshot: AtomicUsize,
+    /// Encoded by `encode_interrupt_reason`. Only meaningful while `CTX_INTERRUPT_REASON` is
+    /// set.
+    interrupt_reason: AtomicUsize,
+}
+
+/// Claims a slot for `ctx` on the current thread, returning its index, or `None` if the table
+/// is full.
+fn register_active_ctx(ctx: *mut vm::Ctx) -> Option<usize> {
+    let index = ACTIVE_CTXS.iter().position(|slot| {
+        slot.ctx
+            .compare_exchange(0, ctx as usize, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    })?;
+    let thread = unsafe { libc::pthread_self() } as usize;
+    ACTIVE_CTXS[index].thread.store(thread, Ordering::SeqCst);
+    Some(index)
+}
+
+/// Releases the slot claimed by `register_active_ctx`, dropping a SIGINT that was not consumed.
+fn unregister_active_ctx(index: usize) {
+    let slot = &ACTIVE_CTXS[index];
+    slot.thread.store(0, Ordering::SeqCst);
+    slot.delivered.store(false, Ordering::SeqCst);
+    slot.interrupt_flags.store(0, Ordering::SeqCst);
+    slot.trace_id.store(0, Ordering::SeqCst);
+    slot.snapshot.store(0, Ordering::SeqCst);
+    slot.interrupt_reason.store(0, Ordering::SeqCst);
+    slot.ctx.store(0, Ordering::SeqCst);
+}
+
+/// Sets the `CTX_INTERRUPT_*` flags `flags` on the active slots of `ctx`, returning whether
+/// there was any.
+fn set_ctx_interrupt_flags(ctx: *mut vm::Ctx, flags: usize) -> bool {
+    set_ctx_interrupt_flags_with(ctx, flags, |_| ())
+}
+
+/// Like `set_ctx_interrupt_flags`, calling `init` on each slot before its flags are set.
+fn set_ctx_interrupt_flags_with<F: Fn(&ActiveCtxSlot)>(
+    ctx: *mut vm::Ctx,
+    flags: usize,
+    init: F,
+) -> bool {
+    let mut found = false;
+    for slot in ACTIVE_CTXS.iter() {
+        if slot.ctx.load(Ordering::SeqCst) == ctx as usize {
+            init(slot);
+            slot.interrupt_flags.fetch_or(flags, Ordering::SeqCst);
+            // The slot may have been released and claimed for another `Ctx` in between.
+            if slot.ctx.load(Ordering::SeqCst) == ctx as usize {
+                found = true;
+            } else {
+                slot.interrupt_flags.fetch_and(!flags, Ordering::SeqCst);
+            }
         }
     }
+    found
 }
 
-/// Ensure the signal handler is installed.
-pub fn ensure_sighandler() {
-    INSTALL_SIGHANDLER.call_once(|| unsafe {
-        install_sighandler();
-    });
+/// Consumes the `CTX_INTERRUPT_*` flag `flag` of `ctx`, returning whether it was set.
+fn take_ctx_interrupt_flag(ctx: *mut vm::Ctx, flag: usize) -> bool {
+    let mut taken = false;
+    for slot in ACTIVE_CTXS.iter() {
+        if slot.ctx.load(Ordering::SeqCst) == ctx as usize
+            && slot.interrupt_flags.fetch_and(!flag, Ordering::SeqCst) & flag != 0
+        {
+            taken = true;
+        }
+    }
+    taken
 }
 
-static INSTALL_SIGHANDLER: Once = Once::new();
+/// Returns whether the `CTX_INTERRUPT_*` flag `flag` is set on any active `Ctx`.
+fn any_ctx_interrupt_flag(flag: usize) -> bool {
+    ACTIVE_CTXS
+        .iter()
+        .any(|slot| slot.interrupt_flags.load(Ordering::SeqCst) & flag != 0)
+}
 
-unsafe fn install_sighandler() {
-    let sa_trap = SigAction::new(
-        SigHandler::SigAction(signal_trap_handler),
-        SaFlags::SA_ONSTACK,
-        SigSet::empty(),
-    );
-    sigaction(SIGFPE, &sa_trap).unwrap();
-    sigaction(SIGILL, &sa_trap).unwrap();
-    sigaction(SIGSEGV, &sa_trap).unwrap();
-    sigaction(SIGBUS, &sa_trap).unwrap();
-    sigaction(SIGTRAP, &sa_trap).unwrap();
-
-    let sa_interrupt = SigAction::new(
-        SigHandler::SigAction(sigint_handler),
-        SaFlags::SA_ONSTACK,
-        SigSet::empty(),
-    );
+/// Clears the `CTX_INTERRUPT_*` flag `flag` of every active `Ctx`.
+fn clear_ctx_interrupt_flag(flag: usize) {
+    for slot in ACTIVE_CTXS.iter() {
+        slot.interrupt_flags.fetch_and(!flag, Ordering::SeqCst);
+    }
+}
 
-    SIGINT_SYS_HANDLER  = Some(sigaction(SIGINT, &sa_interrupt).unwrap());
+/// Consumes a SIGINT delivered to `ctx`, returning whether there was one.
+fn take_sigint_delivered(ctx: *mut vm::Ctx) -> bool {
+    let mut delivered = false;
+    for slot in ACTIVE_CTXS.iter() {
+        if slot.ctx.load(Ordering::SeqCst) == ctx as usize
+            && slot.delivered.swap(false, Ordering::SeqCst)
+        {
+            delivered = true;
+        }
+    }
+    delivered
 }
 
-#[derive(Debug, Clone)]
-/// Info about the fault
-pub struct FaultInfo {
-    /// Faulting address.
-    pub faulting_addr: *const c_void,
-    /// Instruction pointer.
-    pub ip: &'static Cell<usize>,
-    /// Values of known registers.
-    pub known_registers: [Option<u64>; 32],
+/// Returns a boolean indicating if a SIGINT was delivered to `ctx` and has not yet been turned
+/// into a fault.
+///
+/// Only the `Ctx`s active in a `with_ctx` call when the SIGINT arrives receive it.
+pub fn is_sigint_delivered_to(ctx: *const vm::Ctx) -> bool {
+    ACTIVE_CTXS.iter().any(|slot| {
+        slot.ctx.load(Ordering::SeqCst) == ctx as usize && slot.delivered.load(Ordering::SeqCst)
+    })
 }
 
-impl FaultInfo {
-    /// Parses the stack and builds an execution state image.
-    pub unsafe fn read_stack(&self, max_depth: Option<usize>) -> Option<ExecutionStateImage> {
-        let rsp = self.known_registers[X64Register::GPR(GPR::RSP).to_index().0]?;
+/// Returns a boolean indicating if SIGINT triggered the fault.
+///
+/// Always false if the SIGINT handler is not installed. This is only meaningful on the thread
+/// that handled the fault, until the next fault; the same information is carried by
+/// `InstanceImage::from_sigint` and `InvokeError::Interrupted`.
+pub fn was_sigint_triggered_fault() -> bool {
+    SIGINT_HANDLER_INSTALLED.load(Ordering::SeqCst) && WAS_SIGINT_TRIGGERED.with(|x| x.get())
+}
 
-        Some(CURRENT_CODE_VERSIONS.with(|versions| {
-            let versions = versions.borrow();
-            read_stack(
-                || versions.iter(),
-                rsp as usize as *const u64,
-                self.known_registers,
-                Some(self.ip.get() as u64),
-                max_depth,
-            )
-        }))
+/// A consistent snapshot of the SIGINT state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SigintState {
+    /// A SIGINT was delivered to an active `Ctx` and has not yet been turned into a fault.
+    pub delivered: bool,
+    /// The last fault on the current thread was triggered by SIGINT.
+    pub triggered_on_this_thread: bool,
+}
+
+/// Returns a snapshot of the SIGINT state.
+///
+/// The thread-local state is read after the global one, so a SIGINT that is consumed between
+/// the two reads is reported as triggered rather than lost.
+pub fn sigint_state() -> SigintState {
+    let delivered = is_sigint_pending();
+    SigintState {
+        delivered,
+        triggered_on_this_thread: was_sigint_triggered_fault(),
     }
 }
 
-#[cfg(all(target_os = "freebsd", target_arch = "aarch64"))]
-/// Get fault info from siginfo and ucontext.
-pub unsafe fn get_fault_info(siginfo: *const c_void, ucontext: *mut c_void) -> FaultInfo {
-    #[repr(C)]
-    pub struct ucontext_t {
-        uc_sigmask: libc::sigset_t,
-        uc_mcontext: mcontext_t,
-        uc_link: *mut ucontext_t,
-        uc_stack: libc::stack_t,
-        uc_flags: i32,
-        __spare__: [i32; 4],
+/// Runs a callback function with the given `Ctx`.
+///
+/// Calls may be nested, e.g. from a host function calling into another instance; the previous
+/// `Ctx` is restored when `cb` returns or panics.
+pub unsafe fn with_ctx<R, F: FnOnce() -> R>(ctx: *mut vm::Ctx, cb: F) -> R {
+    struct Guard {
+        addr: *mut *mut vm::Ctx,
+        old: *mut vm::Ctx,
+        active_slot: Option<usize>,
     }
-    #[repr(C)]
-    pub struct gpregs {
-        gp_x: [u64; 30],
-        gp_lr: u64,
-        gp_sp: u64,
-        gp_elr: u64,
-        gp_spsr: u64,
-        gp_pad: i32,
+
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            unsafe {
+                *self.addr = self.old;
+            }
+            CTX_STACK.with(|x| x.borrow_mut().pop());
+            if let Some(index) = self.active_slot {
+                unsafe { clear_stale_ctx_interrupt(index) };
+                unregister_active_ctx(index);
+            }
+        }
+    }
+
+    let addr = CURRENT_CTX.with(|x| x.get());
+    // Only the outermost call for `ctx` on this thread holds a slot, so that its interrupt
+    // state outlives nested calls.
+    let active_slot = if ctx.is_null() || CTX_STACK.with(|x| x.borrow().contains(&ctx)) {
+        None
+    } else {
+        register_active_ctx(ctx)
     };
-    #[repr(C)]
-    pub struct fpregs {
-        fp_q: [u128; 32],
-        fp_sr: u32,
-        fp_cr: u32,
-        fp_flags: i32,
-        fp_pad: i32,
+    let _guard = Guard {
+        addr,
+        old: *addr,
+        active_slot,
     };
-    #[repr(C)]
-    pub struct mcontext_t {
-        mc_gpregs: gpregs,
-        mc_fpregs: fpregs,
-        mc_flags: i32,
-        mc_pad: i32,
-        mc_spare: [u64; 8],
+    *addr = ctx;
+    CTX_STACK.with(|x| x.borrow_mut().push(ctx));
+    cb()
+}
+
+/// Calls `f` with the `Ctx` set by the innermost `with_ctx` call on the current thread, or with
+/// `None` outside of any, e.g. when a breakpoint callback fires outside an invocation.
+///
+/// The `Ctx` is borrowed from the caller of `with_ctx` and must not be kept beyond `f`.
+pub fn with_current_ctx<R, F: FnOnce(Option<&mut vm::Ctx>) -> R>(f: F) -> R {
+    let ctx = CURRENT_CTX.with(|x| unsafe { *x.get() });
+    f(unsafe { ctx.as_mut() })
+}
+
+/// Resets the fault handling state of the current thread: unwind scopes, the current `Ctx`,
+/// code versions and per-fault flags.
+///
+/// This is meant for thread pools returning a thread to the pool, so that state left behind by
+/// an invocation that went wrong does not leak into the next one on the same thread.
+///
+/// Must not be called inside `catch_unsafe_unwind` or `with_ctx`, whose state it discards.
+pub unsafe fn reset_fault_thread_state() {
+    UNWIND.with(|x| *x.get() = None);
+    CATCH_SCOPE_DEPTH.with(|x| x.set(0));
+    CURRENT_CTX.with(|x| *x.get() = ::std::ptr::null_mut());
+    CTX_STACK.with(|x| x.borrow_mut().clear());
+    CURRENT_CODE_VERSIONS.with(|x| x.borrow_mut().clear());
+    CURRENT_CODE_HASHES.with(|x| x.borrow_mut().clear());
+    WAS_SIGINT_TRIGGERED.with(|x| x.set(false));
+    BREAKPOINT_DISARM_REQUESTED.with(|x| x.set(false));
+    BREAKPOINT_SUSPEND_REQUESTED.with(|x| x.set(false));
+    WATCHPOINT_STEP.with(|x| x.set(None));
+}
+
+/// Reads the wasm-level stack pointer of the `Ctx` running on the current thread.
+///
+/// Compilers such as LLVM keep the wasm stack in linear memory and track its top in a mutable
+/// global; `stack_pointer_global` is the index of that global. Returns `None` if no `Ctx` is
+/// active or the global does not exist.
+pub unsafe fn read_wasm_stack_pointer(stack_pointer_global: LocalGlobalIndex) -> Option<u64> {
+    let ctx = *CURRENT_CTX.with(|x| x.get());
+    if ctx.is_null() {
+        return None;
     }
+    (*(*ctx).local_backing)
+        .globals
+        .get(stack_pointer_global)
+        .map(|x| x.get().to_u128() as u64)
+}
 
-    let siginfo = siginfo as *const siginfo_t;
-    let si_addr = (*siginfo).si_addr;
+/// Pushes a new `CodeVersion` to the current code versions.
+///
+/// Every push must be matched by a `pop_code_version`, even if the code in between panics;
+/// prefer `register_code_version`, which guarantees it.
+pub fn push_code_version(version: CodeVersion) {
+    let hash = if cfg!(debug_assertions) && CODE_INTEGRITY_CHECK.load(Ordering::SeqCst) {
+        Some(unsafe { hash_code(&version) })
+    } else {
+        None
+    };
+    CURRENT_CODE_HASHES.with(|x| x.borrow_mut().push(hash));
+    CURRENT_CODE_VERSIONS.with(|x| x.borrow_mut().push(version));
+}
 
-    let ucontext = ucontext as *mut ucontext_t;
-    let gregs = &(*ucontext).uc_mcontext.mc_gpregs;
+/// Pops a `CodeVersion` from the current code versions.
+pub fn pop_code_version() -> Option<CodeVersion> {
+    CURRENT_CODE_HASHES.with(|x| x.borrow_mut().pop());
+    CURRENT_CODE_VERSIONS.with(|x| x.borrow_mut().pop())
+}
 
-    let mut known_registers: [Option<u64>; 32] = [None; 32];
+/// Calls `f` with the current code versions, outermost first, e.g. to enumerate the modules
+/// running on the current thread.
+///
+/// Code versions cannot be pushed or popped from within `f`.
+pub fn with_code_versions<R, F: FnOnce(&[CodeVersion]) -> R>(f: F) -> R {
+    CURRENT_CODE_VERSIONS.with(|x| f(&x.borrow()))
+}
 
-    known_registers[X64Register::GPR(GPR::R15).to_index().0] = Some(gregs.gp_x[15] as _);
-    known_registers[X64Register::GPR(GPR::R14).to_index().0] = Some(gregs.gp_x[14] as _);
-    known_registers[X64Register::GPR(GPR::R13).to_index().0] = Some(gregs.gp_x[13] as _);
-    known_registers[X64Register::GPR(GPR::R12).to_index().0] = Some(gregs.gp_x[12] as _);
-    known_registers[X64Register::GPR(GPR::R11).to_index().0] = Some(gregs.gp_x[11] as _);
-    known_registers[X64Register::GPR(GPR::R10).to_index().0] = Some(gregs.gp_x[10] as _);
-    known_registers[X64Register::GPR(GPR::R9).to_index().0] = Some(gregs.gp_x[9] as _);
-    known_registers[X64Register::GPR(GPR::R8).to_index().0] = Some(gregs.gp_x[8] as _);
-    known_registers[X64Register::GPR(GPR::RSI).to_index().0] = Some(gregs.gp_x[6] as _);
-    known_registers[X64Register::GPR(GPR::RDI).to_index().0] = Some(gregs.gp_x[7] as _);
-    known_registers[X64Register::GPR(GPR::RDX).to_index().0] = Some(gregs.gp_x[2] as _);
-    known_registers[X64Register::GPR(GPR::RCX).to_index().0] = Some(gregs.gp_x[1] as _);
-    known_registers[X64Register::GPR(GPR::RBX).to_index().0] = Some(gregs.gp_x[3] as _);
-    known_registers[X64Register::GPR(GPR::RAX).to_index().0] = Some(gregs.gp_x[0] as _);
+/// Keeps a `CodeVersion` pushed to the current code versions until it is dropped; see
+/// `register_code_version`.
+#[must_use = "the code version is popped when the guard is dropped"]
+pub struct CodeVersionGuard {
+    // Code versions are per thread, so the guard must be dropped on the thread it was
+    // created on.
+    _not_send: PhantomData<*const ()>,
+}
 
-    known_registers[X64Register::GPR(GPR::RBP).to_index().0] = Some(gregs.gp_x[5] as _);
-    known_registers[X64Register::GPR(GPR::RSP).to_index().0] = Some(gregs.gp_x[28] as _);
+impl Drop for CodeVersionGuard {
+    fn drop(&mut self) {
+        pop_code_version();
+    }
+}
 
-    FaultInfo {
-        faulting_addr: si_addr as usize as _,
-        ip: std::mem::transmute::<&mut u64, &'static Cell<usize>>(
-            &mut (*ucontext).uc_mcontext.mc_gpregs.gp_elr,
-        ),
-        known_registers,
+/// Pushes a new `CodeVersion` to the current code versions, and returns a guard popping it when
+/// dropped, so that the code versions stay balanced when the code in between panics or returns
+/// early.
+///
+/// Guards must be dropped in the reverse order of their creation.
+pub fn register_code_version(version: CodeVersion) -> CodeVersionGuard {
+    push_code_version(version);
+    CodeVersionGuard {
+        _not_send: PhantomData,
     }
 }
 
-#[cfg(all(target_os = "freebsd", target_arch = "x86_64"))]
-/// Get fault info from siginfo and ucontext.
-pub unsafe fn get_fault_info(siginfo: *const c_void, ucontext: *mut c_void) -> FaultInfo {
-    use crate::state::x64::XMM;
-    #[repr(C)]
-    pub struct ucontext_t {
-        uc_sigmask: libc::sigset_t,
-        uc_mcontext: mcontext_t,
-        uc_link: *mut ucontext_t,
-        uc_stack: libc::stack_t,
-        uc_flags: i32,
-        __spare__: [i32; 4],
+/// Enables or disables verifying code versions against a hash of their code before an
+/// inline breakpoint is read from them.
+///
+/// The hash is recorded by `push_code_version`, so only code versions pushed while the check
+/// is enabled are verified. The check only takes effect in debug builds.
+pub fn set_code_integrity_check(enabled: bool) {
+    CODE_INTEGRITY_CHECK.store(enabled, Ordering::SeqCst);
+}
+
+unsafe fn hash_code(version: &CodeVersion) -> u64 {
+    hash_code_range(version.base, version.msm.total_size)
+}
+
+unsafe fn hash_code_range(base: usize, size: usize) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    std::slice::from_raw_parts(base as *const u8, size).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Hashes the code of the innermost code version containing `ip`.
+unsafe fn hash_code_at(ip: usize) -> Option<u64> {
+    CURRENT_CODE_VERSIONS.with(|versions| {
+        versions
+            .borrow()
+            .iter()
+            .rev()
+            .find(|v| ip >= v.base && ip < v.base + v.msm.total_size)
+            .map(|v| hash_code(v))
+    })
+}
+
+/// Checks the code of the `index`-th code version against the hash recorded when it was pushed.
+unsafe fn code_version_intact(index: usize, version: &CodeVersion) -> bool {
+    match CURRENT_CODE_HASHES.with(|x| x.borrow().get(index).cloned().unwrap_or(None)) {
+        Some(hash) => hash_code(version) == hash,
+        None => true,
     }
-    #[repr(C)]
-    pub struct mcontext_t {
-        mc_onstack: u64,
-        mc_rdi: u64,
-        mc_rsi: u64,
-        mc_rdx: u64,
-        mc_rcx: u64,
-        mc_r8: u64,
-        mc_r9: u64,
-        mc_rax: u64,
-        mc_rbx: u64,
-        mc_rbp: u64,
-        mc_r10: u64,
-        mc_r11: u64,
-        mc_r12: u64,
-        mc_r13: u64,
-        mc_r14: u64,
-        mc_r15: u64,
-        mc_trapno: u32,
-        mc_fs: u16,
-        mc_gs: u16,
-        mc_addr: u64,
-        mc_flags: u32,
-        mc_es: u16,
-        mc_ds: u16,
-        mc_err: u64,
-        mc_rip: u64,
-        mc_cs: u64,
-        mc_rflags: u64,
-        mc_rsp: u64,
-        mc_ss: u64,
-        mc_len: i64,
+}
 
-        mc_fpformat: i64,
-        mc_ownedfp: i64,
-        mc_savefpu: *const savefpu,
-        mc_fpstate: [i64; 63], // mc_fpstate[0] is a pointer to savefpu
+/// Maps the interrupt signal page and faults it in, if not done yet.
+///
+/// The page is otherwise mapped on first use, e.g. by the first `set_wasm_interrupt`; calling
+/// this during setup moves that cost out of latency-sensitive paths. The allocator set with
+/// `set_fault_allocator` must be set before.
+pub fn init_interrupt_signal_mem() {
+    lazy_static::initialize(&INTERRUPT_SIGNAL_MEM);
+}
 
-        mc_fsbase: u64,
-        mc_gsbase: u64,
+/// Gets the wasm interrupt signal mem.
+pub unsafe fn get_wasm_interrupt_signal_mem() -> *mut u8 {
+    INTERRUPT_SIGNAL_MEM.0
+}
 
-        mc_xfpustate: u64,
-        mc_xfpustate_len: u64,
+/// Sets the wasm interrupt on the given `Ctx`.
+pub unsafe fn set_wasm_interrupt_on_ctx(ctx: *mut vm::Ctx) {
+    arm_interrupt_on_ctx(ctx, (&*ctx).internal.interrupt_signal_mem);
+}
 
-        mc_spare: [i64; 4],
+/// Arms `mem` for `ctx`, recording that it was armed for `ctx` if it is running in `with_ctx`.
+unsafe fn arm_interrupt_on_ctx(ctx: *mut vm::Ctx, mem: *mut u8) {
+    if !set_ctx_interrupt_flags(ctx, CTX_INTERRUPT_ARMED) {
+        INTERRUPT_ARMED_GLOBALLY.store(true, Ordering::SeqCst);
     }
-    #[repr(C)]
-    pub struct xmmacc {
-        element: [u32; 4],
+    arm_interrupt_signal_mem(mem);
+}
+
+unsafe fn arm_interrupt_signal_mem(mem: *mut u8) {
+    if INTERRUPTS_DISABLED.load(Ordering::SeqCst) > 0 {
+        INTERRUPT_PENDING.store(true, Ordering::SeqCst);
+        return;
     }
-    #[repr(C)]
-    pub struct __envxmm64 {
-        en_cw: u16,
-        en_sw: u16,
-        en_tw: u8,
-        en_zero: u8,
-        en_opcode: u16,
-        en_rip: u64,
-        en_rdp: u64,
-        en_mxcsr: u32,
-        en_mxcsr_mask: u32,
+    if mprotect(mem as _, *INTERRUPT_SIGNAL_MEM_SIZE, PROT_NONE) < 0 {
+        panic!("cannot set PROT_NONE on signal mem");
     }
-    #[repr(C)]
-    pub struct fpacc87 {
-        fp_bytes: [u8; 10],
+    INTERRUPT_ARMED.store(true, Ordering::SeqCst);
+}
+
+/// Disarms the interrupt signal memory, keeping the state of the interrupts of each `Ctx`.
+unsafe fn disarm_interrupt_signal_mem() {
+    let mem: *mut u8 = INTERRUPT_SIGNAL_MEM.0;
+    if mprotect(mem as _, *INTERRUPT_SIGNAL_MEM_SIZE, PROT_READ | PROT_WRITE) < 0 {
+        panic!("cannot set PROT_READ | PROT_WRITE on signal mem");
     }
-    #[repr(C)]
-    pub struct sv_fp {
-        fp_acc: fpacc87,
-        fp_pad: [u8; 6],
+    INTERRUPT_ARMED.store(false, Ordering::SeqCst);
+}
+
+/// A handle to interrupt the code running on a `Ctx` from any thread.
+///
+/// The interrupted call fails with `InvokeError::Interrupted` if the `Ctx` is running in
+/// `with_ctx` when `interrupt` is called; otherwise the interrupt is reported like one set with
+/// `set_wasm_interrupt`.
+#[derive(Debug, Clone)]
+pub struct InterruptHandle {
+    ctx: usize,
+    signal_mem: usize,
+}
+
+impl InterruptHandle {
+    /// Creates a handle to interrupt the code running on `ctx`.
+    pub fn new(ctx: &vm::Ctx) -> InterruptHandle {
+        InterruptHandle {
+            ctx: ctx as *const vm::Ctx as usize,
+            signal_mem: ctx.internal.interrupt_signal_mem as usize,
+        }
     }
-    #[repr(C, align(16))]
-    pub struct savefpu {
-        sv_env: __envxmm64,
-        sv_fp_t: [sv_fp; 8],
-        sv_xmm: [xmmacc; 16],
-        sv_pad: [u8; 96],
+
+    /// Interrupts the code running on the `Ctx` of this handle at its next interrupt check.
+    pub fn interrupt(&self) {
+        set_ctx_interrupt_flags(self.ctx as *mut vm::Ctx, CTX_INTERRUPT_HANDLE);
+        unsafe { arm_interrupt_on_ctx(self.ctx as *mut vm::Ctx, self.signal_mem as *mut u8) };
     }
+}
 
-    let siginfo = siginfo as *const siginfo_t;
-    let si_addr = (*siginfo).si_addr;
+/// Interrupts the code running on `ctx` like `InterruptHandle::interrupt`, and sends the signal
+/// numbered `signal` to the thread running it, so that a thread blocked in a host function,
+/// e.g. in a system call, is woken up and reaches the next interrupt check.
+///
+/// `signal` must not be one handled by this module, and must have a handler installed that
+/// returns, e.g. a no-op handler for `SIGUSR1`; its default action would otherwise apply to the
+/// whole process. Blocking system calls are only interrupted if that handler was installed
+/// without `SA_RESTART`.
+///
+/// Returns false if `ctx` is not running in `with_ctx` on any thread, e.g. because more than
+/// `MAX_ACTIVE_CTXS` were active when it was entered, or the thread could not be signaled. The
+/// interrupt is not left armed then.
+pub unsafe fn interrupt_ctx_thread(ctx: *mut vm::Ctx, signal: i32) -> bool {
+    let thread = ACTIVE_CTXS
+        .iter()
+        .filter(|slot| slot.ctx.load(Ordering::SeqCst) == ctx as usize)
+        .map(|slot| slot.thread.load(Ordering::SeqCst))
+        .find(|&thread| thread != 0);
+    let thread = match thread {
+        Some(thread) => thread,
+        None => return false,
+    };
+    // Armed before signaling, so that the woken thread cannot pass the check first.
+    InterruptHandle::new(&*ctx).interrupt();
+    if libc::pthread_kill(thread as libc::pthread_t, signal) != 0 {
+        take_ctx_interrupt_flag(ctx, CTX_INTERRUPT_HANDLE | CTX_INTERRUPT_ARMED);
+        disarm_unless_interrupt_pending();
+        return false;
+    }
+    true
+}
 
-    let ucontext = ucontext as *mut ucontext_t;
-    let gregs = &mut (*ucontext).uc_mcontext;
+fn take_handle_interrupt(ctx: *mut vm::Ctx) -> bool {
+    take_ctx_interrupt_flag(ctx, CTX_INTERRUPT_HANDLE)
+}
 
-    fn read_xmm(reg: &xmmacc) -> u64 {
-        (reg.element[0] as u64) | ((reg.element[1] as u64) << 32)
+/// Sets the wasm interrupt on the given `Ctx`, tagged with `trace_id`.
+///
+/// The trace id is reported in the `InstanceImage` produced when the interrupt fires. It is
+/// dropped if `ctx` is not running in `with_ctx`.
+pub unsafe fn set_wasm_interrupt_on_ctx_with_trace_id(ctx: *mut vm::Ctx, trace_id: u64) {
+    set_ctx_interrupt_flags_with(ctx, CTX_INTERRUPT_TRACE_ID, |slot| {
+        slot.trace_id.store(trace_id, Ordering::SeqCst)
+    });
+    set_wasm_interrupt_on_ctx(ctx);
+}
+
+fn take_interrupt_trace_id(ctx: *mut vm::Ctx) -> Option<u64> {
+    ACTIVE_CTXS
+        .iter()
+        .filter(|slot| slot.ctx.load(Ordering::SeqCst) == ctx as usize)
+        .filter(|slot| {
+            slot.interrupt_flags
+                .fetch_and(!CTX_INTERRUPT_TRACE_ID, Ordering::SeqCst)
+                & CTX_INTERRUPT_TRACE_ID
+                != 0
+        })
+        .map(|slot| slot.trace_id.load(Ordering::SeqCst))
+        .last()
+}
+
+/// Sets the wasm interrupt on the given `Ctx` for `reason`, e.g. by metering middleware once
+/// the gas of the call is exhausted.
+///
+/// Instead of an `InstanceImage`, the interrupted call fails with `InvokeError::Interrupted`
+/// carrying `reason`. The reason is reset when the interrupt fires or is cleared, and is
+/// dropped if `ctx` is not running in `with_ctx`.
+pub unsafe fn set_wasm_interrupt_with_reason(ctx: *mut vm::Ctx, reason: InterruptReason) {
+    let encoded = encode_interrupt_reason(Some(reason));
+    set_ctx_interrupt_flags_with(ctx, CTX_INTERRUPT_REASON, |slot| {
+        slot.interrupt_reason.store(encoded, Ordering::SeqCst)
+    });
+    set_wasm_interrupt_on_ctx(ctx);
+}
+
+/// Returns the reason the interrupt of `ctx` was set with, if any.
+pub fn wasm_interrupt_reason(ctx: *mut vm::Ctx) -> Option<InterruptReason> {
+    ACTIVE_CTXS
+        .iter()
+        .filter(|slot| slot.ctx.load(Ordering::SeqCst) == ctx as usize)
+        .filter(|slot| slot.interrupt_flags.load(Ordering::SeqCst) & CTX_INTERRUPT_REASON != 0)
+        .map(|slot| slot.interrupt_reason.load(Ordering::SeqCst))
+        .last()
+        .and_then(decode_interrupt_reason)
+}
+
+fn take_interrupt_reason(ctx: *mut vm::Ctx) -> Option<InterruptReason> {
+    ACTIVE_CTXS
+        .iter()
+        .filter(|slot| slot.ctx.load(Ordering::SeqCst) == ctx as usize)
+        .filter(|slot| {
+            slot.interrupt_flags
+                .fetch_and(!CTX_INTERRUPT_REASON, Ordering::SeqCst)
+                & CTX_INTERRUPT_REASON
+                != 0
+        })
+        .map(|slot| slot.interrupt_reason.load(Ordering::SeqCst))
+        .last()
+        .and_then(decode_interrupt_reason)
+}
+
+fn encode_interrupt_reason(reason: Option<InterruptReason>) -> usize {
+    match reason {
+        None => 0,
+        Some(InterruptReason::GasExhausted) => 1,
+        Some(InterruptReason::Timeout) => 2,
+        Some(InterruptReason::HostRequested) => 3,
     }
+}
 
-    let mut known_registers: [Option<u64>; 32] = [None; 32];
-    known_registers[X64Register::GPR(GPR::R15).to_index().0] = Some(gregs.mc_r15);
-    known_registers[X64Register::GPR(GPR::R14).to_index().0] = Some(gregs.mc_r14);
-    known_registers[X64Register::GPR(GPR::R13).to_index().0] = Some(gregs.mc_r13);
-    known_registers[X64Register::GPR(GPR::R12).to_index().0] = Some(gregs.mc_r12);
-    known_registers[X64Register::GPR(GPR::R11).to_index().0] = Some(gregs.mc_r11);
-    known_registers[X64Register::GPR(GPR::R10).to_index().0] = Some(gregs.mc_r10);
-    known_registers[X64Register::GPR(GPR::R9).to_index().0] = Some(gregs.mc_r9);
-    known_registers[X64Register::GPR(GPR::R8).to_index().0] = Some(gregs.mc_r8);
-    known_registers[X64Register::GPR(GPR::RSI).to_index().0] = Some(gregs.mc_rsi);
-    known_registers[X64Register::GPR(GPR::RDI).to_index().0] = Some(gregs.mc_rdi);
-    known_registers[X64Register::GPR(GPR::RDX).to_index().0] = Some(gregs.mc_rdx);
-    known_registers[X64Register::GPR(GPR::RCX).to_index().0] = Some(gregs.mc_rcx);
-    known_registers[X64Register::GPR(GPR::RBX).to_index().0] = Some(gregs.mc_rbx);
-    known_registers[X64Register::GPR(GPR::RAX).to_index().0] = Some(gregs.mc_rax);
+fn decode_interrupt_reason(x: usize) -> Option<InterruptReason> {
+    match x {
+        1 => Some(InterruptReason::GasExhausted),
+        2 => Some(InterruptReason::Timeout),
+        3 => Some(InterruptReason::HostRequested),
+        _ => None,
+    }
+}
 
-    known_registers[X64Register::GPR(GPR::RBP).to_index().0] = Some(gregs.mc_rbp);
-    known_registers[X64Register::GPR(GPR::RSP).to_index().0] = Some(gregs.mc_rsp);
+/// Sets the wasm interrupt on the given `Ctx`, capturing only the linear memory when it fires.
+///
+/// Instead of an `InstanceImage`, the interrupted call fails with
+/// `InvokeError::PartialResult` holding a copy of the instance's linear memory. Registers and
+/// the stack are not captured, so execution cannot be resumed. If `ctx` is not running in
+/// `with_ctx`, the interrupt produces an `InstanceImage` instead.
+pub unsafe fn interrupt_and_capture_memory(ctx: *mut vm::Ctx) {
+    set_ctx_interrupt_flags(ctx, CTX_INTERRUPT_MEMORY_CAPTURE);
+    set_wasm_interrupt_on_ctx(ctx);
+}
 
-    // https://lists.freebsd.org/pipermail/freebsd-arch/2011-December/012077.html
-    // https://people.freebsd.org/~kib/misc/defer_sig.c
-    const _MC_HASFPXSTATE: u32 = 0x4;
-    if (gregs.mc_flags & _MC_HASFPXSTATE) == 0 {
-        // XXX mc_fpstate[0] is actually a pointer to a struct savefpu
-        let fpregs = &*(*ucontext).uc_mcontext.mc_savefpu;
-        known_registers[X64Register::XMM(XMM::XMM0).to_index().0] =
-            Some(read_xmm(&fpregs.sv_xmm[0]));
-        known_registers[X64Register::XMM(XMM::XMM1).to_index().0] =
-            Some(read_xmm(&fpregs.sv_xmm[1]));
-        known_registers[X64Register::XMM(XMM::XMM2).to_index().0] =
-            Some(read_xmm(&fpregs.sv_xmm[2]));
-        known_registers[X64Register::XMM(XMM::XMM3).to_index().0] =
-            Some(read_xmm(&fpregs.sv_xmm[3]));
-        known_registers[X64Register::XMM(XMM::XMM4).to_index().0] =
-            Some(read_xmm(&fpregs.sv_xmm[4]));
-        known_registers[X64Register::XMM(XMM::XMM5).to_index().0] =
-            Some(read_xmm(&fpregs.sv_xmm[5]));
-        known_registers[X64Register::XMM(XMM::XMM6).to_index().0] =
-            Some(read_xmm(&fpregs.sv_xmm[6]));
-        known_registers[X64Register::XMM(XMM::XMM7).to_index().0] =
-            Some(read_xmm(&fpregs.sv_xmm[7]));
-        known_registers[X64Register::XMM(XMM::XMM8).to_index().0] =
-            Some(read_xmm(&fpregs.sv_xmm[8]));
-        known_registers[X64Register::XMM(XMM::XMM9).to_index().0] =
-            Some(read_xmm(&fpregs.sv_xmm[9]));
-        known_registers[X64Register::XMM(XMM::XMM10).to_index().0] =
-            Some(read_xmm(&fpregs.sv_xmm[10]));
-        known_registers[X64Register::XMM(XMM::XMM11).to_index().0] =
-            Some(read_xmm(&fpregs.sv_xmm[11]));
-        known_registers[X64Register::XMM(XMM::XMM12).to_index().0] =
-            Some(read_xmm(&fpregs.sv_xmm[12]));
-        known_registers[X64Register::XMM(XMM::XMM13).to_index().0] =
-            Some(read_xmm(&fpregs.sv_xmm[13]));
-        known_registers[X64Register::XMM(XMM::XMM14).to_index().0] =
-            Some(read_xmm(&fpregs.sv_xmm[14]));
-        known_registers[X64Register::XMM(XMM::XMM15).to_index().0] =
-            Some(read_xmm(&fpregs.sv_xmm[15]));
+fn take_memory_capture(ctx: *mut vm::Ctx) -> bool {
+    take_ctx_interrupt_flag(ctx, CTX_INTERRUPT_MEMORY_CAPTURE)
+}
+
+unsafe fn copy_linear_memory(ctx: &vm::Ctx) -> Vec<u8> {
+    if ctx.internal.memory_base.is_null() {
+        vec![]
+    } else {
+        std::slice::from_raw_parts(ctx.internal.memory_base, ctx.internal.memory_bound).to_vec()
     }
+}
 
-    FaultInfo {
-        faulting_addr: si_addr,
-        ip: std::mem::transmute::<&mut u64, &'static Cell<usize>>(
-            &mut (*ucontext).uc_mcontext.mc_rip,
-        ),
-        known_registers,
+/// Fills the `InvokeError::PartialResult` unwound by the signal handler with the linear memory
+/// of its `Ctx`.
+///
+/// The signal handler must not allocate, so the memory is only copied once the unwind has
+/// completed. The `Ctx` is still alive then, since the call it belongs to has not returned yet.
+unsafe fn complete_memory_capture(error: &mut RuntimeError) {
+    let ctx = PENDING_MEMORY_CAPTURE.with(|x| x.replace(::std::ptr::null_mut()));
+    if let RuntimeError::InvokeError(InvokeError::PartialResult(memory)) = error {
+        if !ctx.is_null() {
+            *memory = copy_linear_memory(&*ctx);
+        }
     }
 }
 
-#[cfg(all(
-    any(target_os = "linux", target_os = "android"),
-    target_arch = "aarch64"
-))]
-/// Get fault info from siginfo and ucontext.
-pub unsafe fn get_fault_info(siginfo: *const c_void, ucontext: *mut c_void) -> FaultInfo {
-    #[allow(dead_code)]
-    #[allow(non_camel_case_types)]
-    #[repr(packed)]
-    struct sigcontext {
-        fault_address: u64,
-        regs: [u64; 31],
-        sp: u64,
-        pc: u64,
-        pstate: u64,
-        reserved: [u8; 4096],
+/// Sets a wasm interrupt.
+pub unsafe fn set_wasm_interrupt() {
+    INTERRUPT_ARMED_GLOBALLY.store(true, Ordering::SeqCst);
+    arm_interrupt_signal_mem(INTERRUPT_SIGNAL_MEM.0);
+}
+
+/// Clears the wasm interrupt, and the reasons it was set with.
+pub unsafe fn clear_wasm_interrupt() {
+    disarm_interrupt_signal_mem();
+    INTERRUPT_ARMED_GLOBALLY.store(false, Ordering::SeqCst);
+    clear_ctx_interrupt_flag(CTX_INTERRUPT_ARMED | CTX_INTERRUPT_REASON);
+}
+
+/// Returns a boolean indicating if a wasm interrupt is currently armed.
+pub fn is_wasm_interrupt_set() -> bool {
+    INTERRUPT_ARMED.load(Ordering::SeqCst)
+}
+
+/// Runs `f` with wasm interrupts disabled.
+///
+/// An armed interrupt is cleared on entry, and interrupts requested while `f` runs are
+/// deferred. Either is re-armed on `ctx` once `f` returns, even if it panics.
+pub unsafe fn with_interrupts_disabled<R, F: FnOnce() -> R>(ctx: *mut vm::Ctx, f: F) -> R {
+    struct Guard(*mut vm::Ctx);
+
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            if INTERRUPTS_DISABLED.fetch_sub(1, Ordering::SeqCst) == 1
+                && INTERRUPT_PENDING.swap(false, Ordering::SeqCst)
+            {
+                unsafe {
+                    set_wasm_interrupt_on_ctx(self.0);
+                }
+            }
+        }
     }
 
-    #[allow(dead_code)]
-    #[allow(non_camel_case_types)]
-    #[repr(packed)]
-    struct ucontext {
-        unknown: [u8; 176],
-        uc_mcontext: sigcontext,
+    INTERRUPTS_DISABLED.fetch_add(1, Ordering::SeqCst);
+    let _guard = Guard(ctx);
+    if is_wasm_interrupt_set() {
+        // Keep the reason of the deferred interrupt for when it is re-armed.
+        disarm_interrupt_signal_mem();
+        INTERRUPT_PENDING.store(true, Ordering::SeqCst);
+    }
+    f()
+}
+
+/// How `begin_unsafe_unwind` unwinds the frames of a host function called from wasm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnwindStrategy {
+    /// Jump straight to the innermost `catch_unsafe_unwind` scope with `longjmp`, without
+    /// running the destructors of the values in the frames in between. This is the default.
+    Longjmp = 0,
+    /// Unwind the frames of the host function with a Rust panic, running their destructors,
+    /// and only `longjmp` over the frames of wasm code once the panic is caught at the
+    /// boundary of the host function.
+    Panic = 1,
+}
+
+/// Sets how `begin_unsafe_unwind` unwinds the frames of a host function called from wasm,
+/// e.g. to run the `Drop` of host data structures on the stack when a host function traps.
+///
+/// Traps raised by wasm code itself are always unwound with `longjmp` from the signal
+/// handler, since the generated code has no unwind tables, and so are the calls to
+/// `RunnableModule::do_early_trap`, which may come from frames that cannot be unwound with a
+/// panic, e.g. those of C host functions. Under `UnwindStrategy::Panic`, a panic unwinding a
+/// host function is not reported to the panic hook.
+pub fn set_unwind_strategy(strategy: UnwindStrategy) {
+    UNWIND_STRATEGY.store(strategy as usize, Ordering::SeqCst);
+}
+
+/// Returns the strategy set by `set_unwind_strategy`.
+pub fn unwind_strategy() -> UnwindStrategy {
+    match UNWIND_STRATEGY.load(Ordering::SeqCst) {
+        1 => UnwindStrategy::Panic,
+        _ => UnwindStrategy::Longjmp,
+    }
+}
+
+/// The payload of the panic raised by `begin_unsafe_unwind` under `UnwindStrategy::Panic`.
+struct UnwindPayload(Box<RuntimeError>);
+
+/// Marks the current thread as running a host function called from wasm, or not, until
+/// dropped, so that `begin_unsafe_unwind` only panics where the panic is caught before
+/// reaching the frames of wasm code.
+pub(crate) struct HostFunctionGuard(bool);
+
+impl HostFunctionGuard {
+    /// Sets whether a host function is running, saving the previous state.
+    pub(crate) fn enter(in_host_function: bool) -> HostFunctionGuard {
+        HostFunctionGuard(IN_HOST_FUNCTION.with(|x| x.replace(in_host_function)))
+    }
+}
+
+impl Drop for HostFunctionGuard {
+    fn drop(&mut self) {
+        IN_HOST_FUNCTION.with(|x| x.set(self.0));
+    }
+}
+
+/// Returns the error to trap with for a panic caught at the boundary of a host function: the
+/// error passed to `begin_unsafe_unwind` under `UnwindStrategy::Panic`, or the payload of any
+/// other panic as a `RuntimeError::User`.
+pub(crate) fn runtime_error_from_panic(payload: Box<dyn Any + Send>) -> RuntimeError {
+    match payload.downcast::<UnwindPayload>() {
+        Ok(payload) => *payload.0,
+        Err(payload) => RuntimeError::User(payload),
+    }
+}
+
+/// Catches an unsafe unwind with the given functions and breakpoints.
+///
+/// The frames between `begin_unsafe_unwind` and this scope are discarded with `longjmp`, without
+/// running the destructors of their values, unless they belong to a host function unwound as
+/// set with `set_unwind_strategy`.
+pub unsafe fn catch_unsafe_unwind<R, F: FnOnce() -> R>(
+    f: F,
+    breakpoints: Option<BreakpointMap>,
+) -> Result<R, RuntimeError> {
+    // Make the stack bounds available to the signal handler.
+    thread_stack_bounds();
+
+    let hooks = if HAS_EXECUTION_BOUNDARY_HOOKS.load(Ordering::SeqCst) {
+        *EXECUTION_BOUNDARY_HOOKS.read().unwrap()
+    } else {
+        None
+    };
+    let observer = match INVOCATION_OBSERVER.load(Ordering::SeqCst) {
+        0 => None,
+        x => Some(std::mem::transmute::<usize, InvocationObserver>(x)),
+    };
+    let started = observer.map(|_| Instant::now());
+    let notify = |result: &Result<R, RuntimeError>| {
+        if let (Some(observer), Some(started)) = (observer, started) {
+            observer(result.as_ref().err(), started.elapsed());
+        }
+    };
+
+    let unwind = UNWIND.with(|x| x.get());
+    let old = (*unwind).take();
+    *unwind = Some(UnwindInfo {
+        jmpbuf: [0; SETJMP_BUFFER_LEN],
+        breakpoints: breakpoints,
+        disarmed_breakpoints: HashSet::new(),
+        breakpoint_hits: HashMap::new(),
+        payload: None,
+        stack_marker: &old as *const Option<UnwindInfo> as usize,
+        outer: &old,
+    });
+
+    ACTIVE_UNWIND_SCOPES.fetch_add(1, Ordering::SeqCst);
+    CATCH_SCOPE_DEPTH.with(|x| x.set(x.get() + 1));
+    // `f` may call wasm code directly, so no host function is running until it calls one.
+    let in_host_function = IN_HOST_FUNCTION.with(|x| x.replace(false));
+
+    if raw::setjmp(&mut (*unwind).as_mut().unwrap().jmpbuf as *mut SetJmpBuffer as *mut _) != 0 {
+        // error
+        ACTIVE_UNWIND_SCOPES.fetch_sub(1, Ordering::SeqCst);
+        CATCH_SCOPE_DEPTH.with(|x| x.set(x.get() - 1));
+        IN_HOST_FUNCTION.with(|x| x.set(in_host_function));
+        if let Some((_, exit)) = hooks {
+            exit();
+        }
+        let ret = (*unwind).as_mut().and_then(|x| x.payload.take());
+        *unwind = old;
+        // A `longjmp` without a payload means the unwind state was corrupted.
+        let mut error = ret.map_or(
+            RuntimeError::InvokeError(InvokeError::FailedWithNoError),
+            |x| *x,
+        );
+        complete_memory_capture(&mut error);
+        let result = Err(error);
+        notify(&result);
+        result
+    } else {
+        if let Some((enter, _)) = hooks {
+            enter();
+        }
+        let ret = f();
+        // implicit control flow to the error case...
+        if ACTIVE_UNWIND_SCOPES.fetch_sub(1, Ordering::SeqCst) == 1 {
+            clear_stale_interrupt();
+        }
+        CATCH_SCOPE_DEPTH.with(|x| x.set(x.get() - 1));
+        IN_HOST_FUNCTION.with(|x| x.set(in_host_function));
+        if let Some((_, exit)) = hooks {
+            exit();
+        }
+        let payload = (*unwind).as_mut().and_then(|x| x.payload.take());
+        *unwind = old;
+        let result = match payload {
+            // A payload without a `longjmp` means the unwind state was corrupted. Report the
+            // error instead of dropping it.
+            Some(e) => Err(*e),
+            None => Ok(ret),
+        };
+        notify(&result);
+        result
+    }
+}
+
+/// Like `catch_unsafe_unwind`, but also returns the CPU time consumed by the current thread
+/// while running `f`, e.g. to bill guests for the CPU they used rather than wall-clock time.
+///
+/// The measurement starts right before `f` is called and ends right after it returns, so the
+/// bookkeeping of the scope is not included. If `f` is unwound, e.g. by a trap, it ends once
+/// the unwind has completed and includes the time spent handling the fault.
+pub unsafe fn catch_unsafe_unwind_timed<R, F: FnOnce() -> R>(
+    f: F,
+    breakpoints: Option<BreakpointMap>,
+) -> (Result<R, RuntimeError>, Duration) {
+    let started: Cell<Option<Duration>> = Cell::new(None);
+    let ended: Cell<Option<Duration>> = Cell::new(None);
+    let result = catch_unsafe_unwind(
+        || {
+            started.set(Some(thread_cpu_time()));
+            let ret = f();
+            ended.set(Some(thread_cpu_time()));
+            ret
+        },
+        breakpoints,
+    );
+    let elapsed = match started.get() {
+        Some(started) => ended.get().unwrap_or_else(thread_cpu_time) - started,
+        None => Duration::from_secs(0),
+    };
+    (result, elapsed)
+}
+
+/// Runs `f` with `ctx` current inside a `catch_unsafe_unwind` scope with the given breakpoints,
+/// e.g. to call into an instance from the embedder.
+///
+/// The scope is set up within `with_ctx`, so the previous `Ctx` is restored even if `f` is
+/// unwound by a trap.
+pub unsafe fn run_wasm<R, F: FnOnce() -> R>(
+    ctx: *mut vm::Ctx,
+    breakpoints: Option<BreakpointMap>,
+    f: F,
+) -> Result<R, RuntimeError> {
+    with_ctx(ctx, || catch_unsafe_unwind(f, breakpoints))
+}
+
+/// Returns the CPU time consumed by the current thread so far.
+fn thread_cpu_time() -> Duration {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut time) };
+    Duration::new(time.tv_sec as u64, time.tv_nsec as u32)
+}
+
+/// Returns true if the current thread is within a `catch_unsafe_unwind` scope, i.e. if
+/// `begin_unsafe_unwind` can be called.
+pub fn is_in_catch_scope() -> bool {
+    catch_scope_depth() > 0
+}
+
+/// Returns the number of nested `catch_unsafe_unwind` scopes on the current thread, e.g. when
+/// host functions call back into wasm.
+pub fn catch_scope_depth() -> usize {
+    CATCH_SCOPE_DEPTH.with(|x| x.get())
+}
+
+/// Walks the wasm stack starting at `rsp` and builds an execution state image, without a
+/// `FaultInfo`, e.g. to replay a recorded fault or to test the walker with a synthetic stack.
+///
+/// `ip` is the address of the innermost frame; if `None`, it is read from the top of the
+/// stack. Frames are looked up in `code_versions`. The walk stops after `max_depth` frames, or
+/// before reading at or above `stack_base` if given.
+pub unsafe fn walk_stack(
+    rsp: usize,
+    known_registers: [Option<u64>; 32],
+    ip: Option<usize>,
+    code_versions: &[CodeVersion],
+    max_depth: Option<usize>,
+    stack_base: Option<usize>,
+) -> ExecutionStateImage {
+    read_stack(
+        || code_versions.iter(),
+        rsp as *const u64,
+        known_registers,
+        ip.map(|x| x as u64),
+        max_depth,
+        stack_base,
+    )
+}
+
+/// Captures the backtrace of the wasm code that called the current host function, without a
+/// fault, e.g. to log the wasm call stack from an import.
+///
+/// The frames of the calling wasm code are read from the return address of its call to the
+/// stub of the import, found on the stack between this function and the enclosing
+/// `catch_unsafe_unwind` scope. The current host function is recorded as a `HostFrame` at depth
+/// 0. Values held in registers at the call are unknown.
+///
+/// Returns `None` if not called from a host function invoked by wasm with its code versions
+/// tracked.
+#[inline(never)]
+pub fn capture_backtrace() -> Option<ExecutionStateImage> {
+    let marker = 0u8;
+    let stack_ptr = (&marker as *const u8 as usize + 7) & !7;
+    unsafe {
+        let scope = match *UNWIND.with(|x| x.get()) {
+            Some(ref x) => x,
+            None => return None,
+        };
+        let mut image = CURRENT_CODE_VERSIONS.with(|versions| {
+            let versions = versions.borrow();
+            let import_stubs: Vec<Vec<usize>> = versions.iter().map(import_stub_offsets).collect();
+            let (slot, (version, import_index)) = (stack_ptr..scope.stack_marker)
+                .step_by(8)
+                .find_map(|slot| {
+                    find_import_call(&versions, &import_stubs, *(slot as *const usize))
+                        .map(|x| (slot, x))
+                })?;
+            let mut image = read_stack(
+                || versions.iter(),
+                slot as *const u64,
+                [None; 32],
+                None,
+                max_backtrace_depth(),
+                Some(scope.stack_marker),
+            );
+            image.host_frames.push(HostFrame {
+                depth: 0,
+                import_index,
+                name: import_name(&versions[version], import_index),
+            });
+            Some(image)
+        })?;
+        read_host_frames(&mut image);
+        Some(image)
+    }
+}
+
+/// Appends to `image` the wasm frames beyond host functions that called back into wasm, and
+/// records a `HostFrame` for each of those host functions.
+///
+/// The frames of a host function lie between the frames of two nested `catch_unsafe_unwind`
+/// scopes. The return address of the call from wasm to the stub of the import is searched for
+/// there, and the wasm frames are read from it on. Values held in registers at the call are
+/// unknown.
+///
+/// No frames are appended to an image that is already truncated, and the result is truncated
+/// to `max_backtrace_depth` frames.
+unsafe fn read_host_frames(image: &mut ExecutionStateImage) {
+    if image.truncated {
+        return;
+    }
+    let max_frames = max_backtrace_depth().map_or(MAX_BACKTRACE_FRAMES, |x| {
+        std::cmp::min(x, MAX_BACKTRACE_FRAMES)
+    });
+    let mut scope = match *UNWIND.with(|x| x.get()) {
+        Some(ref x) => x,
+        None => return,
+    };
+    CURRENT_CODE_VERSIONS.with(|versions| {
+        let versions = versions.borrow();
+        let import_stubs: Vec<Vec<usize>> = versions.iter().map(import_stub_offsets).collect();
+
+        while let Some(ref outer) = *scope.outer {
+            let found = (scope.stack_marker..outer.stack_marker)
+                .step_by(8)
+                .find_map(|slot| {
+                    find_import_call(&versions, &import_stubs, *(slot as *const usize))
+                        .map(|x| (slot, x))
+                });
+            let (slot, (version, import_index)) = match found {
+                Some(x) => x,
+                None => return,
+            };
+            image.host_frames.push(HostFrame {
+                depth: image.frames.len(),
+                import_index,
+                name: import_name(&versions[version], import_index),
+            });
+
+            let outer_image = read_stack(
+                || versions.iter(),
+                slot as *const u64,
+                [None; 32],
+                None,
+                None,
+                Some(outer.stack_marker),
+            );
+            image.frames.extend(outer_image.frames);
+            if outer_image.truncated || image.frames.len() > max_frames {
+                image.frames.truncate(max_frames);
+                image.truncated = true;
+                return;
+            }
+            scope = outer;
+        }
+    });
+}
+
+/// Returns the offsets of the stubs of the imported functions in `version`, by import index.
+fn import_stub_offsets(version: &CodeVersion) -> Vec<usize> {
+    let module = &version.runnable_module;
+    match (module.get_offsets(), module.get_local_function_offsets()) {
+        (Some(mut offsets), Some(local)) if offsets.len() >= local.len() => {
+            offsets.truncate(offsets.len() - local.len());
+            offsets
+        }
+        _ => vec![],
+    }
+}
+
+/// If `ret` is the return address of a `call rel32` from wasm code to the stub of an import,
+/// returns the index of its code version and the import index.
+unsafe fn find_import_call(
+    versions: &[CodeVersion],
+    import_stubs: &[Vec<usize>],
+    ret: usize,
+) -> Option<(usize, usize)> {
+    versions.iter().enumerate().find_map(|(i, v)| {
+        if ret < v.base + 5 || ret >= v.base + v.msm.total_size {
+            return None;
+        }
+        let call = (ret - 5) as *const u8;
+        if *call != 0xe8 {
+            return None;
+        }
+        let target = ret.wrapping_add(std::ptr::read_unaligned(call.add(1) as *const i32) as usize);
+        let import_index = import_stubs[i].iter().position(|&x| v.base + x == target)?;
+        v.msm.lookup_call_ip(ret, v.base)?;
+        Some((i, import_index))
+    })
+}
+
+/// Resolves the `namespace.name` of an import of the module of `version`, from the `Ctx`s of
+/// the active `with_ctx` calls.
+unsafe fn import_name(version: &CodeVersion, import_index: usize) -> Option<String> {
+    CTX_STACK.with(|ctxs| {
+        ctxs.borrow().iter().rev().find_map(|&ctx| {
+            if ctx.is_null() {
+                return None;
+            }
+            let module = &*(*ctx).module;
+            if !Arc::ptr_eq(&module.runnable_module, &version.runnable_module) {
+                return None;
+            }
+            let import = module
+                .info
+                .imported_functions
+                .get(ImportedFuncIndex::new(import_index))?;
+            Some(format!(
+                "{}.{}",
+                module.info.namespace_table.get(import.namespace_index),
+                module.info.name_table.get(import.name_index)
+            ))
+        })
+    })
+}
+
+/// Clears an interrupt armed for no `Ctx` in `with_ctx` that is still armed after all protected
+/// calls have returned, so that it does not fire in an unrelated later call.
+///
+/// Interrupts armed for a `Ctx` in `with_ctx` are left to `clear_stale_ctx_interrupt`.
+unsafe fn clear_stale_interrupt() {
+    if INTERRUPT_ARMED_GLOBALLY.swap(false, Ordering::SeqCst)
+        && INTERRUPT_ARMED.load(Ordering::SeqCst)
+    {
+        if !any_ctx_interrupt_flag(CTX_INTERRUPT_ARMED) {
+            disarm_interrupt_signal_mem();
+        }
+        UNDELIVERED_INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Clears the interrupt armed for the `Ctx` of the active slot `index` if it has not fired when
+/// the outermost `with_ctx` call for it returns, so that it does not fire in an unrelated later
+/// call.
+///
+/// The interrupt signal memory is shared, so it stays armed while an interrupt armed for
+/// another `Ctx`, or for none, is pending.
+unsafe fn clear_stale_ctx_interrupt(index: usize) {
+    let flags = ACTIVE_CTXS[index]
+        .interrupt_flags
+        .fetch_and(!CTX_INTERRUPT_ARMED, Ordering::SeqCst);
+    if flags & CTX_INTERRUPT_ARMED == 0 {
+        return;
+    }
+    disarm_unless_interrupt_pending();
+    UNDELIVERED_INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Disarms the interrupt signal memory unless an interrupt armed for a `Ctx`, or for none, has
+/// not fired yet.
+unsafe fn disarm_unless_interrupt_pending() {
+    if INTERRUPT_ARMED.load(Ordering::SeqCst)
+        && !INTERRUPT_ARMED_GLOBALLY.load(Ordering::SeqCst)
+        && !any_ctx_interrupt_flag(CTX_INTERRUPT_ARMED)
+    {
+        disarm_interrupt_signal_mem();
+    }
+}
+
+/// Returns the number of interrupts that were armed but cleared without being delivered,
+/// because the code they targeted returned without reaching an interrupt check.
+pub fn undelivered_interrupts() -> usize {
+    UNDELIVERED_INTERRUPTS.load(Ordering::SeqCst)
+}
+
+/// Begins an unsafe unwind.
+///
+/// Under `UnwindStrategy::Panic`, the frames of a host function called from wasm are unwound
+/// with a panic first. Panics if the current thread is not within a `catch_unsafe_unwind`
+/// scope; see `is_in_catch_scope`.
+pub unsafe fn begin_unsafe_unwind(e: Box<RuntimeError>) -> ! {
+    if unwind_strategy() == UnwindStrategy::Panic && IN_HOST_FUNCTION.with(|x| x.get()) {
+        panic::resume_unwind(Box::new(UnwindPayload(e)));
+    }
+    begin_unsafe_unwind_with_longjmp(e)
+}
+
+/// Begins an unsafe unwind with `longjmp` whatever the `UnwindStrategy`, e.g. from frames
+/// that cannot be unwound with a panic.
+///
+/// Panics if the current thread is not within a `catch_unsafe_unwind` scope.
+pub unsafe fn begin_unsafe_unwind_with_longjmp(e: Box<RuntimeError>) -> ! {
+    let unwind = UNWIND.with(|x| x.get());
+    let inner = (*unwind)
+        .as_mut()
+        .expect("not within a catch_unsafe_unwind scope");
+    inner.payload = Some(e);
+    raw::longjmp(&mut inner.jmpbuf as *mut SetJmpBuffer as *mut _, 0xffff);
+}
+
+/// A kind of fault synthesized by `inject_fault`.
+#[cfg(feature = "fault-injection")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    /// A trap with a known exception code.
+    Trap(ExceptionCode),
+    /// A fault that could not be attributed to a known cause, raised by the given signal.
+    UnknownTrap(i32),
+    /// An access to a guard region registered with `register_guard_region`.
+    StackOverflow,
+    /// An interrupt set with the given reason, if any.
+    Interrupted(Option<InterruptReason>),
+    /// A timeout set with `set_timeout`.
+    Timeout,
+    /// A suspend while no `Ctx` is current.
+    NoContext,
+    /// A `SIGABRT` caught as enabled by `set_catch_sigabrt`.
+    Aborted,
+}
+
+/// Unwinds the current `catch_unsafe_unwind` scope with the error a real fault of `kind` would
+/// produce, e.g. to test the error handling of host code without faulting wasm code.
+///
+/// The fault is recorded and reported as by the signal handler, so it is returned by
+/// `recent_faults` and passed to the trap observer and event sink, but carries no addresses:
+/// the trap code has a `srcloc` of 0, the `FaultInfo` has no registers, and the backtrace of
+/// a trap is empty. Panics if the current thread is not within a `catch_unsafe_unwind` scope.
+#[cfg(feature = "fault-injection")]
+pub unsafe fn inject_fault(kind: FaultKind) -> ! {
+    let (signal, classification, error) = match kind {
+        FaultKind::Trap(code) => {
+            let signal = match code {
+                ExceptionCode::IllegalArithmetic => SIGFPE,
+                ExceptionCode::MemoryOutOfBounds | ExceptionCode::CallStackExhausted => SIGSEGV,
+                _ => SIGILL,
+            };
+            (
+                signal as i32,
+                FaultClassification::Trap(code),
+                trap_error(code, 0),
+            )
+        }
+        FaultKind::UnknownTrap(signal) => (
+            signal,
+            FaultClassification::Unknown,
+            RuntimeError::InvokeError(InvokeError::UnknownTrap {
+                address: 0,
+                ip: None,
+                signal: signal_description(signal),
+            }),
+        ),
+        FaultKind::StackOverflow => (
+            SIGSEGV as i32,
+            FaultClassification::StackOverflow,
+            RuntimeError::InvokeError(InvokeError::StackOverflow),
+        ),
+        FaultKind::Interrupted(reason) => (
+            SIGSEGV as i32,
+            FaultClassification::Suspend,
+            RuntimeError::InvokeError(InvokeError::Interrupted {
+                from_sigint: false,
+                reason,
+            }),
+        ),
+        FaultKind::Timeout => (
+            SIGSEGV as i32,
+            FaultClassification::Suspend,
+            RuntimeError::InvokeError(InvokeError::Timeout),
+        ),
+        FaultKind::NoContext => (
+            SIGSEGV as i32,
+            FaultClassification::Suspend,
+            RuntimeError::InvokeError(InvokeError::NoContext),
+        ),
+        FaultKind::Aborted => (
+            SIGABRT as i32,
+            FaultClassification::Unknown,
+            RuntimeError::InvokeError(InvokeError::Aborted),
+        ),
+    };
+    match kind {
+        FaultKind::Trap(_) | FaultKind::UnknownTrap(_) | FaultKind::StackOverflow => {
+            LAST_TRAP_STATE.with(|x| {
+                *x.borrow_mut() = Some(ExecutionStateImage {
+                    frames: vec![],
+                    truncated: false,
+                    host_frames: vec![],
+                })
+            });
+        }
+        _ => {}
+    }
+    record_fault(signal, 0, classification);
+
+    let ip = Cell::new(0usize);
+    let fault = FaultInfo {
+        faulting_addr: std::ptr::null(),
+        ip: &*(&ip as *const Cell<usize>),
+        known_registers: [None; 32],
+        stack_bounds: cached_stack_bounds(),
+        raw_siginfo: None,
+        signal: Some(signal),
+        trap_no: None,
+        fs_base: None,
+        gs_base: None,
+        fpregs: std::ptr::null(),
+        ucontext: std::ptr::null_mut(),
+        gpr_cells: [None; 16],
+    };
+    let error = Box::new(error);
+    notify_trap_observer(&fault, &error);
+    begin_unsafe_unwind(error)
+}
+
+/// Builds a `BreakpointMap` from offsets into the code of a `CodeVersion`.
+pub struct BreakpointBuilder {
+    base: usize,
+    code_size: usize,
+    breakpoints: HashMap<usize, BreakpointHandler>,
+}
+
+impl BreakpointBuilder {
+    /// Creates a new empty `BreakpointBuilder` for the code of `version`.
+    pub fn new(version: &CodeVersion) -> BreakpointBuilder {
+        BreakpointBuilder {
+            base: version.base,
+            code_size: version.msm.total_size,
+            breakpoints: HashMap::new(),
+        }
+    }
+
+    /// Adds a breakpoint at `offset` from the start of the code, replacing any breakpoint
+    /// previously added there. Returns the absolute instruction pointer of the breakpoint.
+    pub fn add_breakpoint<F>(&mut self, offset: usize, handler: F) -> Result<usize, FaultError>
+    where
+        F: Fn(BreakpointInfo) -> Result<(), RuntimeError> + Send + Sync + 'static,
+    {
+        if offset >= self.code_size {
+            return Err(FaultError::BreakpointOutOfRange {
+                offset,
+                code_size: self.code_size,
+            });
+        }
+        let ip = self.base + offset;
+        self.breakpoints.insert(ip, Box::new(handler));
+        Ok(ip)
+    }
+
+    /// Adds a breakpoint at `offset` from the start of the code that only fires once per
+    /// `catch_unsafe_unwind` scope; see `one_shot_breakpoint`.
+    pub fn add_one_shot_breakpoint<F>(
+        &mut self,
+        offset: usize,
+        handler: F,
+    ) -> Result<usize, FaultError>
+    where
+        F: Fn(BreakpointInfo) -> Result<(), RuntimeError> + Send + Sync + 'static,
+    {
+        let handler = one_shot_breakpoint(handler);
+        self.add_breakpoint(offset, move |info| handler(info))
+    }
+
+    /// Adds a breakpoint at `offset` from the start of the code that ignores its first
+    /// `ignore_count` hits per `catch_unsafe_unwind` scope; see `ignore_count_breakpoint`.
+    pub fn add_ignore_count_breakpoint<F>(
+        &mut self,
+        offset: usize,
+        ignore_count: usize,
+        handler: F,
+    ) -> Result<usize, FaultError>
+    where
+        F: Fn(BreakpointInfo) -> Result<(), RuntimeError> + Send + Sync + 'static,
+    {
+        let handler = ignore_count_breakpoint(ignore_count, handler);
+        self.add_breakpoint(offset, move |info| handler(info))
+    }
+
+    /// Builds the `BreakpointMap`, to be passed to `catch_unsafe_unwind`.
+    pub fn build(self) -> BreakpointMap {
+        Arc::new(self.breakpoints)
+    }
+}
+
+/// What `BreakpointMapBuilder` does when two contributions have a breakpoint at the same
+/// instruction pointer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakpointCollisionPolicy {
+    /// Call both handlers in the order they were merged. An error returned by a handler is
+    /// returned without calling the next ones.
+    Chain,
+    /// Fail the merge with `FaultError::BreakpointCollision`.
+    Reject,
+}
+
+/// Merges the breakpoints contributed by independent sources, e.g. middleware, a debugger and
+/// tracing, into a single `BreakpointMap` to be passed to `catch_unsafe_unwind`.
+pub struct BreakpointMapBuilder {
+    policy: BreakpointCollisionPolicy,
+    breakpoints: HashMap<usize, BreakpointHandler>,
+}
+
+impl BreakpointMapBuilder {
+    /// Creates a new empty `BreakpointMapBuilder` resolving collisions with `policy`.
+    pub fn new(policy: BreakpointCollisionPolicy) -> BreakpointMapBuilder {
+        BreakpointMapBuilder {
+            policy,
+            breakpoints: HashMap::new(),
+        }
+    }
+
+    /// Merges the breakpoints of `contribution`, keyed by instruction pointer.
+    ///
+    /// With `BreakpointCollisionPolicy::Reject`, nothing is merged if a breakpoint collides
+    /// with one merged before.
+    pub fn merge(
+        &mut self,
+        contribution: HashMap<usize, BreakpointHandler>,
+    ) -> Result<(), FaultError> {
+        if self.policy == BreakpointCollisionPolicy::Reject {
+            if let Some(&ip) = contribution
+                .keys()
+                .find(|ip| self.breakpoints.contains_key(ip))
+            {
+                return Err(FaultError::BreakpointCollision { ip });
+            }
+        }
+        for (ip, handler) in contribution {
+            let handler: BreakpointHandler = match self.breakpoints.remove(&ip) {
+                Some(first) => Box::new(move |info| {
+                    first(BreakpointInfo { fault: info.fault })?;
+                    handler(info)
+                }),
+                None => handler,
+            };
+            self.breakpoints.insert(ip, handler);
+        }
+        Ok(())
+    }
+
+    /// Builds the `BreakpointMap`, to be passed to `catch_unsafe_unwind`.
+    pub fn build(self) -> BreakpointMap {
+        Arc::new(self.breakpoints)
+    }
+}
+
+/// Installs hooks called by every `catch_unsafe_unwind` scope immediately before and after
+/// running its closure.
+///
+/// `exit` is also called when the closure is left through an unsafe unwind, e.g. a trap.
+/// Both hooks run on the normal stack, outside of signal handlers.
+pub fn set_execution_boundary_hooks(enter: fn(), exit: fn()) {
+    *EXECUTION_BOUNDARY_HOOKS.write().unwrap() = Some((enter, exit));
+    HAS_EXECUTION_BOUNDARY_HOOKS.store(true, Ordering::SeqCst);
+}
+
+/// Removes the hooks installed with `set_execution_boundary_hooks`.
+pub fn clear_execution_boundary_hooks() {
+    HAS_EXECUTION_BOUNDARY_HOOKS.store(false, Ordering::SeqCst);
+    *EXECUTION_BOUNDARY_HOOKS.write().unwrap() = None;
+}
+
+/// Sets the observer notified when every `catch_unsafe_unwind` scope exits, successfully or
+/// not, e.g. to count invocations and measure their duration. Replaces any previous observer.
+///
+/// The observer runs on the normal stack, outside of signal handlers. Scopes do not read the
+/// clock while no observer is set.
+pub fn set_invocation_observer(observer: InvocationObserver) {
+    INVOCATION_OBSERVER.store(observer as usize, Ordering::SeqCst);
+}
+
+/// Removes the observer set with `set_invocation_observer`.
+pub fn clear_invocation_observer() {
+    INVOCATION_OBSERVER.store(0, Ordering::SeqCst);
+}
+
+/// Sets the observer called by the signal handler right before it unwinds from a fault, be it
+/// a trap, a breakpoint returning an error or an interrupt. Replaces any previous observer.
+///
+/// The observer runs inside the signal handler, on the signal stack, which is small: it should
+/// only do bounded work, e.g. update counters, and avoid allocating, taking locks that the
+/// faulting thread may hold, or calling `set_trap_observer` itself.
+///
+/// The signal handler reads the observer without taking a lock, so a replaced observer may
+/// still be running on another thread and is never dropped.
+pub fn set_trap_observer(observer: TrapObserver) {
+    TRAP_OBSERVER.store(Box::into_raw(Box::new(observer)), Ordering::SeqCst);
+}
+
+/// Removes the observer set with `set_trap_observer`.
+pub fn clear_trap_observer() {
+    TRAP_OBSERVER.store(std::ptr::null_mut(), Ordering::SeqCst);
+}
+
+fn notify_trap_observer(fault: &FaultInfo, error: &RuntimeError) {
+    send_trap_event(fault, error);
+    let observer = TRAP_OBSERVER.load(Ordering::SeqCst);
+    if !observer.is_null() {
+        unsafe { (*observer)(fault, error) };
+    }
+}
+
+/// Sets the channel the signal handler sends a `TrapEvent` to right before it unwinds from a
+/// fault, e.g. to aggregate the traps of many worker threads in a central collector. Replaces
+/// any previous sink.
+///
+/// The events are sent with `try_send` from inside the signal handler: they are dropped rather
+/// than blocking when the channel is full, or when the sink is being replaced concurrently.
+/// Use a bounded channel sized for the expected burst of traps.
+pub fn set_trap_event_sink(sink: mpsc::SyncSender<TrapEvent>) {
+    *TRAP_EVENT_SINK.lock().unwrap() = Some(sink);
+}
+
+/// Removes the sink set with `set_trap_event_sink`.
+pub fn clear_trap_event_sink() {
+    *TRAP_EVENT_SINK.lock().unwrap() = None;
+}
+
+/// Sends a `TrapEvent` for `fault` to the sink, if any. Neither blocks nor allocates.
+fn send_trap_event(fault: &FaultInfo, error: &RuntimeError) {
+    let sink = match TRAP_EVENT_SINK.try_lock() {
+        Ok(sink) => sink,
+        Err(_) => return,
+    };
+    if let Some(sink) = sink.as_ref() {
+        let code = match error {
+            RuntimeError::InvokeError(InvokeError::TrapCode { code, .. }) => Some(*code),
+            _ => None,
+        };
+        let _ = sink.try_send(TrapEvent {
+            signal: fault.signal,
+            faulting_addr: fault.faulting_addr as usize,
+            ip: fault.ip.get(),
+            module_offset: is_wasm_address(fault.ip.get()).map(|v| v.offset),
+            code,
+            thread_id: unsafe { libc::pthread_self() } as u64,
+            timestamp: FaultTimestamp::now(),
+        });
+    }
+}
+
+/// The maximum number of entries in each of the tables of fault recovery handlers, guard
+/// regions and watchpoints.
+const MAX_RANGES: usize = 64;
+
+/// A preallocated table of non-empty address ranges with a word of data each, which the signal
+/// handler searches without taking a lock, like `ACTIVE_CTXS`.
+///
+/// Writers are serialized by `lock`. Readers skip a slot that is being written, so that they
+/// see a concurrent update either before or after it happens.
+struct RangeTable {
+    lock: Mutex<()>,
+    slots: Vec<RangeSlot>,
+}
+
+/// A slot of a `RangeTable`, free while `end` is 0.
+#[derive(Default)]
+struct RangeSlot {
+    /// Odd while the slot is being written.
+    seq: AtomicUsize,
+    start: AtomicUsize,
+    end: AtomicUsize,
+    data: AtomicUsize,
+}
+
+impl RangeSlot {
+    fn write(&self, range: Range<usize>, data: usize) {
+        self.seq.fetch_add(1, Ordering::SeqCst);
+        self.start.store(range.start, Ordering::SeqCst);
+        self.end.store(range.end, Ordering::SeqCst);
+        self.data.store(data, Ordering::SeqCst);
+        self.seq.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Returns the range and data of the slot, or `None` if it is free or being written.
+    fn read(&self) -> Option<(Range<usize>, usize)> {
+        let seq = self.seq.load(Ordering::SeqCst);
+        if seq % 2 == 1 {
+            return None;
+        }
+        let start = self.start.load(Ordering::SeqCst);
+        let end = self.end.load(Ordering::SeqCst);
+        let data = self.data.load(Ordering::SeqCst);
+        if end == 0 || self.seq.load(Ordering::SeqCst) != seq {
+            return None;
+        }
+        Some((start..end, data))
+    }
+}
+
+impl RangeTable {
+    fn new() -> RangeTable {
+        RangeTable {
+            lock: Mutex::new(()),
+            slots: (0..MAX_RANGES).map(|_| RangeSlot::default()).collect(),
+        }
+    }
+
+    /// Adds `range` with `data`. Returns false if the table is full.
+    fn insert(&self, range: Range<usize>, data: usize) -> bool {
+        let _lock = self.lock.lock().unwrap();
+        match self
+            .slots
+            .iter()
+            .find(|slot| slot.end.load(Ordering::SeqCst) == 0)
+        {
+            Some(slot) => {
+                slot.write(range, data);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes every entry for exactly `range`. Returns false if there was none.
+    fn remove(&self, range: Range<usize>) -> bool {
+        let _lock = self.lock.lock().unwrap();
+        let mut removed = false;
+        for slot in self.slots.iter() {
+            if slot.read().map(|(r, _)| r).as_ref() == Some(&range) {
+                slot.write(0..0, 0);
+                removed = true;
+            }
+        }
+        removed
+    }
+
+    /// Returns the range and data of every entry.
+    fn entries(&self) -> impl Iterator<Item = (Range<usize>, usize)> + '_ {
+        self.slots.iter().filter_map(RangeSlot::read)
+    }
+
+    /// Returns the range and data of the first entry whose range contains `addr`.
+    fn find(&self, addr: usize) -> Option<(Range<usize>, usize)> {
+        self.entries().find(|(r, _)| r.contains(&addr))
+    }
+}
+
+/// Registers `handler` to be called for `SIGSEGV` and `SIGBUS` faults whose faulting address
+/// is in `range`, before any other handling takes place.
+///
+/// This allows embedders to recover from accesses to pages they manage themselves, e.g. by
+/// committing a lazily allocated page and returning `RecoveryAction::Resume`. The handler runs
+/// inside the signal handler and must be async-signal-safe. At most `MAX_RANGES` handlers can
+/// be registered at the same time.
+pub fn register_fault_recovery(
+    range: Range<usize>,
+    handler: FaultRecoveryHandler,
+) -> Result<(), FaultError> {
+    if FAULT_RECOVERIES.insert(range, handler as usize) {
+        Ok(())
+    } else {
+        Err(FaultError::TooManyRanges)
+    }
+}
+
+/// Removes the handlers registered with `register_fault_recovery` for exactly `range`.
+pub fn unregister_fault_recovery(range: Range<usize>) {
+    FAULT_RECOVERIES.remove(range);
+}
+
+fn find_fault_recovery(addr: usize) -> Option<FaultRecoveryHandler> {
+    FAULT_RECOVERIES
+        .find(addr)
+        .map(|(_, handler)| unsafe { std::mem::transmute::<usize, FaultRecoveryHandler>(handler) })
+}
+
+/// Registers the `len` bytes at `base` as a guard region of a stack, e.g. the guard page below
+/// a stack mapped by the embedder.
+///
+/// `SIGSEGV` and `SIGBUS` faults in a guard region fail the call with
+/// `InvokeError::StackOverflow` instead of an unknown trap. Recovery handlers registered with
+/// `register_fault_recovery` for the same addresses take precedence. At most `MAX_RANGES`
+/// guard regions can be registered at the same time.
+pub fn register_guard_region(base: usize, len: usize) -> Result<(), FaultError> {
+    if GUARD_REGIONS.insert(base..base + len, 0) {
+        Ok(())
+    } else {
+        Err(FaultError::TooManyRanges)
+    }
+}
+
+/// Removes the guard region registered with `register_guard_region` for exactly `base` and
+/// `len`.
+pub fn unregister_guard_region(base: usize, len: usize) {
+    GUARD_REGIONS.remove(base..base + len);
+}
+
+fn is_in_guard_region(addr: usize) -> bool {
+    GUARD_REGIONS.find(addr).is_some()
+}
+
+/// The page size, recorded by `register_watchpoint` so that the signal handler does not query
+/// it.
+static WATCHPOINT_PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the pages containing `range`, which are protected while it is watched.
+fn watched_pages(range: &Range<usize>) -> Range<usize> {
+    let page_size = WATCHPOINT_PAGE_SIZE.load(Ordering::SeqCst);
+    (range.start & !(page_size - 1))..((range.end + page_size - 1) & !(page_size - 1))
+}
+
+/// Watches the `len` bytes at `addr`: `handler` is called for every access to them, e.g. to
+/// implement memory watchpoints in a debugger.
+///
+/// This works like the interrupt: the pages containing the region are protected with
+/// `PROT_NONE`, and the handler is called from the signal handler when an access faults. The
+/// access is then completed by single-stepping it with the pages accessible, after which they
+/// are protected again. Accesses to the rest of these pages are completed the same way without
+/// calling the handler. Single-stepping is only supported on Linux x86_64; on other platforms
+/// this returns `FaultError::WatchpointsUnsupported`. At most `MAX_RANGES` watchpoints can be
+/// registered at the same time.
+///
+/// The region must be readable and writable memory, e.g. wasm linear memory, and the handler
+/// must be async-signal-safe.
+pub unsafe fn register_watchpoint(
+    addr: usize,
+    len: usize,
+    handler: WatchpointHandler,
+) -> Result<(), FaultError> {
+    if !SINGLE_STEP_SUPPORTED {
+        return Err(FaultError::WatchpointsUnsupported);
+    }
+    WATCHPOINT_PAGE_SIZE.store(page_size::get(), Ordering::SeqCst);
+    let range = addr..addr + len;
+    let pages = watched_pages(&range);
+    if !WATCHPOINTS.insert(range.clone(), handler as usize) {
+        return Err(FaultError::TooManyRanges);
+    }
+    if mprotect(pages.start as _, pages.end - pages.start, PROT_NONE) < 0 {
+        let errno = nix::errno::errno();
+        WATCHPOINTS.remove(range);
+        return Err(FaultError::WatchpointProtectFailed { addr, len, errno });
+    }
+    Ok(())
+}
+
+/// Removes the watchpoints registered with `register_watchpoint` for exactly `addr` and `len`.
+///
+/// Their pages are made readable and writable again, unless other watchpoints share them.
+pub unsafe fn unregister_watchpoint(addr: usize, len: usize) {
+    let range = addr..addr + len;
+    if !WATCHPOINTS.remove(range.clone()) {
+        return;
+    }
+    let pages = watched_pages(&range);
+    if !WATCHPOINTS.entries().any(|(r, _)| {
+        let other = watched_pages(&r);
+        other.start < pages.end && pages.start < other.end
+    }) {
+        mprotect(
+            pages.start as _,
+            pages.end - pages.start,
+            PROT_READ | PROT_WRITE,
+        );
+    }
+}
+
+/// Returns the watched pages containing `addr`, and the handler of the watchpoint covering
+/// `addr` itself, if any.
+fn find_watchpoint(addr: usize) -> Option<(Range<usize>, Option<WatchpointHandler>)> {
+    let pages = WATCHPOINTS
+        .entries()
+        .map(|(r, _)| watched_pages(&r))
+        .find(|pages| pages.contains(&addr))?;
+    let handler = WATCHPOINTS
+        .find(addr)
+        .map(|(_, handler)| unsafe { std::mem::transmute::<usize, WatchpointHandler>(handler) });
+    Some((pages, handler))
+}
+
+/// Makes `pages` accessible and arms single-stepping, so that the faulting access completes
+/// and `finish_watchpoint_step` protects them again.
+///
+/// Returns false if the pages cannot be made accessible.
+unsafe fn step_over_watchpoint(fault: &FaultInfo, pages: Range<usize>) -> bool {
+    if mprotect(
+        pages.start as _,
+        pages.end - pages.start,
+        PROT_READ | PROT_WRITE,
+    ) < 0
+    {
+        return false;
+    }
+    if set_trap_flag(fault.ucontext, true) {
+        WATCHPOINT_STEP.with(|x| x.set(Some((pages.start, pages.end))));
+    }
+    true
+}
+
+/// Protects the pages of a single-stepped watched access again. Returns false if this thread
+/// was not single-stepping one.
+unsafe fn finish_watchpoint_step(ucontext: *mut c_void) -> bool {
+    let (start, end) = match WATCHPOINT_STEP.with(|x| x.take()) {
+        Some(x) => x,
+        None => return false,
+    };
+    set_trap_flag(ucontext, false);
+    // The handler may have unregistered the watchpoint.
+    if WATCHPOINTS
+        .entries()
+        .any(|(r, _)| watched_pages(&r) == (start..end))
+    {
+        mprotect(start as _, end - start, PROT_NONE);
+    }
+    true
+}
+
+/// Whether `set_trap_flag` is supported, so that watched accesses can be single-stepped.
+const SINGLE_STEP_SUPPORTED: bool = cfg!(all(target_os = "linux", target_arch = "x86_64"));
+
+/// Sets or clears the trap flag of the signal context, so that the thread traps after its next
+/// instruction. Returns false if this is not supported.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+unsafe fn set_trap_flag(ucontext: *mut c_void, enabled: bool) -> bool {
+    const TRAP_FLAG: i64 = 0x100;
+    let gregs = &mut (*(ucontext as *mut libc::ucontext_t)).uc_mcontext.gregs;
+    if enabled {
+        gregs[libc::REG_EFL as usize] |= TRAP_FLAG;
+    } else {
+        gregs[libc::REG_EFL as usize] &= !TRAP_FLAG;
+    }
+    true
+}
+
+#[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+unsafe fn set_trap_flag(_ucontext: *mut c_void, _enabled: bool) -> bool {
+    false
+}
+
+/// Returns the length of the x86-64 `div` or `idiv` instruction at `code`, or `None` if it is
+/// another instruction.
+#[cfg(target_arch = "x86_64")]
+unsafe fn division_instruction_len(code: *const u8) -> Option<usize> {
+    let mut len = 0;
+    // Operand size override, then REX prefix.
+    if *code == 0x66 {
+        len += 1;
+    }
+    if *code.add(len) & 0xf0 == 0x40 {
+        len += 1;
+    }
+    match *code.add(len) {
+        0xf6 | 0xf7 => {}
+        _ => return None,
+    }
+    let modrm = *code.add(len + 1);
+    len += 2;
+    let (md, reg, rm) = (modrm >> 6, (modrm >> 3) & 0x7, modrm & 0x7);
+    // `/6` is `div`, `/7` is `idiv`.
+    if reg != 6 && reg != 7 {
+        return None;
+    }
+    if md != 3 && rm == 4 {
+        let sib = *code.add(len);
+        len += 1;
+        if md == 0 && sib & 0x7 == 5 {
+            len += 4;
+        }
+    }
+    Some(
+        len + match (md, rm) {
+            (0, 5) => 4,
+            (1, _) => 1,
+            (2, _) => 4,
+            _ => 0,
+        },
+    )
+}
+
+/// Sets the handler called for traps classified as `code`, replacing any previous handler.
+///
+/// The handler runs inside the signal handler, before the stack is read, and must be
+/// async-signal-safe. Returning `RecoveryAction::Resume` continues execution from the registers
+/// and instruction pointer left in the `FaultInfo`, e.g. after writing a saturated result with
+/// `FaultInfo::set_register` and moving the instruction pointer past the trapping instruction.
+pub fn set_trap_recovery_handler(code: ExceptionCode, handler: TrapRecoveryHandler) {
+    TRAP_RECOVERIES[code as usize].store(handler as usize, Ordering::SeqCst);
+}
+
+/// Removes the handler set with `set_trap_recovery_handler` for `code`.
+pub fn clear_trap_recovery_handler(code: ExceptionCode) {
+    TRAP_RECOVERIES[code as usize].store(0, Ordering::SeqCst);
+}
+
+fn find_trap_recovery(code: ExceptionCode) -> Option<TrapRecoveryHandler> {
+    match TRAP_RECOVERIES[code as usize].load(Ordering::SeqCst) {
+        0 => None,
+        handler => Some(unsafe { std::mem::transmute::<usize, TrapRecoveryHandler>(handler) }),
+    }
+}
+
+/// Installs a breakpoint map that is consulted by every `catch_unsafe_unwind` scope.
+///
+/// Breakpoints in the map passed to `catch_unsafe_unwind` take precedence over the ones
+/// installed here when both contain the same address.
+///
+/// The signal handler reads the map without taking a lock, so a replaced map may still be in
+/// use on another thread and is never dropped.
+pub fn set_global_breakpoints(breakpoints: BreakpointMap) {
+    GLOBAL_BREAKPOINTS.store(Box::into_raw(Box::new(breakpoints)), Ordering::SeqCst);
+}
+
+/// Removes the breakpoint map installed with `set_global_breakpoints`.
+pub fn clear_global_breakpoints() {
+    GLOBAL_BREAKPOINTS.store(std::ptr::null_mut(), Ordering::SeqCst);
+}
+
+/// Wraps `handler` into a breakpoint handler that is removed once it has run: further hits of
+/// the breakpoint in the same `catch_unsafe_unwind` scope resume execution without calling it,
+/// e.g. to run to a location once.
+///
+/// A breakpoint is only removed for the innermost scope, so that it fires again in later or
+/// outer scopes.
+pub fn one_shot_breakpoint<F>(handler: F) -> BreakpointHandler
+where
+    F: Fn(BreakpointInfo) -> Result<(), RuntimeError> + Send + Sync + 'static,
+{
+    Box::new(move |info| {
+        BREAKPOINT_DISARM_REQUESTED.with(|x| x.set(true));
+        handler(info)
+    })
+}
+
+/// Wraps `handler` into a breakpoint handler that ignores the first `ignore_count` hits of the
+/// breakpoint in a `catch_unsafe_unwind` scope, resuming execution without calling it, e.g. to
+/// break on the Nth iteration of a loop.
+///
+/// Hits are counted for the innermost scope only, so that the count starts over in later or
+/// outer scopes.
+pub fn ignore_count_breakpoint<F>(ignore_count: usize, handler: F) -> BreakpointHandler
+where
+    F: Fn(BreakpointInfo) -> Result<(), RuntimeError> + Send + Sync + 'static,
+{
+    Box::new(move |info| {
+        let hits = match info.fault {
+            Some(fault) => unsafe { count_scope_breakpoint_hit(fault.ip.get()) },
+            None => std::usize::MAX,
+        };
+        if hits <= ignore_count {
+            return Ok(());
+        }
+        handler(info)
+    })
+}
+
+/// Counts a hit of the breakpoint at `ip` in the current scope, returning the number of hits
+/// so far.
+unsafe fn count_scope_breakpoint_hit(ip: usize) -> usize {
+    let unwind = UNWIND.with(|x| x.get());
+    match *unwind {
+        Some(ref mut inner) => {
+            let hits = inner.breakpoint_hits.entry(ip).or_insert(0);
+            *hits += 1;
+            *hits
+        }
+        None => std::usize::MAX,
+    }
+}
+
+/// Returns true if the breakpoint at `ip` was removed from the current scope after firing as
+/// a one-shot breakpoint.
+unsafe fn is_breakpoint_disarmed(ip: usize) -> bool {
+    let unwind = UNWIND.with(|x| x.get());
+    (*unwind)
+        .as_ref()
+        .map_or(false, |x| x.disarmed_breakpoints.contains(&ip))
+}
+
+/// Removes the breakpoint at `ip` from the current scope if its handler asked for it.
+///
+/// This is done after the handler has returned, as the handler is borrowed from the scope.
+unsafe fn disarm_breakpoint_if_requested(ip: usize) {
+    if !BREAKPOINT_DISARM_REQUESTED.with(|x| x.replace(false)) {
+        return;
+    }
+    let unwind = UNWIND.with(|x| x.get());
+    if let Some(ref mut inner) = *unwind {
+        inner.disarmed_breakpoints.insert(ip);
+    }
+}
+
+impl<'a> BreakpointInfo<'a> {
+    /// Requests that execution be suspended at this breakpoint once its handler returns
+    /// `Ok(())`: the call then fails with a `RuntimeError::InstanceImage` carrying the state at
+    /// the breakpoint, e.g. to inspect it and resume later.
+    ///
+    /// Ignored if the handler returns an error. Inline breakpoints cannot be suspended at.
+    pub fn request_suspend(&self) {
+        BREAKPOINT_SUSPEND_REQUESTED.with(|x| x.set(true));
+    }
+}
+
+/// Hit counts of breakpoints, by instruction pointer.
+pub type BreakpointStats = HashMap<usize, u64>;
+
+/// Enables or disables counting the hits of each breakpoint, disabled by default.
+///
+/// Counts are kept per thread and read with `take_breakpoint_stats`.
+pub fn set_breakpoint_stats_enabled(enabled: bool) {
+    BREAKPOINT_STATS_ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+/// Returns the breakpoint hits counted on the current thread since the last call, and resets
+/// the counts.
+pub fn take_breakpoint_stats() -> BreakpointStats {
+    BREAKPOINT_STATS.with(|x| std::mem::replace(&mut *x.borrow_mut(), HashMap::new()))
+}
+
+/// Sets the handler called for `InlineBreakpointType::Trace` inline breakpoints, replacing any
+/// previous handler. Without a handler, trace breakpoints are skipped.
+///
+/// The handler runs inside the signal handler and must be async-signal-safe.
+pub fn set_trace_breakpoint_handler(handler: TraceBreakpointHandler) {
+    TRACE_BREAKPOINT_HANDLER.store(handler as usize, Ordering::SeqCst);
+}
+
+/// Removes the handler set with `set_trace_breakpoint_handler`.
+pub fn clear_trace_breakpoint_handler() {
+    TRACE_BREAKPOINT_HANDLER.store(0, Ordering::SeqCst);
+}
+
+/// The maximum number of `InlineBreakpointType::Counter` sites counted per thread. Hits of
+/// further sites are dropped until the counts are taken.
+pub const MAX_COUNTER_BREAKPOINT_SITES: usize = 256;
+
+/// Returns the hits of `InlineBreakpointType::Counter` inline breakpoints counted on the
+/// current thread since the last call, by instruction pointer, and resets the counts.
+pub fn take_counter_breakpoint_counts() -> BreakpointStats {
+    COUNTER_BREAKPOINT_COUNTS.with(|x| {
+        // Counter breakpoints only fire in wasm code, so the signal handler cannot run on this
+        // thread while the table is read.
+        let counts = unsafe { &mut *x.get() };
+        let taken = counts.iter().filter(|x| x.0 != 0).cloned().collect();
+        *counts = [(0, 0); MAX_COUNTER_BREAKPOINT_SITES];
+        taken
+    })
+}
+
+fn count_counter_breakpoint_hit(ip: usize) {
+    COUNTER_BREAKPOINT_COUNTS.with(|x| {
+        let counts = unsafe { &mut *x.get() };
+        for i in 0..MAX_COUNTER_BREAKPOINT_SITES {
+            let slot = &mut counts[(ip + i) % MAX_COUNTER_BREAKPOINT_SITES];
+            if slot.0 == ip || slot.0 == 0 {
+                slot.0 = ip;
+                slot.1 += 1;
+                return;
+            }
+        }
+    })
+}
+
+fn count_breakpoint_hit(ip: usize) {
+    if BREAKPOINT_STATS_ENABLED.load(Ordering::SeqCst) {
+        BREAKPOINT_STATS.with(|x| *x.borrow_mut().entry(ip).or_insert(0) += 1);
+    }
+}
+
+unsafe fn with_breakpoint<R, F: FnOnce(Option<&BreakpointHandler>) -> R>(ip: usize, f: F) -> R {
+    let unwind = UNWIND.with(|x| x.get());
+    let inner = (*unwind)
+        .as_mut()
+        .expect("not within a catch_unsafe_unwind scope");
+    if let Some(handler) = inner.breakpoints.as_ref().and_then(|x| x.get(&ip)) {
+        return f(Some(handler));
+    }
+
+    // A replaced map is leaked rather than freed, so a handler is free to replace it.
+    let global = GLOBAL_BREAKPOINTS.load(Ordering::SeqCst);
+    f(global.as_ref().and_then(|x| x.get(&ip)))
+}
+
+/// Sets the size of the stacks the signal handler runs on, 1MB by default. `size` must be a
+/// multiple of 16 and at least 4096 bytes.
+///
+/// Each thread keeps one stack of this size mapped for reuse once it has handled a signal, so
+/// this should be called before any wasm code is run.
+pub fn set_trap_stack_size(size: usize) {
+    assert!(size % 16 == 0);
+    assert!(size >= 4096);
+    TRAP_STACK_SIZE.store(size, Ordering::SeqCst);
+}
+
+/// Returns the size of the stacks the signal handler runs on.
+pub fn trap_stack_size() -> usize {
+    TRAP_STACK_SIZE.load(Ordering::SeqCst)
+}
+
+/// Enables or disables measuring how much of its stack each `allocate_and_run` call uses,
+/// reported by `trap_stack_high_water`, e.g. to tune `set_trap_stack_size`. Disabled by default.
+///
+/// The stack is filled with a sentinel before every call and scanned afterwards, which costs
+/// time proportional to the stack size on every signal.
+pub fn set_trap_stack_watermark(enabled: bool) {
+    TRAP_STACK_WATERMARK.store(enabled, Ordering::SeqCst);
+}
+
+/// Returns the largest number of bytes of stack used by an `allocate_and_run` call on the
+/// current thread, e.g. by the signal handler, while enabled with `set_trap_stack_watermark`.
+pub fn trap_stack_high_water() -> usize {
+    TRAP_STACK_HIGH_WATER.with(|x| x.get())
+}
+
+/// Resets the value returned by `trap_stack_high_water` on the current thread.
+pub fn reset_trap_stack_high_water() {
+    TRAP_STACK_HIGH_WATER.with(|x| x.set(0));
+}
+
+/// Limits the number of frames the signal handler reads for the backtrace of a trap, `None` by
+/// default. Backtraces cut at the limit are marked as truncated.
+///
+/// Reading the stack of a deeply recursive guest is linear in its depth, so a bound keeps the
+/// time spent in the handler predictable. Suspends always read the full stack, since the
+/// resulting image must be resumable.
+pub fn set_max_backtrace_depth(depth: Option<usize>) {
+    MAX_BACKTRACE_DEPTH.store(depth.unwrap_or(std::usize::MAX), Ordering::SeqCst);
+}
+
+/// Returns the limit set with `set_max_backtrace_depth`.
+pub fn max_backtrace_depth() -> Option<usize> {
+    match MAX_BACKTRACE_DEPTH.load(Ordering::SeqCst) {
+        std::usize::MAX => None,
+        depth => Some(depth),
+    }
+}
+
+/// A stack mapped for `allocate_and_run`, with a guard page below it.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+struct TrapStack {
+    allocator: &'static dyn FaultAllocator,
+    mapping: *mut u8,
+    size: usize,
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+impl TrapStack {
+    unsafe fn map(size: usize) -> Option<TrapStack> {
+        let allocator = fault_allocator();
+        let mapping = allocator.map(size + *TRAP_STACK_GUARD_SIZE, PROT_READ | PROT_WRITE);
+        if mapping.is_null() {
+            return None;
+        }
+        mprotect(mapping as _, *TRAP_STACK_GUARD_SIZE, PROT_NONE);
+        Some(TrapStack {
+            allocator,
+            mapping,
+            size,
+        })
+    }
+
+    /// Takes the stack pooled for this thread if it has the requested size, or maps a new one,
+    /// e.g. when the pooled stack is in use by an outer call.
+    unsafe fn acquire(size: usize) -> Option<TrapStack> {
+        match TRAP_STACK_POOL.with(|x| x.take()) {
+            Some(stack) if stack.size == size => Some(stack),
+            _ => TrapStack::map(size),
+        }
+    }
+
+    /// Returns the stack to the pool of this thread if it has the trap stack size and the pool
+    /// is empty, or unmaps it.
+    fn release(self) {
+        if self.size == trap_stack_size() {
+            TRAP_STACK_POOL.with(|x| {
+                let pooled = x.take();
+                x.set(Some(pooled.unwrap_or(self)));
+            });
+        }
+    }
+
+    fn bottom(&self) -> *mut u64 {
+        unsafe { self.mapping.add(*TRAP_STACK_GUARD_SIZE) as *mut u64 }
+    }
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+impl TrapStack {
+    /// Rebuilds a stack from the range returned by `MappedAltStackProvider::alloc`.
+    ///
+    /// The fault allocator is not expected to change while stacks are mapped, see
+    /// `set_fault_allocator`.
+    unsafe fn from_range(stack_begin: *mut u64, stack_end: *mut u64) -> TrapStack {
+        TrapStack {
+            allocator: fault_allocator(),
+            mapping: (stack_begin as *mut u8).sub(*TRAP_STACK_GUARD_SIZE),
+            size: stack_end as usize - stack_begin as usize,
+        }
+    }
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+impl Drop for TrapStack {
+    fn drop(&mut self) {
+        unsafe {
+            self.allocator
+                .unmap(self.mapping, self.size + *TRAP_STACK_GUARD_SIZE);
+        }
+    }
+}
+
+/// Allocates the stacks `allocate_and_run` runs closures on.
+///
+/// Stacks are allocated and released from within signal handlers, so implementations must be
+/// async-signal-safe. Only used on x86_64 and aarch64.
+pub trait AltStackProvider {
+    /// Allocates a stack of at least `size` bytes. Returns `(stack_begin, stack_end)`, the
+    /// lowest address of the stack and the address one past its highest, or `None` on failure.
+    /// `stack_end` must be 16-byte aligned.
+    unsafe fn alloc(&self, size: usize) -> Option<(*mut u64, *mut u64)>;
+    /// Releases a stack returned by `alloc`.
+    unsafe fn release(&self, stack_begin: *mut u64, stack_end: *mut u64);
+}
+
+/// The default `AltStackProvider`, mapping stacks with the fault allocator with a guard page
+/// below them.
+///
+/// A stack of the trap stack size is kept per thread and reused, so that handling a signal
+/// does not map memory except on re-entrant use. Other sizes are mapped for every call.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub struct MappedAltStackProvider;
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+impl AltStackProvider for MappedAltStackProvider {
+    unsafe fn alloc(&self, size: usize) -> Option<(*mut u64, *mut u64)> {
+        let stack = TrapStack::acquire(size)?;
+        let range = (stack.bottom(), stack.bottom().add(size / 8));
+        // Ownership is taken back in `release`.
+        ::std::mem::forget(stack);
+        Some(range)
+    }
+
+    unsafe fn release(&self, stack_begin: *mut u64, stack_end: *mut u64) {
+        TrapStack::from_range(stack_begin, stack_end).release();
+    }
+}
+
+/// Sets the provider of the stacks of `allocate_and_run` on the current thread, e.g. to run
+/// on stacks with guard pages from a custom allocator. Replaces any previous provider.
+///
+/// Stacks are still in use while the closure of an `allocate_and_run` call runs, so the
+/// provider should not be changed from within it.
+pub fn set_alt_stack_provider(provider: Box<dyn AltStackProvider>) {
+    ALT_STACK_PROVIDER.with(|x| *x.borrow_mut() = Some(provider));
+}
+
+/// Restores the default provider of the stacks of `allocate_and_run` on the current thread.
+pub fn clear_alt_stack_provider() {
+    ALT_STACK_PROVIDER.with(|x| *x.borrow_mut() = None);
+}
+
+/// Calls `f` with the provider of the current thread.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn with_alt_stack_provider<R, F: FnOnce(&dyn AltStackProvider) -> R>(f: F) -> R {
+    ALT_STACK_PROVIDER.with(|x| match *x.borrow() {
+        Some(ref provider) => f(&**provider),
+        None => f(&MappedAltStackProvider),
+    })
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+/// Allocates and runs with the given stack size and closure.
+pub fn allocate_and_run<R, F: FnOnce() -> R>(_size: usize, f: F) -> R {
+    f()
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+/// Allocates and runs with the given stack size and closure.
+///
+/// The stack is allocated by the `AltStackProvider` of the current thread, by default
+/// `MappedAltStackProvider`.
+pub fn allocate_and_run<R, F: FnOnce() -> R>(size: usize, f: F) -> R {
+    struct Context<F: FnOnce() -> R, R> {
+        f: Option<F>,
+        ret: Option<R>,
+    }
+
+    extern "C" fn invoke<F: FnOnce() -> R, R>(ctx: &mut Context<F, R>) {
+        let f = ctx.f.take().unwrap();
+        ctx.ret = Some(f());
+    }
+
+    unsafe {
+        let mut ctx = Context {
+            f: Some(f),
+            ret: None,
+        };
+        assert!(size % 16 == 0);
+        assert!(size >= 4096);
+
+        let (stack_bottom, stack_end) = match with_alt_stack_provider(|p| p.alloc(size)) {
+            Some(x) => x,
+            None => panic!("cannot allocate stack"),
+        };
+        assert!(stack_end as usize - stack_bottom as usize >= size);
+        assert!(stack_end as usize % 16 == 0);
+
+        let guard = stack_bottom as usize - *TRAP_STACK_GUARD_SIZE..stack_bottom as usize;
+        TRAP_STACK_GUARDS.with(|x| x.borrow_mut().push(guard));
+
+        let layout = ALT_STACK_LAYOUT;
+        *stack_end.sub(layout.target_slot) = invoke::<F, R> as usize as u64;
+        let stack_begin = layout.stack_begin(stack_end);
+        *stack_begin.add(layout.arg_register_index) =
+            &mut ctx as *mut Context<F, R> as usize as u64;
+
+        // Slots below `stack_begin` are only written by the closure.
+        const STACK_SENTINEL: u64 = 0x5741_534d_4552_5354;
+        let watermark = TRAP_STACK_WATERMARK.load(Ordering::SeqCst);
+        let unused_slots = stack_begin as usize - stack_bottom as usize;
+        if watermark {
+            std::slice::from_raw_parts_mut(stack_bottom, unused_slots / 8)
+                .iter_mut()
+                .for_each(|x| *x = STACK_SENTINEL);
+        }
+
+        run_on_alternative_stack(stack_end, stack_begin);
+        if watermark {
+            let untouched = std::slice::from_raw_parts(stack_bottom, unused_slots / 8)
+                .iter()
+                .take_while(|&&x| x == STACK_SENTINEL)
+                .count();
+            let used = stack_end as usize - stack_bottom as usize - untouched * 8;
+            TRAP_STACK_HIGH_WATER.with(|x| x.set(std::cmp::max(x.get(), used)));
+        }
+        TRAP_STACK_GUARDS.with(|x| x.borrow_mut().pop());
+        with_alt_stack_provider(|p| p.release(stack_bottom, stack_end));
+        ctx.ret.take().unwrap()
+    }
+}
+
+/// Aborts if a fault at `addr` hit the page below the stack of an active `allocate_and_run`
+/// call on the current thread, i.e. the trap handler overflowed its stack.
+///
+/// Unwinding is not possible from there, and returning would fault again.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn abort_on_trap_stack_overflow(addr: usize) {
+    if TRAP_STACK_GUARDS.with(|x| x.borrow().iter().any(|guard| guard.contains(&addr))) {
+        eprintln!("Wasmer encountered a trap handler stack overflow, aborting");
+        process::abort();
+    }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn abort_on_trap_stack_overflow(_addr: usize) {}
+
+/// Space left between the stack bound set by `run_with_escalating_stack` and the end of the
+/// allocated stack, so that the trap raised at the bound can still be handled.
+const ESCALATING_STACK_RED_ZONE: usize = 65536;
+
+/// Runs `f` on a stack of each of `sizes` in turn until it completes without exhausting it.
+///
+/// The stack bound of `ctx` is set for every attempt, so that code compiled with
+/// `enforce_stack_check` traps with `ExceptionCode::CallStackExhausted` instead of overflowing
+/// the stack. On such a trap `f` is called again from the start on the next stack size; any
+/// other result is returned as is. `f` must therefore be idempotent: side effects of failed
+/// attempts, e.g. writes to linear memory, are not rolled back.
+///
+/// Returns the result of the last attempt if all sizes are exhausted.
+pub unsafe fn run_with_escalating_stack<R, F: FnMut() -> Result<R, RuntimeError>>(
+    ctx: *mut vm::Ctx,
+    sizes: &[usize],
+    mut f: F,
+) -> Result<R, RuntimeError> {
+    assert!(!sizes.is_empty(), "no stack sizes given");
+
+    let old_bound = (*ctx).internal.stack_lower_bound;
+    let mut result = None;
+    for &size in sizes {
+        assert!(size > ESCALATING_STACK_RED_ZONE);
+        let ret = allocate_and_run(size, || {
+            // The allocated stack spans `size` bytes below this point, give or take the
+            // frames set up by `allocate_and_run`.
+            let marker = 0u8;
+            let top = &marker as *const u8 as usize;
+            (*ctx).internal.stack_lower_bound = (top - size + ESCALATING_STACK_RED_ZONE) as _;
+            f()
+        });
+        (*ctx).internal.stack_lower_bound = old_bound;
+
+        let exhausted = match ret {
+            Err(RuntimeError::InvokeError(InvokeError::TrapCode {
+                code: ExceptionCode::CallStackExhausted,
+                ..
+            })) => true,
+            _ => false,
+        };
+        result = Some(ret);
+        if !exhausted {
+            break;
+        }
+    }
+    result.unwrap()
+}
+
+/// Runs `f` under a watchdog with a soft and a hard deadline, both measured from the call.
+///
+/// When the soft deadline passes, the next interrupt check of the code running on `ctx`
+/// captures an `InstanceImage`, passes it to `on_soft` and lets execution continue unchanged.
+/// When the hard deadline passes, `ctx` is interrupted as with `set_wasm_interrupt_on_ctx`.
+/// Neither fires if `f` returns before its deadline.
+///
+/// The code running on `ctx` must be compiled with state tracking for the snapshot to be
+/// captured, and `ctx` must be running in `with_ctx`, otherwise the soft deadline is ignored.
+/// `on_soft` is called from the signal handler.
+pub unsafe fn run_with_watchdog<R, F: FnOnce() -> R>(
+    ctx: *mut vm::Ctx,
+    soft: Duration,
+    hard: Duration,
+    on_soft: fn(InstanceImage),
+    f: F,
+) -> R {
+    let (done_tx, done_rx) = mpsc::channel::<()>();
+    let ctx_addr = ctx as usize;
+    let watchdog = thread::spawn(move || {
+        if let Err(mpsc::RecvTimeoutError::Timeout) = done_rx.recv_timeout(soft) {
+            let requested = set_ctx_interrupt_flags_with(
+                ctx_addr as *mut vm::Ctx,
+                CTX_INTERRUPT_SNAPSHOT,
+                |slot| slot.snapshot.store(on_soft as usize, Ordering::SeqCst),
+            );
+            if requested {
+                set_wasm_interrupt_on_ctx(ctx_addr as *mut vm::Ctx);
+            }
+        } else {
+            return;
+        }
+        if let Err(mpsc::RecvTimeoutError::Timeout) =
+            done_rx.recv_timeout(hard.checked_sub(soft).unwrap_or_default())
+        {
+            take_ctx_interrupt_flag(ctx_addr as *mut vm::Ctx, CTX_INTERRUPT_SNAPSHOT);
+            set_wasm_interrupt_on_ctx(ctx_addr as *mut vm::Ctx);
+        }
+    });
+
+    let ret = f();
+    drop(done_tx);
+    watchdog.join().unwrap();
+
+    // Don't leave an interrupt armed by the watchdog for code running after `f`.
+    if take_snapshot_request(ctx).is_some() || is_wasm_interrupt_set() {
+        clear_wasm_interrupt();
+    }
+    ret
+}
+
+fn take_snapshot_request(ctx: *mut vm::Ctx) -> Option<fn(InstanceImage)> {
+    ACTIVE_CTXS
+        .iter()
+        .filter(|slot| slot.ctx.load(Ordering::SeqCst) == ctx as usize)
+        .filter(|slot| {
+            slot.interrupt_flags
+                .fetch_and(!CTX_INTERRUPT_SNAPSHOT, Ordering::SeqCst)
+                & CTX_INTERRUPT_SNAPSHOT
+                != 0
+        })
+        .map(|slot| unsafe {
+            std::mem::transmute::<usize, fn(InstanceImage)>(slot.snapshot.load(Ordering::SeqCst))
+        })
+        .last()
+}
+
+/// Runs `f`, interrupting the code running on `ctx` if it is still running after `timeout`.
+///
+/// The interrupted call fails with `InvokeError::Timeout` if `ctx` is running in `with_ctx`,
+/// and is reported like one set with `set_wasm_interrupt` otherwise. The watcher thread is
+/// stopped and joined before returning, whether `f` returns, traps or panics.
+pub unsafe fn run_with_timeout<R, F: FnOnce() -> R>(
+    ctx: *mut vm::Ctx,
+    timeout: Duration,
+    f: F,
+) -> R {
+    struct Watcher {
+        ctx_addr: usize,
+        done_tx: Option<mpsc::Sender<()>>,
+        thread: Option<thread::JoinHandle<bool>>,
+    }
+
+    impl Drop for Watcher {
+        fn drop(&mut self) {
+            self.done_tx.take();
+            let fired = match self.thread.take() {
+                Some(thread) => thread.join().unwrap_or(false),
+                None => false,
+            };
+            // Don't leave an interrupt armed by the watcher for code running after `f`.
+            if fired && (take_timeout(self.ctx_addr as *mut vm::Ctx) || is_wasm_interrupt_set()) {
+                unsafe { clear_wasm_interrupt() };
+            }
+        }
+    }
+
+    let (done_tx, done_rx) = mpsc::channel::<()>();
+    let ctx_addr = ctx as usize;
+    let thread = thread::spawn(move || {
+        if let Err(mpsc::RecvTimeoutError::Timeout) = done_rx.recv_timeout(timeout) {
+            set_ctx_interrupt_flags(ctx_addr as *mut vm::Ctx, CTX_INTERRUPT_TIMEOUT);
+            set_wasm_interrupt_on_ctx(ctx_addr as *mut vm::Ctx);
+            true
+        } else {
+            false
+        }
+    });
+    let _watcher = Watcher {
+        ctx_addr,
+        done_tx: Some(done_tx),
+        thread: Some(thread),
+    };
+    f()
+}
+
+fn take_timeout(ctx: *mut vm::Ctx) -> bool {
+    take_ctx_interrupt_flag(ctx, CTX_INTERRUPT_TIMEOUT)
+}
+
+unsafe fn call_signal_handler(
+    sig: Signal,
+    siginfo: *mut siginfo_t,
+    ucontext: *mut c_void,
+    sig_action: &SigAction,
+) {
+    match sig_action.handler() {
+        SigHandler::SigDfl => {
+            sigaction(sig, sig_action).unwrap();
+            return;
+        }
+        SigHandler::SigIgn => return,
+        SigHandler::Handler(handler) => handler(sig as _),
+        SigHandler::SigAction(handler) => handler(sig as _, siginfo as _, ucontext),
+    }
+}
+
+extern "C" fn signal_trap_handler(
+    signum: ::nix::libc::c_int,
+    siginfo: *mut siginfo_t,
+    ucontext: *mut c_void,
+) {
+    use crate::backend::{Architecture, InlineBreakpointType};
+
+    #[cfg(target_arch = "x86_64")]
+    static ARCH: Option<Architecture> = Some(Architecture::X64);
+
+    #[cfg(target_arch = "aarch64")]
+    static ARCH: Option<Architecture> = Some(Architecture::Aarch64);
+
+    // No backend emits inline breakpoints for other architectures.
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    static ARCH: Option<Architecture> = None;
+
+    let mut should_unwind = false;
+    let mut unwind_result: Option<Box<RuntimeError>> = None;
+    let mut chained_handler: Option<(Signal, SigAction)> = None;
+    let get_unwind_result = |uw_result: Option<Box<RuntimeError>>| -> Box<RuntimeError> {
+        uw_result
+            .unwrap_or_else(|| Box::new(RuntimeError::InvokeError(InvokeError::FailedWithNoError)))
+    };
+
+    FAULT_TIMESTAMP.with(|x| x.set(Some(FaultTimestamp::now())));
+    // The handlers called from here must not unwind with a panic, even if a host function
+    // faulted.
+    let _host_function = HostFunctionGuard::enter(false);
+
+    unsafe {
+        let mut fault = get_fault_info(siginfo as _, ucontext);
+        if signum == SIGSEGV as i32 || signum == SIGBUS as i32 {
+            abort_on_trap_stack_overflow(fault.faulting_addr as usize);
+        }
+        if signum == SIGTRAP as i32 && finish_watchpoint_step(ucontext) {
+            return;
+        }
+
+        // The signal being handled is blocked until this handler returns, so an overflow of
+        // the trap stack would kill the process without a message. Unblock the signals raised
+        // by the guard page so that it is reported above.
+        let mut guard_signals = SigSet::empty();
+        guard_signals.add(SIGSEGV);
+        guard_signals.add(SIGBUS);
+        let _ = guard_signals.thread_unblock();
+
+        let early_return = allocate_and_run(trap_stack_size(), || {
+            let arch = match ARCH {
+                Some(x) => x,
+                None => return false,
+            };
+            CURRENT_CODE_VERSIONS.with(|versions| {
+                let versions = versions.borrow();
+                for (i, v) in versions.iter().enumerate() {
+                    let magic_size =
+                        if let Some(x) = v.runnable_module.get_inline_breakpoint_size(arch) {
+                            x
+                        } else {
+                            continue;
+                        };
+                    let ip = fault.ip.get();
+                    let end = v.base + v.msm.total_size;
+                    if ip >= v.base && ip < end && ip + magic_size <= end {
+                        if !code_version_intact(i, v) {
+                            eprintln!(
+                                "Wasmer detected corrupted code in the {} code version at 0x{:x}, not reading inline breakpoints from it",
+                                v.backend, ip
+                            );
+                            break;
+                        }
+                        if let Some(ib) = v.runnable_module.read_inline_breakpoint(
+                            arch,
+                            std::slice::from_raw_parts(ip as *const u8, magic_size),
+                        ) {
+                            match ib.ty {
+                                InlineBreakpointType::Middleware => {
+                                    let out: Option<Result<(), RuntimeError>> =
+                                        with_breakpoint(ip, |handler| {
+                                            handler.map(|x| {
+                                                x(BreakpointInfo {
+                                                    fault: Some(&fault),
+                                                })
+                                            })
+                                        });
+                                    if let Some(Ok(())) = out {
+                                    } else if let Some(Err(e)) = out {
+                                        should_unwind = true;
+                                        unwind_result = Some(Box::new(e));
+                                    }
+                                }
+                                InlineBreakpointType::Trace => {
+                                    let handler = TRACE_BREAKPOINT_HANDLER.load(Ordering::SeqCst);
+                                    if handler != 0 {
+                                        let handler = std::mem::transmute::<
+                                            usize,
+                                            TraceBreakpointHandler,
+                                        >(handler);
+                                        handler(&fault);
+                                    }
+                                }
+                                InlineBreakpointType::Counter => {
+                                    count_counter_breakpoint_hit(ip);
+                                }
+                            }
+
+                            record_fault(signum, ip, FaultClassification::Breakpoint);
+                            fault.ip.set(ip + magic_size);
+                            return true;
+                        }
+                        break;
+                    }
+                }
+                false
+            })
+        });
+        if should_unwind {
+            let error = get_unwind_result(unwind_result);
+            notify_trap_observer(&fault, &error);
+            begin_unsafe_unwind(error);
+        }
+        if early_return {
+            return;
+        }
+
+        should_unwind = allocate_and_run(trap_stack_size(), || {
+            let mut is_suspend_signal = false;
+            let mut is_stack_overflow = false;
+            let mut interrupt_reason = None;
+
+            WAS_SIGINT_TRIGGERED.with(|x| x.set(false));
+
+            match Signal::from_c_int(signum) {
+                Ok(SIGTRAP) => {
+                    // breakpoint
+                    if is_breakpoint_disarmed(fault.ip.get()) {
+                        return false;
+                    }
+                    BREAKPOINT_DISARM_REQUESTED.with(|x| x.set(false));
+                    BREAKPOINT_SUSPEND_REQUESTED.with(|x| x.set(false));
+                    let out: Option<Result<(), RuntimeError>> =
+                        with_breakpoint(fault.ip.get(), |handler| {
+                            handler.map(|x| -> Result<(), RuntimeError> {
+                                count_breakpoint_hit(fault.ip.get());
+                                x(BreakpointInfo {
+                                    fault: Some(&fault),
+                                })
+                            })
+                        });
+                    disarm_breakpoint_if_requested(fault.ip.get());
+                    match out {
+                        Some(Ok(())) => {
+                            record_fault(signum, fault.ip.get(), FaultClassification::Breakpoint);
+                            if !BREAKPOINT_SUSPEND_REQUESTED.with(|x| x.replace(false)) {
+                                return false;
+                            }
+                            fault.load_fp_registers();
+                            let es_image = match fault.read_stack(None) {
+                                Some(x) => x,
+                                None => {
+                                    eprintln!(
+                                        "Wasmer cannot read the stack at the breakpoint at 0x{:x}, resuming instead of suspending",
+                                        fault.ip.get()
+                                    );
+                                    return false;
+                                }
+                            };
+                            let ctx = CURRENT_CTX.with(|x| *x.get());
+                            let mut image = if ctx.is_null() {
+                                InstanceImage {
+                                    memory: None,
+                                    globals: vec![],
+                                    execution_state: es_image,
+                                    interrupt_trace_id: None,
+                                    code_hash: None,
+                                    from_sigint: false,
+                                }
+                            } else {
+                                build_instance_image(&mut *ctx, es_image)
+                            };
+                            image.code_hash = hash_code_at(fault.ip.get());
+                            unwind_result =
+                                Some(Box::new(RuntimeError::InstanceImage(Box::new(image))));
+                            return true;
+                        }
+                        Some(Err(e)) => {
+                            record_fault(signum, fault.ip.get(), FaultClassification::Breakpoint);
+                            unwind_result = Some(Box::new(e));
+                            return true;
+                        }
+                        None => {}
+                    }
+                }
+                Ok(SIGSEGV) | Ok(SIGBUS) => {
+                    if let Some((pages, handler)) = find_watchpoint(fault.faulting_addr as usize) {
+                        if let Some(handler) = handler {
+                            handler(&fault);
+                        }
+                        if step_over_watchpoint(&fault, pages) {
+                            return false;
+                        }
+                    }
+                    if let Some(handler) = find_fault_recovery(fault.faulting_addr as usize) {
+                        if handler(&fault) == RecoveryAction::Resume {
+                            return false;
+                        }
+                    }
+                    if is_in_guard_region(fault.faulting_addr as usize) {
+                        is_stack_overflow = true;
+                    }
+                    let signal_mem = get_wasm_interrupt_signal_mem() as usize;
+                    if (signal_mem..signal_mem + *INTERRUPT_SIGNAL_MEM_SIZE)
+                        .contains(&(fault.faulting_addr as usize))
+                    {
+                        is_suspend_signal = true;
+                        let ctx = CURRENT_CTX.with(|x| *x.get());
+                        // The interrupts of other `Ctx`s keep their reasons.
+                        interrupt_reason = take_interrupt_reason(ctx);
+                        disarm_interrupt_signal_mem();
+                        INTERRUPT_ARMED_GLOBALLY.store(false, Ordering::SeqCst);
+                        take_ctx_interrupt_flag(ctx, CTX_INTERRUPT_ARMED);
+                        if take_sigint_delivered(ctx) {
+                            WAS_SIGINT_TRIGGERED.with(|x| x.set(true));
+                        }
+                    }
+                }
+                _ => {}
+            }
+
+            // A fault that did not happen in wasm code is left to the handler installed before
+            // ours, e.g. the crash reporter of the host.
+            // A stack overflow may happen in a host function called from wasm, and is reported
+            // as such.
+            if !is_suspend_signal && !is_stack_overflow && is_foreign_fault(fault.ip.get()) {
+                if let Some(prev) = previous_trap_handler(signum) {
+                    chained_handler = Some(prev);
+                    return false;
+                }
+            }
+
+            // Now we have looked up all possible handler tables but failed to find a handler
+            // for this exception that allows a normal return.
+            //
+            // So here we check whether this exception is caused by a suspend signal, return the
+            // state image if so, or throw the exception out otherwise.
+
+            // The C library may hold locks taken by `abort()`, so nothing more is done here.
+            if signum == SIGABRT as i32 {
+                record_fault(signum, fault.ip.get(), FaultClassification::Unknown);
+                unwind_result = Some(Box::new(RuntimeError::InvokeError(InvokeError::Aborted)));
+                return true;
+            }
+
+            // The `Ctx` is null if the fault happened outside of `with_ctx`, e.g. in host code
+            // running between invocations, and must not be dereferenced then.
+            let ctx: *mut vm::Ctx = CURRENT_CTX.with(|x| *x.get());
+            if is_suspend_signal && (take_handle_interrupt(ctx) || interrupt_reason.is_some()) {
+                record_fault(signum, fault.ip.get(), FaultClassification::Suspend);
+                unwind_result = Some(Box::new(RuntimeError::InvokeError(
+                    InvokeError::Interrupted {
+                        from_sigint: was_sigint_triggered_fault(),
+                        reason: interrupt_reason,
+                    },
+                )));
+                return true;
+            }
+            if is_suspend_signal && take_timeout(ctx) {
+                record_fault(signum, fault.ip.get(), FaultClassification::Suspend);
+                unwind_result = Some(Box::new(RuntimeError::InvokeError(InvokeError::Timeout)));
+                return true;
+            }
+            if is_suspend_signal && take_memory_capture(ctx) {
+                record_fault(signum, fault.ip.get(), FaultClassification::Suspend);
+                PENDING_MEMORY_CAPTURE.with(|x| x.set(ctx));
+                unwind_result = Some(Box::new(RuntimeError::InvokeError(
+                    InvokeError::PartialResult(vec![]),
+                )));
+                return true;
+            }
+
+            if !is_suspend_signal {
+                let ip = fault.ip.get();
+                if let Some(FaultClassification::Trap(code)) = classify_fault(ip).first() {
+                    if let Some(handler) = find_trap_recovery(*code) {
+                        if handler(&mut fault) == RecoveryAction::Resume {
+                            record_fault(signum, ip, FaultClassification::Trap(*code));
+                            return false;
+                        }
+                    }
+                }
+            }
+
+            if is_suspend_signal && ctx.is_null() {
+                record_fault(signum, fault.ip.get(), FaultClassification::Suspend);
+                unwind_result = Some(Box::new(RuntimeError::InvokeError(InvokeError::NoContext)));
+                return true;
+            }
+
+            // Resuming from a suspend needs the full register state. For other faults, the
+            // floating point registers are only decoded if requested.
+            if is_suspend_signal {
+                fault.load_fp_registers();
+            }
+            let es_image = match fault.read_stack(if is_suspend_signal {
+                None
+            } else {
+                max_backtrace_depth()
+            }) {
+                Some(x) => x,
+                None => {
+                    // The registers of the signal context are unknown on this target, so the
+                    // fault is reported as a generic error without a backtrace.
+                    record_fault(signum, fault.ip.get(), FaultClassification::Unknown);
+                    LAST_TRAP_STATE.with(|x| {
+                        *x.borrow_mut() = Some(ExecutionStateImage {
+                            frames: vec![],
+                            truncated: false,
+                            host_frames: vec![],
+                        })
+                    });
+                    unwind_result = Some(Box::new(RuntimeError::InvokeError(
+                        InvokeError::UnknownTrap {
+                            address: fault.faulting_addr as usize,
+                            ip: Some(fault.ip.get()).filter(|&ip| ip != 0),
+                            signal: signal_description(signum),
+                        },
+                    )));
+                    return true;
+                }
+            };
+
+            if is_suspend_signal {
+                if let Some(on_snapshot) = take_snapshot_request(ctx) {
+                    // A non-destructive snapshot: the interrupt is already cleared, so returning
+                    // resumes execution at the faulting instruction.
+                    record_fault(signum, fault.ip.get(), FaultClassification::Suspend);
+                    on_snapshot(build_instance_image(&mut *ctx, es_image));
+                    return false;
+                }
+            }
+
+            if is_suspend_signal {
+                // If this is a suspend signal, we parse the runtime state and return the resulting image.
+                record_fault(signum, fault.ip.get(), FaultClassification::Suspend);
+                let mut image = build_instance_image(&mut *ctx, es_image);
+                image.interrupt_trace_id = take_interrupt_trace_id(ctx);
+                image.code_hash = hash_code_at(fault.ip.get());
+                image.from_sigint = was_sigint_triggered_fault();
+                unwind_result = Some(Box::new(RuntimeError::InstanceImage(Box::new(image))));
+            } else {
+                // Otherwise, this is a real exception and we just throw it to the caller.
+                let mut es_image = es_image;
+                read_host_frames(&mut es_image);
+                if !es_image.frames.is_empty() && print_backtrace_on_trap() {
+                    eprintln!(
+                        "\n{}",
+                        "Wasmer encountered an error while running your WebAssembly program."
+                    );
+                    let names = CURRENT_CODE_VERSIONS.with(|versions| {
+                        versions
+                            .borrow()
+                            .iter()
+                            .rev()
+                            .find(|v| {
+                                fault.ip.get() >= v.base
+                                    && fault.ip.get() < v.base + v.msm.total_size
+                            })
+                            .and_then(|v| v.function_names.clone())
+                    });
+                    es_image.print_backtrace_if_needed_with_names(names.as_ref().map(|x| &**x));
+                }
+                LAST_TRAP_STATE.with(|x| *x.borrow_mut() = Some(es_image));
+
+                if is_stack_overflow {
+                    record_fault(signum, fault.ip.get(), FaultClassification::StackOverflow);
+                    unwind_result = Some(Box::new(RuntimeError::InvokeError(
+                        InvokeError::StackOverflow,
+                    )));
+                    return true;
+                }
+
+                // Look up the exception tables and try to find an exception code.
+                let candidates = classify_fault(fault.ip.get());
+                if candidates.len() > 1 {
+                    eprintln!(
+                        "Wasmer found {} candidate classifications for the fault at 0x{:x}, using the first: {:?}",
+                        candidates.len(),
+                        fault.ip.get(),
+                        candidates
+                    );
+                }
+                let exc_code = match candidates.first() {
+                    Some(FaultClassification::Trap(code)) => Some(*code),
+                    _ => None,
+                };
+                record_fault(
+                    signum,
+                    fault.ip.get(),
+                    exc_code.map_or(FaultClassification::Unknown, FaultClassification::Trap),
+                );
+                if let Some(code) = exc_code {
+                    unwind_result = Some(Box::new(trap_error(code, fault.ip.get())));
+                } else {
+                    unwind_result = Some(Box::new(RuntimeError::InvokeError(
+                        InvokeError::UnknownTrap {
+                            address: fault.faulting_addr as usize,
+                            ip: Some(fault.ip.get()),
+                            signal: signal_description(signum),
+                        },
+                    )));
+                }
+            }
+
+            true
+        });
+
+        if let Some((signal, prev)) = chained_handler {
+            call_signal_handler(signal, siginfo, ucontext, &prev);
+            return;
+        }
+        if should_unwind {
+            let error = get_unwind_result(unwind_result);
+            notify_trap_observer(&fault, &error);
+            begin_unsafe_unwind(error);
+        }
+    }
+}
+
+/// Returns the error of a trap with `code` at `ip`.
+unsafe fn trap_error(code: ExceptionCode, ip: usize) -> RuntimeError {
+    let version = is_wasm_address(ip);
+    RuntimeError::InvokeError(InvokeError::TrapCode {
+        code,
+        srcloc: trap_srcloc(ip).unwrap_or(0),
+        memory_size: match code {
+            ExceptionCode::MemoryOutOfBounds => current_memory_size(),
+            _ => None,
+        },
+        code_base: version.as_ref().map(|v| v.base),
+        module_offset: version.as_ref().map(|v| v.offset),
+    })
+}
+
+/// Returns a description of the fault signal `signum`, for `InvokeError::UnknownTrap`.
+fn signal_description(signum: ::nix::libc::c_int) -> &'static str {
+    match Signal::from_c_int(signum) {
+        Ok(SIGFPE) => "floating-point exception",
+        Ok(SIGILL) => "illegal instruction",
+        Ok(SIGSEGV) => "segmentation violation",
+        Ok(SIGBUS) => "bus error",
+        Ok(SIGTRAP) => "trace trap",
+        Ok(SIGABRT) => "abort",
+        Err(_) => "error while getting the Signal",
+        _ => "unknown trapped signal",
+    }
+}
+
+/// Returns true if a fault at `ip` cannot have been raised by wasm code, i.e. it happened
+/// outside any `catch_unsafe_unwind` scope, or code versions are pushed and none contains `ip`.
+///
+/// Without any code version pushed, all faults within a scope are attributed to wasm code, and
+/// so are those at an `ip` of 0, which `get_fault_info` reports on targets whose signal
+/// context is not supported.
+fn is_foreign_fault(ip: usize) -> bool {
+    if !is_in_catch_scope() {
+        return true;
+    }
+    if ip == 0 {
+        return false;
+    }
+    let any_versions = CURRENT_CODE_VERSIONS.with(|versions| !versions.borrow().is_empty());
+    any_versions && is_wasm_address(ip).is_none()
+}
+
+/// Signals whose previous handlers are chained to for faults outside wasm code. The previous
+/// handler of `SIGABRT`, if caught, is saved separately.
+const CHAINED_SIGNALS: [Signal; 4] = [SIGFPE, SIGILL, SIGSEGV, SIGBUS];
+
+type PreviousHandlers = [Option<SigAction>; 4];
+
+static mut TRAP_SYS_HANDLERS: PreviousHandlers = [None; 4];
+
+fn previous_trap_handler(signum: ::nix::libc::c_int) -> Option<(Signal, SigAction)> {
+    let signal = Signal::from_c_int(signum).ok()?;
+    if signal == SIGABRT {
+        return unsafe { SIGABRT_SYS_HANDLER.map(|prev| (signal, prev)) };
+    }
+    let index = CHAINED_SIGNALS.iter().position(|x| *x == signal)?;
+    unsafe { TRAP_SYS_HANDLERS[index].map(|prev| (signal, prev)) }
+}
+
+static mut SIGINT_SYS_HANDLER: Option<SigAction> = None;
+static mut SIGTRAP_SYS_HANDLER: Option<SigAction> = None;
+static mut SIGABRT_SYS_HANDLER: Option<SigAction> = None;
+
+static CATCH_SIGABRT: AtomicBool = AtomicBool::new(false);
+
+/// Sets whether the signal handlers also catch `SIGABRT`, so that a call to `abort()` while
+/// running wasm code, e.g. from a host function, fails the call with `InvokeError::Aborted`
+/// instead of killing the process. Disabled by default.
+///
+/// This only takes effect if called before the signal handlers are installed by one of the
+/// `ensure_sighandler*` functions. `SIGABRT` raised outside wasm code, as decided for other
+/// faults, is passed to the handler installed before ours, which aborts by default. Unwinding
+/// out of `abort()` may leave the C library in an inconsistent state, e.g. with its stdio
+/// locks held, so the process should be restarted soon after.
+pub fn set_catch_sigabrt(enabled: bool) {
+    CATCH_SIGABRT.store(enabled, Ordering::SeqCst);
+}
+
+/// What the SIGINT handler does with a SIGINT that arrives before the previous one has been
+/// turned into a fault on the WebAssembly side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DoubleSigintPolicy {
+    /// Call the handler set with `set_double_sigint_handler`, or abort the process if there is
+    /// none. This is the default.
+    Abort = 0,
+    /// Drop the second SIGINT.
+    IgnoreSecond = 1,
+    /// Pass the second SIGINT to the handler installed before ours, if any, without delivering
+    /// it to wasm.
+    ForwardToPrevious = 2,
+}
+
+/// Sets the policy for a SIGINT that arrives before the previous one has been turned into a
+/// fault on the WebAssembly side.
+pub fn set_double_sigint_policy(policy: DoubleSigintPolicy) {
+    DOUBLE_SIGINT_POLICY.store(policy as usize, Ordering::SeqCst);
+}
+
+/// Returns the policy set by `set_double_sigint_policy`.
+pub fn double_sigint_policy() -> DoubleSigintPolicy {
+    match DOUBLE_SIGINT_POLICY.load(Ordering::SeqCst) {
+        1 => DoubleSigintPolicy::IgnoreSecond,
+        2 => DoubleSigintPolicy::ForwardToPrevious,
+        _ => DoubleSigintPolicy::Abort,
+    }
+}
+
+/// Sets the handler called instead of aborting the process when a second SIGINT arrives under
+/// `DoubleSigintPolicy::Abort`, e.g. to flush logs and exit with a specific code.
+///
+/// The handler runs inside the signal handler and is called at most once: a later double
+/// SIGINT aborts the process, unless another handler is set. Replaces any previous handler.
+pub fn set_double_sigint_handler<F: FnOnce() + Send + 'static>(handler: F) {
+    *DOUBLE_SIGINT_HANDLER.lock().unwrap() = Some(Box::new(handler));
+}
+
+/// Removes the handler set with `set_double_sigint_handler`.
+pub fn clear_double_sigint_handler() {
+    *DOUBLE_SIGINT_HANDLER.lock().unwrap() = None;
+}
+
+/// Takes the handler set with `set_double_sigint_handler`. Does not block, since the thread
+/// receiving the SIGINT may hold the lock.
+fn take_double_sigint_handler() -> Option<Box<dyn FnOnce() + Send>> {
+    DOUBLE_SIGINT_HANDLER
+        .try_lock()
+        .ok()
+        .and_then(|mut x| x.take())
+}
+
+/// Returns a boolean indicating if an active `Ctx` has not yet consumed the previous SIGINT.
+fn is_sigint_pending() -> bool {
+    ACTIVE_CTXS
+        .iter()
+        .any(|slot| slot.ctx.load(Ordering::SeqCst) != 0 && slot.delivered.load(Ordering::SeqCst))
+}
+
+/// Marks a SIGINT as delivered to the `Ctx`s that are active right now, returning whether there
+/// was any; a `Ctx` that is entered later is not interrupted by it.
+fn deliver_sigint_to_active_ctxs() -> bool {
+    let mut delivered = false;
+    for slot in ACTIVE_CTXS.iter() {
+        if slot.ctx.load(Ordering::SeqCst) == 0 {
+            continue;
+        }
+        slot.delivered.store(true, Ordering::SeqCst);
+        delivered = true;
+    }
+    delivered
+}
+
+extern "C" fn sigint_handler(
+    _signum: ::nix::libc::c_int,
+    _siginfo: *mut siginfo_t,
+    _ucontext: *mut c_void,
+) {
+    unsafe {
+        if is_sigint_pending() {
+            match double_sigint_policy() {
+                DoubleSigintPolicy::Abort => {
+                    if let Some(handler) = take_double_sigint_handler() {
+                        handler();
+                        return;
+                    }
+                    eprintln!(
+                        "Got another SIGINT before trap is triggered on WebAssembly side, aborting"
+                    );
+                    process::abort();
+                }
+                DoubleSigintPolicy::IgnoreSecond => {}
+                DoubleSigintPolicy::ForwardToPrevious => {
+                    if let Some(prev_handler) = SIGINT_SYS_HANDLER {
+                        call_signal_handler(SIGINT, _siginfo, _ucontext, &prev_handler);
+                    }
+                }
+            }
+            return;
+        }
+
+        if deliver_sigint_to_active_ctxs() {
+            set_wasm_interrupt();
+        }
+
+        if let Some(prev_handler) = SIGINT_SYS_HANDLER {
+            call_signal_handler(SIGINT, _siginfo, _ucontext, &prev_handler);
+        }
+    }
+}
+
+/// The set of signals for which the fault handlers were, or failed to be, installed.
+#[derive(Debug, Clone, Default)]
+pub struct FaultInstallReport {
+    /// Signals whose handler was installed successfully.
+    pub installed: Vec<Signal>,
+    /// Signals whose handler could not be installed, with the error returned by `sigaction`.
+    pub failed: Vec<(Signal, nix::Error)>,
+}
+
+impl FaultInstallReport {
+    /// Returns true if the handlers for all signals were installed.
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// An error produced while installing the fault signal handlers.
+#[derive(Debug, Clone)]
+pub enum FaultError {
+    /// None of the signal handlers could be installed.
+    InstallFailed(FaultInstallReport),
+    /// A breakpoint offset is outside the code of its code version.
+    BreakpointOutOfRange {
+        /// The requested offset.
+        offset: usize,
+        /// The size of the code of the code version.
+        code_size: usize,
+    },
+    /// Two contributions to a `BreakpointMapBuilder` have a breakpoint at the same instruction
+    /// pointer.
+    BreakpointCollision {
+        /// The instruction pointer of the breakpoints.
+        ip: usize,
+    },
+    /// A table of fault recovery handlers, guard regions or watchpoints already holds
+    /// `MAX_RANGES` entries.
+    TooManyRanges,
+    /// Watched accesses cannot be single-stepped on this platform.
+    WatchpointsUnsupported,
+    /// The memory of a watchpoint could not be protected.
+    WatchpointProtectFailed {
+        /// The address of the watched region.
+        addr: usize,
+        /// The length of the watched region.
+        len: usize,
+        /// The error number set by `mprotect`.
+        errno: i32,
+    },
+}
+
+impl std::fmt::Display for FaultError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FaultError::InstallFailed(report) => {
+                write!(f, "cannot install any signal handler:")?;
+                for (signal, e) in report.failed.iter() {
+                    write!(f, " {:?} ({})", signal, e)?;
+                }
+                Ok(())
+            }
+            FaultError::BreakpointOutOfRange { offset, code_size } => write!(
+                f,
+                "breakpoint offset {} is outside of the code ({} bytes)",
+                offset, code_size
+            ),
+            FaultError::BreakpointCollision { ip } => {
+                write!(f, "more than one breakpoint at 0x{:x}", ip)
+            }
+            FaultError::TooManyRanges => write!(f, "more than {} regions registered", MAX_RANGES),
+            FaultError::WatchpointsUnsupported => {
+                write!(f, "watchpoints are not supported on this platform")
+            }
+            FaultError::WatchpointProtectFailed { addr, len, errno } => write!(
+                f,
+                "cannot protect the watched memory at 0x{:x} ({} bytes): {}",
+                addr,
+                len,
+                nix::errno::Errno::from_i32(*errno).desc()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FaultError {}
+
+/// Ensure the signal handler is installed.
+///
+/// Panics if none of the signal handlers could be installed. Use `ensure_sighandler_checked`
+/// to inspect which signals are covered instead.
+pub fn ensure_sighandler() {
+    if let Err(e) = ensure_sighandler_checked() {
+        panic!("{}", e);
+    }
+}
+
+/// Ensure the signal handlers are installed, except for the SIGINT handler.
+///
+/// SIGINT is left to the embedder, so Ctrl-C does not interrupt wasm code and
+/// `was_sigint_triggered_fault` always returns false. Installation is only attempted once, by
+/// whichever of the `ensure_sighandler*` functions is called first.
+pub fn ensure_sighandler_without_sigint() {
+    if let Err(e) = ensure_sighandler_checked_with(false) {
+        panic!("{}", e);
+    }
+}
+
+/// Ensure the signal handler is installed, returning a report of the signals covered.
+///
+/// Installation is only attempted once; later calls return the result of the first attempt.
+pub fn ensure_sighandler_checked() -> Result<FaultInstallReport, FaultError> {
+    ensure_sighandler_checked_with(true)
+}
+
+fn ensure_sighandler_checked_with(install_sigint: bool) -> Result<FaultInstallReport, FaultError> {
+    unsafe {
+        INSTALL_SIGHANDLER.call_once(|| {
+            INSTALL_RESULT = Some(install_sighandler(install_sigint));
+        });
+        INSTALL_RESULT
+            .clone()
+            .expect("signal handler installation did not complete")
+    }
+}
+
+static INSTALL_SIGHANDLER: Once = Once::new();
+static mut INSTALL_RESULT: Option<Result<FaultInstallReport, FaultError>> = None;
+
+type SigactionFn = unsafe fn(Signal, &SigAction) -> nix::Result<SigAction>;
+
+unsafe fn install_sighandler(install_sigint: bool) -> Result<FaultInstallReport, FaultError> {
+    let mut previous = [None; 4];
+    let result = install_sighandler_with(sigaction, install_sigint, &mut previous);
+    TRAP_SYS_HANDLERS = previous;
+    result
+}
+
+/// Installs the signal handlers with `sigaction`, saving the handlers they replace for the
+/// signals in `CHAINED_SIGNALS` into `previous`.
+unsafe fn install_sighandler_with(
+    sigaction: SigactionFn,
+    install_sigint: bool,
+    previous: &mut PreviousHandlers,
+) -> Result<FaultInstallReport, FaultError> {
+    let mut report = FaultInstallReport::default();
+    let mut record = |signal: Signal, result: nix::Result<SigAction>| match result {
+        Ok(old) => {
+            report.installed.push(signal);
+            Some(old)
+        }
+        Err(e) => {
+            report.failed.push((signal, e));
+            None
+        }
+    };
+
+    let sa_trap = SigAction::new(
+        SigHandler::SigAction(signal_trap_handler),
+        SaFlags::SA_ONSTACK,
+        SigSet::empty(),
+    );
+    for &signal in [SIGFPE, SIGILL, SIGSEGV, SIGBUS, SIGTRAP].iter() {
+        let old = record(signal, sigaction(signal, &sa_trap));
+        if let Some(index) = CHAINED_SIGNALS.iter().position(|x| *x == signal) {
+            previous[index] = old;
+        } else if signal == SIGTRAP {
+            SIGTRAP_SYS_HANDLER = old;
+        }
+    }
+
+    if CATCH_SIGABRT.load(Ordering::SeqCst) {
+        SIGABRT_SYS_HANDLER = record(SIGABRT, sigaction(SIGABRT, &sa_trap));
+    }
+
+    if install_sigint {
+        let sa_interrupt = SigAction::new(
+            SigHandler::SigAction(sigint_handler),
+            SaFlags::SA_ONSTACK,
+            SigSet::empty(),
+        );
+        if let Some(old) = record(SIGINT, sigaction(SIGINT, &sa_interrupt)) {
+            SIGINT_SYS_HANDLER = Some(old);
+            // Allocate the table now, not in the signal handler.
+            lazy_static::initialize(&ACTIVE_CTXS);
+            SIGINT_HANDLER_INSTALLED.store(true, Ordering::SeqCst);
+        }
+    }
+
+    // Allocate the tables searched by the signal handler now.
+    lazy_static::initialize(&FAULT_RECOVERIES);
+    lazy_static::initialize(&GUARD_REGIONS);
+    lazy_static::initialize(&WATCHPOINTS);
+
+    // Query the page size now, not in the signal handler.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    lazy_static::initialize(&TRAP_STACK_GUARD_SIZE);
+
+    if report.installed.is_empty() {
+        Err(FaultError::InstallFailed(report))
+    } else {
+        Ok(report)
+    }
+}
+
+/// Runs `f` with the signal handlers that were installed before ours, then reinstalls ours,
+/// e.g. for native code that raises and handles SIGSEGV itself.
+///
+/// Signal handlers are process-wide, so no wasm code may run on any thread while `f` runs.
+/// Signals whose handler was not installed by us are left as is.
+pub unsafe fn with_signal_handlers_suspended<R, F: FnOnce() -> R>(f: F) -> R {
+    struct Guard(Vec<(Signal, SigAction)>);
+
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            for (signal, ours) in self.0.drain(..) {
+                unsafe {
+                    let _ = sigaction(signal, &ours);
+                }
+            }
+        }
+    }
+
+    let mut previous: Vec<(Signal, SigAction)> = CHAINED_SIGNALS
+        .iter()
+        .zip(TRAP_SYS_HANDLERS.iter())
+        .filter_map(|(&signal, prev)| prev.map(|prev| (signal, prev)))
+        .collect();
+    if let Some(prev) = SIGTRAP_SYS_HANDLER {
+        previous.push((SIGTRAP, prev));
+    }
+    if let Some(prev) = SIGABRT_SYS_HANDLER {
+        previous.push((SIGABRT, prev));
+    }
+    if SIGINT_HANDLER_INSTALLED.load(Ordering::SeqCst) {
+        if let Some(prev) = SIGINT_SYS_HANDLER {
+            previous.push((SIGINT, prev));
+        }
+    }
+
+    let mut guard = Guard(vec![]);
+    for (signal, prev) in previous {
+        if let Ok(ours) = sigaction(signal, &prev) {
+            guard.0.push((signal, ours));
+        }
+    }
+    f()
+}
+
+#[derive(Debug, Clone)]
+/// Info about the fault
+pub struct FaultInfo {
+    /// Faulting address.
+    pub faulting_addr: *const c_void,
+    /// Instruction pointer.
+    pub ip: &'static Cell<usize>,
+    /// Values of known registers.
+    pub known_registers: [Option<u64>; 32],
+    /// Stack bounds of the faulting thread, if known.
+    pub stack_bounds: Option<StackBounds>,
+    /// Portable fields of the raw `siginfo_t`, if enabled with `set_capture_raw_siginfo`.
+    pub raw_siginfo: Option<SigInfoSnapshot>,
+    /// The number of the signal that raised the fault, if known.
+    pub signal: Option<i32>,
+    /// The hardware trap number of the fault, e.g. the exception vector on x86, if reported by
+    /// the platform.
+    pub trap_no: Option<u32>,
+    /// The base address of the `fs` segment, e.g. the thread pointer on x86_64, if saved in
+    /// the signal context.
+    pub fs_base: Option<u64>,
+    /// The base address of the `gs` segment, if saved in the signal context.
+    pub gs_base: Option<u64>,
+    /// The floating point state of the signal context, if it has not been decoded into
+    /// `known_registers` yet. Only valid while the signal handler runs.
+    pub fpregs: *const c_void,
+    /// The raw signal context. Only valid while the signal handler runs.
+    pub ucontext: *mut c_void,
+    /// Cells aliasing the general purpose registers of the signal context, indexed by `GPR`,
+    /// for the registers that can be written back with `set_gpr`. Only valid while the signal
+    /// handler runs.
+    pub gpr_cells: [Option<&'static Cell<u64>>; 16],
+}
+
+/// A frame yielded by `FaultInfo::for_each_frame`, borrowed for the duration of the callback.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameInfoRef<'a> {
+    /// Local function id.
+    pub local_function_id: usize,
+    /// Wasm instruction offset.
+    pub wasm_inst_offset: usize,
+    /// The instruction pointer of the frame: the faulting instruction for the innermost frame,
+    /// and the return address for the others.
+    pub ip: usize,
+    /// The symbol name of the function, if the code version has function names.
+    pub function_name: Option<&'a str>,
+}
+
+/// A copy of the portable fields of a `siginfo_t`.
+///
+/// `si_pid` and `si_uid` are only meaningful for signals sent by a process, e.g. with `kill`,
+/// and `si_addr` only for signals generated by the CPU; `si_code` tells the two apart. On
+/// Linux these fields share storage, so the ones that do not apply hold garbage.
+#[derive(Debug, Clone, Copy)]
+pub struct SigInfoSnapshot {
+    /// Signal number.
+    pub si_signo: i32,
+    /// Errno value.
+    pub si_errno: i32,
+    /// Signal code.
+    pub si_code: i32,
+    /// Faulting address.
+    pub si_addr: usize,
+    /// Sending process ID.
+    pub si_pid: i32,
+    /// Real user ID of the sending process.
+    pub si_uid: u32,
+}
+
+/// Enables or disables capturing `FaultInfo::raw_siginfo`.
+pub fn set_capture_raw_siginfo(enabled: bool) {
+    CAPTURE_RAW_SIGINFO.store(enabled, Ordering::SeqCst);
+}
+
+/// Enables or disables decoding the floating point registers on every fault.
+///
+/// By default, on Linux x86_64, only the general purpose registers are decoded, except when
+/// execution is suspended; backtraces of traps then lack the values held in floating point
+/// registers. Other platforms always decode them.
+pub fn set_capture_fp_registers(enabled: bool) {
+    CAPTURE_FP_REGISTERS.store(enabled, Ordering::SeqCst);
+}
+
+/// Enables or disables printing an error message and a backtrace to stderr when a trap
+/// occurs. Enabled by default.
+///
+/// The backtrace of a trap is still recorded when printing is disabled, and can be retrieved
+/// with `TrapReport::with_backtrace`.
+pub fn set_print_backtrace_on_trap(enabled: bool) {
+    PRINT_BACKTRACE_ON_TRAP.store(enabled, Ordering::SeqCst);
+}
+
+/// Returns whether traps are printed to stderr, as set with `set_print_backtrace_on_trap`.
+pub fn print_backtrace_on_trap() -> bool {
+    PRINT_BACKTRACE_ON_TRAP.load(Ordering::SeqCst)
+}
+
+/// Reads `si_signo`, the first field of `siginfo_t` on all platforms.
+unsafe fn siginfo_signo(siginfo: *const c_void) -> i32 {
+    *(siginfo as *const i32)
+}
+
+unsafe fn capture_raw_siginfo(siginfo: *const c_void) -> Option<SigInfoSnapshot> {
+    if CAPTURE_RAW_SIGINFO.load(Ordering::SeqCst) {
+        Some(snapshot_siginfo(siginfo))
+    } else {
+        None
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn snapshot_siginfo(siginfo: *const c_void) -> SigInfoSnapshot {
+    #[allow(dead_code)]
+    #[allow(non_camel_case_types)]
+    #[repr(C)]
+    struct siginfo_t {
+        si_signo: i32,
+        si_errno: i32,
+        si_code: i32,
+        _pad: i32,
+        // Union of `si_addr` and (`si_pid`, `si_uid`).
+        si_fields: u64,
+    }
+
+    let siginfo = &*(siginfo as *const siginfo_t);
+    SigInfoSnapshot {
+        si_signo: siginfo.si_signo,
+        si_errno: siginfo.si_errno,
+        si_code: siginfo.si_code,
+        si_addr: siginfo.si_fields as usize,
+        si_pid: siginfo.si_fields as u32 as i32,
+        si_uid: (siginfo.si_fields >> 32) as u32,
+    }
+}
+
+#[cfg(any(target_os = "illumos", target_os = "solaris"))]
+unsafe fn snapshot_siginfo(siginfo: *const c_void) -> SigInfoSnapshot {
+    #[allow(dead_code)]
+    #[allow(non_camel_case_types)]
+    #[repr(C)]
+    struct siginfo_t {
+        si_signo: i32,
+        si_code: i32,
+        si_errno: i32,
+        _pad: i32,
+        // Union of `si_addr` and (`si_pid`, `si_uid`).
+        si_fields: [u32; 4],
+    }
+
+    let siginfo = &*(siginfo as *const siginfo_t);
+    SigInfoSnapshot {
+        si_signo: siginfo.si_signo,
+        si_errno: siginfo.si_errno,
+        si_code: siginfo.si_code,
+        si_addr: (siginfo.si_fields[0] as usize) | ((siginfo.si_fields[1] as usize) << 32),
+        si_pid: siginfo.si_fields[0] as i32,
+        si_uid: siginfo.si_fields[2],
+    }
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "illumos",
+    target_os = "solaris"
+)))]
+unsafe fn snapshot_siginfo(siginfo: *const c_void) -> SigInfoSnapshot {
+    #[allow(dead_code)]
+    #[allow(non_camel_case_types)]
+    #[repr(C)]
+    struct siginfo_t {
+        si_signo: i32,
+        si_errno: i32,
+        si_code: i32,
+        si_pid: i32,
+        si_uid: u32,
+        si_status: i32,
+        si_addr: *mut c_void,
+    }
+
+    let siginfo = &*(siginfo as *const siginfo_t);
+    SigInfoSnapshot {
+        si_signo: siginfo.si_signo,
+        si_errno: siginfo.si_errno,
+        si_code: siginfo.si_code,
+        si_addr: siginfo.si_addr as usize,
+        si_pid: siginfo.si_pid,
+        si_uid: siginfo.si_uid,
+    }
+}
+
+impl FaultInfo {
+    /// Decodes the floating point registers into `known_registers`, if they have not been
+    /// decoded yet.
+    ///
+    /// Floating point registers are only decoded eagerly if enabled with
+    /// `set_capture_fp_registers`. This must be called while the signal handler runs.
+    pub unsafe fn load_fp_registers(&mut self) {
+        if !self.fpregs.is_null() {
+            decode_fp_registers(self.fpregs, &mut self.known_registers);
+            self.fpregs = std::ptr::null();
+        }
+    }
+
+    /// Returns the value of a general purpose register, if known.
+    pub fn gpr(&self, reg: GPR) -> Option<u64> {
+        self.known_registers[X64Register::GPR(reg).to_index().0]
+    }
+
+    /// Returns the value of the low 64 bits of an XMM register, if known.
+    ///
+    /// XMM registers are only known after `load_fp_registers`, unless enabled with
+    /// `set_capture_fp_registers`.
+    pub fn xmm(&self, reg: XMM) -> Option<u64> {
+        self.known_registers[X64Register::XMM(reg).to_index().0]
+    }
+
+    /// Returns an iterator over the registers whose value is known, in index order.
+    pub fn registers<'a>(&'a self) -> impl Iterator<Item = (X64Register, u64)> + 'a {
+        self.known_registers
+            .iter()
+            .enumerate()
+            .filter_map(|(i, x)| Some((X64Register::from_index(RegisterIndex(i))?, (*x)?)))
+    }
+
+    /// Writes a general purpose register back into the signal context, so that the new value
+    /// is seen when execution resumes, and updates `known_registers` to match. Returns false if
+    /// the register is not backed by the signal context, see `gpr_cells`.
+    ///
+    /// This must be called while the signal handler runs.
+    pub unsafe fn set_gpr(&mut self, reg: GPR, value: u64) -> bool {
+        match self.gpr_cells[reg as usize] {
+            Some(cell) => {
+                cell.set(value);
+                self.known_registers[X64Register::GPR(reg).to_index().0] = Some(value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Moves the instruction pointer past the faulting instruction if it is a `div` or `idiv`,
+    /// e.g. to continue with a sentinel result written with `set_register` from a handler set
+    /// for `ExceptionCode::IllegalArithmetic` with `set_trap_recovery_handler`. Returns false,
+    /// leaving the instruction pointer unchanged, for any other instruction.
+    ///
+    /// This must be called while the signal handler runs.
+    #[cfg(target_arch = "x86_64")]
+    pub unsafe fn skip_division(&self) -> bool {
+        match division_instruction_len(self.ip.get() as *const u8) {
+            Some(len) => {
+                self.ip.set(self.ip.get() + len);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Like `set_gpr`, for any register. XMM registers are never written back.
+    ///
+    /// This must be called while the signal handler runs.
+    pub unsafe fn set_register(&mut self, reg: X64Register, value: u64) -> bool {
+        match reg {
+            X64Register::GPR(x) => self.set_gpr(x, value),
+            X64Register::XMM(_) => false,
+        }
+    }
+
+    /// Walks the wasm frames of the faulting thread, innermost first, and calls `f` with each
+    /// of them.
+    ///
+    /// Unlike `read_stack`, this never allocates, so it can be used by hosts that cannot
+    /// allocate during error handling. Frames are found by following the frame pointer chain,
+    /// so the values of locals and stack slots are not recovered. This must be called while
+    /// the signal handler runs.
+    pub unsafe fn for_each_frame(&self, f: &mut impl FnMut(&FrameInfoRef)) {
+        let mut rbp = match self.known_registers[X64Register::GPR(GPR::RBP).to_index().0] {
+            Some(x) => x as usize,
+            None => return,
+        };
+        let mut ip = self.ip.get();
+
+        CURRENT_CODE_VERSIONS.with(|versions| {
+            let versions = versions.borrow();
+            for _ in 0..MAX_BACKTRACE_FRAMES {
+                let frame = versions.iter().rev().find_map(|v| {
+                    v.msm
+                        .lookup_frame_ip(ip, v.base)
+                        .map(|(fsm, wasm_inst_offset)| FrameInfoRef {
+                            local_function_id: fsm.local_function_id,
+                            wasm_inst_offset,
+                            ip,
+                            function_name: v.function_names.as_ref().and_then(|names| {
+                                names
+                                    .get(&(fsm.local_function_id as u32))
+                                    .map(|x| x.as_str())
+                            }),
+                        })
+                });
+                match frame {
+                    Some(frame) => f(&frame),
+                    None => return,
+                }
+
+                // (saved_rbp, return_address)
+                if rbp == 0 || self.stack_bounds.map_or(false, |x| !x.contains(rbp)) {
+                    return;
+                }
+                ip = *(rbp as *const usize).offset(1);
+                rbp = *(rbp as *const usize);
+            }
+        });
+    }
+
+    /// Parses the stack and builds an execution state image.
+    pub unsafe fn read_stack(&self, max_depth: Option<usize>) -> Option<ExecutionStateImage> {
+        let rsp = self.known_registers[X64Register::GPR(GPR::RSP).to_index().0]?;
+
+        Some(CURRENT_CODE_VERSIONS.with(|versions| {
+            walk_stack(
+                rsp as usize,
+                self.known_registers,
+                Some(self.ip.get()),
+                &versions.borrow(),
+                max_depth,
+                self.stack_bounds
+                    .filter(|x| x.contains(rsp as usize))
+                    .map(|x| x.base),
+            )
+        }))
+    }
+}
+
+#[cfg(all(target_os = "freebsd", target_arch = "aarch64"))]
+/// Get fault info from siginfo and ucontext.
+pub unsafe fn get_fault_info(siginfo: *const c_void, ucontext: *mut c_void) -> FaultInfo {
+    #[repr(C)]
+    pub struct ucontext_t {
+        uc_sigmask: libc::sigset_t,
+        uc_mcontext: mcontext_t,
+        uc_link: *mut ucontext_t,
+        uc_stack: libc::stack_t,
+        uc_flags: i32,
+        __spare__: [i32; 4],
+    }
+    #[repr(C)]
+    pub struct gpregs {
+        gp_x: [u64; 30],
+        gp_lr: u64,
+        gp_sp: u64,
+        gp_elr: u64,
+        gp_spsr: u64,
+        gp_pad: i32,
+    };
+    #[repr(C)]
+    pub struct fpregs {
+        fp_q: [u128; 32],
+        fp_sr: u32,
+        fp_cr: u32,
+        fp_flags: i32,
+        fp_pad: i32,
+    };
+    #[repr(C)]
+    pub struct mcontext_t {
+        mc_gpregs: gpregs,
+        mc_fpregs: fpregs,
+        mc_flags: i32,
+        mc_pad: i32,
+        mc_spare: [u64; 8],
+    }
+
+    let siginfo = siginfo as *const siginfo_t;
+    let si_addr = (*siginfo).si_addr;
+
+    let ucontext = ucontext as *mut ucontext_t;
+    let gregs = &mut (*ucontext).uc_mcontext.mc_gpregs;
+
+    let mut known_registers: [Option<u64>; 32] = [None; 32];
+
+    known_registers[X64Register::GPR(GPR::R15).to_index().0] = Some(gregs.gp_x[15] as _);
+    known_registers[X64Register::GPR(GPR::R14).to_index().0] = Some(gregs.gp_x[14] as _);
+    known_registers[X64Register::GPR(GPR::R13).to_index().0] = Some(gregs.gp_x[13] as _);
+    known_registers[X64Register::GPR(GPR::R12).to_index().0] = Some(gregs.gp_x[12] as _);
+    known_registers[X64Register::GPR(GPR::R11).to_index().0] = Some(gregs.gp_x[11] as _);
+    known_registers[X64Register::GPR(GPR::R10).to_index().0] = Some(gregs.gp_x[10] as _);
+    known_registers[X64Register::GPR(GPR::R9).to_index().0] = Some(gregs.gp_x[9] as _);
+    known_registers[X64Register::GPR(GPR::R8).to_index().0] = Some(gregs.gp_x[8] as _);
+    known_registers[X64Register::GPR(GPR::RSI).to_index().0] = Some(gregs.gp_x[6] as _);
+    known_registers[X64Register::GPR(GPR::RDI).to_index().0] = Some(gregs.gp_x[7] as _);
+    known_registers[X64Register::GPR(GPR::RDX).to_index().0] = Some(gregs.gp_x[2] as _);
+    known_registers[X64Register::GPR(GPR::RCX).to_index().0] = Some(gregs.gp_x[1] as _);
+    known_registers[X64Register::GPR(GPR::RBX).to_index().0] = Some(gregs.gp_x[3] as _);
+    known_registers[X64Register::GPR(GPR::RAX).to_index().0] = Some(gregs.gp_x[0] as _);
+
+    known_registers[X64Register::GPR(GPR::RBP).to_index().0] = Some(gregs.gp_x[5] as _);
+    known_registers[X64Register::GPR(GPR::RSP).to_index().0] = Some(gregs.gp_x[28] as _);
+
+    let mut gpr_cells: [Option<&'static Cell<u64>>; 16] = [None; 16];
+    gpr_cells[GPR::R15 as usize] = register_cell(&mut gregs.gp_x[15]);
+    gpr_cells[GPR::R14 as usize] = register_cell(&mut gregs.gp_x[14]);
+    gpr_cells[GPR::R13 as usize] = register_cell(&mut gregs.gp_x[13]);
+    gpr_cells[GPR::R12 as usize] = register_cell(&mut gregs.gp_x[12]);
+    gpr_cells[GPR::R11 as usize] = register_cell(&mut gregs.gp_x[11]);
+    gpr_cells[GPR::R10 as usize] = register_cell(&mut gregs.gp_x[10]);
+    gpr_cells[GPR::R9 as usize] = register_cell(&mut gregs.gp_x[9]);
+    gpr_cells[GPR::R8 as usize] = register_cell(&mut gregs.gp_x[8]);
+    gpr_cells[GPR::RSI as usize] = register_cell(&mut gregs.gp_x[6]);
+    gpr_cells[GPR::RDI as usize] = register_cell(&mut gregs.gp_x[7]);
+    gpr_cells[GPR::RDX as usize] = register_cell(&mut gregs.gp_x[2]);
+    gpr_cells[GPR::RCX as usize] = register_cell(&mut gregs.gp_x[1]);
+    gpr_cells[GPR::RBX as usize] = register_cell(&mut gregs.gp_x[3]);
+    gpr_cells[GPR::RAX as usize] = register_cell(&mut gregs.gp_x[0]);
+    gpr_cells[GPR::RBP as usize] = register_cell(&mut gregs.gp_x[5]);
+    gpr_cells[GPR::RSP as usize] = register_cell(&mut gregs.gp_x[28]);
+
+    FaultInfo {
+        faulting_addr: si_addr as usize as _,
+        ip: std::mem::transmute::<&mut u64, &'static Cell<usize>>(
+            &mut (*ucontext).uc_mcontext.mc_gpregs.gp_elr,
+        ),
+        known_registers,
+        stack_bounds: cached_stack_bounds(),
+        raw_siginfo: capture_raw_siginfo(siginfo as _),
+        signal: Some(siginfo_signo(siginfo as _)),
+        trap_no: None,
+        fs_base: None,
+        gs_base: None,
+        fpregs: std::ptr::null(),
+        ucontext: ucontext as *mut c_void,
+        gpr_cells,
+    }
+}
+
+#[cfg(all(target_os = "freebsd", target_arch = "x86_64"))]
+/// Get fault info from siginfo and ucontext.
+pub unsafe fn get_fault_info(siginfo: *const c_void, ucontext: *mut c_void) -> FaultInfo {
+    #[repr(C)]
+    pub struct ucontext_t {
+        uc_sigmask: libc::sigset_t,
+        uc_mcontext: mcontext_t,
+        uc_link: *mut ucontext_t,
+        uc_stack: libc::stack_t,
+        uc_flags: i32,
+        __spare__: [i32; 4],
+    }
+    #[repr(C)]
+    pub struct mcontext_t {
+        mc_onstack: u64,
+        mc_rdi: u64,
+        mc_rsi: u64,
+        mc_rdx: u64,
+        mc_rcx: u64,
+        mc_r8: u64,
+        mc_r9: u64,
+        mc_rax: u64,
+        mc_rbx: u64,
+        mc_rbp: u64,
+        mc_r10: u64,
+        mc_r11: u64,
+        mc_r12: u64,
+        mc_r13: u64,
+        mc_r14: u64,
+        mc_r15: u64,
+        mc_trapno: u32,
+        mc_fs: u16,
+        mc_gs: u16,
+        mc_addr: u64,
+        mc_flags: u32,
+        mc_es: u16,
+        mc_ds: u16,
+        mc_err: u64,
+        mc_rip: u64,
+        mc_cs: u64,
+        mc_rflags: u64,
+        mc_rsp: u64,
+        mc_ss: u64,
+        mc_len: i64,
+
+        mc_fpformat: i64,
+        mc_ownedfp: i64,
+        mc_savefpu: *const savefpu,
+        mc_fpstate: [i64; 63], // mc_fpstate[0] is a pointer to savefpu
+
+        mc_fsbase: u64,
+        mc_gsbase: u64,
+
+        mc_xfpustate: u64,
+        mc_xfpustate_len: u64,
+
+        mc_spare: [i64; 4],
+    }
+    #[repr(C)]
+    pub struct xmmacc {
+        element: [u32; 4],
+    }
+    #[repr(C)]
+    pub struct __envxmm64 {
+        en_cw: u16,
+        en_sw: u16,
+        en_tw: u8,
+        en_zero: u8,
+        en_opcode: u16,
+        en_rip: u64,
+        en_rdp: u64,
+        en_mxcsr: u32,
+        en_mxcsr_mask: u32,
+    }
+    #[repr(C)]
+    pub struct fpacc87 {
+        fp_bytes: [u8; 10],
+    }
+    #[repr(C)]
+    pub struct sv_fp {
+        fp_acc: fpacc87,
+        fp_pad: [u8; 6],
+    }
+    #[repr(C, align(16))]
+    pub struct savefpu {
+        sv_env: __envxmm64,
+        sv_fp_t: [sv_fp; 8],
+        sv_xmm: [xmmacc; 16],
+        sv_pad: [u8; 96],
+    }
+
+    let siginfo = siginfo as *const siginfo_t;
+    let si_addr = (*siginfo).si_addr;
+
+    let ucontext = ucontext as *mut ucontext_t;
+    let gregs = &mut (*ucontext).uc_mcontext;
+
+    fn read_xmm(reg: &xmmacc) -> u64 {
+        (reg.element[0] as u64) | ((reg.element[1] as u64) << 32)
+    }
+
+    let mut known_registers: [Option<u64>; 32] = [None; 32];
+    known_registers[X64Register::GPR(GPR::R15).to_index().0] = Some(gregs.mc_r15);
+    known_registers[X64Register::GPR(GPR::R14).to_index().0] = Some(gregs.mc_r14);
+    known_registers[X64Register::GPR(GPR::R13).to_index().0] = Some(gregs.mc_r13);
+    known_registers[X64Register::GPR(GPR::R12).to_index().0] = Some(gregs.mc_r12);
+    known_registers[X64Register::GPR(GPR::R11).to_index().0] = Some(gregs.mc_r11);
+    known_registers[X64Register::GPR(GPR::R10).to_index().0] = Some(gregs.mc_r10);
+    known_registers[X64Register::GPR(GPR::R9).to_index().0] = Some(gregs.mc_r9);
+    known_registers[X64Register::GPR(GPR::R8).to_index().0] = Some(gregs.mc_r8);
+    known_registers[X64Register::GPR(GPR::RSI).to_index().0] = Some(gregs.mc_rsi);
+    known_registers[X64Register::GPR(GPR::RDI).to_index().0] = Some(gregs.mc_rdi);
+    known_registers[X64Register::GPR(GPR::RDX).to_index().0] = Some(gregs.mc_rdx);
+    known_registers[X64Register::GPR(GPR::RCX).to_index().0] = Some(gregs.mc_rcx);
+    known_registers[X64Register::GPR(GPR::RBX).to_index().0] = Some(gregs.mc_rbx);
+    known_registers[X64Register::GPR(GPR::RAX).to_index().0] = Some(gregs.mc_rax);
+
+    known_registers[X64Register::GPR(GPR::RBP).to_index().0] = Some(gregs.mc_rbp);
+    known_registers[X64Register::GPR(GPR::RSP).to_index().0] = Some(gregs.mc_rsp);
+
+    let mut gpr_cells: [Option<&'static Cell<u64>>; 16] = [None; 16];
+    gpr_cells[GPR::R15 as usize] = register_cell(&mut gregs.mc_r15);
+    gpr_cells[GPR::R14 as usize] = register_cell(&mut gregs.mc_r14);
+    gpr_cells[GPR::R13 as usize] = register_cell(&mut gregs.mc_r13);
+    gpr_cells[GPR::R12 as usize] = register_cell(&mut gregs.mc_r12);
+    gpr_cells[GPR::R11 as usize] = register_cell(&mut gregs.mc_r11);
+    gpr_cells[GPR::R10 as usize] = register_cell(&mut gregs.mc_r10);
+    gpr_cells[GPR::R9 as usize] = register_cell(&mut gregs.mc_r9);
+    gpr_cells[GPR::R8 as usize] = register_cell(&mut gregs.mc_r8);
+    gpr_cells[GPR::RSI as usize] = register_cell(&mut gregs.mc_rsi);
+    gpr_cells[GPR::RDI as usize] = register_cell(&mut gregs.mc_rdi);
+    gpr_cells[GPR::RDX as usize] = register_cell(&mut gregs.mc_rdx);
+    gpr_cells[GPR::RCX as usize] = register_cell(&mut gregs.mc_rcx);
+    gpr_cells[GPR::RBX as usize] = register_cell(&mut gregs.mc_rbx);
+    gpr_cells[GPR::RAX as usize] = register_cell(&mut gregs.mc_rax);
+    gpr_cells[GPR::RBP as usize] = register_cell(&mut gregs.mc_rbp);
+    gpr_cells[GPR::RSP as usize] = register_cell(&mut gregs.mc_rsp);
+
+    // https://lists.freebsd.org/pipermail/freebsd-arch/2011-December/012077.html
+    // https://people.freebsd.org/~kib/misc/defer_sig.c
+    const _MC_HASBASES: u32 = 0x2;
+    const _MC_HASFPXSTATE: u32 = 0x4;
+    let has_bases = (gregs.mc_flags & _MC_HASBASES) != 0;
+    if (gregs.mc_flags & _MC_HASFPXSTATE) == 0 {
+        // XXX mc_fpstate[0] is actually a pointer to a struct savefpu
+        let fpregs = &*(*ucontext).uc_mcontext.mc_savefpu;
+        known_registers[X64Register::XMM(XMM::XMM0).to_index().0] =
+            Some(read_xmm(&fpregs.sv_xmm[0]));
+        known_registers[X64Register::XMM(XMM::XMM1).to_index().0] =
+            Some(read_xmm(&fpregs.sv_xmm[1]));
+        known_registers[X64Register::XMM(XMM::XMM2).to_index().0] =
+            Some(read_xmm(&fpregs.sv_xmm[2]));
+        known_registers[X64Register::XMM(XMM::XMM3).to_index().0] =
+            Some(read_xmm(&fpregs.sv_xmm[3]));
+        known_registers[X64Register::XMM(XMM::XMM4).to_index().0] =
+            Some(read_xmm(&fpregs.sv_xmm[4]));
+        known_registers[X64Register::XMM(XMM::XMM5).to_index().0] =
+            Some(read_xmm(&fpregs.sv_xmm[5]));
+        known_registers[X64Register::XMM(XMM::XMM6).to_index().0] =
+            Some(read_xmm(&fpregs.sv_xmm[6]));
+        known_registers[X64Register::XMM(XMM::XMM7).to_index().0] =
+            Some(read_xmm(&fpregs.sv_xmm[7]));
+        known_registers[X64Register::XMM(XMM::XMM8).to_index().0] =
+            Some(read_xmm(&fpregs.sv_xmm[8]));
+        known_registers[X64Register::XMM(XMM::XMM9).to_index().0] =
+            Some(read_xmm(&fpregs.sv_xmm[9]));
+        known_registers[X64Register::XMM(XMM::XMM10).to_index().0] =
+            Some(read_xmm(&fpregs.sv_xmm[10]));
+        known_registers[X64Register::XMM(XMM::XMM11).to_index().0] =
+            Some(read_xmm(&fpregs.sv_xmm[11]));
+        known_registers[X64Register::XMM(XMM::XMM12).to_index().0] =
+            Some(read_xmm(&fpregs.sv_xmm[12]));
+        known_registers[X64Register::XMM(XMM::XMM13).to_index().0] =
+            Some(read_xmm(&fpregs.sv_xmm[13]));
+        known_registers[X64Register::XMM(XMM::XMM14).to_index().0] =
+            Some(read_xmm(&fpregs.sv_xmm[14]));
+        known_registers[X64Register::XMM(XMM::XMM15).to_index().0] =
+            Some(read_xmm(&fpregs.sv_xmm[15]));
+    }
+
+    FaultInfo {
+        faulting_addr: si_addr,
+        ip: std::mem::transmute::<&mut u64, &'static Cell<usize>>(
+            &mut (*ucontext).uc_mcontext.mc_rip,
+        ),
+        known_registers,
+        stack_bounds: cached_stack_bounds(),
+        raw_siginfo: capture_raw_siginfo(siginfo as _),
+        signal: Some(siginfo_signo(siginfo as _)),
+        trap_no: Some((*ucontext).uc_mcontext.mc_trapno),
+        fs_base: if has_bases {
+            Some((*ucontext).uc_mcontext.mc_fsbase)
+        } else {
+            None
+        },
+        gs_base: if has_bases {
+            Some((*ucontext).uc_mcontext.mc_gsbase)
+        } else {
+            None
+        },
+        fpregs: std::ptr::null(),
+        ucontext: ucontext as *mut c_void,
+        gpr_cells,
+    }
+}
+
+#[cfg(all(target_os = "openbsd", target_arch = "x86_64"))]
+/// Get fault info from siginfo and ucontext.
+pub unsafe fn get_fault_info(siginfo: *const c_void, ucontext: *mut c_void) -> FaultInfo {
+    #[allow(dead_code)]
+    #[allow(non_camel_case_types)]
+    #[repr(C)]
+    struct siginfo_t {
+        si_signo: i32,
+        si_code: i32,
+        si_errno: i32,
+        si_addr: *const c_void,
+        // ...
+    }
+    // On OpenBSD, `ucontext_t` is `struct sigcontext`.
+    #[allow(dead_code)]
+    #[repr(C)]
+    struct sigcontext {
+        sc_rdi: i64,
+        sc_rsi: i64,
+        sc_rdx: i64,
+        sc_rcx: i64,
+        sc_r8: i64,
+        sc_r9: i64,
+        sc_r10: i64,
+        sc_r11: i64,
+        sc_r12: i64,
+        sc_r13: i64,
+        sc_r14: i64,
+        sc_r15: i64,
+        sc_rbp: i64,
+        sc_rbx: i64,
+        sc_rax: i64,
+        sc_gs: i64,
+        sc_fs: i64,
+        sc_es: i64,
+        sc_ds: i64,
+        sc_trapno: i64,
+        sc_err: i64,
+        sc_rip: i64,
+        sc_cs: i64,
+        sc_rflags: i64,
+        sc_rsp: i64,
+        sc_ss: i64,
+        sc_fpstate: *const fxsave64,
+        __sc_unused: i32,
+        sc_mask: i32,
+        sc_cookie: i64,
+    }
+    #[allow(dead_code)]
+    #[repr(C)]
+    struct fxsave64 {
+        fx_fcw: u16,
+        fx_fsw: u16,
+        fx_ftw: u8,
+        fx_unused1: u8,
+        fx_fop: u16,
+        fx_rip: u64,
+        fx_rdp: u64,
+        fx_mxcsr: u32,
+        fx_mxcsr_mask: u32,
+        fx_st: [[u64; 2]; 8],
+        fx_xmm: [[u64; 2]; 16],
+        fx_unused3: [u8; 96],
+    }
+    // Sizes from `<machine/signal.h>` and `<machine/fpu.h>`. Every field is fixed-size, so
+    // these also pin the offsets of `sc_rip`, `sc_fpstate` and `fx_xmm`.
+    const _: [(); 232] = [(); std::mem::size_of::<sigcontext>()];
+    const _: [(); 512] = [(); std::mem::size_of::<fxsave64>()];
+
+    let siginfo = siginfo as *const siginfo_t;
+    let si_addr = (*siginfo).si_addr;
+
+    let ucontext = ucontext as *mut sigcontext;
+    let ctx = &mut *ucontext;
+
+    let mut known_registers: [Option<u64>; 32] = [None; 32];
+    known_registers[X64Register::GPR(GPR::R15).to_index().0] = Some(ctx.sc_r15 as _);
+    known_registers[X64Register::GPR(GPR::R14).to_index().0] = Some(ctx.sc_r14 as _);
+    known_registers[X64Register::GPR(GPR::R13).to_index().0] = Some(ctx.sc_r13 as _);
+    known_registers[X64Register::GPR(GPR::R12).to_index().0] = Some(ctx.sc_r12 as _);
+    known_registers[X64Register::GPR(GPR::R11).to_index().0] = Some(ctx.sc_r11 as _);
+    known_registers[X64Register::GPR(GPR::R10).to_index().0] = Some(ctx.sc_r10 as _);
+    known_registers[X64Register::GPR(GPR::R9).to_index().0] = Some(ctx.sc_r9 as _);
+    known_registers[X64Register::GPR(GPR::R8).to_index().0] = Some(ctx.sc_r8 as _);
+    known_registers[X64Register::GPR(GPR::RSI).to_index().0] = Some(ctx.sc_rsi as _);
+    known_registers[X64Register::GPR(GPR::RDI).to_index().0] = Some(ctx.sc_rdi as _);
+    known_registers[X64Register::GPR(GPR::RDX).to_index().0] = Some(ctx.sc_rdx as _);
+    known_registers[X64Register::GPR(GPR::RCX).to_index().0] = Some(ctx.sc_rcx as _);
+    known_registers[X64Register::GPR(GPR::RBX).to_index().0] = Some(ctx.sc_rbx as _);
+    known_registers[X64Register::GPR(GPR::RAX).to_index().0] = Some(ctx.sc_rax as _);
+
+    known_registers[X64Register::GPR(GPR::RBP).to_index().0] = Some(ctx.sc_rbp as _);
+    known_registers[X64Register::GPR(GPR::RSP).to_index().0] = Some(ctx.sc_rsp as _);
+
+    let mut gpr_cells: [Option<&'static Cell<u64>>; 16] = [None; 16];
+    gpr_cells[GPR::R15 as usize] = register_cell(&mut ctx.sc_r15);
+    gpr_cells[GPR::R14 as usize] = register_cell(&mut ctx.sc_r14);
+    gpr_cells[GPR::R13 as usize] = register_cell(&mut ctx.sc_r13);
+    gpr_cells[GPR::R12 as usize] = register_cell(&mut ctx.sc_r12);
+    gpr_cells[GPR::R11 as usize] = register_cell(&mut ctx.sc_r11);
+    gpr_cells[GPR::R10 as usize] = register_cell(&mut ctx.sc_r10);
+    gpr_cells[GPR::R9 as usize] = register_cell(&mut ctx.sc_r9);
+    gpr_cells[GPR::R8 as usize] = register_cell(&mut ctx.sc_r8);
+    gpr_cells[GPR::RSI as usize] = register_cell(&mut ctx.sc_rsi);
+    gpr_cells[GPR::RDI as usize] = register_cell(&mut ctx.sc_rdi);
+    gpr_cells[GPR::RDX as usize] = register_cell(&mut ctx.sc_rdx);
+    gpr_cells[GPR::RCX as usize] = register_cell(&mut ctx.sc_rcx);
+    gpr_cells[GPR::RBX as usize] = register_cell(&mut ctx.sc_rbx);
+    gpr_cells[GPR::RAX as usize] = register_cell(&mut ctx.sc_rax);
+    gpr_cells[GPR::RBP as usize] = register_cell(&mut ctx.sc_rbp);
+    gpr_cells[GPR::RSP as usize] = register_cell(&mut ctx.sc_rsp);
+
+    if !ctx.sc_fpstate.is_null() {
+        let fpregs = &*ctx.sc_fpstate;
+        known_registers[X64Register::XMM(XMM::XMM0).to_index().0] = Some(fpregs.fx_xmm[0][0]);
+        known_registers[X64Register::XMM(XMM::XMM1).to_index().0] = Some(fpregs.fx_xmm[1][0]);
+        known_registers[X64Register::XMM(XMM::XMM2).to_index().0] = Some(fpregs.fx_xmm[2][0]);
+        known_registers[X64Register::XMM(XMM::XMM3).to_index().0] = Some(fpregs.fx_xmm[3][0]);
+        known_registers[X64Register::XMM(XMM::XMM4).to_index().0] = Some(fpregs.fx_xmm[4][0]);
+        known_registers[X64Register::XMM(XMM::XMM5).to_index().0] = Some(fpregs.fx_xmm[5][0]);
+        known_registers[X64Register::XMM(XMM::XMM6).to_index().0] = Some(fpregs.fx_xmm[6][0]);
+        known_registers[X64Register::XMM(XMM::XMM7).to_index().0] = Some(fpregs.fx_xmm[7][0]);
+        known_registers[X64Register::XMM(XMM::XMM8).to_index().0] = Some(fpregs.fx_xmm[8][0]);
+        known_registers[X64Register::XMM(XMM::XMM9).to_index().0] = Some(fpregs.fx_xmm[9][0]);
+        known_registers[X64Register::XMM(XMM::XMM10).to_index().0] = Some(fpregs.fx_xmm[10][0]);
+        known_registers[X64Register::XMM(XMM::XMM11).to_index().0] = Some(fpregs.fx_xmm[11][0]);
+        known_registers[X64Register::XMM(XMM::XMM12).to_index().0] = Some(fpregs.fx_xmm[12][0]);
+        known_registers[X64Register::XMM(XMM::XMM13).to_index().0] = Some(fpregs.fx_xmm[13][0]);
+        known_registers[X64Register::XMM(XMM::XMM14).to_index().0] = Some(fpregs.fx_xmm[14][0]);
+        known_registers[X64Register::XMM(XMM::XMM15).to_index().0] = Some(fpregs.fx_xmm[15][0]);
+    }
+
+    FaultInfo {
+        faulting_addr: si_addr,
+        ip: std::mem::transmute::<&mut i64, &'static Cell<usize>>(&mut ctx.sc_rip),
+        known_registers,
+        stack_bounds: cached_stack_bounds(),
+        raw_siginfo: capture_raw_siginfo(siginfo as _),
+        signal: Some(siginfo_signo(siginfo as _)),
+        trap_no: Some(ctx.sc_trapno as u32),
+        fs_base: None,
+        gs_base: None,
+        fpregs: std::ptr::null(),
+        ucontext: ucontext as *mut c_void,
+        gpr_cells,
+    }
+}
+
+#[cfg(all(target_os = "netbsd", target_arch = "x86_64"))]
+/// Get fault info from siginfo and ucontext.
+pub unsafe fn get_fault_info(siginfo: *const c_void, ucontext: *mut c_void) -> FaultInfo {
+    #[allow(dead_code)]
+    #[allow(non_camel_case_types)]
+    #[repr(C)]
+    struct siginfo_t {
+        si_signo: i32,
+        si_code: i32,
+        si_errno: i32,
+        _pad: i32,
+        si_addr: *const c_void,
+        // ...
+    }
+    #[allow(dead_code)]
+    #[allow(non_camel_case_types)]
+    #[repr(C)]
+    struct ucontext_t {
+        uc_flags: u32,
+        uc_link: *mut ucontext_t,
+        uc_sigmask: [u32; 4],
+        uc_stack: libc::stack_t,
+        uc_mcontext: mcontext_t,
+    }
+    #[allow(dead_code)]
+    #[allow(non_camel_case_types)]
+    #[repr(C)]
+    struct mcontext_t {
+        __gregs: [u64; 26],
+        _mc_tlsbase: u64,
+        // In the `fxsave` format.
+        __fpregs: [u64; 64],
+    }
+    // Sizes from `<sys/ucontext.h>` and `<amd64/mcontext.h>`. `uc_mcontext` is the last
+    // field, so the difference is its offset.
+    const _: [(); 728] = [(); std::mem::size_of::<mcontext_t>()];
+    const _: [(); 56] = [(); std::mem::size_of::<ucontext_t>() - std::mem::size_of::<mcontext_t>()];
+
+    const _UC_FPU: u32 = 0x04;
+    const _REG_RDI: usize = 0;
+    const _REG_RSI: usize = 1;
+    const _REG_RDX: usize = 2;
+    const _REG_RCX: usize = 3;
+    const _REG_R8: usize = 4;
+    const _REG_R9: usize = 5;
+    const _REG_R10: usize = 6;
+    const _REG_R11: usize = 7;
+    const _REG_R12: usize = 8;
+    const _REG_R13: usize = 9;
+    const _REG_R14: usize = 10;
+    const _REG_R15: usize = 11;
+    const _REG_RBP: usize = 12;
+    const _REG_RBX: usize = 13;
+    const _REG_RAX: usize = 14;
+    const _REG_TRAPNO: usize = 19;
+    const _REG_RIP: usize = 21;
+    const _REG_RSP: usize = 24;
+    /// Index of XMM0 in `__fpregs`, at byte offset 160.
+    const FXSAVE_XMM0: usize = 20;
+
+    let siginfo = siginfo as *const siginfo_t;
+    let si_addr = (*siginfo).si_addr;
+
+    let ucontext = ucontext as *mut ucontext_t;
+    let gregs = &mut (*ucontext).uc_mcontext.__gregs;
+
+    let mut known_registers: [Option<u64>; 32] = [None; 32];
+    known_registers[X64Register::GPR(GPR::R15).to_index().0] = Some(gregs[_REG_R15] as _);
+    known_registers[X64Register::GPR(GPR::R14).to_index().0] = Some(gregs[_REG_R14] as _);
+    known_registers[X64Register::GPR(GPR::R13).to_index().0] = Some(gregs[_REG_R13] as _);
+    known_registers[X64Register::GPR(GPR::R12).to_index().0] = Some(gregs[_REG_R12] as _);
+    known_registers[X64Register::GPR(GPR::R11).to_index().0] = Some(gregs[_REG_R11] as _);
+    known_registers[X64Register::GPR(GPR::R10).to_index().0] = Some(gregs[_REG_R10] as _);
+    known_registers[X64Register::GPR(GPR::R9).to_index().0] = Some(gregs[_REG_R9] as _);
+    known_registers[X64Register::GPR(GPR::R8).to_index().0] = Some(gregs[_REG_R8] as _);
+    known_registers[X64Register::GPR(GPR::RSI).to_index().0] = Some(gregs[_REG_RSI] as _);
+    known_registers[X64Register::GPR(GPR::RDI).to_index().0] = Some(gregs[_REG_RDI] as _);
+    known_registers[X64Register::GPR(GPR::RDX).to_index().0] = Some(gregs[_REG_RDX] as _);
+    known_registers[X64Register::GPR(GPR::RCX).to_index().0] = Some(gregs[_REG_RCX] as _);
+    known_registers[X64Register::GPR(GPR::RBX).to_index().0] = Some(gregs[_REG_RBX] as _);
+    known_registers[X64Register::GPR(GPR::RAX).to_index().0] = Some(gregs[_REG_RAX] as _);
+
+    known_registers[X64Register::GPR(GPR::RBP).to_index().0] = Some(gregs[_REG_RBP] as _);
+    known_registers[X64Register::GPR(GPR::RSP).to_index().0] = Some(gregs[_REG_RSP] as _);
+
+    let mut gpr_cells: [Option<&'static Cell<u64>>; 16] = [None; 16];
+    gpr_cells[GPR::R15 as usize] = register_cell(&mut gregs[_REG_R15]);
+    gpr_cells[GPR::R14 as usize] = register_cell(&mut gregs[_REG_R14]);
+    gpr_cells[GPR::R13 as usize] = register_cell(&mut gregs[_REG_R13]);
+    gpr_cells[GPR::R12 as usize] = register_cell(&mut gregs[_REG_R12]);
+    gpr_cells[GPR::R11 as usize] = register_cell(&mut gregs[_REG_R11]);
+    gpr_cells[GPR::R10 as usize] = register_cell(&mut gregs[_REG_R10]);
+    gpr_cells[GPR::R9 as usize] = register_cell(&mut gregs[_REG_R9]);
+    gpr_cells[GPR::R8 as usize] = register_cell(&mut gregs[_REG_R8]);
+    gpr_cells[GPR::RSI as usize] = register_cell(&mut gregs[_REG_RSI]);
+    gpr_cells[GPR::RDI as usize] = register_cell(&mut gregs[_REG_RDI]);
+    gpr_cells[GPR::RDX as usize] = register_cell(&mut gregs[_REG_RDX]);
+    gpr_cells[GPR::RCX as usize] = register_cell(&mut gregs[_REG_RCX]);
+    gpr_cells[GPR::RBX as usize] = register_cell(&mut gregs[_REG_RBX]);
+    gpr_cells[GPR::RAX as usize] = register_cell(&mut gregs[_REG_RAX]);
+    gpr_cells[GPR::RBP as usize] = register_cell(&mut gregs[_REG_RBP]);
+    gpr_cells[GPR::RSP as usize] = register_cell(&mut gregs[_REG_RSP]);
+
+    if (*ucontext).uc_flags & _UC_FPU != 0 {
+        let fpregs = &(*ucontext).uc_mcontext.__fpregs;
+        known_registers[X64Register::XMM(XMM::XMM0).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 0]);
+        known_registers[X64Register::XMM(XMM::XMM1).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 2]);
+        known_registers[X64Register::XMM(XMM::XMM2).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 4]);
+        known_registers[X64Register::XMM(XMM::XMM3).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 6]);
+        known_registers[X64Register::XMM(XMM::XMM4).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 8]);
+        known_registers[X64Register::XMM(XMM::XMM5).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 10]);
+        known_registers[X64Register::XMM(XMM::XMM6).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 12]);
+        known_registers[X64Register::XMM(XMM::XMM7).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 14]);
+        known_registers[X64Register::XMM(XMM::XMM8).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 16]);
+        known_registers[X64Register::XMM(XMM::XMM9).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 18]);
+        known_registers[X64Register::XMM(XMM::XMM10).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 20]);
+        known_registers[X64Register::XMM(XMM::XMM11).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 22]);
+        known_registers[X64Register::XMM(XMM::XMM12).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 24]);
+        known_registers[X64Register::XMM(XMM::XMM13).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 26]);
+        known_registers[X64Register::XMM(XMM::XMM14).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 28]);
+        known_registers[X64Register::XMM(XMM::XMM15).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 30]);
+    }
+
+    FaultInfo {
+        faulting_addr: si_addr,
+        ip: std::mem::transmute::<&mut u64, &'static Cell<usize>>(&mut gregs[_REG_RIP]),
+        known_registers,
+        stack_bounds: cached_stack_bounds(),
+        raw_siginfo: capture_raw_siginfo(siginfo as _),
+        signal: Some(siginfo_signo(siginfo as _)),
+        trap_no: Some(gregs[_REG_TRAPNO] as u32),
+        fs_base: Some((*ucontext).uc_mcontext._mc_tlsbase),
+        gs_base: None,
+        fpregs: std::ptr::null(),
+        ucontext: ucontext as *mut c_void,
+        gpr_cells,
+    }
+}
+
+#[cfg(all(
+    any(target_os = "illumos", target_os = "solaris"),
+    target_arch = "x86_64"
+))]
+/// Get fault info from siginfo and ucontext.
+pub unsafe fn get_fault_info(siginfo: *const c_void, ucontext: *mut c_void) -> FaultInfo {
+    #[allow(dead_code)]
+    #[allow(non_camel_case_types)]
+    #[repr(C)]
+    struct siginfo_t {
+        si_signo: i32,
+        si_code: i32,
+        si_errno: i32,
+        _pad: i32,
+        si_addr: *const c_void,
+        // ...
+    }
+    #[allow(dead_code)]
+    #[allow(non_camel_case_types)]
+    #[repr(C)]
+    struct ucontext_t {
+        uc_flags: u64,
+        uc_link: *mut ucontext_t,
+        uc_sigmask: [u32; 4],
+        uc_stack: libc::stack_t,
+        uc_mcontext: mcontext_t,
+        // `uc_brand_data[3]`, `uc_xsave` and `uc_filler[1]` on illumos; `uc_xrs`,
+        // `uc_lwpid` and `uc_filler[2]` on Solaris. Five longs either way.
+        uc_filler: [i64; 5],
+    }
+    #[allow(dead_code)]
+    #[allow(non_camel_case_types)]
+    #[repr(C)]
+    struct mcontext_t {
+        gregs: [u64; 28],
+        fpregs: fpregset_t,
+    }
+    /// The `fpchip_state` of a `fpregset_t`, in the `fxsave` format followed by the status
+    /// words.
+    #[allow(dead_code)]
+    #[allow(non_camel_case_types)]
+    #[repr(C, align(16))]
+    struct fpregset_t {
+        fxsave: [u64; 64],
+        status: u32,
+        xstatus: u32,
+    }
+    // Sizes from `<sys/regset.h>` and `<sys/ucontext.h>`: `fpregset_t` is rounded up to the
+    // 16-byte alignment of `upad128_t`, which also puts `uc_mcontext` at offset 64.
+    const _: [(); 528] = [(); std::mem::size_of::<fpregset_t>()];
+    const _: [(); 752] = [(); std::mem::size_of::<mcontext_t>()];
+    const _: [(); 864] = [(); std::mem::size_of::<ucontext_t>()];
+
+    // `UC_FPU` is an alias of `UC_MAU`.
+    const UC_FPU: u64 = 0x08;
+    const REG_R15: usize = 0;
+    const REG_R14: usize = 1;
+    const REG_R13: usize = 2;
+    const REG_R12: usize = 3;
+    const REG_R11: usize = 4;
+    const REG_R10: usize = 5;
+    const REG_R9: usize = 6;
+    const REG_R8: usize = 7;
+    const REG_RDI: usize = 8;
+    const REG_RSI: usize = 9;
+    const REG_RBP: usize = 10;
+    const REG_RBX: usize = 11;
+    const REG_RDX: usize = 12;
+    const REG_RCX: usize = 13;
+    const REG_RAX: usize = 14;
+    const REG_TRAPNO: usize = 15;
+    const REG_RIP: usize = 17;
+    const REG_RSP: usize = 20;
+    const REG_FSBASE: usize = 26;
+    const REG_GSBASE: usize = 27;
+    /// Index of XMM0 in `fxsave`, at byte offset 160.
+    const FXSAVE_XMM0: usize = 20;
+
+    let siginfo = siginfo as *const siginfo_t;
+    let si_addr = (*siginfo).si_addr;
+
+    let ucontext = ucontext as *mut ucontext_t;
+    let gregs = &mut (*ucontext).uc_mcontext.gregs;
+
+    let mut known_registers: [Option<u64>; 32] = [None; 32];
+    known_registers[X64Register::GPR(GPR::R15).to_index().0] = Some(gregs[REG_R15] as _);
+    known_registers[X64Register::GPR(GPR::R14).to_index().0] = Some(gregs[REG_R14] as _);
+    known_registers[X64Register::GPR(GPR::R13).to_index().0] = Some(gregs[REG_R13] as _);
+    known_registers[X64Register::GPR(GPR::R12).to_index().0] = Some(gregs[REG_R12] as _);
+    known_registers[X64Register::GPR(GPR::R11).to_index().0] = Some(gregs[REG_R11] as _);
+    known_registers[X64Register::GPR(GPR::R10).to_index().0] = Some(gregs[REG_R10] as _);
+    known_registers[X64Register::GPR(GPR::R9).to_index().0] = Some(gregs[REG_R9] as _);
+    known_registers[X64Register::GPR(GPR::R8).to_index().0] = Some(gregs[REG_R8] as _);
+    known_registers[X64Register::GPR(GPR::RSI).to_index().0] = Some(gregs[REG_RSI] as _);
+    known_registers[X64Register::GPR(GPR::RDI).to_index().0] = Some(gregs[REG_RDI] as _);
+    known_registers[X64Register::GPR(GPR::RDX).to_index().0] = Some(gregs[REG_RDX] as _);
+    known_registers[X64Register::GPR(GPR::RCX).to_index().0] = Some(gregs[REG_RCX] as _);
+    known_registers[X64Register::GPR(GPR::RBX).to_index().0] = Some(gregs[REG_RBX] as _);
+    known_registers[X64Register::GPR(GPR::RAX).to_index().0] = Some(gregs[REG_RAX] as _);
+
+    known_registers[X64Register::GPR(GPR::RBP).to_index().0] = Some(gregs[REG_RBP] as _);
+    known_registers[X64Register::GPR(GPR::RSP).to_index().0] = Some(gregs[REG_RSP] as _);
+
+    let mut gpr_cells: [Option<&'static Cell<u64>>; 16] = [None; 16];
+    gpr_cells[GPR::R15 as usize] = register_cell(&mut gregs[REG_R15]);
+    gpr_cells[GPR::R14 as usize] = register_cell(&mut gregs[REG_R14]);
+    gpr_cells[GPR::R13 as usize] = register_cell(&mut gregs[REG_R13]);
+    gpr_cells[GPR::R12 as usize] = register_cell(&mut gregs[REG_R12]);
+    gpr_cells[GPR::R11 as usize] = register_cell(&mut gregs[REG_R11]);
+    gpr_cells[GPR::R10 as usize] = register_cell(&mut gregs[REG_R10]);
+    gpr_cells[GPR::R9 as usize] = register_cell(&mut gregs[REG_R9]);
+    gpr_cells[GPR::R8 as usize] = register_cell(&mut gregs[REG_R8]);
+    gpr_cells[GPR::RSI as usize] = register_cell(&mut gregs[REG_RSI]);
+    gpr_cells[GPR::RDI as usize] = register_cell(&mut gregs[REG_RDI]);
+    gpr_cells[GPR::RDX as usize] = register_cell(&mut gregs[REG_RDX]);
+    gpr_cells[GPR::RCX as usize] = register_cell(&mut gregs[REG_RCX]);
+    gpr_cells[GPR::RBX as usize] = register_cell(&mut gregs[REG_RBX]);
+    gpr_cells[GPR::RAX as usize] = register_cell(&mut gregs[REG_RAX]);
+    gpr_cells[GPR::RBP as usize] = register_cell(&mut gregs[REG_RBP]);
+    gpr_cells[GPR::RSP as usize] = register_cell(&mut gregs[REG_RSP]);
+
+    if (*ucontext).uc_flags & UC_FPU != 0 {
+        let fpregs = &(*ucontext).uc_mcontext.fpregs.fxsave;
+        known_registers[X64Register::XMM(XMM::XMM0).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 0]);
+        known_registers[X64Register::XMM(XMM::XMM1).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 2]);
+        known_registers[X64Register::XMM(XMM::XMM2).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 4]);
+        known_registers[X64Register::XMM(XMM::XMM3).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 6]);
+        known_registers[X64Register::XMM(XMM::XMM4).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 8]);
+        known_registers[X64Register::XMM(XMM::XMM5).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 10]);
+        known_registers[X64Register::XMM(XMM::XMM6).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 12]);
+        known_registers[X64Register::XMM(XMM::XMM7).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 14]);
+        known_registers[X64Register::XMM(XMM::XMM8).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 16]);
+        known_registers[X64Register::XMM(XMM::XMM9).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 18]);
+        known_registers[X64Register::XMM(XMM::XMM10).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 20]);
+        known_registers[X64Register::XMM(XMM::XMM11).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 22]);
+        known_registers[X64Register::XMM(XMM::XMM12).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 24]);
+        known_registers[X64Register::XMM(XMM::XMM13).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 26]);
+        known_registers[X64Register::XMM(XMM::XMM14).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 28]);
+        known_registers[X64Register::XMM(XMM::XMM15).to_index().0] = Some(fpregs[FXSAVE_XMM0 + 30]);
+    }
+
+    FaultInfo {
+        faulting_addr: si_addr,
+        ip: std::mem::transmute::<&mut u64, &'static Cell<usize>>(&mut gregs[REG_RIP]),
+        known_registers,
+        stack_bounds: cached_stack_bounds(),
+        raw_siginfo: capture_raw_siginfo(siginfo as _),
+        signal: Some(siginfo_signo(siginfo as _)),
+        trap_no: Some(gregs[REG_TRAPNO] as u32),
+        fs_base: Some(gregs[REG_FSBASE]),
+        gs_base: Some(gregs[REG_GSBASE]),
+        fpregs: std::ptr::null(),
+        ucontext: ucontext as *mut c_void,
+        gpr_cells,
+    }
+}
+
+#[cfg(all(
+    any(target_os = "linux", target_os = "android"),
+    target_arch = "aarch64"
+))]
+/// Get fault info from siginfo and ucontext.
+pub unsafe fn get_fault_info(siginfo: *const c_void, ucontext: *mut c_void) -> FaultInfo {
+    #[allow(dead_code)]
+    #[allow(non_camel_case_types)]
+    #[repr(packed)]
+    struct sigcontext {
+        fault_address: u64,
+        regs: [u64; 31],
+        sp: u64,
+        pc: u64,
+        pstate: u64,
+        reserved: [u8; 4096],
+    }
+
+    #[allow(dead_code)]
+    #[allow(non_camel_case_types)]
+    #[repr(packed)]
+    struct ucontext {
+        unknown: [u8; 176],
+        uc_mcontext: sigcontext,
+    }
+
+    #[allow(dead_code)]
+    #[allow(non_camel_case_types)]
+    #[repr(C)]
+    struct siginfo_t {
+        si_signo: i32,
+        si_errno: i32,
+        si_code: i32,
+        si_addr: u64,
+        // ...
+    }
+
+    let siginfo = siginfo as *const siginfo_t;
+    let si_addr = (*siginfo).si_addr;
+
+    let ucontext = ucontext as *mut ucontext;
+    let gregs = &mut (*ucontext).uc_mcontext.regs;
+
+    let mut known_registers: [Option<u64>; 32] = [None; 32];
+
+    known_registers[X64Register::GPR(GPR::R15).to_index().0] = Some(gregs[15] as _);
+    known_registers[X64Register::GPR(GPR::R14).to_index().0] = Some(gregs[14] as _);
+    known_registers[X64Register::GPR(GPR::R13).to_index().0] = Some(gregs[13] as _);
+    known_registers[X64Register::GPR(GPR::R12).to_index().0] = Some(gregs[12] as _);
+    known_registers[X64Register::GPR(GPR::R11).to_index().0] = Some(gregs[11] as _);
+    known_registers[X64Register::GPR(GPR::R10).to_index().0] = Some(gregs[10] as _);
+    known_registers[X64Register::GPR(GPR::R9).to_index().0] = Some(gregs[9] as _);
+    known_registers[X64Register::GPR(GPR::R8).to_index().0] = Some(gregs[8] as _);
+    known_registers[X64Register::GPR(GPR::RSI).to_index().0] = Some(gregs[6] as _);
+    known_registers[X64Register::GPR(GPR::RDI).to_index().0] = Some(gregs[7] as _);
+    known_registers[X64Register::GPR(GPR::RDX).to_index().0] = Some(gregs[2] as _);
+    known_registers[X64Register::GPR(GPR::RCX).to_index().0] = Some(gregs[1] as _);
+    known_registers[X64Register::GPR(GPR::RBX).to_index().0] = Some(gregs[3] as _);
+    known_registers[X64Register::GPR(GPR::RAX).to_index().0] = Some(gregs[0] as _);
+
+    known_registers[X64Register::GPR(GPR::RBP).to_index().0] = Some(gregs[5] as _);
+    known_registers[X64Register::GPR(GPR::RSP).to_index().0] = Some(gregs[28] as _);
+
+    let mut gpr_cells: [Option<&'static Cell<u64>>; 16] = [None; 16];
+    gpr_cells[GPR::R15 as usize] = register_cell(&mut gregs[15]);
+    gpr_cells[GPR::R14 as usize] = register_cell(&mut gregs[14]);
+    gpr_cells[GPR::R13 as usize] = register_cell(&mut gregs[13]);
+    gpr_cells[GPR::R12 as usize] = register_cell(&mut gregs[12]);
+    gpr_cells[GPR::R11 as usize] = register_cell(&mut gregs[11]);
+    gpr_cells[GPR::R10 as usize] = register_cell(&mut gregs[10]);
+    gpr_cells[GPR::R9 as usize] = register_cell(&mut gregs[9]);
+    gpr_cells[GPR::R8 as usize] = register_cell(&mut gregs[8]);
+    gpr_cells[GPR::RSI as usize] = register_cell(&mut gregs[6]);
+    gpr_cells[GPR::RDI as usize] = register_cell(&mut gregs[7]);
+    gpr_cells[GPR::RDX as usize] = register_cell(&mut gregs[2]);
+    gpr_cells[GPR::RCX as usize] = register_cell(&mut gregs[1]);
+    gpr_cells[GPR::RBX as usize] = register_cell(&mut gregs[3]);
+    gpr_cells[GPR::RAX as usize] = register_cell(&mut gregs[0]);
+    gpr_cells[GPR::RBP as usize] = register_cell(&mut gregs[5]);
+    gpr_cells[GPR::RSP as usize] = register_cell(&mut gregs[28]);
+
+    FaultInfo {
+        faulting_addr: si_addr as usize as _,
+        ip: std::mem::transmute::<&mut u64, &'static Cell<usize>>(&mut (*ucontext).uc_mcontext.pc),
+        known_registers,
+        stack_bounds: cached_stack_bounds(),
+        raw_siginfo: capture_raw_siginfo(siginfo as _),
+        signal: Some(siginfo_signo(siginfo as _)),
+        trap_no: None,
+        fs_base: None,
+        gs_base: None,
+        fpregs: std::ptr::null(),
+        ucontext: ucontext as *mut c_void,
+        gpr_cells,
+    }
+}
+
+#[cfg(all(target_os = "linux", target_arch = "arm"))]
+/// Get fault info from siginfo and ucontext.
+///
+/// Registers are 32 bits wide, so none of them can be written back with `FaultInfo::set_gpr`.
+pub unsafe fn get_fault_info(siginfo: *const c_void, ucontext: *mut c_void) -> FaultInfo {
+    #[allow(dead_code)]
+    #[allow(non_camel_case_types)]
+    #[repr(C)]
+    struct sigcontext {
+        trap_no: u32,
+        error_code: u32,
+        oldmask: u32,
+        arm_r0: u32,
+        arm_r1: u32,
+        arm_r2: u32,
+        arm_r3: u32,
+        arm_r4: u32,
+        arm_r5: u32,
+        arm_r6: u32,
+        arm_r7: u32,
+        arm_r8: u32,
+        arm_r9: u32,
+        arm_r10: u32,
+        arm_fp: u32,
+        arm_ip: u32,
+        arm_sp: u32,
+        arm_lr: u32,
+        arm_pc: u32,
+        arm_cpsr: u32,
+        fault_address: u32,
+    }
+
+    #[allow(dead_code)]
+    #[allow(non_camel_case_types)]
+    #[repr(C)]
+    struct ucontext_t {
+        uc_flags: u32,
+        uc_link: *mut ucontext_t,
+        uc_stack: libc::stack_t,
+        uc_mcontext: sigcontext,
+        // ...
+    }
+
+    #[allow(dead_code)]
+    #[allow(non_camel_case_types)]
+    #[repr(C)]
+    struct siginfo_t {
+        si_signo: i32,
+        si_errno: i32,
+        si_code: i32,
+        si_addr: u32,
+        // ...
+    }
+
+    let siginfo = siginfo as *const siginfo_t;
+    let si_addr = (*siginfo).si_addr;
+
+    let ucontext = ucontext as *mut ucontext_t;
+    let regs = &mut (*ucontext).uc_mcontext;
+
+    // Only the registers with a counterpart in the x64 numbering are reported.
+    let mut known_registers: [Option<u64>; 32] = [None; 32];
+    known_registers[X64Register::GPR(GPR::R10).to_index().0] = Some(regs.arm_r10 as _);
+    known_registers[X64Register::GPR(GPR::R9).to_index().0] = Some(regs.arm_r9 as _);
+    known_registers[X64Register::GPR(GPR::R8).to_index().0] = Some(regs.arm_r8 as _);
+    known_registers[X64Register::GPR(GPR::RSI).to_index().0] = Some(regs.arm_r6 as _);
+    known_registers[X64Register::GPR(GPR::RDI).to_index().0] = Some(regs.arm_r7 as _);
+    known_registers[X64Register::GPR(GPR::RDX).to_index().0] = Some(regs.arm_r2 as _);
+    known_registers[X64Register::GPR(GPR::RCX).to_index().0] = Some(regs.arm_r1 as _);
+    known_registers[X64Register::GPR(GPR::RBX).to_index().0] = Some(regs.arm_r3 as _);
+    known_registers[X64Register::GPR(GPR::RAX).to_index().0] = Some(regs.arm_r0 as _);
+
+    known_registers[X64Register::GPR(GPR::RBP).to_index().0] = Some(regs.arm_fp as _);
+    known_registers[X64Register::GPR(GPR::RSP).to_index().0] = Some(regs.arm_sp as _);
+
+    FaultInfo {
+        faulting_addr: si_addr as usize as _,
+        ip: std::mem::transmute::<&mut u32, &'static Cell<usize>>(&mut regs.arm_pc),
+        known_registers,
+        stack_bounds: cached_stack_bounds(),
+        raw_siginfo: capture_raw_siginfo(siginfo as _),
+        signal: Some(siginfo_signo(siginfo as _)),
+        trap_no: Some(regs.trap_no),
+        fs_base: None,
+        gs_base: None,
+        fpregs: std::ptr::null(),
+        ucontext: ucontext as *mut c_void,
+        gpr_cells: [None; 16],
+    }
+}
+
+#[cfg(all(target_os = "linux", target_arch = "riscv64"))]
+/// Get fault info from siginfo and ucontext.
+pub unsafe fn get_fault_info(siginfo: *const c_void, ucontext: *mut c_void) -> FaultInfo {
+    #[allow(dead_code)]
+    #[allow(non_camel_case_types)]
+    #[repr(C)]
+    struct mcontext_t {
+        // pc, then x1..x31
+        __gregs: [u64; 32],
+        __fpregs: [u8; 528],
+    }
+
+    #[allow(dead_code)]
+    #[allow(non_camel_case_types)]
+    #[repr(C)]
+    struct ucontext {
+        unknown: [u8; 176],
+        uc_mcontext: mcontext_t,
+    }
+
+    #[allow(dead_code)]
+    #[allow(non_camel_case_types)]
+    #[repr(C)]
+    struct siginfo_t {
+        si_signo: i32,
+        si_errno: i32,
+        si_code: i32,
+        si_addr: u64,
+        // ...
+    }
+
+    let siginfo = siginfo as *const siginfo_t;
+    let si_addr = (*siginfo).si_addr;
+
+    let ucontext = ucontext as *mut ucontext;
+    let gregs = &mut (*ucontext).uc_mcontext.__gregs;
+
+    let mut known_registers: [Option<u64>; 32] = [None; 32];
+
+    // Registers are mapped by their role in the calling convention: the callee-saved x86
+    // registers to s1..s5, the stack and frame pointers to sp and s0, and the rest to the
+    // argument and temporary registers.
+    known_registers[X64Register::GPR(GPR::R15).to_index().0] = Some(gregs[21] as _);
+    known_registers[X64Register::GPR(GPR::R14).to_index().0] = Some(gregs[20] as _);
+    known_registers[X64Register::GPR(GPR::R13).to_index().0] = Some(gregs[19] as _);
+    known_registers[X64Register::GPR(GPR::R12).to_index().0] = Some(gregs[18] as _);
+    known_registers[X64Register::GPR(GPR::R11).to_index().0] = Some(gregs[5] as _);
+    known_registers[X64Register::GPR(GPR::R10).to_index().0] = Some(gregs[17] as _);
+    known_registers[X64Register::GPR(GPR::R9).to_index().0] = Some(gregs[16] as _);
+    known_registers[X64Register::GPR(GPR::R8).to_index().0] = Some(gregs[15] as _);
+    known_registers[X64Register::GPR(GPR::RSI).to_index().0] = Some(gregs[13] as _);
+    known_registers[X64Register::GPR(GPR::RDI).to_index().0] = Some(gregs[14] as _);
+    known_registers[X64Register::GPR(GPR::RDX).to_index().0] = Some(gregs[12] as _);
+    known_registers[X64Register::GPR(GPR::RCX).to_index().0] = Some(gregs[11] as _);
+    known_registers[X64Register::GPR(GPR::RBX).to_index().0] = Some(gregs[9] as _);
+    known_registers[X64Register::GPR(GPR::RAX).to_index().0] = Some(gregs[10] as _);
+
+    known_registers[X64Register::GPR(GPR::RBP).to_index().0] = Some(gregs[8] as _);
+    known_registers[X64Register::GPR(GPR::RSP).to_index().0] = Some(gregs[2] as _);
+
+    let mut gpr_cells: [Option<&'static Cell<u64>>; 16] = [None; 16];
+    gpr_cells[GPR::R15 as usize] = register_cell(&mut gregs[21]);
+    gpr_cells[GPR::R14 as usize] = register_cell(&mut gregs[20]);
+    gpr_cells[GPR::R13 as usize] = register_cell(&mut gregs[19]);
+    gpr_cells[GPR::R12 as usize] = register_cell(&mut gregs[18]);
+    gpr_cells[GPR::R11 as usize] = register_cell(&mut gregs[5]);
+    gpr_cells[GPR::R10 as usize] = register_cell(&mut gregs[17]);
+    gpr_cells[GPR::R9 as usize] = register_cell(&mut gregs[16]);
+    gpr_cells[GPR::R8 as usize] = register_cell(&mut gregs[15]);
+    gpr_cells[GPR::RSI as usize] = register_cell(&mut gregs[13]);
+    gpr_cells[GPR::RDI as usize] = register_cell(&mut gregs[14]);
+    gpr_cells[GPR::RDX as usize] = register_cell(&mut gregs[12]);
+    gpr_cells[GPR::RCX as usize] = register_cell(&mut gregs[11]);
+    gpr_cells[GPR::RBX as usize] = register_cell(&mut gregs[9]);
+    gpr_cells[GPR::RAX as usize] = register_cell(&mut gregs[10]);
+    gpr_cells[GPR::RBP as usize] = register_cell(&mut gregs[8]);
+    gpr_cells[GPR::RSP as usize] = register_cell(&mut gregs[2]);
+
+    FaultInfo {
+        faulting_addr: si_addr as usize as _,
+        ip: std::mem::transmute::<&mut u64, &'static Cell<usize>>(&mut gregs[0]),
+        known_registers,
+        stack_bounds: cached_stack_bounds(),
+        raw_siginfo: capture_raw_siginfo(siginfo as _),
+        signal: Some(siginfo_signo(siginfo as _)),
+        trap_no: None,
+        fs_base: None,
+        gs_base: None,
+        fpregs: std::ptr::null(),
+        ucontext: ucontext as *mut c_void,
+        gpr_cells,
+    }
+}
+
+#[cfg(all(
+    any(target_os = "linux", target_os = "android"),
+    target_arch = "x86_64"
+))]
+/// Get fault info from siginfo and ucontext.
+pub unsafe fn get_fault_info(siginfo: *const c_void, ucontext: *mut c_void) -> FaultInfo {
+    use libc::{
+        ucontext_t, REG_R10, REG_R11, REG_R12, REG_R13, REG_R14, REG_R15, REG_R8, REG_R9, REG_RAX,
+        REG_RBP, REG_RBX, REG_RCX, REG_RDI, REG_RDX, REG_RIP, REG_RSI, REG_RSP, REG_TRAPNO,
+    };
+
+    #[allow(dead_code)]
+    #[repr(C)]
+    struct siginfo_t {
+        si_signo: i32,
+        si_errno: i32,
+        si_code: i32,
+        si_addr: u64,
+        // ...
+    }
+
+    let siginfo = siginfo as *const siginfo_t;
+    let si_addr = (*siginfo).si_addr;
+
+    let ucontext = ucontext as *mut ucontext_t;
+    let gregs = &mut (*ucontext).uc_mcontext.gregs;
+
+    let mut known_registers: [Option<u64>; 32] = [None; 32];
+    known_registers[X64Register::GPR(GPR::R15).to_index().0] = Some(gregs[REG_R15 as usize] as _);
+    known_registers[X64Register::GPR(GPR::R14).to_index().0] = Some(gregs[REG_R14 as usize] as _);
+    known_registers[X64Register::GPR(GPR::R13).to_index().0] = Some(gregs[REG_R13 as usize] as _);
+    known_registers[X64Register::GPR(GPR::R12).to_index().0] = Some(gregs[REG_R12 as usize] as _);
+    known_registers[X64Register::GPR(GPR::R11).to_index().0] = Some(gregs[REG_R11 as usize] as _);
+    known_registers[X64Register::GPR(GPR::R10).to_index().0] = Some(gregs[REG_R10 as usize] as _);
+    known_registers[X64Register::GPR(GPR::R9).to_index().0] = Some(gregs[REG_R9 as usize] as _);
+    known_registers[X64Register::GPR(GPR::R8).to_index().0] = Some(gregs[REG_R8 as usize] as _);
+    known_registers[X64Register::GPR(GPR::RSI).to_index().0] = Some(gregs[REG_RSI as usize] as _);
+    known_registers[X64Register::GPR(GPR::RDI).to_index().0] = Some(gregs[REG_RDI as usize] as _);
+    known_registers[X64Register::GPR(GPR::RDX).to_index().0] = Some(gregs[REG_RDX as usize] as _);
+    known_registers[X64Register::GPR(GPR::RCX).to_index().0] = Some(gregs[REG_RCX as usize] as _);
+    known_registers[X64Register::GPR(GPR::RBX).to_index().0] = Some(gregs[REG_RBX as usize] as _);
+    known_registers[X64Register::GPR(GPR::RAX).to_index().0] = Some(gregs[REG_RAX as usize] as _);
+
+    known_registers[X64Register::GPR(GPR::RBP).to_index().0] = Some(gregs[REG_RBP as usize] as _);
+    known_registers[X64Register::GPR(GPR::RSP).to_index().0] = Some(gregs[REG_RSP as usize] as _);
+
+    let mut gpr_cells: [Option<&'static Cell<u64>>; 16] = [None; 16];
+    gpr_cells[GPR::R15 as usize] = register_cell(&mut gregs[REG_R15 as usize]);
+    gpr_cells[GPR::R14 as usize] = register_cell(&mut gregs[REG_R14 as usize]);
+    gpr_cells[GPR::R13 as usize] = register_cell(&mut gregs[REG_R13 as usize]);
+    gpr_cells[GPR::R12 as usize] = register_cell(&mut gregs[REG_R12 as usize]);
+    gpr_cells[GPR::R11 as usize] = register_cell(&mut gregs[REG_R11 as usize]);
+    gpr_cells[GPR::R10 as usize] = register_cell(&mut gregs[REG_R10 as usize]);
+    gpr_cells[GPR::R9 as usize] = register_cell(&mut gregs[REG_R9 as usize]);
+    gpr_cells[GPR::R8 as usize] = register_cell(&mut gregs[REG_R8 as usize]);
+    gpr_cells[GPR::RSI as usize] = register_cell(&mut gregs[REG_RSI as usize]);
+    gpr_cells[GPR::RDI as usize] = register_cell(&mut gregs[REG_RDI as usize]);
+    gpr_cells[GPR::RDX as usize] = register_cell(&mut gregs[REG_RDX as usize]);
+    gpr_cells[GPR::RCX as usize] = register_cell(&mut gregs[REG_RCX as usize]);
+    gpr_cells[GPR::RBX as usize] = register_cell(&mut gregs[REG_RBX as usize]);
+    gpr_cells[GPR::RAX as usize] = register_cell(&mut gregs[REG_RAX as usize]);
+    gpr_cells[GPR::RBP as usize] = register_cell(&mut gregs[REG_RBP as usize]);
+    gpr_cells[GPR::RSP as usize] = register_cell(&mut gregs[REG_RSP as usize]);
+
+    #[cfg(not(target_env = "musl"))]
+    let fpregs = (*ucontext).uc_mcontext.fpregs as *const c_void;
+    // Not every version of the libc crate exposes `fpregs` for musl, so read it through a
+    // mirror of the `mcontext_t` of musl.
+    #[cfg(target_env = "musl")]
+    let fpregs = {
+        #[allow(dead_code)]
+        #[allow(non_camel_case_types)]
+        #[repr(C)]
+        struct mcontext_t {
+            gregs: [i64; 23],
+            fpregs: *const c_void,
+            __reserved1: [u64; 8],
+        }
+        (*(&(*ucontext).uc_mcontext as *const _ as *const mcontext_t)).fpregs
+    };
+
+    let mut fault = FaultInfo {
+        faulting_addr: si_addr as usize as _,
+        ip: std::mem::transmute::<&mut i64, &'static Cell<usize>>(&mut gregs[REG_RIP as usize]),
+        known_registers,
+        stack_bounds: cached_stack_bounds(),
+        raw_siginfo: capture_raw_siginfo(siginfo as _),
+        signal: Some(siginfo_signo(siginfo as _)),
+        trap_no: Some(gregs[REG_TRAPNO as usize] as u32),
+        fs_base: None,
+        gs_base: None,
+        fpregs,
+        ucontext: ucontext as *mut c_void,
+        gpr_cells,
+    };
+    if CAPTURE_FP_REGISTERS.load(Ordering::SeqCst) {
+        fault.load_fp_registers();
+    }
+    fault
+}
+
+/// The floating point state pointed to by the `fpregs` of a Linux x86_64 `mcontext_t`, laid
+/// out the same by glibc and musl.
+#[cfg(all(
+    any(target_os = "linux", target_os = "android"),
+    target_arch = "x86_64"
+))]
+#[allow(dead_code)]
+#[repr(C)]
+struct LinuxFpState {
+    cwd: u16,
+    swd: u16,
+    ftw: u16,
+    fop: u16,
+    rip: u64,
+    rdp: u64,
+    mxcsr: u32,
+    mxcr_mask: u32,
+    _st: [[u16; 8]; 8],
+    _xmm: [LinuxXmmReg; 16],
+    padding: [u64; 12],
+}
+
+#[cfg(all(
+    any(target_os = "linux", target_os = "android"),
+    target_arch = "x86_64"
+))]
+#[repr(C)]
+struct LinuxXmmReg {
+    element: [u32; 4],
+}
+
+/// Decodes the XMM registers of a `LinuxFpState` into `known_registers`.
+#[cfg(all(
+    any(target_os = "linux", target_os = "android"),
+    target_arch = "x86_64"
+))]
+unsafe fn decode_fp_registers(fpregs: *const c_void, known_registers: &mut [Option<u64>; 32]) {
+    fn read_xmm(reg: &LinuxXmmReg) -> u64 {
+        (reg.element[0] as u64) | ((reg.element[1] as u64) << 32)
+    }
+
+    let fpregs = &*(fpregs as *const LinuxFpState);
+    known_registers[X64Register::XMM(XMM::XMM0).to_index().0] = Some(read_xmm(&fpregs._xmm[0]));
+    known_registers[X64Register::XMM(XMM::XMM1).to_index().0] = Some(read_xmm(&fpregs._xmm[1]));
+    known_registers[X64Register::XMM(XMM::XMM2).to_index().0] = Some(read_xmm(&fpregs._xmm[2]));
+    known_registers[X64Register::XMM(XMM::XMM3).to_index().0] = Some(read_xmm(&fpregs._xmm[3]));
+    known_registers[X64Register::XMM(XMM::XMM4).to_index().0] = Some(read_xmm(&fpregs._xmm[4]));
+    known_registers[X64Register::XMM(XMM::XMM5).to_index().0] = Some(read_xmm(&fpregs._xmm[5]));
+    known_registers[X64Register::XMM(XMM::XMM6).to_index().0] = Some(read_xmm(&fpregs._xmm[6]));
+    known_registers[X64Register::XMM(XMM::XMM7).to_index().0] = Some(read_xmm(&fpregs._xmm[7]));
+    known_registers[X64Register::XMM(XMM::XMM8).to_index().0] = Some(read_xmm(&fpregs._xmm[8]));
+    known_registers[X64Register::XMM(XMM::XMM9).to_index().0] = Some(read_xmm(&fpregs._xmm[9]));
+    known_registers[X64Register::XMM(XMM::XMM10).to_index().0] = Some(read_xmm(&fpregs._xmm[10]));
+    known_registers[X64Register::XMM(XMM::XMM11).to_index().0] = Some(read_xmm(&fpregs._xmm[11]));
+    known_registers[X64Register::XMM(XMM::XMM12).to_index().0] = Some(read_xmm(&fpregs._xmm[12]));
+    known_registers[X64Register::XMM(XMM::XMM13).to_index().0] = Some(read_xmm(&fpregs._xmm[13]));
+    known_registers[X64Register::XMM(XMM::XMM14).to_index().0] = Some(read_xmm(&fpregs._xmm[14]));
+    known_registers[X64Register::XMM(XMM::XMM15).to_index().0] = Some(read_xmm(&fpregs._xmm[15]));
+}
+
+#[cfg(not(all(
+    any(target_os = "linux", target_os = "android"),
+    target_arch = "x86_64"
+)))]
+unsafe fn decode_fp_registers(_fpregs: *const c_void, _known_registers: &mut [Option<u64>; 32]) {}
+
+/// Returns a cell aliasing a 64-bit register slot of the signal context.
+///
+/// Unused where `get_fault_info` does not alias registers, e.g. on arm.
+#[allow(dead_code)]
+unsafe fn register_cell<T>(slot: *mut T) -> Option<&'static Cell<u64>> {
+    debug_assert_eq!(std::mem::size_of::<T>(), 8);
+    Some(&*(slot as *const Cell<u64>))
+}
+
+/// Get fault info from siginfo and ucontext.
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub unsafe fn get_fault_info(siginfo: *const c_void, ucontext: *mut c_void) -> FaultInfo {
+    #[allow(dead_code)]
+    #[repr(C)]
+    struct ucontext_t {
+        uc_onstack: u32,
+        uc_sigmask: u32,
+        uc_stack: libc::stack_t,
+        uc_link: *const ucontext_t,
+        uc_mcsize: u64,
+        uc_mcontext: *mut mcontext_t,
+    }
+    #[allow(dead_code)]
+    #[repr(C)]
+    struct arm_exception_state64 {
+        __far: u64,
+        __esr: u32,
+        __exception: u32,
+    }
+    #[allow(dead_code)]
+    #[repr(C)]
+    struct arm_thread_state64 {
+        __x: [u64; 29],
+        __fp: u64,
+        __lr: u64,
+        __sp: u64,
+        __pc: u64,
+        __cpsr: u32,
+        __pad: u32,
+    }
+    #[allow(dead_code)]
+    #[repr(C)]
+    struct arm_neon_state64 {
+        __v: [u128; 32],
+        __fpsr: u32,
+        __fpcr: u32,
+    }
+    #[allow(dead_code)]
+    #[repr(C)]
+    struct mcontext_t {
+        __es: arm_exception_state64,
+        __ss: arm_thread_state64,
+        __ns: arm_neon_state64,
+    }
+
+    let ucontext = ucontext as *mut ucontext_t;
+    let mcontext = (*ucontext).uc_mcontext;
+    let ss = &mut (*mcontext).__ss;
+    let ns = &(*mcontext).__ns;
+    let gregs = &mut ss.__x;
+
+    let mut known_registers: [Option<u64>; 32] = [None; 32];
+
+    known_registers[X64Register::GPR(GPR::R15).to_index().0] = Some(gregs[15]);
+    known_registers[X64Register::GPR(GPR::R14).to_index().0] = Some(gregs[14]);
+    known_registers[X64Register::GPR(GPR::R13).to_index().0] = Some(gregs[13]);
+    known_registers[X64Register::GPR(GPR::R12).to_index().0] = Some(gregs[12]);
+    known_registers[X64Register::GPR(GPR::R11).to_index().0] = Some(gregs[11]);
+    known_registers[X64Register::GPR(GPR::R10).to_index().0] = Some(gregs[10]);
+    known_registers[X64Register::GPR(GPR::R9).to_index().0] = Some(gregs[9]);
+    known_registers[X64Register::GPR(GPR::R8).to_index().0] = Some(gregs[8]);
+    known_registers[X64Register::GPR(GPR::RSI).to_index().0] = Some(gregs[6]);
+    known_registers[X64Register::GPR(GPR::RDI).to_index().0] = Some(gregs[7]);
+    known_registers[X64Register::GPR(GPR::RDX).to_index().0] = Some(gregs[2]);
+    known_registers[X64Register::GPR(GPR::RCX).to_index().0] = Some(gregs[1]);
+    known_registers[X64Register::GPR(GPR::RBX).to_index().0] = Some(gregs[3]);
+    known_registers[X64Register::GPR(GPR::RAX).to_index().0] = Some(gregs[0]);
+
+    known_registers[X64Register::GPR(GPR::RBP).to_index().0] = Some(gregs[5]);
+    known_registers[X64Register::GPR(GPR::RSP).to_index().0] = Some(gregs[28]);
+
+    let mut gpr_cells: [Option<&'static Cell<u64>>; 16] = [None; 16];
+    gpr_cells[GPR::R15 as usize] = register_cell(&mut gregs[15]);
+    gpr_cells[GPR::R14 as usize] = register_cell(&mut gregs[14]);
+    gpr_cells[GPR::R13 as usize] = register_cell(&mut gregs[13]);
+    gpr_cells[GPR::R12 as usize] = register_cell(&mut gregs[12]);
+    gpr_cells[GPR::R11 as usize] = register_cell(&mut gregs[11]);
+    gpr_cells[GPR::R10 as usize] = register_cell(&mut gregs[10]);
+    gpr_cells[GPR::R9 as usize] = register_cell(&mut gregs[9]);
+    gpr_cells[GPR::R8 as usize] = register_cell(&mut gregs[8]);
+    gpr_cells[GPR::RSI as usize] = register_cell(&mut gregs[6]);
+    gpr_cells[GPR::RDI as usize] = register_cell(&mut gregs[7]);
+    gpr_cells[GPR::RDX as usize] = register_cell(&mut gregs[2]);
+    gpr_cells[GPR::RCX as usize] = register_cell(&mut gregs[1]);
+    gpr_cells[GPR::RBX as usize] = register_cell(&mut gregs[3]);
+    gpr_cells[GPR::RAX as usize] = register_cell(&mut gregs[0]);
+    gpr_cells[GPR::RBP as usize] = register_cell(&mut gregs[5]);
+    gpr_cells[GPR::RSP as usize] = register_cell(&mut gregs[28]);
+
+    known_registers[X64Register::XMM(XMM::XMM0).to_index().0] = Some(ns.__v[0] as u64);
+    known_registers[X64Register::XMM(XMM::XMM1).to_index().0] = Some(ns.__v[1] as u64);
+    known_registers[X64Register::XMM(XMM::XMM2).to_index().0] = Some(ns.__v[2] as u64);
+    known_registers[X64Register::XMM(XMM::XMM3).to_index().0] = Some(ns.__v[3] as u64);
+    known_registers[X64Register::XMM(XMM::XMM4).to_index().0] = Some(ns.__v[4] as u64);
+    known_registers[X64Register::XMM(XMM::XMM5).to_index().0] = Some(ns.__v[5] as u64);
+    known_registers[X64Register::XMM(XMM::XMM6).to_index().0] = Some(ns.__v[6] as u64);
+    known_registers[X64Register::XMM(XMM::XMM7).to_index().0] = Some(ns.__v[7] as u64);
+    known_registers[X64Register::XMM(XMM::XMM8).to_index().0] = Some(ns.__v[8] as u64);
+    known_registers[X64Register::XMM(XMM::XMM9).to_index().0] = Some(ns.__v[9] as u64);
+    known_registers[X64Register::XMM(XMM::XMM10).to_index().0] = Some(ns.__v[10] as u64);
+    known_registers[X64Register::XMM(XMM::XMM11).to_index().0] = Some(ns.__v[11] as u64);
+    known_registers[X64Register::XMM(XMM::XMM12).to_index().0] = Some(ns.__v[12] as u64);
+    known_registers[X64Register::XMM(XMM::XMM13).to_index().0] = Some(ns.__v[13] as u64);
+    known_registers[X64Register::XMM(XMM::XMM14).to_index().0] = Some(ns.__v[14] as u64);
+    known_registers[X64Register::XMM(XMM::XMM15).to_index().0] = Some(ns.__v[15] as u64);
+
+    FaultInfo {
+        faulting_addr: (*mcontext).__es.__far as usize as _,
+        ip: std::mem::transmute::<&mut u64, &'static Cell<usize>>(&mut ss.__pc),
+        known_registers,
+        stack_bounds: cached_stack_bounds(),
+        raw_siginfo: capture_raw_siginfo(siginfo as _),
+        signal: Some(siginfo_signo(siginfo as _)),
+        trap_no: Some((*mcontext).__es.__exception),
+        fs_base: None,
+        gs_base: None,
+        fpregs: std::ptr::null(),
+        ucontext: ucontext as *mut c_void,
+        gpr_cells,
+    }
+}
+
+/// Get fault info from siginfo and ucontext.
+#[cfg(all(target_os = "macos", target_arch = "x86_64"))]
+pub unsafe fn get_fault_info(siginfo: *const c_void, ucontext: *mut c_void) -> FaultInfo {
+    #[allow(dead_code)]
+    #[repr(C)]
+    struct ucontext_t {
+        uc_onstack: u32,
+        uc_sigmask: u32,
+        uc_stack: libc::stack_t,
+        uc_link: *const ucontext_t,
+        uc_mcsize: u64,
+        uc_mcontext: *mut mcontext_t,
+    }
+    #[repr(C)]
+    struct exception_state {
+        trapno: u16,
+        cpu: u16,
+        err: u32,
+        faultvaddr: u64,
+    }
+    #[repr(C)]
+    struct regs {
+        rax: u64,
+        rbx: u64,
+        rcx: u64,
+        rdx: u64,
+        rdi: u64,
+        rsi: u64,
+        rbp: u64,
+        rsp: u64,
+        r8: u64,
+        r9: u64,
+        r10: u64,
+        r11: u64,
+        r12: u64,
+        r13: u64,
+        r14: u64,
+        r15: u64,
+        rip: u64,
+        rflags: u64,
+        cs: u64,
+        fs: u64,
+        gs: u64,
+    }
+    #[repr(C)]
+    struct fpstate {
+        _cwd: u16,
+        _swd: u16,
+        _ftw: u16,
+        _fop: u16,
+        _rip: u64,
+        _rdp: u64,
+        _mxcsr: u32,
+        _mxcr_mask: u32,
+        _st: [[u16; 8]; 8],
+        xmm: [[u64; 2]; 16],
+        _padding: [u32; 24],
+    }
+    #[allow(dead_code)]
+    #[repr(C)]
+    struct mcontext_t {
+        es: exception_state,
+        ss: regs,
+        fs: fpstate,
+    }
+
+    let siginfo = siginfo as *const siginfo_t;
+    let si_addr = (*siginfo).si_addr;
+
+    let ucontext = ucontext as *mut ucontext_t;
+    let ss = &mut (*(*ucontext).uc_mcontext).ss;
+    let fs = &(*(*ucontext).uc_mcontext).fs;
+
+    let mut known_registers: [Option<u64>; 32] = [None; 32];
+
+    known_registers[X64Register::GPR(GPR::R15).to_index().0] = Some(ss.r15);
+    known_registers[X64Register::GPR(GPR::R14).to_index().0] = Some(ss.r14);
+    known_registers[X64Register::GPR(GPR::R13).to_index().0] = Some(ss.r13);
+    known_registers[X64Register::GPR(GPR::R12).to_index().0] = Some(ss.r12);
+    known_registers[X64Register::GPR(GPR::R11).to_index().0] = Some(ss.r11);
+    known_registers[X64Register::GPR(GPR::R10).to_index().0] = Some(ss.r10);
+    known_registers[X64Register::GPR(GPR::R9).to_index().0] = Some(ss.r9);
+    known_registers[X64Register::GPR(GPR::R8).to_index().0] = Some(ss.r8);
+    known_registers[X64Register::GPR(GPR::RSI).to_index().0] = Some(ss.rsi);
+    known_registers[X64Register::GPR(GPR::RDI).to_index().0] = Some(ss.rdi);
+    known_registers[X64Register::GPR(GPR::RDX).to_index().0] = Some(ss.rdx);
+    known_registers[X64Register::GPR(GPR::RCX).to_index().0] = Some(ss.rcx);
+    known_registers[X64Register::GPR(GPR::RBX).to_index().0] = Some(ss.rbx);
+    known_registers[X64Register::GPR(GPR::RAX).to_index().0] = Some(ss.rax);
+
+    known_registers[X64Register::GPR(GPR::RBP).to_index().0] = Some(ss.rbp);
+    known_registers[X64Register::GPR(GPR::RSP).to_index().0] = Some(ss.rsp);
+
+    let mut gpr_cells: [Option<&'static Cell<u64>>; 16] = [None; 16];
+    gpr_cells[GPR::R15 as usize] = register_cell(&mut ss.r15);
+    gpr_cells[GPR::R14 as usize] = register_cell(&mut ss.r14);
+    gpr_cells[GPR::R13 as usize] = register_cell(&mut ss.r13);
+    gpr_cells[GPR::R12 as usize] = register_cell(&mut ss.r12);
+    gpr_cells[GPR::R11 as usize] = register_cell(&mut ss.r11);
+    gpr_cells[GPR::R10 as usize] = register_cell(&mut ss.r10);
+    gpr_cells[GPR::R9 as usize] = register_cell(&mut ss.r9);
+    gpr_cells[GPR::R8 as usize] = register_cell(&mut ss.r8);
+    gpr_cells[GPR::RSI as usize] = register_cell(&mut ss.rsi);
+    gpr_cells[GPR::RDI as usize] = register_cell(&mut ss.rdi);
+    gpr_cells[GPR::RDX as usize] = register_cell(&mut ss.rdx);
+    gpr_cells[GPR::RCX as usize] = register_cell(&mut ss.rcx);
+    gpr_cells[GPR::RBX as usize] = register_cell(&mut ss.rbx);
+    gpr_cells[GPR::RAX as usize] = register_cell(&mut ss.rax);
+    gpr_cells[GPR::RBP as usize] = register_cell(&mut ss.rbp);
+    gpr_cells[GPR::RSP as usize] = register_cell(&mut ss.rsp);
+
+    known_registers[X64Register::XMM(XMM::XMM0).to_index().0] = Some(fs.xmm[0][0]);
+    known_registers[X64Register::XMM(XMM::XMM1).to_index().0] = Some(fs.xmm[1][0]);
+    known_registers[X64Register::XMM(XMM::XMM2).to_index().0] = Some(fs.xmm[2][0]);
+    known_registers[X64Register::XMM(XMM::XMM3).to_index().0] = Some(fs.xmm[3][0]);
+    known_registers[X64Register::XMM(XMM::XMM4).to_index().0] = Some(fs.xmm[4][0]);
+    known_registers[X64Register::XMM(XMM::XMM5).to_index().0] = Some(fs.xmm[5][0]);
+    known_registers[X64Register::XMM(XMM::XMM6).to_index().0] = Some(fs.xmm[6][0]);
+    known_registers[X64Register::XMM(XMM::XMM7).to_index().0] = Some(fs.xmm[7][0]);
+    known_registers[X64Register::XMM(XMM::XMM8).to_index().0] = Some(fs.xmm[8][0]);
+    known_registers[X64Register::XMM(XMM::XMM9).to_index().0] = Some(fs.xmm[9][0]);
+    known_registers[X64Register::XMM(XMM::XMM10).to_index().0] = Some(fs.xmm[10][0]);
+    known_registers[X64Register::XMM(XMM::XMM11).to_index().0] = Some(fs.xmm[11][0]);
+    known_registers[X64Register::XMM(XMM::XMM12).to_index().0] = Some(fs.xmm[12][0]);
+    known_registers[X64Register::XMM(XMM::XMM13).to_index().0] = Some(fs.xmm[13][0]);
+    known_registers[X64Register::XMM(XMM::XMM14).to_index().0] = Some(fs.xmm[14][0]);
+    known_registers[X64Register::XMM(XMM::XMM15).to_index().0] = Some(fs.xmm[15][0]);
+
+    FaultInfo {
+        faulting_addr: si_addr,
+        ip: std::mem::transmute::<&mut u64, &'static Cell<usize>>(&mut ss.rip),
+        known_registers,
+        stack_bounds: cached_stack_bounds(),
+        raw_siginfo: capture_raw_siginfo(siginfo as _),
+        signal: Some(siginfo_signo(siginfo as _)),
+        trap_no: Some((*(*ucontext).uc_mcontext).es.trapno as u32),
+        fs_base: None,
+        gs_base: None,
+        fpregs: std::ptr::null(),
+        ucontext: ucontext as *mut c_void,
+        gpr_cells,
+    }
+}
+
+// Keep in sync with the list of supported targets in `build.rs`.
+#[cfg(not(any(
+    all(
+        any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "macos"
+        ),
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ),
+    all(
+        any(
+            target_os = "openbsd",
+            target_os = "netbsd",
+            target_os = "illumos",
+            target_os = "solaris"
+        ),
+        target_arch = "x86_64"
+    ),
+    all(target_os = "linux", any(target_arch = "arm", target_arch = "riscv64")),
+)))]
+/// Get fault info from siginfo and ucontext.
+///
+/// The signal context of this target is not supported, so no register is known: traps are
+/// reported as generic errors without a backtrace, and interrupts cannot be recognized.
+pub unsafe fn get_fault_info(siginfo: *const c_void, ucontext: *mut c_void) -> FaultInfo {
+    thread_local! {
+        static IP: Cell<usize> = Cell::new(0);
+    }
+
+    let ip: &'static Cell<usize> = IP.with(|x| &*(x as *const Cell<usize>));
+    ip.set(0);
+
+    FaultInfo {
+        faulting_addr: std::ptr::null(),
+        ip,
+        known_registers: [None; 32],
+        stack_bounds: cached_stack_bounds(),
+        raw_siginfo: None,
+        signal: Some(siginfo_signo(siginfo)),
+        trap_no: None,
+        fs_base: None,
+        gs_base: None,
+        fpregs: std::ptr::null(),
+        ucontext,
+        gpr_cells: [None; 16],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{
+        Architecture, ExceptionTable, InlineBreakpoint, InlineBreakpointType, RunnableModule,
+    };
+    use crate::module::ModuleInfo;
+    use crate::state::x64::new_machine_state;
+    use crate::state::{
+        FunctionStateMap, MachineStateDiff, MachineValue, ModuleStateMap, OffsetInfo,
+        WasmAbstractValue,
+    };
+    use crate::typed_func::Wasm;
+    use crate::types::{LocalFuncIndex, SigIndex};
+    use std::collections::{BTreeMap, HashMap};
+    use std::ptr::NonNull;
+    use std::sync::Arc;
+
+    #[derive(Default)]
+    struct DummyModule {
+        exception_table: Option<ExceptionTable>,
+        inline_breakpoints: bool,
+    }
+
+    impl RunnableModule for DummyModule {
+        fn get_func(&self, _: &ModuleInfo, _: LocalFuncIndex) -> Option<NonNull<vm::Func>> {
+            None
+        }
+
+        fn get_trampoline(&self, _: &ModuleInfo, _: SigIndex) -> Option<Wasm> {
+            None
+        }
+
+        unsafe fn do_early_trap(&self, data: RuntimeError) -> ! {
+            begin_unsafe_unwind_with_longjmp(Box::new(data))
+        }
+
+        fn get_exception_table(&self) -> Option<&ExceptionTable> {
+            self.exception_table.as_ref()
+        }
+
+        fn get_inline_breakpoint_size(&self, _: Architecture) -> Option<usize> {
+            if self.inline_breakpoints {
+                Some(INLINE_BREAKPOINT_MAGIC.len() + 1)
+            } else {
+                None
+            }
+        }
+
+        fn read_inline_breakpoint(&self, _: Architecture, code: &[u8]) -> Option<InlineBreakpoint> {
+            if !code.starts_with(&INLINE_BREAKPOINT_MAGIC) {
+                return None;
+            }
+            Some(InlineBreakpoint {
+                size: INLINE_BREAKPOINT_MAGIC.len() + 1,
+                ty: InlineBreakpointType::from_u8(*code.get(INLINE_BREAKPOINT_MAGIC.len())?)?,
+            })
+        }
+    }
+
+    /// ud2; ud; int 0xff, as emitted by the singlepass backend before the breakpoint type.
+    const INLINE_BREAKPOINT_MAGIC: [u8; 6] = [0x0f, 0x0b, 0x0f, 0xb9, 0xcd, 0xff];
+
+    /// Builds a `CodeVersion` whose code is `code`.
+    fn dummy_code_version(code: &[u8]) -> CodeVersion {
+        dummy_code_version_with_module(code, DummyModule::default())
+    }
+
+    fn dummy_code_version_with_module(code: &[u8], module: DummyModule) -> CodeVersion {
+        CodeVersion {
+            baseline: true,
+            msm: ModuleStateMap {
+                local_functions: BTreeMap::new(),
+                total_size: code.len(),
+            },
+            base: code.as_ptr() as usize,
+            backend: "dummy",
+            runnable_module: Arc::new(Box::new(module)),
+            function_names: None,
+        }
+    }
+
+    #[test]
+    fn test_global_breakpoint_fires_without_local_map() {
+        const IP: usize = 0xdead_0001;
+        let mut map: HashMap<usize, BreakpointHandler> = HashMap::new();
+        map.insert(
+            IP,
+            Box::new(|_: BreakpointInfo| Err(RuntimeError::User(Box::new("global".to_string())))),
+        );
+        set_global_breakpoints(Arc::new(map));
+
+        let out = unsafe {
+            catch_unsafe_unwind(
+                || with_breakpoint(IP, |h| h.map(|h| h(BreakpointInfo { fault: None }))),
+                None,
+            )
+        };
+        clear_global_breakpoints();
+
+        match out {
+            Ok(Some(Err(RuntimeError::User(e)))) => {
+                assert_eq!(e.downcast_ref::<String>().unwrap(), "global")
+            }
+            _ => panic!("global breakpoint did not fire"),
+        }
+    }
+
+    #[test]
+    fn test_install_sighandler_reports_failed_signal() {
+        unsafe fn failing_sigaction(signal: Signal, _: &SigAction) -> nix::Result<SigAction> {
+            if signal == SIGFPE {
+                Err(nix::Error::Sys(nix::errno::Errno::EINVAL))
+            } else {
+                Ok(SigAction::new(
+                    SigHandler::SigDfl,
+                    SaFlags::empty(),
+                    SigSet::empty(),
+                ))
+            }
+        }
+
+        let report =
+            unsafe { install_sighandler_with(failing_sigaction, true, &mut [None; 4]) }.unwrap();
+        assert!(!report.is_complete());
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, SIGFPE);
+        assert!(report.installed.contains(&SIGSEGV));
+        assert!(!report.installed.contains(&SIGFPE));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+    #[test]
+    fn test_thread_stack_bounds_contain_stack_pointer() {
+        let local = 0u64;
+        let bounds = thread_stack_bounds().unwrap();
+        assert!(bounds.contains(&local as *const u64 as usize));
+        assert_eq!(cached_stack_bounds(), Some(bounds));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_recent_faults_keeps_most_recent_in_order() {
+        ensure_sighandler();
+        let total = FAULT_RING_SIZE + 5;
+        // A `ud2` per fault, each at its own offset.
+        let bytes: Vec<u8> = (0..total).flat_map(|_| vec![0x0f, 0x0b]).collect();
+        unsafe {
+            let (f, _code) = make_code_page(&bytes);
+            push_code_version(dummy_code_version(std::slice::from_raw_parts(
+                f as *const u8,
+                bytes.len(),
+            )));
+            for i in 0..total {
+                let g: extern "C" fn() = std::mem::transmute(f as usize + 2 * i);
+                assert!(catch_unsafe_unwind(|| g(), None).is_err());
+            }
+            pop_code_version().unwrap();
+        }
+
+        let faults = recent_faults();
+        assert_eq!(faults.len(), FAULT_RING_SIZE);
+        for (i, f) in faults.iter().enumerate() {
+            assert_eq!(f.signal, SIGILL as i32);
+            assert_eq!(f.ip_offset, 2 * (total - FAULT_RING_SIZE + i));
+            assert!(f.code_version.is_some());
+            assert_eq!(f.classification, FaultClassification::Unknown);
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_corrupted_code_version_is_detected() {
+        let mut code = vec![0xccu8; 64];
+        set_code_integrity_check(true);
+        push_code_version(dummy_code_version(&code));
+        set_code_integrity_check(false);
+
+        let index = CURRENT_CODE_VERSIONS.with(|x| x.borrow().len()) - 1;
+        let version = CURRENT_CODE_VERSIONS.with(|x| x.borrow()[index].clone());
+        unsafe {
+            assert!(code_version_intact(index, &version));
+            code[10] = 0x90;
+            assert!(!code_version_intact(index, &version));
+        }
+        pop_code_version().unwrap();
+    }
+
+    #[test]
+    fn test_ambiguous_fault_reports_all_candidates() {
+        let code = vec![0u8; 64];
+        for &exc_code in [ExceptionCode::MemoryOutOfBounds, ExceptionCode::Unreachable].iter() {
+            let mut table = ExceptionTable::new();
+            table.offset_to_code.insert(8, exc_code);
+            push_code_version(dummy_code_version_with_module(
+                &code,
+                DummyModule {
+                    exception_table: Some(table),
+                    ..Default::default()
+                },
+            ));
+        }
+
+        let ip = code.as_ptr() as usize + 8;
+        set_fault_diagnostics(true);
+        let candidates = classify_fault(ip);
+        set_fault_diagnostics(false);
+        pop_code_version().unwrap();
+        pop_code_version().unwrap();
+
+        assert_eq!(
+            candidates,
+            vec![
+                FaultClassification::Trap(ExceptionCode::MemoryOutOfBounds),
+                FaultClassification::Trap(ExceptionCode::Unreachable),
+            ]
+        );
+    }
+
+    lazy_static! {
+        /// Serializes the tests that deliver SIGINTs, since a SIGINT is delivered to every
+        /// active `Ctx`.
+        static ref SIGINT_TEST_LOCK: Mutex<()> = Mutex::new(());
+    }
+
+    #[test]
+    fn test_sigint_state_delivered_but_not_triggered() {
+        let _lock = SIGINT_TEST_LOCK.lock().unwrap();
+        let ctx = 0x10 as *mut vm::Ctx;
+        WAS_SIGINT_TRIGGERED.with(|x| x.set(false));
+        let index = register_active_ctx(ctx).unwrap();
+        ACTIVE_CTXS[index].delivered.store(true, Ordering::SeqCst);
+        let state = sigint_state();
+        unregister_active_ctx(index);
+
+        assert_eq!(
+            state,
+            SigintState {
+                delivered: true,
+                triggered_on_this_thread: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_raw_siginfo_captures_sender_pid() {
+        static mut CAPTURED: Option<SigInfoSnapshot> = None;
+
+        extern "C" fn handler(_: libc::c_int, siginfo: *mut siginfo_t, ucontext: *mut c_void) {
+            unsafe {
+                CAPTURED = get_fault_info(siginfo as _, ucontext).raw_siginfo;
+            }
+        }
+
+        set_capture_raw_siginfo(true);
+        unsafe {
+            let sa = SigAction::new(
+                SigHandler::SigAction(handler),
+                SaFlags::empty(),
+                SigSet::empty(),
+            );
+            let old = sigaction(Signal::SIGUSR1, &sa).unwrap();
+            libc::pthread_kill(libc::pthread_self(), libc::SIGUSR1);
+            sigaction(Signal::SIGUSR1, &old).unwrap();
+        }
+        set_capture_raw_siginfo(false);
+
+        let captured = unsafe { CAPTURED }.unwrap();
+        assert_eq!(captured.si_signo, libc::SIGUSR1);
+        assert_eq!(captured.si_pid, unsafe { libc::getpid() });
+    }
+
+    thread_local! {
+        static BOUNDARY_LOG: RefCell<Vec<&'static str>> = RefCell::new(vec![]);
+    }
+
+    fn log_enter() {
+        BOUNDARY_LOG.with(|x| x.borrow_mut().push("enter"));
+    }
+
+    fn log_exit() {
+        BOUNDARY_LOG.with(|x| x.borrow_mut().push("exit"));
+    }
+
+    #[test]
+    fn test_execution_boundary_hooks_bracket_calls() {
+        set_execution_boundary_hooks(log_enter, log_exit);
+
+        let ret = unsafe {
+            catch_unsafe_unwind(
+                || {
+                    BOUNDARY_LOG.with(|x| x.borrow_mut().push("call"));
+                    42
+                },
+                None,
+            )
+        };
+        assert_eq!(ret.unwrap(), 42);
+        assert_eq!(
+            BOUNDARY_LOG.with(|x| x.replace(vec![])),
+            vec!["enter", "call", "exit"]
+        );
+
+        let ret = unsafe {
+            catch_unsafe_unwind(
+                || {
+                    BOUNDARY_LOG.with(|x| x.borrow_mut().push("call"));
+                    begin_unsafe_unwind(Box::new(RuntimeError::User(Box::new("trap"))))
+                },
+                None,
+            )
+        };
+        assert!(ret.is_err());
+        assert_eq!(
+            BOUNDARY_LOG.with(|x| x.replace(vec![])),
+            vec!["enter", "call", "exit"]
+        );
+
+        clear_execution_boundary_hooks();
+        assert_eq!(unsafe { catch_unsafe_unwind(|| 42, None) }.unwrap(), 42);
+        assert!(BOUNDARY_LOG.with(|x| x.borrow().is_empty()));
+    }
+
+    thread_local! {
+        static INVOCATIONS: RefCell<Vec<bool>> = RefCell::new(vec![]);
+    }
+
+    fn log_invocation(error: Option<&RuntimeError>, _elapsed: Duration) {
+        INVOCATIONS.with(|x| x.borrow_mut().push(error.is_none()));
+    }
+
+    #[test]
+    fn test_invocation_observer_sees_both_exits() {
+        set_invocation_observer(log_invocation);
+        let ok = unsafe { catch_unsafe_unwind(|| 42, None) };
+        let err = unsafe {
+            catch_unsafe_unwind(
+                || begin_unsafe_unwind(Box::new(RuntimeError::User(Box::new("trap")))),
+                None,
+            )
+        };
+        clear_invocation_observer();
+
+        assert_eq!(ok.unwrap(), 42);
+        assert!(err.is_err());
+        assert_eq!(INVOCATIONS.with(|x| x.replace(vec![])), vec![true, false]);
+    }
+
+    fn commit_page(fault: &FaultInfo) -> RecoveryAction {
+        let page = fault.faulting_addr as usize & !(4096 - 1);
+        if unsafe { mprotect(page as _, 4096, PROT_READ | PROT_WRITE) } < 0 {
+            return RecoveryAction::Unwind;
+        }
+        RecoveryAction::Resume
+    }
+
+    /// Returns the protection of the mapping containing `addr`, as in `/proc/self/maps`.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    fn mapping_protection(addr: usize) -> String {
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+        for line in maps.lines() {
+            let mut fields = line.split_whitespace();
+            let range = fields.next().unwrap();
+            let mut bounds = range
+                .split('-')
+                .map(|x| usize::from_str_radix(x, 16).unwrap());
+            let (start, end) = (bounds.next().unwrap(), bounds.next().unwrap());
+            if (start..end).contains(&addr) {
+                return fields.next().unwrap().to_string();
+            }
+        }
+        panic!("0x{:x} is not mapped", addr);
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn test_watchpoint_reports_accesses_and_rearms() {
+        static HITS: AtomicUsize = AtomicUsize::new(0);
+        fn count_hit(_fault: &FaultInfo) {
+            HITS.fetch_add(1, Ordering::SeqCst);
+        }
+
+        ensure_sighandler();
+        unsafe {
+            let page = MmapFaultAllocator.map(4096, PROT_READ | PROT_WRITE);
+            let watched = page.add(8) as *mut u64;
+            let unwatched = page.add(64) as *mut u64;
+            register_watchpoint(watched as usize, 8, count_hit).unwrap();
+            assert_eq!(mapping_protection(page as usize), "---p");
+
+            std::ptr::write_volatile(watched, 42);
+            assert_eq!(mapping_protection(page as usize), "---p");
+            std::ptr::write_volatile(unwatched, 7);
+            assert_eq!(mapping_protection(page as usize), "---p");
+            assert_eq!(HITS.load(Ordering::SeqCst), 1);
+            assert_eq!(std::ptr::read_volatile(watched), 42);
+            assert_eq!(HITS.load(Ordering::SeqCst), 2);
+
+            unregister_watchpoint(watched as usize, 8);
+            assert_eq!(mapping_protection(page as usize), "rw-p");
+            std::ptr::write_volatile(watched, 43);
+            assert_eq!(HITS.load(Ordering::SeqCst), 2);
+            assert_eq!(std::ptr::read_volatile(unwatched), 7);
+            MmapFaultAllocator.unmap(page, 4096);
+        }
+    }
+
+    #[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+    #[test]
+    fn test_watchpoint_is_rejected_without_single_step() {
+        fn ignore_hit(_fault: &FaultInfo) {}
+
+        let mut value = 0u64;
+        let addr = &mut value as *mut u64 as usize;
+        match unsafe { register_watchpoint(addr, 8, ignore_hit) } {
+            Err(FaultError::WatchpointsUnsupported) => {}
+            x => panic!("unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_reset_fault_thread_state_clears_stale_state() {
+        let code = vec![0xc3u8; 16];
+        push_code_version(dummy_code_version(&code));
+        CATCH_SCOPE_DEPTH.with(|x| x.set(2));
+        WAS_SIGINT_TRIGGERED.with(|x| x.set(true));
+
+        unsafe { reset_fault_thread_state() };
+        assert!(with_code_versions(|versions| versions.is_empty()));
+        assert_eq!(catch_scope_depth(), 0);
+        assert!(!was_sigint_triggered_fault());
+        assert!(with_current_ctx(|ctx| ctx.is_none()));
+    }
+
+    #[test]
+    fn test_breakpoint_map_builder_resolves_collisions() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        fn contribution() -> HashMap<usize, BreakpointHandler> {
+            let mut breakpoints: HashMap<usize, BreakpointHandler> = HashMap::new();
+            breakpoints.insert(
+                0x1000,
+                Box::new(|_| {
+                    CALLS.fetch_add(1, Ordering::SeqCst);
+                    Ok(())
+                }),
+            );
+            breakpoints
+        }
+
+        let mut builder = BreakpointMapBuilder::new(BreakpointCollisionPolicy::Chain);
+        builder.merge(contribution()).unwrap();
+        builder.merge(contribution()).unwrap();
+        let map = builder.build();
+        assert_eq!(map.len(), 1);
+        map[&0x1000](BreakpointInfo { fault: None }).unwrap();
+        assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+
+        let mut builder = BreakpointMapBuilder::new(BreakpointCollisionPolicy::Reject);
+        builder.merge(contribution()).unwrap();
+        match builder.merge(contribution()) {
+            Err(FaultError::BreakpointCollision { ip }) => assert_eq!(ip, 0x1000),
+            other => panic!("expected a collision, got {:?}", other),
+        }
+        assert_eq!(builder.build().len(), 1);
+    }
+
+    #[test]
+    fn test_catch_unsafe_unwind_timed_measures_cpu_time() {
+        let (result, elapsed) = unsafe {
+            catch_unsafe_unwind_timed(
+                || {
+                    let started = thread_cpu_time();
+                    while thread_cpu_time() - started < Duration::from_millis(10) {}
+                    42
+                },
+                None,
+            )
+        };
+        assert_eq!(result.unwrap(), 42);
+        assert!(elapsed >= Duration::from_millis(10));
+
+        let (result, _) = unsafe {
+            catch_unsafe_unwind_timed(
+                || begin_unsafe_unwind(Box::new(RuntimeError::InvokeError(InvokeError::Timeout))),
+                None,
+            )
+        };
+        match result {
+            Err(RuntimeError::InvokeError(InvokeError::Timeout)) => {}
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_walk_stack_with_synthetic_stack() {
+        // A function with one local, which traps at offset 0x10 and calls at offset 0x20, with
+        // the local and one wasm stack value spilled to the stack.
+        let code = vec![0u8; 0x40];
+        let mut fsm =
+            FunctionStateMap::new(new_machine_state(), 3, 0, vec![WasmAbstractValue::Runtime]);
+        for &inst_offset in [7, 9].iter() {
+            fsm.diffs.push(MachineStateDiff {
+                stack_push: vec![MachineValue::WasmLocal(0), MachineValue::WasmStack(0)],
+                wasm_stack_push: vec![WasmAbstractValue::Runtime],
+                wasm_inst_offset: inst_offset,
+                ..Default::default()
+            });
+        }
+        let offset_info = |start: usize, diff_id: usize| OffsetInfo {
+            end_offset: start + 0x10,
+            diff_id,
+            activate_offset: start,
+        };
+        fsm.trappable_offsets.insert(0x10, offset_info(0x10, 0));
+        fsm.call_offsets.insert(0x20, offset_info(0x20, 1));
+        let mut version = dummy_code_version(&code);
+        version.msm.local_functions.insert(0, fsm);
+        let versions = [version];
+
+        let base = code.as_ptr() as u64;
+        // The wasm stack value, the local, the saved rbp and the return address of each frame.
+        let stack: [u64; 8] = [0x11, 0x12, 0, base + 0x24, 0x21, 0x22, 0, 0];
+        let rsp = stack.as_ptr() as usize;
+        let ip = Some(code.as_ptr() as usize + 0x18);
+
+        let image = unsafe { walk_stack(rsp, [None; 32], ip, &versions, None, None) };
+        assert!(!image.truncated);
+        let frames: Vec<_> = image
+            .frames
+            .iter()
+            .map(|f| {
+                (
+                    f.local_function_id,
+                    f.wasm_inst_offset,
+                    f.stack.clone(),
+                    f.locals.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            frames,
+            vec![
+                (3, 7, vec![Some(0x11)], vec![Some(0x12)]),
+                (3, 9, vec![Some(0x21)], vec![Some(0x22)]),
+            ]
+        );
+
+        let image = unsafe { walk_stack(rsp, [None; 32], ip, &versions, Some(1), None) };
+        assert_eq!(image.frames.len(), 1);
+        assert!(image.truncated);
+
+        // The walk stops before reading the return address of the first frame.
+        let stack_base = Some(&stack[3] as *const u64 as usize);
+        let image = unsafe { walk_stack(rsp, [None; 32], ip, &versions, None, stack_base) };
+        assert_eq!(image.frames.len(), 1);
+        assert!(!image.truncated);
+
+        // Nothing is found outside of the code versions.
+        let image = unsafe { walk_stack(rsp, [None; 32], Some(0x4000), &versions, None, None) };
+        assert!(image.frames.is_empty());
+        assert!(!image.truncated);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_division_instruction_len() {
+        let cases: &[(&[u8], Option<usize>)] = &[
+            // div ecx
+            (&[0xf7, 0xf1], Some(2)),
+            // div cl
+            (&[0xf6, 0xf1], Some(2)),
+            // div r8d
+            (&[0x41, 0xf7, 0xf0], Some(3)),
+            // div cx
+            (&[0x66, 0xf7, 0xf1], Some(3)),
+            // idiv qword [rsp + 8]
+            (&[0x48, 0xf7, 0x7c, 0x24, 0x08], Some(5)),
+            // div dword [rip + 0]
+            (&[0xf7, 0x35, 0, 0, 0, 0], Some(6)),
+            // idiv dword [rax + 0x100]
+            (&[0xf7, 0xb8, 0, 1, 0, 0], Some(6)),
+            // neg eax
+            (&[0xf7, 0xd8], None),
+            // nop
+            (&[0x90, 0x90], None),
+        ];
+        for (code, len) in cases {
+            assert_eq!(unsafe { division_instruction_len(code.as_ptr()) }, *len);
+        }
+    }
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    #[test]
+    fn test_alt_stack_layout_is_consistent() {
+        let layout = ALT_STACK_LAYOUT;
+        assert!(layout.saved_sp_slot > 0);
+        assert!(layout.returning_slot > layout.saved_sp_slot);
+        assert!(layout.target_slot > layout.returning_slot);
+        assert!(layout.arg_register_index < layout.num_saved_registers);
+
+        let mut stack = [0u64; 64];
+        let stack_end = unsafe { stack.as_mut_ptr().add(stack.len()) };
+        let stack_begin = unsafe { layout.stack_begin(stack_end) };
+        // The registers end right below the target.
+        assert_eq!(
+            unsafe { stack_begin.add(layout.num_saved_registers) },
+            unsafe { stack_end.sub(layout.target_slot) }
+        );
+        // The first argument register is loaded from the slot the asm expects.
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(
+            unsafe { stack_begin.add(layout.arg_register_index) },
+            unsafe { stack_end.sub(14) }
+        );
+        #[cfg(target_arch = "aarch64")]
+        assert_eq!(
+            unsafe { stack_begin.add(layout.arg_register_index) },
+            stack_begin
+        );
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn test_inject_fault_unwinds_with_the_error_of_the_fault() {
+        let (tx, rx) = mpsc::sync_channel(16);
+        set_trap_event_sink(tx);
+        let result = unsafe {
+            catch_unsafe_unwind(
+                || inject_fault(FaultKind::Trap(ExceptionCode::IllegalArithmetic)),
+                None,
+            )
+        };
+        clear_trap_event_sink();
+        // Other tests may trap while the sink is set.
+        let thread_id = unsafe { libc::pthread_self() } as u64;
+        let event = rx.iter().find(|e| e.thread_id == thread_id).unwrap();
+        assert_eq!(event.signal, Some(SIGFPE as i32));
+        assert_eq!(event.code, Some(ExceptionCode::IllegalArithmetic));
+        match result {
+            Err(RuntimeError::InvokeError(InvokeError::TrapCode {
+                code: ExceptionCode::IllegalArithmetic,
+                ..
+            })) => {}
+            other => panic!("expected an arithmetic trap, got {:?}", other),
+        }
+        let fault = recent_faults().pop().unwrap();
+        assert_eq!(fault.signal, SIGFPE as i32);
+        assert_eq!(
+            fault.classification,
+            FaultClassification::Trap(ExceptionCode::IllegalArithmetic)
+        );
+
+        let result = unsafe { catch_unsafe_unwind(|| inject_fault(FaultKind::Timeout), None) };
+        match result {
+            Err(RuntimeError::InvokeError(InvokeError::Timeout)) => {}
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        assert_eq!(
+            recent_faults().pop().unwrap().classification,
+            FaultClassification::Suspend
+        );
+    }
+
+    #[test]
+    fn test_fault_recovery_commits_lazy_page() {
+        ensure_sighandler();
+        let page = unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                4096,
+                PROT_NONE,
+                MAP_PRIVATE | MAP_ANON,
+                -1,
+                0,
+            )
+        };
+        assert!(page as isize != -1);
+        let range = page as usize..page as usize + 4096;
+        register_fault_recovery(range.clone(), commit_page).unwrap();
+
+        let value = unsafe {
+            catch_unsafe_unwind(
+                || {
+                    let ptr = page as *mut u32;
+                    std::ptr::write_volatile(ptr, 42);
+                    std::ptr::read_volatile(ptr)
+                },
+                None,
+            )
+        };
+        unregister_fault_recovery(range);
+        assert_eq!(value.unwrap(), 42);
+        unsafe { libc::munmap(page, 4096) };
+    }
+
+    #[test]
+    fn test_range_table_is_bounded_and_reuses_slots() {
+        let table = RangeTable::new();
+        for i in 0..MAX_RANGES {
+            assert!(table.insert(0x1000 * (i + 1)..0x1000 * (i + 1) + 0x10, i));
+        }
+        assert!(!table.insert(0x100_0000..0x100_0010, 0));
+        assert_eq!(table.find(0x2008), Some((0x2000..0x2010, 1)));
+        assert_eq!(table.find(0x2010), None);
+
+        table.remove(0x2000..0x2010);
+        assert_eq!(table.find(0x2008), None);
+        assert!(table.insert(0x100_0000..0x100_0010, 7));
+        assert_eq!(table.find(0x100_0000), Some((0x100_0000..0x100_0010, 7)));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_fault_signature_is_stable() {
+        let record = CompactFaultRecord {
+            signal: 4,
+            ip_offset: 0x10,
+            code_version: Some(3),
+            classification: FaultClassification::Trap(ExceptionCode::Unreachable),
+            memory_size: None,
+            timestamp: FaultTimestamp::now(),
+        };
+        assert_eq!(record.signature(), 0x1422_c674_0ba2_b463);
+        assert_eq!(fnv1a(FNV_OFFSET_BASIS, b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn test_fault_signature_is_independent_of_base_address() {
+        ensure_sighandler();
+        let signature_of_run = |f: extern "C" fn(), code: ExceptionCode| unsafe {
+            let mut table = ExceptionTable::new();
+            table.offset_to_code.insert(1, code);
+            push_code_version(dummy_code_version_with_module(
+                std::slice::from_raw_parts(f as *const u8, 3),
+                DummyModule {
+                    exception_table: Some(table),
+                    ..Default::default()
+                },
+            ));
+            let result = catch_unsafe_unwind(|| f(), None);
+            pop_code_version().unwrap();
+            match result {
+                Err(RuntimeError::InvokeError(InvokeError::TrapCode { code: c, .. })) => {
+                    assert_eq!(c, code)
+                }
+                other => panic!("expected a trap, got {:?}", other.err()),
+            }
+            recent_faults().last().unwrap().signature()
+        };
+        // nop; ud2
+        let (first, _first) = unsafe { make_code_page(&[0x90, 0x0f, 0x0b]) };
+        let (second, _second) = unsafe { make_code_page(&[0x90, 0x0f, 0x0b]) };
+        assert_ne!(first as usize, second as usize);
+        assert_eq!(
+            signature_of_run(first, ExceptionCode::Unreachable),
+            signature_of_run(second, ExceptionCode::Unreachable)
+        );
+        assert_ne!(
+            signature_of_run(first, ExceptionCode::IllegalArithmetic),
+            signature_of_run(first, ExceptionCode::Unreachable)
+        );
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    static RECORDED_MAPS: AtomicUsize = AtomicUsize::new(0);
+    #[cfg(target_arch = "x86_64")]
+    static RECORDED_UNMAPS: AtomicUsize = AtomicUsize::new(0);
+
+    #[cfg(target_arch = "x86_64")]
+    struct RecordingAllocator;
+
+    #[cfg(target_arch = "x86_64")]
+    impl FaultAllocator for RecordingAllocator {
+        unsafe fn map(&self, size: usize, prot: i32) -> *mut u8 {
+            RECORDED_MAPS.fetch_add(1, Ordering::SeqCst);
+            MmapFaultAllocator.map(size, prot)
+        }
+
+        unsafe fn unmap(&self, ptr: *mut u8, size: usize) {
+            RECORDED_UNMAPS.fetch_add(1, Ordering::SeqCst);
+            MmapFaultAllocator.unmap(ptr, size)
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_custom_fault_allocator_maps_stacks() {
+        set_fault_allocator(Box::new(RecordingAllocator));
+        let maps = RECORDED_MAPS.load(Ordering::SeqCst);
+        let unmaps = RECORDED_UNMAPS.load(Ordering::SeqCst);
+        assert_eq!(allocate_and_run(65536, || 42), 42);
+        assert!(RECORDED_MAPS.load(Ordering::SeqCst) > maps);
+        assert!(RECORDED_UNMAPS.load(Ordering::SeqCst) > unmaps);
+        set_fault_allocator(Box::new(MmapFaultAllocator));
+    }
+
+    #[test]
+    fn test_breakpoint_builder_validates_offsets() {
+        let code = vec![0u8; 64];
+        let version = dummy_code_version(&code);
+        let mut builder = BreakpointBuilder::new(&version);
+        let ip = builder
+            .add_breakpoint(8, |_| Err(RuntimeError::User(Box::new("hit"))))
+            .unwrap();
+        assert_eq!(ip, code.as_ptr() as usize + 8);
+        match builder.add_breakpoint(64, |_| Ok(())) {
+            Err(FaultError::BreakpointOutOfRange {
+                offset: 64,
+                code_size: 64,
+            }) => {}
+            other => panic!("expected an out of range error, got {:?}", other),
+        }
+
+        let map = builder.build();
+        assert_eq!(map.len(), 1);
+        assert!(map[&ip](BreakpointInfo { fault: None }).is_err());
+    }
+
+    #[test]
+    fn test_install_sighandler_can_leave_sigint_untouched() {
+        unsafe fn noop_sigaction(_: Signal, _: &SigAction) -> nix::Result<SigAction> {
+            Ok(SigAction::new(
+                SigHandler::SigDfl,
+                SaFlags::empty(),
+                SigSet::empty(),
+            ))
+        }
+
+        let report =
+            unsafe { install_sighandler_with(noop_sigaction, false, &mut [None; 4]) }.unwrap();
+        assert!(report.is_complete());
+        assert!(!report.installed.contains(&SIGINT));
+        assert!(report.installed.contains(&SIGSEGV));
+    }
+
+    #[cfg(all(
+        any(target_os = "linux", target_os = "android"),
+        target_arch = "x86_64"
+    ))]
+    #[test]
+    fn test_fp_registers_are_decoded_lazily() {
+        use crate::state::x64::XMM;
+
+        let xmm0 = X64Register::XMM(XMM::XMM0).to_index().0;
+        let rax = X64Register::GPR(GPR::RAX).to_index().0;
+        unsafe {
+            let mut fpstate: LinuxFpState = std::mem::zeroed();
+            fpstate._xmm[0].element[0] = 0x1234;
+            let mut ucontext: libc::ucontext_t = std::mem::zeroed();
+            ucontext.uc_mcontext.gregs[libc::REG_RAX as usize] = 42;
+            // `fpregs` follows the 23 general purpose registers with both glibc and musl.
+            let gregs = &mut ucontext.uc_mcontext.gregs as *mut _ as *mut *const LinuxFpState;
+            *gregs.add(23) = &fpstate;
+            let mut siginfo: libc::siginfo_t = std::mem::zeroed();
+            let siginfo = &mut siginfo as *mut libc::siginfo_t as *const c_void;
+            let ucontext = &mut ucontext as *mut libc::ucontext_t as *mut c_void;
+
+            let mut fault = get_fault_info(siginfo, ucontext);
+            assert_eq!(fault.known_registers[rax], Some(42));
+            assert_eq!(fault.known_registers[xmm0], None);
+            assert!(!fault.fpregs.is_null());
+
+            fault.load_fp_registers();
+            assert_eq!(fault.known_registers[xmm0], Some(0x1234));
+            assert!(fault.fpregs.is_null());
+        }
+    }
+
+    #[test]
+    fn test_backtrace_output_uses_function_names() {
+        use crate::state::WasmFunctionStateDump;
+
+        let image = ExecutionStateImage {
+            frames: vec![WasmFunctionStateDump {
+                local_function_id: 3,
+                wasm_inst_offset: 10,
+                stack: vec![],
+                locals: vec![],
+            }],
+            truncated: false,
+            host_frames: vec![],
+        };
+        let mut names = HashMap::new();
+        names.insert(3u32, "env.do_work".to_string());
+
+        assert!(image
+            .output_with_names(Some(&names))
+            .contains("Local function 3 (env.do_work)"));
+        assert!(!image.output().contains("env.do_work"));
+    }
+
+    #[test]
+    fn test_catch_scope_depth_tracks_nesting() {
+        assert!(!is_in_catch_scope());
+        let depths = unsafe {
+            catch_unsafe_unwind(
+                || {
+                    let inner = catch_unsafe_unwind(catch_scope_depth, None).unwrap();
+                    (catch_scope_depth(), inner)
+                },
+                None,
+            )
+        };
+        assert_eq!(depths.unwrap(), (1, 2));
+        assert!(!is_in_catch_scope());
+
+        let ret = unsafe {
+            catch_unsafe_unwind(
+                || begin_unsafe_unwind(Box::new(RuntimeError::User(Box::new("trap")))),
+                None,
+            )
+        };
+        assert!(ret.is_err());
+        assert_eq!(catch_scope_depth(), 0);
+    }
+
+    #[test]
+    fn test_stray_unwind_payload_is_reported() {
+        let ret = unsafe {
+            catch_unsafe_unwind(
+                || {
+                    UNWIND.with(|x| {
+                        (*x.get()).as_mut().unwrap().payload =
+                            Some(Box::new(RuntimeError::InvokeError(InvokeError::Timeout)))
+                    });
+                    42
+                },
+                None,
+            )
+        };
+        match ret {
+            Err(RuntimeError::InvokeError(InvokeError::Timeout)) => {}
+            other => panic!("expected the stray payload, got {:?}", other),
+        }
+        assert!(!is_in_catch_scope());
+    }
+
+    #[test]
+    fn test_registers_are_accessible_by_name() {
+        let mut known_registers = [None; 32];
+        known_registers[X64Register::GPR(GPR::RBX).to_index().0] = Some(3);
+        known_registers[X64Register::XMM(XMM::XMM2).to_index().0] = Some(18);
+        let fault = FaultInfo {
+            faulting_addr: std::ptr::null(),
+            ip: Box::leak(Box::new(Cell::new(0))),
+            known_registers,
+            stack_bounds: None,
+            raw_siginfo: None,
+            signal: None,
+            trap_no: None,
+            fs_base: None,
+            gs_base: None,
+            fpregs: std::ptr::null(),
+            ucontext: std::ptr::null_mut(),
+            gpr_cells: [None; 16],
+        };
+
+        assert_eq!(fault.gpr(GPR::RBX), Some(3));
+        assert_eq!(fault.gpr(GPR::RAX), None);
+        assert_eq!(fault.xmm(XMM::XMM2), Some(18));
+        assert_eq!(fault.xmm(XMM::XMM0), None);
+        assert_eq!(
+            fault.registers().collect::<Vec<_>>(),
+            vec![
+                (X64Register::GPR(GPR::RBX), 3),
+                (X64Register::XMM(XMM::XMM2), 18)
+            ]
+        );
+        for i in 0..32 {
+            let reg = X64Register::from_index(RegisterIndex(i)).unwrap();
+            assert_eq!(reg.to_index().0, i);
+        }
+        assert!(X64Register::from_index(RegisterIndex(32)).is_none());
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn test_fault_info_reports_signal_and_trap_number() {
+        unsafe {
+            let mut ucontext: libc::ucontext_t = std::mem::zeroed();
+            // #DE, the divide error exception.
+            ucontext.uc_mcontext.gregs[libc::REG_TRAPNO as usize] = 0;
+            let mut siginfo: libc::siginfo_t = std::mem::zeroed();
+            siginfo.si_signo = libc::SIGFPE;
+            let fault = get_fault_info(
+                &siginfo as *const libc::siginfo_t as *const c_void,
+                &mut ucontext as *mut libc::ucontext_t as *mut c_void,
+            );
+            assert_eq!(fault.signal, Some(libc::SIGFPE));
+            assert_eq!(fault.trap_no, Some(0));
+        }
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn test_set_gpr_writes_back_into_the_signal_context() {
+        unsafe {
+            let mut ucontext: libc::ucontext_t = std::mem::zeroed();
+            let siginfo = [0u64; 16];
+            let mut fault = get_fault_info(
+                siginfo.as_ptr() as *const c_void,
+                &mut ucontext as *mut libc::ucontext_t as *mut c_void,
+            );
+
+            assert!(fault.set_gpr(GPR::RAX, 7));
+            assert_eq!(ucontext.uc_mcontext.gregs[libc::REG_RAX as usize], 7);
+            assert_eq!(fault.gpr(GPR::RAX), Some(7));
+            assert!(fault.set_register(X64Register::GPR(GPR::R12), 9));
+            assert_eq!(ucontext.uc_mcontext.gregs[libc::REG_R12 as usize], 9);
+            assert!(!fault.set_register(X64Register::XMM(XMM::XMM0), 1));
+
+            fault.gpr_cells = [None; 16];
+            assert!(!fault.set_gpr(GPR::RBX, 1));
+            assert_eq!(fault.gpr(GPR::RBX), Some(0));
+        }
+    }
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    #[test]
+    fn test_allocate_and_run_switches_stacks() {
+        fn stack_address() -> usize {
+            let x = 0u64;
+            &x as *const u64 as usize
+        }
+
+        let size = 65536;
+        let caller = stack_address();
+        let mut calls = 0;
+        let callee = allocate_and_run(size, || {
+            calls += 1;
+            stack_address()
+        });
+        assert_eq!(calls, 1);
+        assert!(callee < caller - size || callee > caller + size);
+
+        // The stack is switched again by a nested call, and the values are carried back.
+        let (outer, inner) = allocate_and_run(size, || {
+            (stack_address(), allocate_and_run(size, stack_address))
+        });
+        assert!(inner < outer - size || inner > outer + size);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_allocate_and_run_reuses_pooled_trap_stack() {
+        thread::spawn(|| {
+            let size = trap_stack_size();
+            fn stack_address() -> usize {
+                let x = 0u64;
+                &x as *const u64 as usize
+            }
+            let first = allocate_and_run(size, stack_address);
+            let second = allocate_and_run(size, stack_address);
+            assert_eq!(first, second);
+
+            // A nested call cannot use the pooled stack, as it is in use.
+            let (outer, inner) = allocate_and_run(size, || {
+                (stack_address(), allocate_and_run(size, stack_address))
+            });
+            assert!(inner < outer - size || inner > outer + size);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_allocate_and_run_tracks_trap_stack_guard() {
+        thread::spawn(|| {
+            let (address, guards) = allocate_and_run(trap_stack_size(), || {
+                let x = 0u64;
+                let guards = TRAP_STACK_GUARDS.with(|x| x.borrow().clone());
+                (&x as *const u64 as usize, guards)
+            });
+            assert_eq!(guards.len(), 1);
+            assert!(guards[0].end <= address);
+            assert!(address - guards[0].end < trap_stack_size());
+            assert!(TRAP_STACK_GUARDS.with(|x| x.borrow().is_empty()));
+
+            // Not in a guard page, so this returns.
+            abort_on_trap_stack_overflow(address);
+        })
+        .join()
+        .unwrap();
+    }
+
+    /// Allocates stacks from the heap, recording the ranges handed out and the number of
+    /// stacks released.
+    #[cfg(target_arch = "x86_64")]
+    struct VecAltStackProvider {
+        stacks: RefCell<Vec<Box<[u64]>>>,
+        ranges: std::rc::Rc<RefCell<Vec<(usize, usize)>>>,
+        released: std::rc::Rc<Cell<usize>>,
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    impl AltStackProvider for VecAltStackProvider {
+        unsafe fn alloc(&self, size: usize) -> Option<(*mut u64, *mut u64)> {
+            // One more word, so that the end can be aligned to 16 bytes.
+            let mut stack = vec![0u64; size / 8 + 1].into_boxed_slice();
+            let mut begin = stack.as_mut_ptr();
+            if begin as usize % 16 != 0 {
+                begin = begin.add(1);
+            }
+            let end = begin.add(size / 8);
+            self.stacks.borrow_mut().push(stack);
+            self.ranges
+                .borrow_mut()
+                .push((begin as usize, end as usize));
+            Some((begin, end))
+        }
+
+        unsafe fn release(&self, stack_begin: *mut u64, _stack_end: *mut u64) {
+            let stack = self.stacks.borrow_mut().pop().unwrap();
+            assert!(stack_begin as usize - stack.as_ptr() as usize <= 8);
+            self.released.set(self.released.get() + 1);
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_allocate_and_run_uses_alt_stack_provider() {
+        thread::spawn(|| {
+            let ranges = std::rc::Rc::new(RefCell::new(vec![]));
+            let released = std::rc::Rc::new(Cell::new(0));
+            set_alt_stack_provider(Box::new(VecAltStackProvider {
+                stacks: RefCell::new(vec![]),
+                ranges: ranges.clone(),
+                released: released.clone(),
+            }));
+            let size = 65536;
+            let address = allocate_and_run(size, || {
+                let x = 0u64;
+                &x as *const u64 as usize
+            });
+            let (begin, end) = ranges.borrow()[0];
+            assert!(address >= begin && address < end);
+            assert_eq!(end - begin, size);
+            assert_eq!(released.get(), 1);
+
+            clear_alt_stack_provider();
+            allocate_and_run(size, || {});
+            assert_eq!(ranges.borrow().len(), 1);
+            assert_eq!(released.get(), 1);
+        })
+        .join()
+        .unwrap();
+    }
+
+    /// Unmaps a page mapped by `make_code_page` when dropped.
+    #[cfg(target_arch = "x86_64")]
+    struct Unmap(*mut u8);
+
+    #[cfg(target_arch = "x86_64")]
+    impl Drop for Unmap {
+        fn drop(&mut self) {
+            unsafe { MmapFaultAllocator.unmap(self.0, 4096) };
+        }
+    }
+
+    /// Maps a page holding the machine code `bytes`, and returns it as a function along with
+    /// the guard that unmaps it.
+    #[cfg(target_arch = "x86_64")]
+    unsafe fn make_code_page(bytes: &[u8]) -> (extern "C" fn(), Unmap) {
+        let code = MmapFaultAllocator.map(4096, PROT_READ | PROT_WRITE);
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), code, bytes.len());
+        assert_eq!(mprotect(code as _, 4096, PROT_READ | libc::PROT_EXEC), 0);
+        (std::mem::transmute(code), Unmap(code))
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_breakpoint_hits_are_counted() {
+        unsafe {
+            ensure_sighandler();
+            // int3; ret
+            let (f, _code) = make_code_page(&[0xcc, 0xc3]);
+            let ip = f as usize + 1;
+            let mut map: HashMap<usize, BreakpointHandler> = HashMap::new();
+            map.insert(ip, Box::new(|_: BreakpointInfo| Ok(())));
+            let map: BreakpointMap = Arc::new(map);
+
+            set_breakpoint_stats_enabled(true);
+            take_breakpoint_stats();
+            for _ in 0..3 {
+                catch_unsafe_unwind(|| f(), Some(map.clone())).unwrap();
+            }
+            let stats = take_breakpoint_stats();
+            set_breakpoint_stats_enabled(false);
+            catch_unsafe_unwind(|| f(), Some(map.clone())).unwrap();
+
+            assert_eq!(stats.len(), 1);
+            assert_eq!(stats[&ip], 3);
+            assert!(take_breakpoint_stats().is_empty());
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_one_shot_breakpoint_fires_once_per_scope() {
+        unsafe {
+            ensure_sighandler();
+            // int3; ret
+            let (f, _code) = make_code_page(&[0xcc, 0xc3]);
+            let hits = Arc::new(AtomicUsize::new(0));
+            let mut map: HashMap<usize, BreakpointHandler> = HashMap::new();
+            let counter = hits.clone();
+            map.insert(
+                f as usize + 1,
+                one_shot_breakpoint(move |_: BreakpointInfo| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    Ok(())
+                }),
+            );
+            let map: BreakpointMap = Arc::new(map);
+
+            catch_unsafe_unwind(
+                || {
+                    f();
+                    f();
+                    f();
+                },
+                Some(map.clone()),
+            )
+            .unwrap();
+            assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+            // A new scope starts with the breakpoint armed again.
+            catch_unsafe_unwind(|| f(), Some(map.clone())).unwrap();
+            assert_eq!(hits.load(Ordering::SeqCst), 2);
+        }
     }
 
-    #[allow(dead_code)]
-    #[allow(non_camel_case_types)]
-    #[repr(C)]
-    struct siginfo_t {
-        si_signo: i32,
-        si_errno: i32,
-        si_code: i32,
-        si_addr: u64,
-        // ...
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_ignore_count_breakpoint_skips_first_hits_per_scope() {
+        unsafe {
+            ensure_sighandler();
+            // int3; ret
+            let (f, _code) = make_code_page(&[0xcc, 0xc3]);
+            let hits = Arc::new(AtomicUsize::new(0));
+            let mut map: HashMap<usize, BreakpointHandler> = HashMap::new();
+            let counter = hits.clone();
+            map.insert(
+                f as usize + 1,
+                ignore_count_breakpoint(2, move |_: BreakpointInfo| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    Ok(())
+                }),
+            );
+            let map: BreakpointMap = Arc::new(map);
+
+            catch_unsafe_unwind(
+                || {
+                    for _ in 0..4 {
+                        f();
+                    }
+                },
+                Some(map.clone()),
+            )
+            .unwrap();
+            assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+            // A new scope starts counting again.
+            catch_unsafe_unwind(|| f(), Some(map.clone())).unwrap();
+            assert_eq!(hits.load(Ordering::SeqCst), 2);
+        }
     }
 
-    let siginfo = siginfo as *const siginfo_t;
-    let si_addr = (*siginfo).si_addr;
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_trap_stack_high_water_covers_used_stack() {
+        reset_trap_stack_high_water();
+        allocate_and_run(65536, || ());
+        assert_eq!(trap_stack_high_water(), 0);
 
-    let ucontext = ucontext as *mut ucontext;
-    let gregs = &(*ucontext).uc_mcontext.regs;
+        set_trap_stack_watermark(true);
+        allocate_and_run(65536, || {
+            let buffer = [1u8; 16384];
+            unsafe { std::ptr::read_volatile(&buffer) };
+        });
+        set_trap_stack_watermark(false);
+        let used = trap_stack_high_water();
+        assert!(used >= 16384 && used < 65536, "{}", used);
 
-    let mut known_registers: [Option<u64>; 32] = [None; 32];
+        reset_trap_stack_high_water();
+        assert_eq!(trap_stack_high_water(), 0);
+    }
 
-    known_registers[X64Register::GPR(GPR::R15).to_index().0] = Some(gregs[15] as _);
-    known_registers[X64Register::GPR(GPR::R14).to_index().0] = Some(gregs[14] as _);
-    known_registers[X64Register::GPR(GPR::R13).to_index().0] = Some(gregs[13] as _);
-    known_registers[X64Register::GPR(GPR::R12).to_index().0] = Some(gregs[12] as _);
-    known_registers[X64Register::GPR(GPR::R11).to_index().0] = Some(gregs[11] as _);
-    known_registers[X64Register::GPR(GPR::R10).to_index().0] = Some(gregs[10] as _);
-    known_registers[X64Register::GPR(GPR::R9).to_index().0] = Some(gregs[9] as _);
-    known_registers[X64Register::GPR(GPR::R8).to_index().0] = Some(gregs[8] as _);
-    known_registers[X64Register::GPR(GPR::RSI).to_index().0] = Some(gregs[6] as _);
-    known_registers[X64Register::GPR(GPR::RDI).to_index().0] = Some(gregs[7] as _);
-    known_registers[X64Register::GPR(GPR::RDX).to_index().0] = Some(gregs[2] as _);
-    known_registers[X64Register::GPR(GPR::RCX).to_index().0] = Some(gregs[1] as _);
-    known_registers[X64Register::GPR(GPR::RBX).to_index().0] = Some(gregs[3] as _);
-    known_registers[X64Register::GPR(GPR::RAX).to_index().0] = Some(gregs[0] as _);
+    #[test]
+    fn test_init_interrupt_signal_mem_is_idempotent() {
+        init_interrupt_signal_mem();
+        let mem = unsafe { get_wasm_interrupt_signal_mem() };
+        init_interrupt_signal_mem();
+        assert!(!mem.is_null());
+        assert_eq!(unsafe { get_wasm_interrupt_signal_mem() }, mem);
+    }
 
-    known_registers[X64Register::GPR(GPR::RBP).to_index().0] = Some(gregs[5] as _);
-    known_registers[X64Register::GPR(GPR::RSP).to_index().0] = Some(gregs[28] as _);
+    #[test]
+    fn test_interrupt_signal_mem_spans_one_page() {
+        init_interrupt_signal_mem();
+        let mem = unsafe { get_wasm_interrupt_signal_mem() } as usize;
+        assert_eq!(*INTERRUPT_SIGNAL_MEM_SIZE, page_size::get());
+        assert_eq!(mem % page_size::get(), 0);
+    }
 
-    FaultInfo {
-        faulting_addr: si_addr as usize as _,
-        ip: std::mem::transmute::<&mut u64, &'static Cell<usize>>(&mut (*ucontext).uc_mcontext.pc),
-        known_registers,
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_unclassified_fault_reports_unknown_trap() {
+        unsafe {
+            ensure_sighandler();
+            // ud2
+            let (f, _code) = make_code_page(&[0x0f, 0x0b]);
+
+            let result = catch_unsafe_unwind(|| f(), None);
+            match result {
+                Err(RuntimeError::InvokeError(InvokeError::UnknownTrap { ip, signal, .. })) => {
+                    assert_eq!(ip, Some(f as usize));
+                    assert_eq!(signal, "illegal instruction");
+                }
+                other => panic!("expected an unknown trap, got {:?}", other.err()),
+            }
+        }
     }
-}
 
-#[cfg(all(
-    any(target_os = "linux", target_os = "android"),
-    target_arch = "x86_64"
-))]
-/// Get fault info from siginfo and ucontext.
-pub unsafe fn get_fault_info(siginfo: *const c_void, ucontext: *mut c_void) -> FaultInfo {
-    use libc::{
-        ucontext_t, REG_R10, REG_R11, REG_R12, REG_R13, REG_R14, REG_R15, REG_R8, REG_R9, REG_RAX,
-        REG_RBP, REG_RBX, REG_RCX, REG_RDI, REG_RDX, REG_RIP, REG_RSI, REG_RSP,
-    };
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_breakpoint_can_request_suspend() {
+        unsafe {
+            ensure_sighandler();
+            // int3; ret
+            let (f, _code) = make_code_page(&[0xcc, 0xc3]);
+            let mut map: HashMap<usize, BreakpointHandler> = HashMap::new();
+            map.insert(
+                f as usize + 1,
+                Box::new(|info: BreakpointInfo| {
+                    info.request_suspend();
+                    Ok(())
+                }),
+            );
 
-    #[cfg(not(target_env = "musl"))]
-    fn read_xmm(reg: &libc::_libc_xmmreg) -> u64 {
-        (reg.element[0] as u64) | ((reg.element[1] as u64) << 32)
+            let result = catch_unsafe_unwind(|| f(), Some(Arc::new(map)));
+            match result {
+                Err(RuntimeError::InstanceImage(image)) => {
+                    let image = image.downcast::<InstanceImage>().unwrap();
+                    assert!(image.memory.is_none());
+                }
+                Err(e) => panic!("expected an instance image, got {:?}", e),
+                Ok(()) => panic!("expected an instance image"),
+            }
+        }
     }
 
-    #[allow(dead_code)]
-    #[repr(C)]
-    struct siginfo_t {
-        si_signo: i32,
-        si_errno: i32,
-        si_code: i32,
-        si_addr: u64,
-        // ...
+    #[cfg(target_arch = "x86_64")]
+    fn trace_breakpoint(fault: &FaultInfo) {
+        TRACED_IPS.with(|x| x.borrow_mut().push(fault.ip.get()));
     }
 
-    let siginfo = siginfo as *const siginfo_t;
-    let si_addr = (*siginfo).si_addr;
+    thread_local! {
+        #[cfg(target_arch = "x86_64")]
+        static TRACED_IPS: RefCell<Vec<usize>> = RefCell::new(vec![]);
+    }
 
-    let ucontext = ucontext as *mut ucontext_t;
-    let gregs = &mut (*ucontext).uc_mcontext.gregs;
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_trace_and_counter_inline_breakpoints_resume() {
+        unsafe {
+            ensure_sighandler();
+            let mut bytes = vec![];
+            for &ty in &[
+                InlineBreakpointType::Trace,
+                InlineBreakpointType::Counter,
+                InlineBreakpointType::Counter,
+            ] {
+                bytes.extend_from_slice(&INLINE_BREAKPOINT_MAGIC);
+                bytes.push(ty as u8);
+            }
+            // ret
+            bytes.push(0xc3);
+            let (f, _code) = make_code_page(&bytes);
+            let code = f as *const u8;
+            let site_size = INLINE_BREAKPOINT_MAGIC.len() + 1;
 
-    let mut known_registers: [Option<u64>; 32] = [None; 32];
-    known_registers[X64Register::GPR(GPR::R15).to_index().0] = Some(gregs[REG_R15 as usize] as _);
-    known_registers[X64Register::GPR(GPR::R14).to_index().0] = Some(gregs[REG_R14 as usize] as _);
-    known_registers[X64Register::GPR(GPR::R13).to_index().0] = Some(gregs[REG_R13 as usize] as _);
-    known_registers[X64Register::GPR(GPR::R12).to_index().0] = Some(gregs[REG_R12 as usize] as _);
-    known_registers[X64Register::GPR(GPR::R11).to_index().0] = Some(gregs[REG_R11 as usize] as _);
-    known_registers[X64Register::GPR(GPR::R10).to_index().0] = Some(gregs[REG_R10 as usize] as _);
-    known_registers[X64Register::GPR(GPR::R9).to_index().0] = Some(gregs[REG_R9 as usize] as _);
-    known_registers[X64Register::GPR(GPR::R8).to_index().0] = Some(gregs[REG_R8 as usize] as _);
-    known_registers[X64Register::GPR(GPR::RSI).to_index().0] = Some(gregs[REG_RSI as usize] as _);
-    known_registers[X64Register::GPR(GPR::RDI).to_index().0] = Some(gregs[REG_RDI as usize] as _);
-    known_registers[X64Register::GPR(GPR::RDX).to_index().0] = Some(gregs[REG_RDX as usize] as _);
-    known_registers[X64Register::GPR(GPR::RCX).to_index().0] = Some(gregs[REG_RCX as usize] as _);
-    known_registers[X64Register::GPR(GPR::RBX).to_index().0] = Some(gregs[REG_RBX as usize] as _);
-    known_registers[X64Register::GPR(GPR::RAX).to_index().0] = Some(gregs[REG_RAX as usize] as _);
+            set_trace_breakpoint_handler(trace_breakpoint);
+            take_counter_breakpoint_counts();
+            push_code_version(dummy_code_version_with_module(
+                std::slice::from_raw_parts(code, bytes.len()),
+                DummyModule {
+                    inline_breakpoints: true,
+                    ..Default::default()
+                },
+            ));
+            for _ in 0..2 {
+                catch_unsafe_unwind(|| f(), None).unwrap();
+            }
+            pop_code_version();
+            clear_trace_breakpoint_handler();
 
-    known_registers[X64Register::GPR(GPR::RBP).to_index().0] = Some(gregs[REG_RBP as usize] as _);
-    known_registers[X64Register::GPR(GPR::RSP).to_index().0] = Some(gregs[REG_RSP as usize] as _);
+            let traced = TRACED_IPS.with(|x| x.replace(vec![]));
+            assert_eq!(traced, vec![code as usize, code as usize]);
+            let counts = take_counter_breakpoint_counts();
+            assert_eq!(counts.len(), 2);
+            assert_eq!(counts[&(code as usize + site_size)], 2);
+            assert_eq!(counts[&(code as usize + 2 * site_size)], 2);
+        }
+    }
 
-    // Skip reading floating point registers when building with musl libc.
-    // FIXME: Depends on https://github.com/rust-lang/libc/pull/1646
-    #[cfg(not(target_env = "musl"))]
-    {
-        use crate::state::x64::XMM;
-        if !(*ucontext).uc_mcontext.fpregs.is_null() {
-            let fpregs = &*(*ucontext).uc_mcontext.fpregs;
-            known_registers[X64Register::XMM(XMM::XMM0).to_index().0] =
-                Some(read_xmm(&fpregs._xmm[0]));
-            known_registers[X64Register::XMM(XMM::XMM1).to_index().0] =
-                Some(read_xmm(&fpregs._xmm[1]));
-            known_registers[X64Register::XMM(XMM::XMM2).to_index().0] =
-                Some(read_xmm(&fpregs._xmm[2]));
-            known_registers[X64Register::XMM(XMM::XMM3).to_index().0] =
-                Some(read_xmm(&fpregs._xmm[3]));
-            known_registers[X64Register::XMM(XMM::XMM4).to_index().0] =
-                Some(read_xmm(&fpregs._xmm[4]));
-            known_registers[X64Register::XMM(XMM::XMM5).to_index().0] =
-                Some(read_xmm(&fpregs._xmm[5]));
-            known_registers[X64Register::XMM(XMM::XMM6).to_index().0] =
-                Some(read_xmm(&fpregs._xmm[6]));
-            known_registers[X64Register::XMM(XMM::XMM7).to_index().0] =
-                Some(read_xmm(&fpregs._xmm[7]));
-            known_registers[X64Register::XMM(XMM::XMM8).to_index().0] =
-                Some(read_xmm(&fpregs._xmm[8]));
-            known_registers[X64Register::XMM(XMM::XMM9).to_index().0] =
-                Some(read_xmm(&fpregs._xmm[9]));
-            known_registers[X64Register::XMM(XMM::XMM10).to_index().0] =
-                Some(read_xmm(&fpregs._xmm[10]));
-            known_registers[X64Register::XMM(XMM::XMM11).to_index().0] =
-                Some(read_xmm(&fpregs._xmm[11]));
-            known_registers[X64Register::XMM(XMM::XMM12).to_index().0] =
-                Some(read_xmm(&fpregs._xmm[12]));
-            known_registers[X64Register::XMM(XMM::XMM13).to_index().0] =
-                Some(read_xmm(&fpregs._xmm[13]));
-            known_registers[X64Register::XMM(XMM::XMM14).to_index().0] =
-                Some(read_xmm(&fpregs._xmm[14]));
-            known_registers[X64Register::XMM(XMM::XMM15).to_index().0] =
-                Some(read_xmm(&fpregs._xmm[15]));
+    #[test]
+    fn test_counter_breakpoint_counts_are_bounded() {
+        take_counter_breakpoint_counts();
+        for ip in 1..=MAX_COUNTER_BREAKPOINT_SITES + 8 {
+            count_counter_breakpoint_hit(ip * 16);
         }
+        count_counter_breakpoint_hit(16);
+
+        let counts = take_counter_breakpoint_counts();
+        assert_eq!(counts.len(), MAX_COUNTER_BREAKPOINT_SITES);
+        assert_eq!(counts[&16], 2);
+        assert!(!counts.contains_key(&((MAX_COUNTER_BREAKPOINT_SITES + 1) * 16)));
+        assert!(take_counter_breakpoint_counts().is_empty());
     }
 
-    FaultInfo {
-        faulting_addr: si_addr as usize as _,
-        ip: std::mem::transmute::<&mut i64, &'static Cell<usize>>(&mut gregs[REG_RIP as usize]),
-        known_registers,
+    #[test]
+    fn test_is_wasm_address_finds_innermost_code_version() {
+        let code = [0u8; 16];
+        push_code_version(dummy_code_version(&code));
+        push_code_version(dummy_code_version(&code[..8]));
+        let base = code.as_ptr() as usize;
+
+        assert_eq!(
+            is_wasm_address(base + 4),
+            Some(CodeVersionRef {
+                index: 1,
+                base,
+                offset: 4,
+                backend: "dummy",
+            })
+        );
+        assert_eq!(
+            is_wasm_address(base + 12).map(|x| (x.index, x.offset)),
+            Some((0, 12))
+        );
+        assert_eq!(is_wasm_address(base + 16), None);
+
+        pop_code_version();
+        pop_code_version();
+        assert_eq!(is_wasm_address(base), None);
     }
-}
 
-/// Get fault info from siginfo and ucontext.
-#[cfg(all(target_os = "macos", target_arch = "x86_64"))]
-pub unsafe fn get_fault_info(siginfo: *const c_void, ucontext: *mut c_void) -> FaultInfo {
-    use crate::state::x64::XMM;
-    #[allow(dead_code)]
-    #[repr(C)]
-    struct ucontext_t {
-        uc_onstack: u32,
-        uc_sigmask: u32,
-        uc_stack: libc::stack_t,
-        uc_link: *const ucontext_t,
-        uc_mcsize: u64,
-        uc_mcontext: *mut mcontext_t,
+    #[test]
+    fn test_code_version_guard_pops_on_panic() {
+        let code = [0u8; 16];
+        let base = code.as_ptr() as usize;
+        let _outer = register_code_version(dummy_code_version(&code));
+        let result = std::panic::catch_unwind(|| {
+            let _inner = register_code_version(dummy_code_version(&code));
+            assert_eq!(is_wasm_address(base).unwrap().index, 1);
+            panic!("unwinding past the guard");
+        });
+        assert!(result.is_err());
+        assert_eq!(is_wasm_address(base).unwrap().index, 0);
     }
-    #[repr(C)]
-    struct exception_state {
-        trapno: u16,
-        cpu: u16,
-        err: u32,
-        faultvaddr: u64,
+
+    #[test]
+    fn test_with_code_versions_lists_pushed_versions() {
+        let outer = [0u8; 16];
+        let inner = [0u8; 16];
+        let _outer = register_code_version(dummy_code_version(&outer));
+        let _inner = register_code_version(dummy_code_version(&inner));
+        let bases =
+            with_code_versions(|versions| versions.iter().map(|v| v.base).collect::<Vec<_>>());
+        assert_eq!(
+            bases,
+            vec![outer.as_ptr() as usize, inner.as_ptr() as usize]
+        );
     }
-    #[repr(C)]
-    struct regs {
-        rax: u64,
-        rbx: u64,
-        rcx: u64,
-        rdx: u64,
-        rdi: u64,
-        rsi: u64,
-        rbp: u64,
-        rsp: u64,
-        r8: u64,
-        r9: u64,
-        r10: u64,
-        r11: u64,
-        r12: u64,
-        r13: u64,
-        r14: u64,
-        r15: u64,
-        rip: u64,
-        rflags: u64,
-        cs: u64,
-        fs: u64,
-        gs: u64,
+
+    #[test]
+    fn test_sigint_is_delivered_to_active_ctxs_only() {
+        let _lock = SIGINT_TEST_LOCK.lock().unwrap();
+        let active = 0x20 as *mut vm::Ctx;
+        let inactive = 0x30 as *mut vm::Ctx;
+
+        unsafe {
+            with_ctx(active, || {
+                assert!(deliver_sigint_to_active_ctxs());
+                assert!(is_sigint_delivered_to(active));
+                assert!(!is_sigint_delivered_to(inactive));
+                assert!(take_sigint_delivered(active));
+                assert!(!is_sigint_delivered_to(active));
+
+                // An unconsumed SIGINT is dropped when the call returns.
+                assert!(deliver_sigint_to_active_ctxs());
+            });
+        }
+        assert!(!is_sigint_delivered_to(active));
     }
-    #[repr(C)]
-    struct fpstate {
-        _cwd: u16,
-        _swd: u16,
-        _ftw: u16,
-        _fop: u16,
-        _rip: u64,
-        _rdp: u64,
-        _mxcsr: u32,
-        _mxcr_mask: u32,
-        _st: [[u16; 8]; 8],
-        xmm: [[u64; 2]; 16],
-        _padding: [u32; 24],
+
+    #[test]
+    fn test_double_sigint_handler_is_taken_once() {
+        static DOUBLE_SIGINTS: AtomicUsize = AtomicUsize::new(0);
+
+        let _lock = SIGINT_TEST_LOCK.lock().unwrap();
+        set_double_sigint_handler(|| {
+            DOUBLE_SIGINTS.fetch_add(1, Ordering::SeqCst);
+        });
+        // The handler only replaces the abort.
+        set_double_sigint_policy(DoubleSigintPolicy::IgnoreSecond);
+        raise_double_sigint();
+        set_double_sigint_policy(DoubleSigintPolicy::Abort);
+        assert_eq!(DOUBLE_SIGINTS.load(Ordering::SeqCst), 0);
+
+        assert_eq!(raise_double_sigint(), 1);
+        assert_eq!(DOUBLE_SIGINTS.load(Ordering::SeqCst), 1);
+        assert!(take_double_sigint_handler().is_none());
+
+        set_double_sigint_handler(|| {});
+        clear_double_sigint_handler();
+        assert!(take_double_sigint_handler().is_none());
     }
-    #[allow(dead_code)]
-    #[repr(C)]
-    struct mcontext_t {
-        es: exception_state,
-        ss: regs,
-        fs: fpstate,
+
+    static FORWARDED_SIGINTS: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn count_forwarded_sigint(_: libc::c_int) {
+        FORWARDED_SIGINTS.fetch_add(1, Ordering::SeqCst);
     }
 
-    let siginfo = siginfo as *const siginfo_t;
-    let si_addr = (*siginfo).si_addr;
+    /// Raises two SIGINTs while a `Ctx` is active, with `sigint_handler` installed in place of
+    /// `count_forwarded_sigint`. Returns the number of SIGINTs forwarded to the latter.
+    fn raise_double_sigint() -> usize {
+        // Install the handlers first, so that they do not replace the ones set up here.
+        ensure_sighandler();
+        let ctx = 0x40 as *mut vm::Ctx;
+        unsafe {
+            let ours = SigAction::new(
+                SigHandler::SigAction(sigint_handler),
+                SaFlags::SA_ONSTACK,
+                SigSet::empty(),
+            );
+            let old = sigaction(SIGINT, &ours).unwrap();
+            let sys_handler = SIGINT_SYS_HANDLER;
+            SIGINT_SYS_HANDLER = Some(SigAction::new(
+                SigHandler::Handler(count_forwarded_sigint),
+                SaFlags::empty(),
+                SigSet::empty(),
+            ));
+            FORWARDED_SIGINTS.store(0, Ordering::SeqCst);
 
-    let ucontext = ucontext as *mut ucontext_t;
-    let ss = &mut (*(*ucontext).uc_mcontext).ss;
-    let fs = &(*(*ucontext).uc_mcontext).fs;
+            with_ctx(ctx, || {
+                libc::raise(libc::SIGINT);
+                assert!(is_sigint_delivered_to(ctx));
+                libc::raise(libc::SIGINT);
+            });
+            clear_wasm_interrupt();
 
-    let mut known_registers: [Option<u64>; 32] = [None; 32];
+            SIGINT_SYS_HANDLER = sys_handler;
+            sigaction(SIGINT, &old).unwrap();
+        }
+        FORWARDED_SIGINTS.load(Ordering::SeqCst)
+    }
 
-    known_registers[X64Register::GPR(GPR::R15).to_index().0] = Some(ss.r15);
-    known_registers[X64Register::GPR(GPR::R14).to_index().0] = Some(ss.r14);
-    known_registers[X64Register::GPR(GPR::R13).to_index().0] = Some(ss.r13);
-    known_registers[X64Register::GPR(GPR::R12).to_index().0] = Some(ss.r12);
-    known_registers[X64Register::GPR(GPR::R11).to_index().0] = Some(ss.r11);
-    known_registers[X64Register::GPR(GPR::R10).to_index().0] = Some(ss.r10);
-    known_registers[X64Register::GPR(GPR::R9).to_index().0] = Some(ss.r9);
-    known_registers[X64Register::GPR(GPR::R8).to_index().0] = Some(ss.r8);
-    known_registers[X64Register::GPR(GPR::RSI).to_index().0] = Some(ss.rsi);
-    known_registers[X64Register::GPR(GPR::RDI).to_index().0] = Some(ss.rdi);
-    known_registers[X64Register::GPR(GPR::RDX).to_index().0] = Some(ss.rdx);
-    known_registers[X64Register::GPR(GPR::RCX).to_index().0] = Some(ss.rcx);
-    known_registers[X64Register::GPR(GPR::RBX).to_index().0] = Some(ss.rbx);
-    known_registers[X64Register::GPR(GPR::RAX).to_index().0] = Some(ss.rax);
+    #[test]
+    fn test_double_sigint_policy_applies_to_second_sigint() {
+        static DOUBLE_SIGINTS: AtomicUsize = AtomicUsize::new(0);
 
-    known_registers[X64Register::GPR(GPR::RBP).to_index().0] = Some(ss.rbp);
-    known_registers[X64Register::GPR(GPR::RSP).to_index().0] = Some(ss.rsp);
+        let _lock = SIGINT_TEST_LOCK.lock().unwrap();
+        assert_eq!(double_sigint_policy(), DoubleSigintPolicy::Abort);
 
-    known_registers[X64Register::XMM(XMM::XMM0).to_index().0] = Some(fs.xmm[0][0]);
-    known_registers[X64Register::XMM(XMM::XMM1).to_index().0] = Some(fs.xmm[1][0]);
-    known_registers[X64Register::XMM(XMM::XMM2).to_index().0] = Some(fs.xmm[2][0]);
-    known_registers[X64Register::XMM(XMM::XMM3).to_index().0] = Some(fs.xmm[3][0]);
-    known_registers[X64Register::XMM(XMM::XMM4).to_index().0] = Some(fs.xmm[4][0]);
-    known_registers[X64Register::XMM(XMM::XMM5).to_index().0] = Some(fs.xmm[5][0]);
-    known_registers[X64Register::XMM(XMM::XMM6).to_index().0] = Some(fs.xmm[6][0]);
-    known_registers[X64Register::XMM(XMM::XMM7).to_index().0] = Some(fs.xmm[7][0]);
-    known_registers[X64Register::XMM(XMM::XMM8).to_index().0] = Some(fs.xmm[8][0]);
-    known_registers[X64Register::XMM(XMM::XMM9).to_index().0] = Some(fs.xmm[9][0]);
-    known_registers[X64Register::XMM(XMM::XMM10).to_index().0] = Some(fs.xmm[10][0]);
-    known_registers[X64Register::XMM(XMM::XMM11).to_index().0] = Some(fs.xmm[11][0]);
-    known_registers[X64Register::XMM(XMM::XMM12).to_index().0] = Some(fs.xmm[12][0]);
-    known_registers[X64Register::XMM(XMM::XMM13).to_index().0] = Some(fs.xmm[13][0]);
-    known_registers[X64Register::XMM(XMM::XMM14).to_index().0] = Some(fs.xmm[14][0]);
-    known_registers[X64Register::XMM(XMM::XMM15).to_index().0] = Some(fs.xmm[15][0]);
+        set_double_sigint_policy(DoubleSigintPolicy::IgnoreSecond);
+        assert_eq!(double_sigint_policy(), DoubleSigintPolicy::IgnoreSecond);
+        assert_eq!(raise_double_sigint(), 1);
 
-    FaultInfo {
-        faulting_addr: si_addr,
-        ip: std::mem::transmute::<&mut u64, &'static Cell<usize>>(&mut ss.rip),
-        known_registers,
+        set_double_sigint_policy(DoubleSigintPolicy::ForwardToPrevious);
+        assert_eq!(
+            double_sigint_policy(),
+            DoubleSigintPolicy::ForwardToPrevious
+        );
+        assert_eq!(raise_double_sigint(), 2);
+
+        // The process is not aborted while a double SIGINT handler is set.
+        set_double_sigint_policy(DoubleSigintPolicy::Abort);
+        assert_eq!(double_sigint_policy(), DoubleSigintPolicy::Abort);
+        set_double_sigint_handler(|| {
+            DOUBLE_SIGINTS.fetch_add(1, Ordering::SeqCst);
+        });
+        assert_eq!(raise_double_sigint(), 1);
+        assert_eq!(DOUBLE_SIGINTS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_install_sighandler_catches_sigabrt_if_enabled() {
+        unsafe fn noop_sigaction(_: Signal, _: &SigAction) -> nix::Result<SigAction> {
+            Ok(SigAction::new(
+                SigHandler::SigDfl,
+                SaFlags::empty(),
+                SigSet::empty(),
+            ))
+        }
+
+        let report =
+            unsafe { install_sighandler_with(noop_sigaction, false, &mut [None; 4]) }.unwrap();
+        assert!(!report.installed.contains(&SIGABRT));
+
+        set_catch_sigabrt(true);
+        let report =
+            unsafe { install_sighandler_with(noop_sigaction, false, &mut [None; 4]) }.unwrap();
+        set_catch_sigabrt(false);
+        assert!(report.installed.contains(&SIGABRT));
+        assert!(previous_trap_handler(SIGABRT as i32).is_some());
+        unsafe {
+            SIGABRT_SYS_HANDLER = None;
+        }
+    }
+
+    #[test]
+    fn test_install_sighandler_saves_previous_handlers() {
+        unsafe fn ignoring_sigaction(_: Signal, _: &SigAction) -> nix::Result<SigAction> {
+            Ok(SigAction::new(
+                SigHandler::SigIgn,
+                SaFlags::empty(),
+                SigSet::empty(),
+            ))
+        }
+
+        let mut previous = [None; 4];
+        unsafe { install_sighandler_with(ignoring_sigaction, false, &mut previous) }.unwrap();
+        for (i, prev) in previous.iter().enumerate() {
+            match prev.map(|x| x.handler()) {
+                Some(SigHandler::SigIgn) => {}
+                _ => panic!("previous handler of {:?} was not saved", CHAINED_SIGNALS[i]),
+            }
+        }
+    }
+
+    #[test]
+    fn test_faults_outside_code_versions_are_foreign() {
+        let code = vec![0u8; 64];
+        let ip = code.as_ptr() as usize + 8;
+        assert!(is_foreign_fault(ip));
+        unsafe {
+            catch_unsafe_unwind(
+                || {
+                    // Without code versions, every fault is attributed to wasm code.
+                    assert!(!is_foreign_fault(ip));
+                    push_code_version(dummy_code_version(&code));
+                    assert!(!is_foreign_fault(ip));
+                    assert!(is_foreign_fault(ip + 64));
+                    assert!(!is_foreign_fault(0));
+                    pop_code_version();
+                },
+                None,
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_panic_unwind_strategy_runs_host_destructors() {
+        static DROPPED: AtomicBool = AtomicBool::new(false);
+        struct HostData;
+        impl Drop for HostData {
+            fn drop(&mut self) {
+                DROPPED.store(true, Ordering::SeqCst);
+            }
+        }
+
+        set_unwind_strategy(UnwindStrategy::Panic);
+        let result = unsafe {
+            catch_unsafe_unwind(
+                || {
+                    // As in the wrapper of a host function called from wasm.
+                    let caught = panic::catch_unwind(|| {
+                        let _host_function = HostFunctionGuard::enter(true);
+                        let _data = HostData;
+                        begin_unsafe_unwind(Box::new(RuntimeError::InvokeError(
+                            InvokeError::Timeout,
+                        )))
+                    });
+                    let error = runtime_error_from_panic(caught.unwrap_err());
+                    begin_unsafe_unwind_with_longjmp(Box::new(error))
+                },
+                None,
+            )
+        };
+        set_unwind_strategy(UnwindStrategy::Longjmp);
+        assert!(DROPPED.load(Ordering::SeqCst));
+        match result {
+            Err(RuntimeError::InvokeError(InvokeError::Timeout)) => {}
+            x => panic!("unexpected result: {:?}", x.map(|_| ())),
+        }
     }
 }
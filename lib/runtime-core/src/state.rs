@@ -3,7 +3,8 @@
 //! generated code from one tier to another, or serializing state of a running instace.
 
 use crate::backend::RunnableModule;
-use std::collections::BTreeMap;
+use crate::types::LocalGlobalIndex;
+use std::collections::{BTreeMap, HashMap};
 use std::ops::Bound::{Included, Unbounded};
 use std::sync::Arc;
 
@@ -155,11 +156,76 @@ pub struct WasmFunctionStateDump {
     pub locals: Vec<Option<u64>>,
 }
 
+/// A value of a local or of the value stack of a frame, recovered from an execution state image.
+///
+/// Values are the raw 64-bit slots of the machine state; their wasm type is given by the
+/// signature and body of the function.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum WasmValue {
+    /// The recovered value.
+    Known(u64),
+    /// The value was held in a register that was not captured when the image was taken.
+    Unavailable,
+}
+
+impl WasmValue {
+    /// Returns the recovered value, or `None` if it is unavailable.
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            WasmValue::Known(x) => Some(x),
+            WasmValue::Unavailable => None,
+        }
+    }
+}
+
+impl From<Option<u64>> for WasmValue {
+    fn from(x: Option<u64>) -> WasmValue {
+        x.map(WasmValue::Known).unwrap_or(WasmValue::Unavailable)
+    }
+}
+
+/// A frame of a backtrace, innermost first.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BacktraceFrame {
+    /// Local function id.
+    pub local_function_id: usize,
+    /// Wasm instruction offset.
+    pub wasm_inst_offset: usize,
+    /// Recovered values of the locals, `None` where unknown.
+    pub locals: Vec<Option<u64>>,
+    /// Recovered values of the value stack, `None` where unknown.
+    pub stack: Vec<Option<u64>>,
+}
+
+/// The maximum number of frames `read_stack` captures, whatever depth limit is requested.
+///
+/// Defaults to 4096 and can be overridden by setting `WASMER_MAX_BACKTRACE_FRAMES` at build time.
+pub const MAX_BACKTRACE_FRAMES: usize =
+    include!(concat!(env!("OUT_DIR"), "/max_backtrace_frames.rs"));
+
 /// An image of the execution state.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ExecutionStateImage {
     /// Frames.
     pub frames: Vec<WasmFunctionStateDump>,
+    /// Whether frames were dropped because the stack is deeper than `MAX_BACKTRACE_FRAMES`.
+    #[serde(default)]
+    pub truncated: bool,
+    /// Host functions that called back into wasm, between the frames of the backtrace.
+    #[serde(default)]
+    pub host_frames: Vec<HostFrame>,
+}
+
+/// A host function called from wasm that called back into wasm, in a backtrace.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostFrame {
+    /// The number of wasm frames of the backtrace called by the host function, i.e. the index
+    /// in `frames` of the frame that called it.
+    pub depth: usize,
+    /// Index of the imported function.
+    pub import_index: usize,
+    /// `namespace.name` of the import, if it could be resolved.
+    pub name: Option<String>,
 }
 
 /// Represents an image of an `Instance` including its memory, globals, and execution state.
@@ -171,6 +237,17 @@ pub struct InstanceImage {
     pub globals: Vec<u128>,
     /// `ExecutionStateImage` for this `InstanceImage`
     pub execution_state: ExecutionStateImage,
+    /// Trace id attached to the interrupt that produced this `InstanceImage`, if any.
+    #[serde(default)]
+    pub interrupt_trace_id: Option<u64>,
+    /// Hash of the machine code the image was taken from, checked by
+    /// `fault::resume_from_image`.
+    #[serde(default)]
+    pub code_hash: Option<u64>,
+    /// Whether the interrupt that produced this `InstanceImage` was triggered by SIGINT, as
+    /// reported by `fault::was_sigint_triggered_fault` while the fault was handled.
+    #[serde(default)]
+    pub from_sigint: bool,
 }
 
 /// A `CodeVersion` is a container for a unit of generated code for a module.
@@ -190,6 +267,10 @@ pub struct CodeVersion {
 
     /// `RunnableModule` for this code version.
     pub runnable_module: Arc<Box<dyn RunnableModule>>,
+
+    /// Symbol names of local functions, keyed by local function index, used to symbolicate
+    /// backtraces of faults in this code version.
+    pub function_names: Option<Arc<HashMap<u32, String>>>,
 }
 
 impl ModuleStateMap {
@@ -200,14 +281,24 @@ impl ModuleStateMap {
         base: usize,
         offset_table_provider: F,
     ) -> Option<(&FunctionStateMap, MachineState)> {
+        self.lookup_diff(ip, base, offset_table_provider)
+            .map(|(fsm, diff)| (fsm, diff.build_state(fsm)))
+    }
+
+    /// Looks up the `MachineStateDiff` of an ip, without building the machine state.
+    fn lookup_diff<F: FnOnce(&FunctionStateMap) -> &BTreeMap<usize, OffsetInfo>>(
+        &self,
+        ip: usize,
+        base: usize,
+        offset_table_provider: F,
+    ) -> Option<(&FunctionStateMap, &MachineStateDiff)> {
         if ip < base || ip - base >= self.total_size {
             None
         } else {
             let (_, fsm) = self
                 .local_functions
                 .range((Unbounded, Included(&(ip - base))))
-                .last()
-                .unwrap();
+                .last()?;
 
             match offset_table_provider(fsm)
                 .range((Unbounded, Included(&(ip - base))))
@@ -217,7 +308,7 @@ impl ModuleStateMap {
                     if ip - base >= x.end_offset {
                         None
                     } else if x.diff_id < fsm.diffs.len() {
-                        Some((fsm, fsm.diffs[x.diff_id].build_state(fsm)))
+                        Some((fsm, &fsm.diffs[x.diff_id]))
                     } else {
                         None
                     }
@@ -226,6 +317,15 @@ impl ModuleStateMap {
             }
         }
     }
+
+    /// Looks up a call, trappable or loop ip from self and returns its function state map and
+    /// wasm instruction offset. Unlike `lookup_call_ip` and friends, this does not allocate.
+    pub fn lookup_frame_ip(&self, ip: usize, base: usize) -> Option<(&FunctionStateMap, usize)> {
+        self.lookup_diff(ip, base, |fsm| &fsm.call_offsets)
+            .or_else(|| self.lookup_diff(ip, base, |fsm| &fsm.trappable_offsets))
+            .or_else(|| self.lookup_diff(ip, base, |fsm| &fsm.loop_offsets))
+            .map(|(fsm, diff)| (fsm, diff.wasm_inst_offset))
+    }
     /// Looks up a call ip from self using the given ip and base values.
     pub fn lookup_call_ip(
         &self,
@@ -383,14 +483,45 @@ impl MachineStateDiff {
     }
 }
 
+impl WasmFunctionStateDump {
+    /// Returns the values of the locals of this frame, parameters first.
+    pub fn local_values(&self) -> Vec<WasmValue> {
+        self.locals.iter().map(|&x| WasmValue::from(x)).collect()
+    }
+
+    /// Returns the values of the value stack of this frame, bottom first.
+    pub fn stack_values(&self) -> Vec<WasmValue> {
+        self.stack.iter().map(|&x| WasmValue::from(x)).collect()
+    }
+}
+
 impl ExecutionStateImage {
+    /// Returns the frames of the backtrace in a structured form, e.g. for serialization.
+    pub fn backtrace_frames(&self) -> Vec<BacktraceFrame> {
+        self.frames
+            .iter()
+            .map(|f| BacktraceFrame {
+                local_function_id: f.local_function_id,
+                wasm_inst_offset: f.wasm_inst_offset,
+                locals: f.locals.clone(),
+                stack: f.stack.clone(),
+            })
+            .collect()
+    }
+
     /// Prints a backtrace if the `WASMER_BACKTRACE` environment variable is 1.
     pub fn print_backtrace_if_needed(&self) {
+        self.print_backtrace_if_needed_with_names(None)
+    }
+
+    /// Prints a backtrace if the `WASMER_BACKTRACE` environment variable is 1, naming
+    /// functions with `names` as in `output_with_names`.
+    pub fn print_backtrace_if_needed_with_names(&self, names: Option<&HashMap<u32, String>>) {
         use std::env;
 
         if let Ok(x) = env::var("WASMER_BACKTRACE") {
             if x == "1" {
-                eprintln!("{}", self.output());
+                eprintln!("{}", self.output_with_names(names));
                 return;
             }
         }
@@ -400,6 +531,12 @@ impl ExecutionStateImage {
 
     /// Converts self into a `String`, used for display purposes.
     pub fn output(&self) -> String {
+        self.output_with_names(None)
+    }
+
+    /// Converts self into a `String`, used for display purposes, naming the functions of
+    /// frames with their entry in `names`, keyed by local function index.
+    pub fn output_with_names(&self, names: Option<&HashMap<u32, String>>) -> String {
         fn join_strings(x: impl Iterator<Item = String>, sep: &str) -> String {
             let mut ret = String::new();
             let mut first = true;
@@ -435,15 +572,24 @@ impl ExecutionStateImage {
         }
 
         let mut ret = String::new();
+        let frames = self.backtrace_frames();
 
-        if self.frames.is_empty() {
+        if frames.is_empty() {
             ret += &"Unknown fault address, cannot read stack.";
             ret += "\n";
         } else {
+            if self.truncated {
+                ret += &format!("(Backtrace truncated to {} frames)", frames.len());
+                ret += "\n";
+            }
             ret += &"Backtrace:";
             ret += "\n";
-            for (i, f) in self.frames.iter().enumerate() {
+            for (i, f) in frames.iter().enumerate() {
+                ret += &self.output_host_frames(i);
                 ret += &format!("* Frame {} @ Local function {}", i, f.local_function_id);
+                if let Some(name) = names.and_then(|x| x.get(&(f.local_function_id as u32))) {
+                    ret += &format!(" ({})", name);
+                }
                 ret += "\n";
                 ret += &format!("  {} {}\n", "Offset:", format!("{}", f.wasm_inst_offset),);
                 ret += &format!(
@@ -457,10 +603,23 @@ impl ExecutionStateImage {
                     format_optional_u64_sequence(&f.stack)
                 );
             }
+            ret += &self.output_host_frames(frames.len());
         }
 
         ret
     }
+
+    fn output_host_frames(&self, depth: usize) -> String {
+        let mut ret = String::new();
+        for h in self.host_frames.iter().filter(|h| h.depth == depth) {
+            ret += &format!("* Host function {}", h.import_index);
+            if let Some(ref name) = h.name {
+                ret += &format!(" ({})", name);
+            }
+            ret += "\n\n";
+        }
+        ret
+    }
 }
 
 impl InstanceImage {
@@ -478,6 +637,94 @@ impl InstanceImage {
         use bincode::serialize;
         serialize(self).unwrap()
     }
+
+    /// Returns the values of the locals of the `frame`-th frame, counting from the innermost,
+    /// or `None` if the image has no such frame.
+    pub fn frame_locals(&self, frame: usize) -> Option<Vec<WasmValue>> {
+        self.execution_state
+            .frames
+            .get(frame)
+            .map(|f| f.local_values())
+    }
+
+    /// Returns the values of the value stack of the `frame`-th frame, counting from the
+    /// innermost, or `None` if the image has no such frame.
+    pub fn frame_stack(&self, frame: usize) -> Option<Vec<WasmValue>> {
+        self.execution_state
+            .frames
+            .get(frame)
+            .map(|f| f.stack_values())
+    }
+
+    /// Returns the wasm-level stack pointer stored in the global `stack_pointer_global`.
+    pub fn wasm_stack_pointer(&self, stack_pointer_global: LocalGlobalIndex) -> Option<u64> {
+        use crate::structures::TypedIndex;
+        self.globals
+            .get(stack_pointer_global.index())
+            .map(|x| *x as u64)
+    }
+}
+
+/// Magic bytes at the start of a serialized `InstanceImage`.
+const INSTANCE_IMAGE_MAGIC: &[u8; 8] = b"WASMERII";
+
+/// Version of the layout of a serialized `InstanceImage`. Increment when the layout of
+/// `InstanceImage` or of any type it contains changes.
+pub const INSTANCE_IMAGE_FORMAT_VERSION: u32 = 3;
+
+/// An error reading an `InstanceImage` with `deserialize_instance_image`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InstanceImageFormatError {
+    /// The input is not a serialized instance image.
+    BadMagic,
+    /// The image was serialized with a format version this build cannot read.
+    UnsupportedVersion(u32),
+    /// The image is truncated or malformed.
+    Malformed(String),
+}
+
+impl std::fmt::Display for InstanceImageFormatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            InstanceImageFormatError::BadMagic => write!(f, "not a serialized instance image"),
+            InstanceImageFormatError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported instance image format version {} (expected {})",
+                version, INSTANCE_IMAGE_FORMAT_VERSION
+            ),
+            InstanceImageFormatError::Malformed(e) => write!(f, "malformed instance image: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for InstanceImageFormatError {}
+
+/// Serializes `image` so that it can be persisted and resumed later, possibly on another
+/// machine running the same build of the module.
+///
+/// The output starts with a magic and `INSTANCE_IMAGE_FORMAT_VERSION` as a little-endian `u32`,
+/// followed by the image in `bincode` format.
+pub fn serialize_instance_image(image: &InstanceImage) -> Vec<u8> {
+    let mut out = INSTANCE_IMAGE_MAGIC.to_vec();
+    out.extend_from_slice(&INSTANCE_IMAGE_FORMAT_VERSION.to_le_bytes());
+    out.extend_from_slice(&image.to_bytes());
+    out
+}
+
+/// Reads an image written by `serialize_instance_image`.
+pub fn deserialize_instance_image(input: &[u8]) -> Result<InstanceImage, InstanceImageFormatError> {
+    let header_len = INSTANCE_IMAGE_MAGIC.len() + 4;
+    if input.len() < header_len || &input[..INSTANCE_IMAGE_MAGIC.len()] != INSTANCE_IMAGE_MAGIC {
+        return Err(InstanceImageFormatError::BadMagic);
+    }
+    let mut version = [0u8; 4];
+    version.copy_from_slice(&input[INSTANCE_IMAGE_MAGIC.len()..header_len]);
+    let version = u32::from_le_bytes(version);
+    if version != INSTANCE_IMAGE_FORMAT_VERSION {
+        return Err(InstanceImageFormatError::UnsupportedVersion(version));
+    }
+    bincode::deserialize(&input[header_len..])
+        .map_err(|e| InstanceImageFormatError::Malformed(e.to_string()))
 }
 
 /// X64-specific structures and methods that do not depend on an x64 machine to run.
@@ -580,6 +827,51 @@ pub mod x64_decl {
             }
         }
 
+        /// Returns the register with the given index, the inverse of `to_index`.
+        pub fn from_index(index: RegisterIndex) -> Option<X64Register> {
+            static GPRS: [GPR; 16] = [
+                GPR::RAX,
+                GPR::RCX,
+                GPR::RDX,
+                GPR::RBX,
+                GPR::RSP,
+                GPR::RBP,
+                GPR::RSI,
+                GPR::RDI,
+                GPR::R8,
+                GPR::R9,
+                GPR::R10,
+                GPR::R11,
+                GPR::R12,
+                GPR::R13,
+                GPR::R14,
+                GPR::R15,
+            ];
+            static XMMS: [XMM; 16] = [
+                XMM::XMM0,
+                XMM::XMM1,
+                XMM::XMM2,
+                XMM::XMM3,
+                XMM::XMM4,
+                XMM::XMM5,
+                XMM::XMM6,
+                XMM::XMM7,
+                XMM::XMM8,
+                XMM::XMM9,
+                XMM::XMM10,
+                XMM::XMM11,
+                XMM::XMM12,
+                XMM::XMM13,
+                XMM::XMM14,
+                XMM::XMM15,
+            ];
+            match index.0 {
+                x @ 0..=15 => Some(X64Register::GPR(GPRS[x])),
+                x @ 16..=31 => Some(X64Register::XMM(XMMS[x - 16])),
+                _ => None,
+            }
+        }
+
         /// Converts a DWARD regnum to X64Register.
         pub fn from_dwarf_regnum(x: u16) -> Option<X64Register> {
             Some(match x {
@@ -1114,12 +1406,18 @@ pub mod x64 {
                 memory: memory,
                 globals: globals,
                 execution_state: execution_state,
+                interrupt_trace_id: None,
+                code_hash: None,
+                from_sigint: false,
             }
         }
     }
 
     /// Returns a `ExecutionStateImage` for the given versions, stack, initial registers and
     /// initial address.
+    ///
+    /// If `stack_base` is given, the walk stops before reading at or above that address. A walk
+    /// stopped after `max_depth` frames is marked as truncated.
     #[warn(unused_variables)]
     pub unsafe fn read_stack<'a, I: Iterator<Item = &'a CodeVersion>, F: Fn() -> I + 'a>(
         versions: F,
@@ -1127,6 +1425,7 @@ pub mod x64 {
         initially_known_registers: [Option<u64>; 32],
         mut initial_address: Option<u64>,
         max_depth: Option<usize>,
+        stack_base: Option<usize>,
     ) -> ExecutionStateImage {
         let mut known_registers: [Option<u64>; 32] = initially_known_registers;
         let mut results: Vec<WasmFunctionStateDump> = vec![];
@@ -1135,7 +1434,27 @@ pub mod x64 {
         for depth in 0.. {
             if let Some(max_depth) = max_depth {
                 if depth >= max_depth {
-                    return ExecutionStateImage { frames: results };
+                    return ExecutionStateImage {
+                        frames: results,
+                        truncated: true,
+                        host_frames: vec![],
+                    };
+                }
+            }
+            if depth >= MAX_BACKTRACE_FRAMES {
+                return ExecutionStateImage {
+                    frames: results,
+                    truncated: true,
+                    host_frames: vec![],
+                };
+            }
+            if let Some(stack_base) = stack_base {
+                if stack as usize >= stack_base {
+                    return ExecutionStateImage {
+                        frames: results,
+                        truncated: false,
+                        host_frames: vec![],
+                    };
                 }
             }
 
@@ -1171,7 +1490,11 @@ pub mod x64 {
             let (fsm, state) = if let Some(x) = fsm_state {
                 x
             } else {
-                return ExecutionStateImage { frames: results };
+                return ExecutionStateImage {
+                    frames: results,
+                    truncated: false,
+                    host_frames: vec![],
+                };
             };
 
             {
@@ -131,6 +131,7 @@ pub unsafe fn run_tiering<F: Fn(InteractiveShellContext) -> ShellExitOperation>(
         base: baseline.module.runnable_module.get_code().unwrap().as_ptr() as usize,
         backend: baseline_backend,
         runnable_module: baseline.module.runnable_module.clone(),
+        function_names: None,
     });
     let n_versions: Cell<usize> = Cell::new(1);
 
@@ -194,6 +195,7 @@ pub unsafe fn run_tiering<F: Fn(InteractiveShellContext) -> ShellExitOperation>(
                     .as_ptr() as usize,
                 backend: backend_id,
                 runnable_module: optimized.module.runnable_module.clone(),
+                function_names: None,
             });
             n_versions.set(n_versions.get() + 1);
 
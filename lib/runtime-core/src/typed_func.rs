@@ -74,6 +74,18 @@ pub struct Host(());
 
 impl Kind for Host {}
 
+/// Returns the error to trap with for a panic caught at the boundary of a host function.
+#[cfg(unix)]
+fn host_panic_error(payload: Box<dyn Any + Send>) -> RuntimeError {
+    crate::fault::runtime_error_from_panic(payload)
+}
+
+/// Returns the error to trap with for a panic caught at the boundary of a host function.
+#[cfg(not(unix))]
+fn host_panic_error(payload: Box<dyn Any + Send>) -> RuntimeError {
+    RuntimeError::User(payload)
+}
+
 /// Represents a list of WebAssembly values.
 pub trait WasmTypeList {
     /// CStruct type.
@@ -336,14 +348,17 @@ impl<'a> DynamicFunc<'a> {
                     }
                 })
                 .collect();
-            match panic::catch_unwind(panic::AssertUnwindSafe(|| (ctx.func)(vmctx, &args))) {
+            match panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                let _host_function = crate::fault::HostFunctionGuard::enter(true);
+                (ctx.func)(vmctx, &args)
+            })) {
                 Ok(x) => x,
                 Err(e) => {
                     // At this point, there is an error that needs to be trapped.
                     drop(args); // Release the Vec which will leak otherwise.
                     (&*vmctx.module)
                         .runnable_module
-                        .do_early_trap(RuntimeError::User(e))
+                        .do_early_trap(host_panic_error(e))
                 }
             }
         }
@@ -669,6 +684,8 @@ macro_rules! impl_traits {
                     let err = match panic::catch_unwind(
                         panic::AssertUnwindSafe(
                             || {
+                                #[cfg(unix)]
+                                let _host_function = crate::fault::HostFunctionGuard::enter(true);
                                 func(vmctx $( , WasmExternType::from_native($x) )* ).report()
                                 //   ^^^^^ The imported function
                                 //         expects `vm::Ctx` as first
@@ -682,7 +699,7 @@ macro_rules! impl_traits {
                             RuntimeError::User(b as Box<dyn Any + Send>)
                         },
                         // TODO(blocking): this line is wrong!
-                        Err(err) => RuntimeError::User(err),
+                        Err(err) => host_panic_error(err),
                     };
 
                     // At this point, there is an error that needs to
@@ -786,6 +803,8 @@ macro_rules! impl_traits {
                     let err = match panic::catch_unwind(
                         panic::AssertUnwindSafe(
                             || {
+                                #[cfg(unix)]
+                                let _host_function = crate::fault::HostFunctionGuard::enter(true);
                                 func($( WasmExternType::from_native($x), )* ).report()
                             }
                         )
@@ -796,7 +815,7 @@ macro_rules! impl_traits {
                             RuntimeError::User(b as Box<dyn Any + Send>)
                         },
                         // TODO(blocking): this line is wrong!
-                        Err(err) => RuntimeError::User(err),
+                        Err(err) => host_panic_error(err),
                     };
 
                     // At this point, there is an error that needs to
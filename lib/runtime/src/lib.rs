@@ -113,7 +113,10 @@ pub use wasmer_runtime_core::{func, imports};
 
 #[cfg(unix)]
 pub use wasmer_runtime_core::{
-    fault::{pop_code_version, push_code_version},
+    fault::{
+        pop_code_version, push_code_version, register_code_version, with_code_versions,
+        CodeVersionGuard,
+    },
     state::CodeVersion,
 };
 
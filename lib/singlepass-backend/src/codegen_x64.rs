@@ -681,7 +681,7 @@ impl RunnableModule for X64ExecutionContext {
     }
 
     unsafe fn do_early_trap(&self, data: RuntimeError) -> ! {
-        fault::begin_unsafe_unwind(Box::new(data));
+        fault::begin_unsafe_unwind_with_longjmp(Box::new(data));
     }
 
     fn get_code(&self) -> Option<&[u8]> {
@@ -727,10 +727,9 @@ impl RunnableModule for X64ExecutionContext {
                 {
                     Some(InlineBreakpoint {
                         size: INLINE_BREAKPOINT_SIZE_X86_SINGLEPASS,
-                        ty: match code[INLINE_BREAKPOINT_SIZE_X86_SINGLEPASS - 1] {
-                            0 => InlineBreakpointType::Middleware,
-                            _ => return None,
-                        },
+                        ty: InlineBreakpointType::from_u8(
+                            code[INLINE_BREAKPOINT_SIZE_X86_SINGLEPASS - 1],
+                        )?,
                     })
                 } else {
                     None
@@ -747,10 +746,9 @@ impl RunnableModule for X64ExecutionContext {
                 {
                     Some(InlineBreakpoint {
                         size: INLINE_BREAKPOINT_SIZE_AARCH64_SINGLEPASS,
-                        ty: match code[INLINE_BREAKPOINT_SIZE_AARCH64_SINGLEPASS - 4] {
-                            0 => InlineBreakpointType::Middleware,
-                            _ => return None,
-                        },
+                        ty: InlineBreakpointType::from_u8(
+                            code[INLINE_BREAKPOINT_SIZE_AARCH64_SINGLEPASS - 4],
+                        )?,
                     })
                 } else {
                     None
@@ -1216,18 +1214,34 @@ impl X64FunctionCode {
             .insert(m.state.wasm_inst_offset, SuspendOffset::Trappable(offset));
     }
 
-    /// Marks each address in the code range emitted by `f` with the exception code `code`.
+    /// Marks the address `offset` with the exception code `code`, raised by the operator at
+    /// `source_loc` if there is one.
+    fn mark_address_with_exception_code(
+        etable: &mut ExceptionTable,
+        offset: usize,
+        code: ExceptionCode,
+        source_loc: Option<u32>,
+    ) {
+        etable.offset_to_code.insert(offset, code);
+        if let Some(source_loc) = source_loc {
+            etable.offset_to_srcloc.insert(offset, source_loc);
+        }
+    }
+
+    /// Marks each address in the code range emitted by `f` with the exception code `code`,
+    /// raised by the operator at `source_loc` if there is one.
     fn mark_range_with_exception_code<F: FnOnce(&mut Assembler) -> R, R>(
         a: &mut Assembler,
         etable: &mut ExceptionTable,
         code: ExceptionCode,
+        source_loc: Option<u32>,
         f: F,
     ) -> R {
         let begin = a.get_offset().0;
         let ret = f(a);
         let end = a.get_offset().0;
         for i in begin..end {
-            etable.offset_to_code.insert(i, code);
+            Self::mark_address_with_exception_code(etable, i, code, source_loc);
         }
         ret
     }
@@ -1295,16 +1309,22 @@ impl X64FunctionCode {
             Location::Imm64(_) | Location::Imm32(_) => {
                 a.emit_mov(sz, loc, Location::GPR(GPR::RCX)); // must not be used during div (rax, rdx)
                 Self::mark_trappable(a, m, fsm, control_stack);
-                etable
-                    .offset_to_code
-                    .insert(a.get_offset().0, ExceptionCode::IllegalArithmetic);
+                Self::mark_address_with_exception_code(
+                    etable,
+                    a.get_offset().0,
+                    ExceptionCode::IllegalArithmetic,
+                    m.source_loc,
+                );
                 op(a, sz, Location::GPR(GPR::RCX));
             }
             _ => {
                 Self::mark_trappable(a, m, fsm, control_stack);
-                etable
-                    .offset_to_code
-                    .insert(a.get_offset().0, ExceptionCode::IllegalArithmetic);
+                Self::mark_address_with_exception_code(
+                    etable,
+                    a.get_offset().0,
+                    ExceptionCode::IllegalArithmetic,
+                    m.source_loc,
+                );
                 op(a, sz, loc);
             }
         }
@@ -2284,6 +2304,7 @@ impl X64FunctionCode {
                 a,
                 etable,
                 ExceptionCode::MemoryOutOfBounds,
+                m.source_loc,
                 |a| a.emit_conditional_trap(Condition::Above),
             );
 
@@ -2329,14 +2350,20 @@ impl X64FunctionCode {
                 a,
                 etable,
                 ExceptionCode::MemoryOutOfBounds,
+                m.source_loc,
                 |a| a.emit_conditional_trap(Condition::NotEqual),
             );
             m.release_temp_gpr(tmp_aligncheck);
         }
 
-        Self::mark_range_with_exception_code(a, etable, ExceptionCode::MemoryOutOfBounds, |a| {
-            cb(a, m, tmp_addr)
-        })?;
+        let source_loc = m.source_loc;
+        Self::mark_range_with_exception_code(
+            a,
+            etable,
+            ExceptionCode::MemoryOutOfBounds,
+            source_loc,
+            |a| cb(a, m, tmp_addr),
+        )?;
 
         m.release_temp_gpr(tmp_addr);
         Ok(())
@@ -2467,9 +2494,12 @@ impl X64FunctionCode {
 
         Self::emit_f32_int_conv_check(a, m, reg, lower_bound, upper_bound, trap, trap, trap, end);
         a.emit_label(trap);
-        etable
-            .offset_to_code
-            .insert(a.get_offset().0, ExceptionCode::IllegalArithmetic);
+        Self::mark_address_with_exception_code(
+            etable,
+            a.get_offset().0,
+            ExceptionCode::IllegalArithmetic,
+            m.source_loc,
+        );
         a.emit_ud2();
         a.emit_label(end);
     }
@@ -2595,9 +2625,12 @@ impl X64FunctionCode {
 
         Self::emit_f64_int_conv_check(a, m, reg, lower_bound, upper_bound, trap, trap, trap, end);
         a.emit_label(trap);
-        etable
-            .offset_to_code
-            .insert(a.get_offset().0, ExceptionCode::IllegalArithmetic);
+        Self::mark_address_with_exception_code(
+            etable,
+            a.get_offset().0,
+            ExceptionCode::IllegalArithmetic,
+            m.source_loc,
+        );
         a.emit_ud2();
         a.emit_label(end);
     }
@@ -2725,7 +2758,8 @@ impl FunctionCodeGenerator<CodegenError> for X64FunctionCode {
             Self::mark_range_with_exception_code(
                 a,
                 self.exception_table.as_mut().unwrap(),
-                ExceptionCode::MemoryOutOfBounds,
+                ExceptionCode::CallStackExhausted,
+                None,
                 |a| a.emit_conditional_trap(Condition::Below),
             );
         }
@@ -2812,7 +2846,7 @@ impl FunctionCodeGenerator<CodegenError> for X64FunctionCode {
         &mut self,
         ev: Event,
         module_info: &ModuleInfo,
-        _source_loc: u32,
+        source_loc: u32,
     ) -> Result<(), CodegenError> {
         assert!(self.fp_stack.len() <= self.value_stack.len());
 
@@ -2827,6 +2861,7 @@ impl FunctionCodeGenerator<CodegenError> for X64FunctionCode {
         }
 
         self.machine.state.wasm_inst_offset = self.machine.state.wasm_inst_offset.wrapping_add(1);
+        self.machine.source_loc = Some(source_loc);
 
         //println!("{:?} {}", op, self.value_stack.len());
         let was_unreachable;
@@ -7038,6 +7073,7 @@ impl FunctionCodeGenerator<CodegenError> for X64FunctionCode {
                     a,
                     self.exception_table.as_mut().unwrap(),
                     ExceptionCode::CallIndirectOOB,
+                    self.machine.source_loc,
                     |a| a.emit_conditional_trap(Condition::BelowEqual),
                 );
                 a.emit_mov(Size::S32, func_index, Location::GPR(table_count));
@@ -7069,6 +7105,7 @@ impl FunctionCodeGenerator<CodegenError> for X64FunctionCode {
                     a,
                     self.exception_table.as_mut().unwrap(),
                     ExceptionCode::IncorrectCallIndirectSignature,
+                    self.machine.source_loc,
                     |a| a.emit_conditional_trap(Condition::NotEqual),
                 );
 
@@ -8263,11 +8300,12 @@ impl FunctionCodeGenerator<CodegenError> for X64FunctionCode {
             }
             Operator::Unreachable => {
                 Self::mark_trappable(a, &self.machine, &mut self.fsm, &mut self.control_stack);
-                self.exception_table
-                    .as_mut()
-                    .unwrap()
-                    .offset_to_code
-                    .insert(a.get_offset().0, ExceptionCode::Unreachable);
+                Self::mark_address_with_exception_code(
+                    self.exception_table.as_mut().unwrap(),
+                    a.get_offset().0,
+                    ExceptionCode::Unreachable,
+                    self.machine.source_loc,
+                );
                 a.emit_ud2();
                 self.unreachable_depth = 1;
             }
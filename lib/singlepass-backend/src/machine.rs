@@ -15,6 +15,9 @@ pub struct Machine {
     save_area_offset: Option<MachineStackOffset>,
     pub state: MachineState,
     pub(crate) track_state: bool,
+    /// The source location, as an offset into the wasm binary, of the operator being
+    /// translated, or `None` outside of operators, e.g. in the function prologue.
+    pub(crate) source_loc: Option<u32>,
 }
 
 impl Machine {
@@ -26,6 +29,7 @@ impl Machine {
             save_area_offset: None,
             state: x64::new_machine_state(),
             track_state: true,
+            source_loc: None,
         }
     }
 
@@ -409,6 +409,7 @@ fn execute_wasi(
                 base: instance.module.runnable_module.get_code().unwrap().as_ptr() as usize,
                 backend: options.backend.to_string(),
                 runnable_module: instance.module.runnable_module.clone(),
+                function_names: None,
             });
             true
         } else {
@@ -815,6 +816,7 @@ fn execute_wasm(options: &Run) -> Result<(), String> {
                         base: instance.module.runnable_module.get_code().unwrap().as_ptr() as usize,
                         backend: options.backend.to_string(),
                         runnable_module: instance.module.runnable_module.clone(),
+                        function_names: None,
                     });
                     true
                 } else {
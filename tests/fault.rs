@@ -0,0 +1,1466 @@
+#![cfg(all(unix, feature = "backend-singlepass", test))]
+#[macro_use]
+extern crate lazy_static;
+
+use std::cell::{Cell, RefCell};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use wabt::wat2wasm;
+use wasmer::compiler::compile_with;
+use wasmer::import::ImportObject;
+use wasmer::imports;
+use wasmer::vm::Ctx;
+use wasmer::wasm::Instance;
+use wasmer::{func, Func};
+use wasmer_runtime_core::backend::{
+    Architecture, CompilerConfig, ExceptionCode, InlineBreakpointType,
+};
+use wasmer_runtime_core::compile_with_config;
+use wasmer_runtime_core::error::{InterruptReason, InvokeError, RuntimeError};
+use wasmer_runtime_core::fault::*;
+use wasmer_runtime_core::state::x64::{build_instance_image, X64Register, GPR};
+use wasmer_runtime_core::state::{
+    deserialize_instance_image, serialize_instance_image, BacktraceFrame, CodeVersion,
+    ExecutionStateImage, HostFrame, InstanceImage, InstanceImageFormatError, WasmValue,
+    INSTANCE_IMAGE_FORMAT_VERSION, MAX_BACKTRACE_FRAMES,
+};
+use wasmer_runtime_core::structures::TypedIndex;
+use wasmer_runtime_core::types::LocalGlobalIndex;
+use wasmer_singlepass_backend::SinglePassCompiler;
+
+lazy_static! {
+    static ref INTERRUPT_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Serializes tests that arm the process-wide wasm interrupt or run wasm code, as returning from
+/// the last running call clears a stale interrupt.
+fn lock_interrupt() -> MutexGuard<'static, ()> {
+    INTERRUPT_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn instantiate_with_config(wat: &str, config: CompilerConfig) -> Instance {
+    let wasm_binary = wat2wasm(wat).unwrap();
+    let module = compile_with_config(&wasm_binary, &SinglePassCompiler::new(), config).unwrap();
+    module.instantiate(&imports! {}).unwrap()
+}
+
+fn instantiate(wat: &str) -> Instance {
+    let wasm_binary = wat2wasm(wat).unwrap();
+    let module = compile_with(&wasm_binary, &SinglePassCompiler::new()).unwrap();
+    module.instantiate(&imports! {}).unwrap()
+}
+
+/// Instantiates `wat` with state tracking and preemption points, so that faults can be
+/// turned into execution state images.
+fn instantiate_tracked(wat: &str, import_object: &ImportObject) -> Instance {
+    let wasm_binary = wat2wasm(wat).unwrap();
+    let module = compile_with_config(
+        &wasm_binary,
+        &SinglePassCompiler::new(),
+        CompilerConfig {
+            track_state: true,
+            full_preemption: true,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    module.instantiate(import_object).unwrap()
+}
+
+/// Calls `f` with the code version of `instance` pushed and its `Ctx` current.
+fn run_tracked<R, F: FnOnce() -> R>(instance: &Instance, f: F) -> R {
+    let ctx = instance.context() as *const Ctx as *mut Ctx;
+    let runnable_module = instance.module.runnable_module.clone();
+    let _version = register_code_version(CodeVersion {
+        baseline: true,
+        msm: runnable_module.get_module_state_map().unwrap(),
+        base: runnable_module.get_code().unwrap().as_ptr() as usize,
+        backend: "singlepass",
+        runnable_module,
+        function_names: None,
+    });
+    unsafe { with_ctx(ctx, f) }
+}
+
+/// Extracts the `InstanceImage` carried by a suspend fault.
+fn expect_image(result: Result<(), RuntimeError>) -> InstanceImage {
+    match result {
+        Err(RuntimeError::InstanceImage(image)) => *image.downcast::<InstanceImage>().unwrap(),
+        other => panic!("expected an instance image, got {:?}", other),
+    }
+}
+
+/// A module whose `run` export recurses `depth` times and then calls the `env.interrupt`
+/// import before spinning until interrupted.
+static RECURSE_THEN_SPIN_WAT: &'static str = r#"
+    (module
+        (import "env" "interrupt" (func $interrupt))
+        (func $run (export "run") (param i32)
+            (if (local.get 0)
+                (then (call $run (i32.sub (local.get 0) (i32.const 1))))
+                (else
+                    (call $interrupt)
+                    (loop $spin (br $spin))))))
+"#;
+
+fn interrupt(_ctx: &mut Ctx) {
+    unsafe { set_wasm_interrupt() };
+}
+
+fn interrupt_with_trace_id(ctx: &mut Ctx) {
+    unsafe { set_wasm_interrupt_on_ctx_with_trace_id(ctx, 42) };
+}
+
+fn suspend(ctx: &mut Ctx) {
+    unsafe { request_suspend(ctx) };
+}
+
+fn interrupt_and_capture(ctx: &mut Ctx) {
+    unsafe { interrupt_and_capture_memory(ctx) };
+}
+
+#[test]
+fn test_wasm_stack_pointer_is_reported() {
+    static WAT: &'static str = r#"
+        (module
+            (memory 1)
+            (global $sp (mut i32) (i32.const 1024))
+            (func (export "push") (param i32)
+                (global.set $sp (i32.sub (global.get $sp) (local.get 0)))))
+    "#;
+    let _lock = lock_interrupt();
+    let mut instance = instantiate(WAT);
+    let push: Func<i32> = instance.exports.get("push").unwrap();
+    push.call(16).unwrap();
+
+    let sp_global = LocalGlobalIndex::new(0);
+    let sp = unsafe {
+        with_ctx(instance.context_mut(), || {
+            read_wasm_stack_pointer(sp_global)
+        })
+    };
+    assert_eq!(sp, Some(1008));
+
+    let image = build_instance_image(
+        instance.context_mut(),
+        ExecutionStateImage {
+            frames: vec![],
+            truncated: false,
+            host_frames: vec![],
+        },
+    );
+    assert_eq!(image.wasm_stack_pointer(sp_global), Some(1008));
+}
+
+#[test]
+fn test_backtrace_never_exceeds_hard_cap() {
+    let import_object = imports! {
+        "env" => {
+            "interrupt" => func!(interrupt),
+        },
+    };
+    let instance = instantiate_tracked(RECURSE_THEN_SPIN_WAT, &import_object);
+    let run: Func<i32> = instance.exports.get("run").unwrap();
+
+    let _lock = lock_interrupt();
+    let depth = MAX_BACKTRACE_FRAMES as i32 + 100;
+    let image = expect_image(run_tracked(&instance, || run.call(depth)));
+    assert_eq!(image.execution_state.frames.len(), MAX_BACKTRACE_FRAMES);
+    assert!(image.execution_state.truncated);
+}
+
+#[test]
+fn test_interrupts_disabled_restored_after_panic() {
+    static WAT: &'static str = r#"(module (func (export "nop")))"#;
+    let mut instance = instantiate(WAT);
+    let ctx = instance.context_mut() as *mut Ctx;
+
+    let _lock = lock_interrupt();
+    unsafe { set_wasm_interrupt() };
+    let result = std::panic::catch_unwind(|| unsafe {
+        with_interrupts_disabled(ctx, || {
+            assert!(!is_wasm_interrupt_set());
+            panic!("host operation failed");
+        })
+    });
+    assert!(result.is_err());
+    assert!(is_wasm_interrupt_set());
+    unsafe { clear_wasm_interrupt() };
+}
+
+#[test]
+fn test_interrupt_trace_id_is_reported() {
+    let import_object = imports! {
+        "env" => {
+            "interrupt" => func!(interrupt_with_trace_id),
+        },
+    };
+    let instance = instantiate_tracked(RECURSE_THEN_SPIN_WAT, &import_object);
+    let run: Func<i32> = instance.exports.get("run").unwrap();
+
+    let _lock = lock_interrupt();
+    let image = expect_image(run_tracked(&instance, || run.call(0)));
+    assert_eq!(image.interrupt_trace_id, Some(42));
+}
+
+#[test]
+fn test_escalating_stack_retries_after_exhaustion() {
+    static WAT: &'static str = r#"
+        (module
+            (func $rec (export "rec") (param i32)
+                (if (local.get 0)
+                    (then (call $rec (i32.sub (local.get 0) (i32.const 1)))))))
+    "#;
+    let _lock = lock_interrupt();
+    let instance = instantiate_with_config(
+        WAT,
+        CompilerConfig {
+            enforce_stack_check: true,
+            ..Default::default()
+        },
+    );
+    let ctx = instance.context() as *const Ctx as *mut Ctx;
+    let rec: Func<i32> = instance.exports.get("rec").unwrap();
+
+    let mut attempts = 0;
+    let result = unsafe {
+        run_with_escalating_stack(ctx, &[128 * 1024, 8 * 1024 * 1024], || {
+            attempts += 1;
+            rec.call(10_000)
+        })
+    };
+    assert!(result.is_ok());
+    assert_eq!(attempts, 2);
+}
+
+#[test]
+fn test_possible_trap_codes_reflect_trapping_instructions() {
+    static WAT: &'static str = r#"
+        (module
+            (memory 1)
+            (func (export "div") (param i32 i32) (result i32)
+                (i32.div_s (local.get 0) (local.get 1)))
+            (func (export "load") (param i32) (result i32)
+                (i32.load (local.get 0)))
+            (func (export "trap")
+                (unreachable)))
+    "#;
+    let instance = instantiate(WAT);
+    let runnable_module = instance.module.runnable_module.clone();
+    let version = CodeVersion {
+        baseline: true,
+        msm: runnable_module.get_module_state_map().unwrap(),
+        base: runnable_module.get_code().unwrap().as_ptr() as usize,
+        backend: "singlepass",
+        runnable_module,
+        function_names: None,
+    };
+
+    let codes = possible_trap_codes(&version);
+    assert!(codes.contains(&ExceptionCode::Unreachable));
+    assert!(codes.contains(&ExceptionCode::IllegalArithmetic));
+    assert!(codes.contains(&ExceptionCode::MemoryOutOfBounds));
+    assert!(!codes.contains(&ExceptionCode::CallIndirectOOB));
+}
+
+#[test]
+fn test_interrupt_captures_partial_memory() {
+    static WAT: &'static str = r#"
+        (module
+            (import "env" "interrupt" (func $interrupt))
+            (memory 1)
+            (func (export "run")
+                (local $i i32)
+                (loop $write
+                    (i32.store8 (local.get $i) (i32.add (local.get $i) (i32.const 1)))
+                    (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                    (if (i32.eq (local.get $i) (i32.const 100))
+                        (then (call $interrupt)))
+                    (br_if $write (i32.lt_u (local.get $i) (i32.const 65536))))))
+    "#;
+    let import_object = imports! {
+        "env" => {
+            "interrupt" => func!(interrupt_and_capture),
+        },
+    };
+    let instance = instantiate_tracked(WAT, &import_object);
+    let run: Func = instance.exports.get("run").unwrap();
+
+    let _lock = lock_interrupt();
+    let memory = match run_tracked(&instance, || run.call()) {
+        Err(RuntimeError::InvokeError(InvokeError::PartialResult(memory))) => memory,
+        other => panic!("expected a partial result, got {:?}", other),
+    };
+    assert_eq!(memory.len(), 65536);
+    for i in 0..100 {
+        assert_eq!(memory[i], (i + 1) as u8);
+    }
+    assert_eq!(memory[65535], 0);
+}
+
+#[test]
+fn test_out_of_bounds_trap_reports_grown_memory_size() {
+    static WAT: &'static str = r#"
+        (module
+            (memory 1)
+            (func (export "grow_and_read") (result i32)
+                (drop (memory.grow (i32.const 1)))
+                (i32.load (i32.mul (memory.size) (i32.const 65536)))))
+    "#;
+    let _lock = lock_interrupt();
+    let instance = instantiate_tracked(WAT, &imports! {});
+    let grow_and_read: Func<(), i32> = instance.exports.get("grow_and_read").unwrap();
+
+    match run_tracked(&instance, || grow_and_read.call()) {
+        Err(RuntimeError::InvokeError(InvokeError::TrapCode {
+            code: ExceptionCode::MemoryOutOfBounds,
+            memory_size,
+            ..
+        })) => assert_eq!(memory_size, Some(2 * 65536)),
+        other => panic!("expected an out of bounds trap, got {:?}", other),
+    }
+    let record = recent_faults().pop().unwrap();
+    assert_eq!(record.memory_size, Some(2 * 65536));
+}
+
+static SOFT_SNAPSHOTS: AtomicUsize = AtomicUsize::new(0);
+
+fn log_soft_snapshot(image: InstanceImage) {
+    assert!(!image.execution_state.frames.is_empty());
+    SOFT_SNAPSHOTS.fetch_add(1, Ordering::SeqCst);
+}
+
+fn sleep(_ctx: &mut Ctx) {
+    std::thread::sleep(Duration::from_millis(200));
+}
+
+#[test]
+fn test_watchdog_snapshot_does_not_alter_execution() {
+    static WAT: &'static str = r#"
+        (module
+            (import "env" "sleep" (func $sleep))
+            (func (export "run") (result i32)
+                (local $i i32)
+                (call $sleep)
+                (loop $count
+                    (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                    (br_if $count (i32.lt_u (local.get $i) (i32.const 1000))))
+                (local.get $i)))
+    "#;
+    let import_object = imports! {
+        "env" => {
+            "sleep" => func!(sleep),
+        },
+    };
+    let instance = instantiate_tracked(WAT, &import_object);
+    let ctx = instance.context() as *const Ctx as *mut Ctx;
+    let run: Func<(), i32> = instance.exports.get("run").unwrap();
+
+    let _lock = lock_interrupt();
+    let result = run_tracked(&instance, || unsafe {
+        run_with_watchdog(
+            ctx,
+            Duration::from_millis(20),
+            Duration::from_secs(60),
+            log_soft_snapshot,
+            || run.call(),
+        )
+    });
+    assert_eq!(result.unwrap(), 1000);
+    assert_eq!(SOFT_SNAPSHOTS.load(Ordering::SeqCst), 1);
+    assert!(!is_wasm_interrupt_set());
+}
+
+#[test]
+fn test_backtrace_is_available_as_structured_frames() {
+    let import_object = imports! {
+        "env" => {
+            "interrupt" => func!(interrupt),
+        },
+    };
+    let instance = instantiate_tracked(RECURSE_THEN_SPIN_WAT, &import_object);
+    let run: Func<i32> = instance.exports.get("run").unwrap();
+
+    let _lock = lock_interrupt();
+    let image = expect_image(run_tracked(&instance, || run.call(2)));
+    let frames = image.execution_state.backtrace_frames();
+    assert_eq!(frames.len(), 3);
+    for (frame, dump) in frames.iter().zip(image.execution_state.frames.iter()) {
+        assert_eq!(
+            *frame,
+            BacktraceFrame {
+                local_function_id: dump.local_function_id,
+                wasm_inst_offset: dump.wasm_inst_offset,
+                locals: dump.locals.clone(),
+                stack: dump.stack.clone(),
+            }
+        );
+    }
+    assert!(image
+        .execution_state
+        .output()
+        .contains(&format!("Local function {}", frames[0].local_function_id)));
+}
+
+thread_local! {
+    static INNER_INSTANCE: RefCell<Option<Instance>> = RefCell::new(None);
+}
+
+/// Calls the trapping `trap` export of `INNER_INSTANCE`, returning 1 if the trap and the
+/// restored outer state are as expected.
+fn call_inner(_ctx: &mut Ctx) -> i32 {
+    INNER_INSTANCE.with(|inner| {
+        let inner = inner.borrow();
+        let inner = inner.as_ref().unwrap();
+        let trap: Func = inner.exports.get("trap").unwrap();
+
+        let trapped = match run_tracked(inner, || trap.call()) {
+            Err(RuntimeError::InvokeError(InvokeError::TrapCode {
+                code: ExceptionCode::Unreachable,
+                ..
+            })) => true,
+            _ => false,
+        };
+        let attributed_to_inner = recent_faults().last().unwrap().code_version == Some(1);
+        let outer_restored =
+            unsafe { read_wasm_stack_pointer(LocalGlobalIndex::new(0)) } == Some(111);
+        (trapped && attributed_to_inner && outer_restored) as i32
+    })
+}
+
+#[test]
+fn test_nested_protected_calls_resolve_inner_trap() {
+    static OUTER_WAT: &'static str = r#"
+        (module
+            (import "env" "call_inner" (func $call_inner (result i32)))
+            (global $marker (mut i32) (i32.const 111))
+            (func (export "run") (result i32)
+                (call $call_inner)))
+    "#;
+    static INNER_WAT: &'static str = r#"
+        (module
+            (global $marker (mut i32) (i32.const 222))
+            (func (export "trap")
+                (unreachable)))
+    "#;
+    let _lock = lock_interrupt();
+    INNER_INSTANCE.with(|inner| {
+        *inner.borrow_mut() = Some(instantiate_tracked(INNER_WAT, &imports! {}));
+    });
+    let import_object = imports! {
+        "env" => {
+            "call_inner" => func!(call_inner),
+        },
+    };
+    let outer = instantiate_tracked(OUTER_WAT, &import_object);
+    let run: Func<(), i32> = outer.exports.get("run").unwrap();
+
+    assert_eq!(run_tracked(&outer, || run.call()).unwrap(), 1);
+    assert_eq!(
+        unsafe { read_wasm_stack_pointer(LocalGlobalIndex::new(0)) },
+        None
+    );
+    INNER_INSTANCE.with(|inner| inner.borrow_mut().take());
+}
+
+#[test]
+fn test_timeout_interrupts_with_distinct_error() {
+    static WAT: &'static str = r#"
+        (module
+            (func (export "spin") (param i32) (result i32)
+                (if (local.get 0)
+                    (then (loop $spin (br $spin))))
+                (i32.const 7)))
+    "#;
+    let instance = instantiate_tracked(WAT, &imports! {});
+    let ctx = instance.context() as *const Ctx as *mut Ctx;
+    let spin: Func<i32, i32> = instance.exports.get("spin").unwrap();
+
+    let _lock = lock_interrupt();
+    let result = run_tracked(&instance, || unsafe {
+        run_with_timeout(ctx, Duration::from_millis(50), || spin.call(1))
+    });
+    match result {
+        Err(RuntimeError::InvokeError(InvokeError::Timeout)) => {}
+        other => panic!("expected a timeout, got {:?}", other),
+    }
+
+    let result = run_tracked(&instance, || unsafe {
+        run_with_timeout(ctx, Duration::from_secs(60), || spin.call(0))
+    });
+    assert_eq!(result.unwrap(), 7);
+    assert!(!is_wasm_interrupt_set());
+}
+
+#[test]
+fn test_unused_interrupt_is_cleared_after_call() {
+    static WAT: &'static str = r#"(module (func (export "nop")))"#;
+    let instance = instantiate(WAT);
+    let nop: Func = instance.exports.get("nop").unwrap();
+
+    let _lock = lock_interrupt();
+    let undelivered = undelivered_interrupts();
+    unsafe { set_wasm_interrupt() };
+    nop.call().unwrap();
+
+    assert!(!is_wasm_interrupt_set());
+    assert_eq!(undelivered_interrupts(), undelivered + 1);
+    unsafe { std::ptr::write_volatile(get_wasm_interrupt_signal_mem(), 1) };
+}
+
+#[test]
+fn test_unused_ctx_interrupt_is_cleared_when_its_ctx_exits() {
+    static WAT: &'static str = r#"(module (func (export "nop")))"#;
+    let instance = instantiate_tracked(WAT, &imports! {});
+    let ctx = instance.context() as *const Ctx as *mut Ctx;
+    let other = instantiate(WAT);
+    let nop: Func = other.exports.get("nop").unwrap();
+
+    let _lock = lock_interrupt();
+    let undelivered = undelivered_interrupts();
+    run_tracked(&instance, || {
+        unsafe { set_wasm_interrupt_on_ctx(ctx) };
+        // A call on another `Ctx` returning does not clear the interrupt of `ctx`.
+        nop.call().unwrap();
+        assert!(is_wasm_interrupt_set());
+    });
+
+    assert!(!is_wasm_interrupt_set());
+    assert_eq!(undelivered_interrupts(), undelivered + 1);
+    unsafe { std::ptr::write_volatile(get_wasm_interrupt_signal_mem(), 1) };
+}
+
+#[test]
+fn test_trap_report_describes_real_trap() {
+    static WAT: &'static str = r#"
+        (module
+            (func $fail
+                (unreachable))
+            (func (export "run")
+                (call $fail)))
+    "#;
+    let instance = instantiate_tracked(WAT, &imports! {});
+    let run: Func = instance.exports.get("run").unwrap();
+    let _lock = lock_interrupt();
+
+    let error = run_tracked(&instance, || run.call()).unwrap_err();
+    let report = TrapReport::from_runtime_error(&error)
+        .with_label("run")
+        .with_backtrace(&error);
+    assert_eq!(report.code, Some(ExceptionCode::Unreachable));
+    assert_eq!(report.description, ExceptionCode::Unreachable.to_string());
+    // 0x00 is the `unreachable` opcode.
+    let srcloc = report.srcloc.unwrap() as usize;
+    assert_ne!(srcloc, 0);
+    assert_eq!(wat2wasm(WAT).unwrap()[srcloc], 0x00);
+    assert_eq!(report.signal, Some(libc::SIGILL));
+    assert_eq!(report.label, Some("run".to_string()));
+    assert_eq!(
+        report.thread_id,
+        format!("{:?}", std::thread::current().id())
+    );
+    let backtrace = report.backtrace.as_ref().unwrap();
+    assert_eq!(backtrace.len(), 2);
+    assert_eq!(report.top_frame.as_ref(), backtrace.first());
+    assert_eq!(backtrace[0].local_function_id, 0);
+    assert_eq!(backtrace[1].local_function_id, 1);
+
+    assert!(report.to_string().starts_with("run: "));
+    let json = serde_json::to_string(&report).unwrap();
+    let parsed: TrapReport = serde_json::from_str(&json).unwrap();
+    assert_eq!(parsed.code, report.code);
+    assert_eq!(parsed.backtrace, report.backtrace);
+}
+
+#[test]
+fn test_interrupt_handle_from_another_thread() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<InterruptHandle>();
+
+    static WAT: &'static str = r#"
+        (module
+            (func (export "spin")
+                (loop $spin (br $spin))))
+    "#;
+    let instance = instantiate_tracked(WAT, &imports! {});
+    let spin: Func = instance.exports.get("spin").unwrap();
+    let handle = InterruptHandle::new(instance.context());
+
+    let _lock = lock_interrupt();
+    let monitor = {
+        let handle = handle.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            handle.interrupt();
+        })
+    };
+    match run_tracked(&instance, || spin.call()) {
+        Err(RuntimeError::InvokeError(InvokeError::Interrupted {
+            from_sigint: false,
+            reason: None,
+        })) => {}
+        other => panic!("expected an interrupt, got {:?}", other),
+    }
+    monitor.join().unwrap();
+}
+
+fn saturate_division(fault: &mut FaultInfo) -> RecoveryAction {
+    unsafe {
+        assert!(fault.set_register(X64Register::GPR(GPR::RAX), i32::max_value() as u32 as u64));
+        assert!(fault.set_register(X64Register::GPR(GPR::RDX), 0));
+        assert!(fault.skip_division());
+    }
+    RecoveryAction::Resume
+}
+
+#[test]
+fn test_trap_recovery_handler_saturates_overflow() {
+    static WAT: &'static str = r#"
+        (module
+            (func (export "div") (param i32 i32) (result i32)
+                (i32.sub (i32.div_s (local.get 0) (local.get 1)) (i32.const 1))))
+    "#;
+    let _lock = lock_interrupt();
+    let instance = instantiate_tracked(WAT, &imports! {});
+    let div: Func<(i32, i32), i32> = instance.exports.get("div").unwrap();
+
+    set_trap_recovery_handler(ExceptionCode::IllegalArithmetic, saturate_division);
+    let result = run_tracked(&instance, || div.call(i32::min_value(), -1));
+    clear_trap_recovery_handler(ExceptionCode::IllegalArithmetic);
+    assert_eq!(result.unwrap(), i32::max_value() - 1);
+    assert_eq!(
+        recent_faults().pop().unwrap().classification,
+        FaultClassification::Trap(ExceptionCode::IllegalArithmetic)
+    );
+
+    match run_tracked(&instance, || div.call(i32::min_value(), -1)) {
+        Err(RuntimeError::InvokeError(InvokeError::TrapCode {
+            code: ExceptionCode::IllegalArithmetic,
+            ..
+        })) => {}
+        other => panic!("expected an arithmetic trap, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_trap_code_reports_source_location() {
+    static WAT: &'static str = r#"
+        (module
+            (memory 1)
+            (func (export "read") (result i32)
+                (i32.load (i32.add (i32.const 65536) (i32.const 4)))))
+    "#;
+    let _lock = lock_interrupt();
+    let instance = instantiate_tracked(WAT, &imports! {});
+    let read: Func<(), i32> = instance.exports.get("read").unwrap();
+
+    let srcloc = match run_tracked(&instance, || read.call()) {
+        Err(RuntimeError::InvokeError(InvokeError::TrapCode {
+            code: ExceptionCode::MemoryOutOfBounds,
+            srcloc,
+            ..
+        })) => srcloc as usize,
+        other => panic!("expected an out of bounds trap, got {:?}", other),
+    };
+    // 0x28 is the `i32.load` opcode.
+    assert_eq!(wat2wasm(WAT).unwrap()[srcloc], 0x28);
+}
+
+thread_local! {
+    static CALLBACK_REPORT: RefCell<Option<TrapReport>> = RefCell::new(None);
+}
+
+/// Calls the trapping `trap` export of `INNER_INSTANCE` and saves the report of the trap.
+fn call_back_into_wasm(_ctx: &mut Ctx) {
+    INNER_INSTANCE.with(|inner| {
+        let inner = inner.borrow();
+        let inner = inner.as_ref().unwrap();
+        let trap: Func = inner.exports.get("trap").unwrap();
+
+        let error = run_tracked(inner, || trap.call()).unwrap_err();
+        let report = TrapReport::from_runtime_error(&error).with_backtrace(&error);
+        CALLBACK_REPORT.with(|x| *x.borrow_mut() = Some(report));
+    })
+}
+
+#[test]
+fn test_backtrace_names_host_function_on_the_stack() {
+    static OUTER_WAT: &'static str = r#"
+        (module
+            (import "env" "log" (func $log))
+            (import "env" "callback" (func $callback))
+            (func $run_callback
+                (call $callback))
+            (func (export "run")
+                (call $run_callback)))
+    "#;
+    static INNER_WAT: &'static str = r#"
+        (module
+            (func (export "trap")
+                (unreachable)))
+    "#;
+    fn log(_ctx: &mut Ctx) {}
+
+    let _lock = lock_interrupt();
+    INNER_INSTANCE.with(|inner| {
+        *inner.borrow_mut() = Some(instantiate_tracked(INNER_WAT, &imports! {}));
+    });
+    let import_object = imports! {
+        "env" => {
+            "log" => func!(log),
+            "callback" => func!(call_back_into_wasm),
+        },
+    };
+    let outer = instantiate_tracked(OUTER_WAT, &import_object);
+    let run: Func = outer.exports.get("run").unwrap();
+
+    run_tracked(&outer, || run.call()).unwrap();
+    INNER_INSTANCE.with(|inner| inner.borrow_mut().take());
+
+    let report = CALLBACK_REPORT.with(|x| x.borrow_mut().take()).unwrap();
+    assert_eq!(report.code, Some(ExceptionCode::Unreachable));
+    let backtrace = report.backtrace.unwrap();
+    assert_eq!(
+        backtrace
+            .iter()
+            .map(|x| x.local_function_id)
+            .collect::<Vec<_>>(),
+        vec![0, 0, 1]
+    );
+    assert_eq!(
+        report.host_frames,
+        vec![HostFrame {
+            depth: 1,
+            import_index: 1,
+            name: Some("env.callback".to_string()),
+        }]
+    );
+}
+
+#[test]
+fn test_trap_report_captures_fault_timestamp() {
+    static WAT: &'static str = r#"
+        (module
+            (func (export "run")
+                (unreachable)))
+    "#;
+    let instance = instantiate_tracked(WAT, &imports! {});
+    let run: Func = instance.exports.get("run").unwrap();
+    let _lock = lock_interrupt();
+
+    let before = FaultTimestamp::now();
+    let before_wall = std::time::SystemTime::now();
+    let error = run_tracked(&instance, || run.call()).unwrap_err();
+    let after = FaultTimestamp::now();
+    let report = TrapReport::from_runtime_error(&error);
+
+    let timestamp = report.timestamp.unwrap();
+    assert!(timestamp.monotonic >= before.monotonic);
+    assert!(timestamp.monotonic <= after.monotonic);
+    let skew = timestamp
+        .system_time()
+        .duration_since(before_wall)
+        .unwrap_or_else(|e| e.duration());
+    assert!(skew < Duration::from_secs(1));
+    assert_eq!(recent_faults().last().unwrap().timestamp, timestamp);
+}
+
+#[test]
+fn test_instance_image_round_trips_through_versioned_format() {
+    static WAT: &'static str = r#"
+        (module
+            (import "env" "interrupt" (func $interrupt))
+            (memory 1)
+            (global $counter (mut i32) (i32.const 0))
+            (func (export "run")
+                (i32.store (i32.const 16) (i32.const 0xdead))
+                (global.set $counter (i32.const 7))
+                (call $interrupt)
+                (loop $spin (br $spin))))
+    "#;
+    let import_object = imports! {
+        "env" => {
+            "interrupt" => func!(interrupt),
+        },
+    };
+    let instance = instantiate_tracked(WAT, &import_object);
+    let run: Func = instance.exports.get("run").unwrap();
+    let _lock = lock_interrupt();
+    let image = expect_image(run_tracked(&instance, || run.call()));
+
+    let bytes = serialize_instance_image(&image);
+    let parsed = deserialize_instance_image(&bytes).unwrap();
+    assert_eq!(parsed.memory, image.memory);
+    assert_eq!(parsed.globals, image.globals);
+    assert_eq!(parsed.globals[0], 7);
+    assert_eq!(
+        parsed.execution_state.backtrace_frames(),
+        image.execution_state.backtrace_frames()
+    );
+    assert_eq!(parsed.memory.as_ref().unwrap()[16], 0xad);
+
+    let mut future = bytes.clone();
+    future[8..12].copy_from_slice(&(INSTANCE_IMAGE_FORMAT_VERSION + 1).to_le_bytes());
+    match deserialize_instance_image(&future) {
+        Err(InstanceImageFormatError::UnsupportedVersion(v)) => {
+            assert_eq!(v, INSTANCE_IMAGE_FORMAT_VERSION + 1)
+        }
+        other => panic!(
+            "expected an unsupported version, got {:?}",
+            other.map(|_| ())
+        ),
+    }
+    assert_eq!(
+        deserialize_instance_image(&image.to_bytes()).map(|_| ()),
+        Err(InstanceImageFormatError::BadMagic)
+    );
+    match deserialize_instance_image(&bytes[..bytes.len() / 2]) {
+        Err(InstanceImageFormatError::Malformed(_)) => {}
+        other => panic!("expected a malformed image, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_suspended_call_resumes_from_image() {
+    static WAT: &'static str = r#"
+        (module
+            (import "env" "suspend" (func $suspend))
+            (func (export "run") (result i32)
+                (local $i i32)
+                (loop $count
+                    (if (i32.eq (local.get $i) (i32.const 100))
+                        (then (call $suspend)))
+                    (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                    (br_if $count (i32.lt_u (local.get $i) (i32.const 1000))))
+                (local.get $i)))
+    "#;
+    let import_object = imports! {
+        "env" => {
+            "suspend" => func!(suspend),
+        },
+    };
+    let mut instance = instantiate_tracked(WAT, &import_object);
+    let run: Func<(), i32> = instance.exports.get("run").unwrap();
+    let _lock = lock_interrupt();
+
+    let image = expect_image(run_tracked(&instance, || run.call()).map(|_| ()));
+    assert!(image.code_hash.is_some());
+
+    let mut untracked = image.clone();
+    untracked.code_hash = None;
+    match unsafe { resume_from_image(&mut instance, untracked) } {
+        Err(ResumeError::CodeVersionMismatch { expected: None, .. }) => {}
+        other => panic!("expected a code version mismatch, got {:?}", other),
+    }
+    let mut other_instance = instantiate_tracked(
+        r#"(module (func (export "run") (result i32) (i32.const 0)))"#,
+        &imports! {},
+    );
+    match unsafe { resume_from_image(&mut other_instance, image.clone()) } {
+        Err(ResumeError::CodeVersionMismatch {
+            expected: Some(_), ..
+        }) => {}
+        other => panic!("expected a code version mismatch, got {:?}", other),
+    }
+
+    let result = unsafe { resume_from_image(&mut instance, image) };
+    assert_eq!(result.unwrap(), 1000);
+}
+
+thread_local! {
+    static OBSERVED_TRAPS: RefCell<Vec<(usize, Option<ExceptionCode>)>> = RefCell::new(vec![]);
+}
+
+#[test]
+fn test_trap_observer_sees_every_unwinding_fault() {
+    static WAT: &'static str = r#"
+        (module
+            (func (export "run") (param i32) (result i32)
+                (i32.div_u (i32.const 1) (local.get 0))))
+    "#;
+    let instance = instantiate_tracked(WAT, &imports! {});
+    let run: Func<i32, i32> = instance.exports.get("run").unwrap();
+    let _lock = lock_interrupt();
+
+    set_trap_observer(Box::new(|fault, error| {
+        let code = match error {
+            RuntimeError::InvokeError(InvokeError::TrapCode { code, .. }) => Some(*code),
+            _ => None,
+        };
+        let ip = fault.ip.get();
+        OBSERVED_TRAPS.with(|x| x.borrow_mut().push((ip, code)));
+    }));
+    let results: Vec<_> = (0..3)
+        .map(|i| run_tracked(&instance, || run.call(i)))
+        .collect();
+    clear_trap_observer();
+
+    assert!(results[0].is_err());
+    assert_eq!(results[1].as_ref().ok(), Some(&1));
+    assert_eq!(results[2].as_ref().ok(), Some(&0));
+    let observed = OBSERVED_TRAPS.with(|x| x.borrow().clone());
+    assert_eq!(observed.len(), 1);
+    assert_ne!(observed[0].0, 0);
+    assert_eq!(observed[0].1, Some(ExceptionCode::IllegalArithmetic));
+}
+
+#[test]
+fn test_trap_event_sink_receives_traps() {
+    static WAT: &'static str = r#"
+        (module
+            (func (export "run") (param i32) (result i32)
+                (i32.div_u (i32.const 1) (local.get 0))))
+    "#;
+    let instance = instantiate_tracked(WAT, &imports! {});
+    let run: Func<i32, i32> = instance.exports.get("run").unwrap();
+    let _lock = lock_interrupt();
+
+    let (tx, rx) = std::sync::mpsc::sync_channel(1);
+    set_trap_event_sink(tx);
+    let results: Vec<_> = (0..3)
+        .map(|_| run_tracked(&instance, || run.call(0)))
+        .collect();
+    assert_eq!(run_tracked(&instance, || run.call(1)).unwrap(), 1);
+    clear_trap_event_sink();
+
+    assert!(results.iter().all(|x| x.is_err()));
+    // The channel holds a single event; the others are dropped.
+    let events: Vec<TrapEvent> = rx.iter().collect();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].code, Some(ExceptionCode::IllegalArithmetic));
+    assert!(events[0].module_offset.is_some());
+    assert_eq!(events[0].thread_id, unsafe { libc::pthread_self() } as u64);
+}
+
+fn raise_sigint(_ctx: &mut Ctx) {
+    unsafe { libc::raise(libc::SIGINT) };
+}
+
+#[test]
+fn test_sigint_origin_is_carried_by_the_image() {
+    static WAT: &'static str = r#"
+        (module
+            (import "env" "raise_sigint" (func $raise_sigint))
+            (func (export "run")
+                (call $raise_sigint)
+                (loop $spin (br $spin))))
+    "#;
+    let import_object = imports! {
+        "env" => {
+            "raise_sigint" => func!(raise_sigint),
+        },
+    };
+    let instance = instantiate_tracked(WAT, &import_object);
+    let run: Func = instance.exports.get("run").unwrap();
+    let _lock = lock_interrupt();
+    ensure_sighandler();
+
+    let image = expect_image(run_tracked(&instance, || run.call()));
+    assert!(image.from_sigint);
+    let parsed = deserialize_instance_image(&serialize_instance_image(&image)).unwrap();
+    assert!(parsed.from_sigint);
+}
+
+#[test]
+fn test_max_backtrace_depth_truncates_trap_backtraces() {
+    static WAT: &'static str = r#"
+        (module
+            (func $run (export "run") (param i32)
+                (if (local.get 0)
+                    (then (call $run (i32.sub (local.get 0) (i32.const 1))))
+                    (else (unreachable)))))
+    "#;
+    let instance = instantiate_tracked(WAT, &imports! {});
+    let run: Func<i32> = instance.exports.get("run").unwrap();
+    let _lock = lock_interrupt();
+
+    set_max_backtrace_depth(Some(3));
+    assert_eq!(max_backtrace_depth(), Some(3));
+    let error = run_tracked(&instance, || run.call(10)).unwrap_err();
+    set_max_backtrace_depth(None);
+    assert_eq!(max_backtrace_depth(), None);
+
+    let report = TrapReport::from_runtime_error(&error).with_backtrace(&error);
+    assert_eq!(report.code, Some(ExceptionCode::Unreachable));
+    assert_eq!(report.backtrace.unwrap().len(), 3);
+
+    let error = run_tracked(&instance, || run.call(10)).unwrap_err();
+    let report = TrapReport::from_runtime_error(&error).with_backtrace(&error);
+    assert_eq!(report.backtrace.unwrap().len(), 11);
+}
+
+#[test]
+fn test_max_backtrace_depth_does_not_limit_suspends() {
+    let import_object = imports! {
+        "env" => {
+            "interrupt" => func!(interrupt),
+        },
+    };
+    let instance = instantiate_tracked(RECURSE_THEN_SPIN_WAT, &import_object);
+    let run: Func<i32> = instance.exports.get("run").unwrap();
+    let _lock = lock_interrupt();
+
+    set_max_backtrace_depth(Some(1));
+    let result = run_tracked(&instance, || run.call(4));
+    set_max_backtrace_depth(None);
+
+    let image = expect_image(result);
+    assert_eq!(image.execution_state.frames.len(), 5);
+    assert!(!image.execution_state.truncated);
+}
+
+thread_local! {
+    static GUARD_PAGE: Cell<usize> = Cell::new(0);
+}
+
+fn touch_guard_page(_ctx: &mut Ctx) {
+    let page = GUARD_PAGE.with(|x| x.get());
+    unsafe { std::ptr::write_volatile(page as *mut u64, 1) };
+}
+
+#[test]
+fn test_guard_region_fault_is_a_stack_overflow() {
+    static WAT: &'static str = r#"
+        (module
+            (import "env" "touch_guard_page" (func $touch_guard_page))
+            (func (export "run")
+                (call $touch_guard_page)))
+    "#;
+    let import_object = imports! {
+        "env" => {
+            "touch_guard_page" => func!(touch_guard_page),
+        },
+    };
+    let instance = instantiate_tracked(WAT, &import_object);
+    let run: Func = instance.exports.get("run").unwrap();
+    let _lock = lock_interrupt();
+
+    let page = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            4096,
+            libc::PROT_NONE,
+            libc::MAP_PRIVATE | libc::MAP_ANON,
+            -1,
+            0,
+        )
+    };
+    assert!(page as isize != -1);
+    GUARD_PAGE.with(|x| x.set(page as usize));
+    register_guard_region(page as usize, 4096).unwrap();
+    let result = run_tracked(&instance, || run.call());
+    unregister_guard_region(page as usize, 4096);
+    unsafe { libc::munmap(page, 4096) };
+
+    let error = result.unwrap_err();
+    match error {
+        RuntimeError::InvokeError(InvokeError::StackOverflow) => {}
+        ref other => panic!("expected a stack overflow, got {:?}", other),
+    }
+    assert_eq!(
+        error.to_string(),
+        "Error when calling invoke: Stack overflow"
+    );
+    assert_eq!(
+        recent_faults().last().unwrap().classification,
+        FaultClassification::StackOverflow
+    );
+}
+
+fn is_current_ctx(ctx: &mut Ctx) -> i32 {
+    let expected = ctx as *mut Ctx as usize;
+    let current = with_current_ctx(|current| current.map(|x| x as *mut Ctx as usize));
+    (current == Some(expected)) as i32
+}
+
+#[test]
+fn test_current_ctx_is_only_available_within_with_ctx() {
+    static WAT: &'static str = r#"
+        (module
+            (import "env" "is_current_ctx" (func $is_current_ctx (result i32)))
+            (func (export "run") (result i32)
+                (call $is_current_ctx)))
+    "#;
+    let import_object = imports! {
+        "env" => {
+            "is_current_ctx" => func!(is_current_ctx),
+        },
+    };
+    let instance = instantiate_tracked(WAT, &import_object);
+    let run: Func<(), i32> = instance.exports.get("run").unwrap();
+    let _lock = lock_interrupt();
+
+    assert!(with_current_ctx(|ctx| ctx.is_none()));
+    assert_eq!(run_tracked(&instance, || run.call()).unwrap(), 1);
+    assert!(with_current_ctx(|ctx| ctx.is_none()));
+}
+
+#[test]
+fn test_inline_breakpoint_types_round_trip_through_singlepass() {
+    let instance = instantiate(r#"(module (func (export "nop")))"#);
+    let module = &instance.module.runnable_module;
+    for &ty in &[
+        InlineBreakpointType::Middleware,
+        InlineBreakpointType::Trace,
+        InlineBreakpointType::Counter,
+    ] {
+        assert_eq!(InlineBreakpointType::from_u8(ty as u8), Some(ty));
+
+        // ud2; ud; int 0xff; type
+        let x64 = [0x0f, 0x0b, 0x0f, 0xb9, 0xcd, 0xff, ty as u8];
+        let ib = module
+            .read_inline_breakpoint(Architecture::X64, &x64)
+            .unwrap();
+        assert_eq!(ib.ty, ty);
+        assert_eq!(
+            Some(ib.size),
+            module.get_inline_breakpoint_size(Architecture::X64)
+        );
+
+        // udf #0; udf #65535; type
+        let aarch64 = [0, 0, 0, 0, 0xff, 0xff, 0, 0, ty as u8, 0, 0, 0];
+        let ib = module
+            .read_inline_breakpoint(Architecture::Aarch64, &aarch64)
+            .unwrap();
+        assert_eq!(ib.ty, ty);
+    }
+    let unknown = [0x0f, 0x0b, 0x0f, 0xb9, 0xcd, 0xff, 0xff];
+    assert!(module
+        .read_inline_breakpoint(Architecture::X64, &unknown)
+        .is_none());
+}
+
+fn exhaust_gas(ctx: &mut Ctx) {
+    unsafe { set_wasm_interrupt_with_reason(ctx, InterruptReason::GasExhausted) };
+}
+
+#[test]
+fn test_interrupt_reason_is_reported_and_reset() {
+    static WAT: &'static str = r#"
+        (module
+            (import "env" "exhaust_gas" (func $exhaust_gas))
+            (func (export "run")
+                (call $exhaust_gas)
+                (loop $spin (br $spin))))
+    "#;
+    let import_object = imports! {
+        "env" => {
+            "exhaust_gas" => func!(exhaust_gas),
+        },
+    };
+    let instance = instantiate_tracked(WAT, &import_object);
+    let run: Func = instance.exports.get("run").unwrap();
+    let _lock = lock_interrupt();
+
+    let error = run_tracked(&instance, || run.call()).unwrap_err();
+    match error {
+        RuntimeError::InvokeError(InvokeError::Interrupted {
+            reason: Some(InterruptReason::GasExhausted),
+            ..
+        }) => {}
+        ref other => panic!("expected an out of gas interrupt, got {:?}", other),
+    }
+    assert!(error.to_string().ends_with("(gas exhausted)"));
+    let ctx = instance.context() as *const Ctx as *mut Ctx;
+    assert_eq!(wasm_interrupt_reason(ctx), None);
+
+    run_tracked(&instance, || unsafe {
+        set_wasm_interrupt_with_reason(ctx, InterruptReason::Timeout);
+        assert_eq!(wasm_interrupt_reason(ctx), Some(InterruptReason::Timeout));
+        clear_wasm_interrupt();
+        assert_eq!(wasm_interrupt_reason(ctx), None);
+    });
+}
+
+#[test]
+fn test_frame_locals_are_readable_from_image() {
+    let import_object = imports! {
+        "env" => {
+            "interrupt" => func!(interrupt),
+        },
+    };
+    let instance = instantiate_tracked(RECURSE_THEN_SPIN_WAT, &import_object);
+    let run: Func<i32> = instance.exports.get("run").unwrap();
+
+    let _lock = lock_interrupt();
+    let image = expect_image(run_tracked(&instance, || run.call(2)));
+    assert_eq!(image.execution_state.frames.len(), 3);
+    for (i, dump) in image.execution_state.frames.iter().enumerate() {
+        let locals = image.frame_locals(i).unwrap();
+        assert_eq!(locals.len(), dump.locals.len());
+        for (value, raw) in locals.iter().zip(dump.locals.iter()) {
+            assert_eq!(value.as_u64(), *raw);
+        }
+        // The parameter counts down from the outermost frame.
+        if let WasmValue::Known(x) = locals[0] {
+            assert_eq!(x as u32, i as u32);
+        }
+        assert_eq!(image.frame_stack(i).unwrap().len(), dump.stack.len());
+    }
+    assert_eq!(image.frame_locals(3), None);
+    assert_eq!(WasmValue::from(None), WasmValue::Unavailable);
+}
+
+#[test]
+fn test_trap_code_reports_code_version_and_offset() {
+    static WAT: &'static str = r#"(module (func (export "trap") unreachable))"#;
+    let _lock = lock_interrupt();
+    let instance = instantiate_tracked(WAT, &imports! {});
+    let trap: Func<()> = instance.exports.get("trap").unwrap();
+    let code = instance.module.runnable_module.get_code().unwrap();
+
+    match run_tracked(&instance, || trap.call()) {
+        Err(RuntimeError::InvokeError(InvokeError::TrapCode {
+            code: ExceptionCode::Unreachable,
+            code_base,
+            module_offset,
+            ..
+        })) => {
+            assert_eq!(code_base, Some(code.as_ptr() as usize));
+            assert!(module_offset.unwrap() < code.len());
+        }
+        other => panic!("expected an unreachable trap, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_signal_handlers_are_suspended_and_reinstalled() {
+    unsafe fn current_handler(signal: libc::c_int) -> libc::sighandler_t {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        assert_eq!(libc::sigaction(signal, std::ptr::null(), &mut action), 0);
+        action.sa_sigaction
+    }
+
+    let _lock = lock_interrupt();
+    ensure_sighandler();
+    unsafe {
+        let ours = current_handler(libc::SIGSEGV);
+        let suspended = with_signal_handlers_suspended(|| current_handler(libc::SIGSEGV));
+        assert_ne!(suspended, ours);
+        assert_eq!(current_handler(libc::SIGSEGV), ours);
+    }
+}
+
+#[test]
+fn test_exception_table_reports_trap_sites() {
+    static WAT: &'static str = r#"(module (func (export "trap") unreachable))"#;
+    let instance = instantiate(WAT);
+    let module = &instance.module.runnable_module;
+    let table = module.get_exception_table().unwrap();
+
+    let sites = table.trap_sites(0..module.get_code().unwrap().len());
+    assert!(sites
+        .iter()
+        .any(|&(_, code)| code == ExceptionCode::Unreachable));
+    for &(offset, code) in sites.iter() {
+        assert_eq!(module.exception_code_at(offset), Some(code));
+    }
+    assert!(sites.windows(2).all(|x| x[0].0 < x[1].0));
+}
+
+#[test]
+fn test_backtrace_is_captured_from_host_function() {
+    static WAT: &'static str = r#"
+        (module
+            (import "env" "log" (func $log))
+            (func $run (export "run") (param i32)
+                (if (local.get 0)
+                    (then (call $run (i32.sub (local.get 0) (i32.const 1))))
+                    (else (call $log)))))
+    "#;
+    thread_local! {
+        static CAPTURED: RefCell<Option<ExecutionStateImage>> = RefCell::new(None);
+    }
+    fn log(_ctx: &mut Ctx) {
+        CAPTURED.with(|x| *x.borrow_mut() = capture_backtrace());
+    }
+
+    let _lock = lock_interrupt();
+    assert!(capture_backtrace().is_none());
+    let import_object = imports! {
+        "env" => {
+            "log" => func!(log),
+        },
+    };
+    let instance = instantiate_tracked(WAT, &import_object);
+    let run: Func<i32> = instance.exports.get("run").unwrap();
+    run_tracked(&instance, || run.call(2)).unwrap();
+
+    let image = CAPTURED.with(|x| x.borrow_mut().take()).unwrap();
+    assert_eq!(image.frames.len(), 3);
+    assert!(!image.truncated);
+    assert_eq!(
+        image.host_frames,
+        vec![HostFrame {
+            depth: 0,
+            import_index: 0,
+            name: Some("env.log".to_string()),
+        }]
+    );
+}
+
+#[test]
+fn test_interrupt_outside_of_ctx_reports_no_context() {
+    let _lock = lock_interrupt();
+    ensure_sighandler();
+    let result = unsafe {
+        set_wasm_interrupt();
+        catch_unsafe_unwind(
+            || std::ptr::read_volatile(get_wasm_interrupt_signal_mem()),
+            None,
+        )
+    };
+    match result {
+        Err(RuntimeError::InvokeError(InvokeError::NoContext)) => {}
+        other => panic!("expected a missing context error, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_backtrace_is_recorded_without_printing() {
+    static WAT: &'static str = r#"
+        (module
+            (func $run (export "run") (param i32)
+                (if (local.get 0)
+                    (then (call $run (i32.sub (local.get 0) (i32.const 1))))
+                    (else (unreachable)))))
+    "#;
+    let instance = instantiate_tracked(WAT, &imports! {});
+    let run: Func<i32> = instance.exports.get("run").unwrap();
+    let _lock = lock_interrupt();
+
+    set_print_backtrace_on_trap(false);
+    assert!(!print_backtrace_on_trap());
+    let error = run_tracked(&instance, || run.call(2)).unwrap_err();
+    set_print_backtrace_on_trap(true);
+    assert!(print_backtrace_on_trap());
+
+    let report = TrapReport::from_runtime_error(&error).with_backtrace(&error);
+    assert_eq!(report.code, Some(ExceptionCode::Unreachable));
+    assert_eq!(report.backtrace.unwrap().len(), 3);
+}
+
+#[test]
+fn test_exception_codes_round_trip_through_raw_values() {
+    let codes = [
+        ExceptionCode::Unreachable,
+        ExceptionCode::IncorrectCallIndirectSignature,
+        ExceptionCode::MemoryOutOfBounds,
+        ExceptionCode::CallIndirectOOB,
+        ExceptionCode::IllegalArithmetic,
+        ExceptionCode::MisalignedAtomicAccess,
+        ExceptionCode::CallStackExhausted,
+    ];
+    for &code in codes.iter() {
+        assert_eq!(ExceptionCode::from_u32(code as u32), Some(code));
+    }
+    assert_eq!(ExceptionCode::from_u32(codes.len() as u32), None);
+    assert_eq!(
+        ExceptionCode::MemoryOutOfBounds.to_string(),
+        "memory out-of-bounds access"
+    );
+}
+
+#[test]
+fn test_interrupt_ctx_thread_wakes_blocked_host_call() {
+    static WAT: &'static str = r#"
+        (module
+            (import "env" "block" (func $block))
+            (func (export "run")
+                (call $block)
+                (loop (br 0))))
+    "#;
+    static BLOCKING: AtomicUsize = AtomicUsize::new(0);
+    extern "C" fn wake(_: libc::c_int) {}
+    fn block(_ctx: &mut Ctx) {
+        BLOCKING.store(1, Ordering::SeqCst);
+        unsafe { libc::sleep(10) };
+    }
+
+    let _lock = lock_interrupt();
+    ensure_sighandler();
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = wake as libc::sighandler_t;
+        assert_eq!(
+            libc::sigaction(libc::SIGUSR1, &action, std::ptr::null_mut()),
+            0
+        );
+    }
+
+    let (ctx_tx, ctx_rx) = std::sync::mpsc::channel();
+    let started = std::time::Instant::now();
+    let worker = std::thread::spawn(move || {
+        let import_object = imports! {
+            "env" => {
+                "block" => func!(block),
+            },
+        };
+        let instance = instantiate_tracked(WAT, &import_object);
+        ctx_tx
+            .send(instance.context() as *const Ctx as usize)
+            .unwrap();
+        let run: Func = instance.exports.get("run").unwrap();
+        format!("{:?}", run_tracked(&instance, || run.call()))
+    });
+    let ctx = ctx_rx.recv().unwrap() as *mut Ctx;
+    while BLOCKING.load(Ordering::SeqCst) == 0 {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(unsafe { interrupt_ctx_thread(ctx, libc::SIGUSR1) });
+
+    let result = worker.join().unwrap();
+    assert!(result.contains("Interrupted"), "{}", result);
+    assert!(started.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn test_interrupt_ctx_thread_does_not_arm_if_signaling_fails() {
+    static WAT: &'static str = r#"(module (func (export "nop")))"#;
+    let instance = instantiate_tracked(WAT, &imports! {});
+    let ctx = instance.context() as *const Ctx as *mut Ctx;
+
+    let _lock = lock_interrupt();
+    run_tracked(&instance, || {
+        // Not a valid signal number, so `pthread_kill` fails.
+        assert!(!unsafe { interrupt_ctx_thread(ctx, -1) });
+        assert!(!is_wasm_interrupt_set());
+    });
+}
+
+#[test]
+fn test_run_wasm_restores_ctx_after_unwind() {
+    static WAT: &'static str = r#"
+        (module
+            (func (export "run") (result i32)
+                (i32.const 7)))
+    "#;
+    let instance = instantiate(WAT);
+    let run: Func<(), i32> = instance.exports.get("run").unwrap();
+    let ctx = instance.context() as *const Ctx as *mut Ctx;
+    let current_ctx = || with_current_ctx(|ctx| ctx.map(|x| x as *mut Ctx));
+    let _lock = lock_interrupt();
+
+    let result = unsafe {
+        run_wasm(ctx, None, || {
+            assert_eq!(current_ctx(), Some(ctx));
+            assert!(is_in_catch_scope());
+            run.call().unwrap()
+        })
+    };
+    assert_eq!(result.unwrap(), 7);
+
+    let result = unsafe {
+        run_wasm(ctx, None, || {
+            begin_unsafe_unwind(Box::new(RuntimeError::InvokeError(InvokeError::Timeout)))
+        })
+    };
+    match result {
+        Err(RuntimeError::InvokeError(InvokeError::Timeout)) => {}
+        other => panic!("expected a timeout, got {:?}", other.err()),
+    }
+    assert_eq!(current_ctx(), None);
+    assert!(!is_in_catch_scope());
+}
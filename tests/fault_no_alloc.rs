@@ -0,0 +1,110 @@
+#![cfg(all(unix, feature = "backend-singlepass", test))]
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use wabt::wat2wasm;
+use wasmer::imports;
+use wasmer::vm::Ctx;
+use wasmer::Func;
+use wasmer_runtime_core::backend::{CompilerConfig, ExceptionCode};
+use wasmer_runtime_core::compile_with_config;
+use wasmer_runtime_core::error::{InvokeError, RuntimeError};
+use wasmer_runtime_core::fault::*;
+use wasmer_runtime_core::state::CodeVersion;
+use wasmer_singlepass_backend::SinglePassCompiler;
+
+/// Counts the allocations made by the whole process. This lives in its own test binary so
+/// that no other test allocates concurrently.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+static FRAMES: AtomicUsize = AtomicUsize::new(0);
+static FRAME_IDS: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+static ALLOCATIONS_DURING_WALK: AtomicUsize = AtomicUsize::new(0);
+
+fn count_frames(fault: &mut FaultInfo) -> RecoveryAction {
+    let before = ALLOCATIONS.load(Ordering::SeqCst);
+    unsafe {
+        fault.for_each_frame(&mut |frame| {
+            let i = FRAMES.fetch_add(1, Ordering::SeqCst);
+            if i < FRAME_IDS.len() {
+                FRAME_IDS[i].store(frame.local_function_id, Ordering::SeqCst);
+            }
+        });
+    }
+    ALLOCATIONS_DURING_WALK.store(
+        ALLOCATIONS.load(Ordering::SeqCst) - before,
+        Ordering::SeqCst,
+    );
+    RecoveryAction::Unwind
+}
+
+#[test]
+fn test_for_each_frame_does_not_allocate() {
+    static WAT: &'static str = r#"
+        (module
+            (func $fail
+                (unreachable))
+            (func (export "run")
+                (call $fail)))
+    "#;
+    let wasm_binary = wat2wasm(WAT).unwrap();
+    let module = compile_with_config(
+        &wasm_binary,
+        &SinglePassCompiler::new(),
+        CompilerConfig {
+            track_state: true,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let instance = module.instantiate(&imports! {}).unwrap();
+    let run: Func = instance.exports.get("run").unwrap();
+
+    let runnable_module = instance.module.runnable_module.clone();
+    push_code_version(CodeVersion {
+        baseline: true,
+        msm: runnable_module.get_module_state_map().unwrap(),
+        base: runnable_module.get_code().unwrap().as_ptr() as usize,
+        backend: "singlepass",
+        runnable_module,
+        function_names: None,
+    });
+    set_trap_recovery_handler(ExceptionCode::Unreachable, count_frames);
+    let ctx = instance.context() as *const Ctx as *mut Ctx;
+    let result = unsafe { with_ctx(ctx, || run.call()) };
+    clear_trap_recovery_handler(ExceptionCode::Unreachable);
+    pop_code_version().unwrap();
+
+    match result {
+        Err(RuntimeError::InvokeError(InvokeError::TrapCode {
+            code: ExceptionCode::Unreachable,
+            ..
+        })) => {}
+        other => panic!("expected an unreachable trap, got {:?}", other),
+    }
+    assert_eq!(FRAMES.load(Ordering::SeqCst), 2);
+    assert_eq!(FRAME_IDS[0].load(Ordering::SeqCst), 0);
+    assert_eq!(FRAME_IDS[1].load(Ordering::SeqCst), 1);
+    assert_eq!(ALLOCATIONS_DURING_WALK.load(Ordering::SeqCst), 0);
+}
@@ -133,6 +133,7 @@ fn middleware_test_points_reduced_after_call(backend: &'static str, compiler: im
             base: instance.module.runnable_module.get_code().unwrap().as_ptr() as usize,
             backend: &backend,
             runnable_module: instance.module.runnable_module.clone(),
+            function_names: None,
         });
         true
     } else {
@@ -169,6 +170,7 @@ fn middleware_test_traps_after_costly_call(backend: &'static str, compiler: impl
             base: instance.module.runnable_module.get_code().unwrap().as_ptr() as usize,
             backend: &backend,
             runnable_module: instance.module.runnable_module.clone(),
+            function_names: None,
         });
         true
     } else {